@@ -1,5 +1,6 @@
 #![feature(thread_local)]
 #![feature(allocator_api)]
+#![feature(naked_functions)]
 
 extern crate mallockit;
 
@@ -11,10 +12,12 @@ use mallockit::{
 
 const FREELIST_SPACE: SpaceId = SpaceId::DEFAULT;
 const LARGE_OBJECT_SPACE: SpaceId = SpaceId::LARGE_OBJECT_SPACE;
+/// Objects needing larger cells go to the large object space.
+const MAX_CELL_SIZE: usize = Size2M::BYTES;
 
 #[mallockit::plan]
 struct Buddy {
-    freelist_space: FreeListSpace,
+    freelist_space: FreeListSpace<true>,
     large_object_space: LargeObjectSpace,
 }
 
@@ -23,7 +26,7 @@ impl Plan for Buddy {
 
     fn new() -> Self {
         Self {
-            freelist_space: FreeListSpace::new(FREELIST_SPACE),
+            freelist_space: FreeListSpace::new(FREELIST_SPACE).with_max_cell_size(MAX_CELL_SIZE),
             large_object_space: LargeObjectSpace::new(LARGE_OBJECT_SPACE),
         }
     }
@@ -31,16 +34,34 @@ impl Plan for Buddy {
     fn get_layout(ptr: Address) -> Layout {
         debug_assert!(FREELIST_SPACE.contains(ptr) || LARGE_OBJECT_SPACE.contains(ptr));
         if FREELIST_SPACE.contains(ptr) {
-            FreeListSpace::get_layout(ptr)
+            FreeListSpace::<true>::get_layout(ptr)
         } else {
             Self::get().large_object_space.get_layout::<Size4K>(ptr)
         }
     }
+
+    fn good_size(layout: Layout) -> usize {
+        if Self::get().freelist_space.can_allocate(layout) {
+            FreeListSpace::<true>::good_size(layout)
+        } else {
+            LargeObjectSpace::good_size::<Size4K>(layout)
+        }
+    }
+
+    fn fork_prepare(&'static self) {
+        self.freelist_space.fork_prepare();
+        self.large_object_space.fork_prepare();
+    }
+
+    fn fork_parent(&'static self) {
+        self.large_object_space.fork_release();
+        self.freelist_space.fork_release();
+    }
 }
 
 #[mallockit::mutator]
 struct BuddyMutator {
-    freelist: FreeListAllocator,
+    freelist: FreeListAllocator<true>,
     los: LargeObjectAllocator<Size4K>,
 }
 
@@ -55,7 +76,7 @@ impl Mutator for BuddyMutator {
     }
 
     fn alloc(&mut self, layout: Layout) -> Option<Address> {
-        if FreeListSpace::can_allocate(layout) {
+        if Self::plan().freelist_space.can_allocate(layout) {
             mallockit::stat::track_allocation(layout, false);
             self.freelist.alloc(layout)
         } else {
@@ -74,4 +95,52 @@ impl Mutator for BuddyMutator {
             self.los.dealloc(ptr)
         }
     }
+
+    fn dealloc_sized(&mut self, ptr: Address, layout: Layout) {
+        debug_assert!(FREELIST_SPACE.contains(ptr) || LARGE_OBJECT_SPACE.contains(ptr));
+        if FREELIST_SPACE.contains(ptr) {
+            mallockit::stat::track_deallocation(false);
+            self.freelist.dealloc_sized(ptr, layout)
+        } else {
+            mallockit::stat::track_deallocation(false);
+            self.los.dealloc_sized(ptr, layout)
+        }
+    }
+
+    /// Only shrink in place by less than half. Cells and page runs are powers of two, so the new size
+    /// still identifies them in `dealloc_sized`.
+    fn realloc(&mut self, ptr: Address, new_layout: Layout) -> Option<Address> {
+        let layout = Buddy::get_layout(ptr);
+        if layout.size() >= new_layout.size()
+            && new_layout.size() > layout.size() / 2
+            && layout.align() >= new_layout.align()
+        {
+            return Some(ptr);
+        }
+        let new_ptr = self.alloc(new_layout)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                ptr.as_ptr::<u8>(),
+                new_ptr.as_mut_ptr::<u8>(),
+                usize::min(layout.size(), new_layout.size()),
+            );
+        }
+        self.dealloc(ptr);
+        Some(new_ptr)
+    }
+
+    fn on_thread_exit(&mut self) {
+        self.freelist.flush();
+        self.los.flush();
+    }
+}
+
+#[cfg(test)]
+mod thread_exit_tests {
+    mallockit::rust_allocator_tests!(crate::Global, short_lived_threads);
+}
+
+#[cfg(test)]
+mod cross_thread_tests {
+    mallockit::rust_allocator_tests!(crate::Global, producer_consumer);
 }
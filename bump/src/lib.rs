@@ -1,5 +1,6 @@
 #![feature(thread_local)]
 #![feature(allocator_api)]
+#![feature(naked_functions)]
 
 extern crate mallockit;
 
@@ -29,6 +30,14 @@ impl Plan for Bump {
         debug_assert!(IMMORTAL_SPACE.contains(ptr));
         ImmortalSpace::get_layout(ptr)
     }
+
+    fn fork_prepare(&'static self) {
+        self.immortal.fork_prepare();
+    }
+
+    fn fork_parent(&'static self) {
+        self.immortal.fork_release();
+    }
 }
 
 #[mallockit::mutator]
@@ -50,4 +59,8 @@ impl Mutator for BumpMutator {
     }
 
     fn dealloc(&mut self, _: Address) {}
+
+    fn on_thread_exit(&mut self) {
+        self.bump.flush();
+    }
 }
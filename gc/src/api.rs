@@ -0,0 +1,118 @@
+//! The allocation API of the Boehm-Demers-Weiser collector, for C programs linked against it.
+#![allow(non_snake_case)]
+
+use crate::{gc_space::ObjectKind, Gc, GcMutator};
+use mallockit::{
+    libc,
+    util::{constants::MIN_ALIGNMENT, *},
+    Mutator, Plan,
+};
+
+fn alloc(size: usize, kind: ObjectKind) -> *mut libc::c_void {
+    let Ok(layout) = Layout::from_size_align(size.max(MIN_ALIGNMENT), MIN_ALIGNMENT) else {
+        return std::ptr::null_mut();
+    };
+    match GcMutator::current().alloc_object(layout, kind) {
+        Some(ptr) => ptr.as_mut_ptr(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn GC_init() {
+    GcMutator::current();
+}
+
+/// A collectable object, scanned for pointers. Its memory is zeroed.
+#[no_mangle]
+pub extern "C" fn GC_malloc(size: usize) -> *mut libc::c_void {
+    alloc(size, ObjectKind::Normal)
+}
+
+/// A collectable object that holds no pointers. Its memory is not zeroed.
+#[no_mangle]
+pub extern "C" fn GC_malloc_atomic(size: usize) -> *mut libc::c_void {
+    alloc(size, ObjectKind::Atomic)
+}
+
+/// An object that is scanned for pointers, but only freed by `GC_free`, like one from `malloc`.
+#[no_mangle]
+pub extern "C" fn GC_malloc_uncollectable(size: usize) -> *mut libc::c_void {
+    alloc(size, ObjectKind::Uncollectable)
+}
+
+/// # Safety
+///
+/// `ptr` must be null or the start of an object.
+#[no_mangle]
+pub unsafe extern "C" fn GC_realloc(ptr: *mut libc::c_void, size: usize) -> *mut libc::c_void {
+    if ptr.is_null() {
+        return GC_malloc(size);
+    }
+    if size == 0 {
+        GC_free(ptr);
+        return std::ptr::null_mut();
+    }
+    let Ok(layout) = Layout::from_size_align(size, MIN_ALIGNMENT) else {
+        return std::ptr::null_mut();
+    };
+    match GcMutator::current().realloc(ptr.into(), layout) {
+        Some(ptr) => ptr.as_mut_ptr(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `ptr` must be null or the start of an object that was not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn GC_free(ptr: *mut libc::c_void) {
+    if !ptr.is_null() {
+        GcMutator::current().dealloc(ptr.into());
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn GC_gcollect() {
+    Gc::get().collect();
+}
+
+/// The start of the object that `ptr` points into, or null.
+#[no_mangle]
+pub extern "C" fn GC_base(ptr: *mut libc::c_void) -> *mut libc::c_void {
+    match Gc::get().space.find_object(ptr as usize) {
+        Some((start, _, _)) => start.as_mut_ptr(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// The usable size of the object that `ptr` points into, or zero.
+#[no_mangle]
+pub extern "C" fn GC_size(ptr: *mut libc::c_void) -> usize {
+    match Gc::get().space.find_object(ptr as usize) {
+        Some((_, bytes, _)) => bytes,
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn GC_get_heap_size() -> usize {
+    Gc::get().space.heap_bytes()
+}
+
+/// Collections so far.
+#[no_mangle]
+pub extern "C" fn GC_get_gc_no() -> usize {
+    Gc::get().collector.collections()
+}
+
+/// Stop allocations from triggering collections, until a matching `GC_enable`.
+#[no_mangle]
+pub extern "C" fn GC_disable() {
+    Gc::get().collector.disable();
+}
+
+#[no_mangle]
+pub extern "C" fn GC_enable() {
+    Gc::get().collector.enable();
+}
@@ -0,0 +1,195 @@
+use crate::{
+    gc_space::GcSpace,
+    mark::{MarkStack, Marker, Marking},
+    roots::{self, ThreadRegistry},
+};
+use mallockit::{libc, util::*, worker::WorkerGroup};
+use spin::Mutex;
+use std::{
+    ffi::CStr,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// Data segments scanned, at most.
+const MAX_SEGMENTS: usize = 1024;
+/// Collectable bytes allocated before the first automatic collection.
+const MIN_TRIGGER_BYTES: usize = 4 << 20;
+/// Automatic collections start once the collectable bytes allocated since the last one reach
+/// this fraction of the heap.
+const FREE_SPACE_DIVISOR: usize = 3;
+/// Marking threads by default, including the collecting thread.
+const MAX_DEFAULT_MARKERS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    /// Threads that mark, including the collecting thread.
+    pub markers: usize,
+    /// Whether allocations trigger collections, or only `GC_gcollect` does.
+    pub auto_collect: bool,
+}
+
+impl GcConfig {
+    /// Read `GC_MARKERS` and `GC_DONT_GC`, with the meaning they have for the Boehm collector.
+    ///
+    /// Uses `getenv` directly, as this runs inside the first `malloc`.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(markers) = getenv(c"GC_MARKERS")
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            config.markers = markers.max(1);
+        }
+        if getenv(c"GC_DONT_GC").is_some() {
+            config.auto_collect = false;
+        }
+        config
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        Self {
+            markers: (cpus.max(1) as usize).min(MAX_DEFAULT_MARKERS),
+            auto_collect: true,
+        }
+    }
+}
+
+fn getenv(name: &CStr) -> Option<&'static [u8]> {
+    let value = unsafe { libc::getenv(name.as_ptr()) };
+    if value.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(value) }.to_bytes())
+}
+
+struct CollectorState {
+    segments: [Range<Address>; MAX_SEGMENTS],
+    /// The mark stack of the collecting thread.
+    stack: MarkStack,
+}
+
+/// Stop-the-world conservative mark-sweep collection of a `GcSpace`.
+///
+/// Roots are the stacks of registered threads, the data segments of the executable and its libraries, and
+/// uncollectable objects. Every word in them that points into an allocated object keeps it alive.
+///
+/// A collection takes its own lock, then the space lock and the registry lock, so that no stopped thread
+/// holds a lock the collection needs. This includes the metadata allocator, which threads only enter with
+/// one of these locks held.
+pub struct Collector {
+    pub threads: ThreadRegistry,
+    state: Mutex<CollectorState>,
+    pub marking: Marking,
+    pub markers: WorkerGroup<Marker>,
+    markers_spawned: AtomicBool,
+    auto_collect: bool,
+    /// `GC_disable` calls not yet matched by `GC_enable`.
+    disabled: AtomicUsize,
+    collections: AtomicUsize,
+}
+
+impl Collector {
+    pub fn new(config: GcConfig) -> Self {
+        Self {
+            threads: ThreadRegistry::new(),
+            state: Mutex::new(CollectorState {
+                segments: [const { Address::ZERO..Address::ZERO }; MAX_SEGMENTS],
+                stack: MarkStack::local(),
+            }),
+            marking: Marking::new(),
+            markers: WorkerGroup::new(config.markers - 1),
+            markers_spawned: AtomicBool::new(false),
+            auto_collect: config.auto_collect,
+            disabled: AtomicUsize::new(0),
+            collections: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn collections(&self) -> usize {
+        self.collections.load(Ordering::Relaxed)
+    }
+
+    pub fn disable(&self) {
+        self.disabled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn enable(&self) {
+        let _ = self
+            .disabled
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    /// Whether an allocation should collect before taking more memory from `space`.
+    pub fn should_collect(&self, space: &GcSpace) -> bool {
+        self.auto_collect
+            && self.disabled.load(Ordering::Relaxed) == 0
+            && space.allocated_since_sweep()
+                >= usize::max(MIN_TRIGGER_BYTES, space.heap_bytes() / FREE_SPACE_DIVISOR)
+    }
+
+    /// Spawn the marker threads on the first collection, outside of all locks, as thread creation
+    /// may allocate.
+    fn spawn_markers(&'static self) {
+        if !self.markers_spawned.swap(true, Ordering::Relaxed) {
+            self.markers.spawn();
+        }
+    }
+
+    /// Free every collectable object of `space` that is not reachable from the roots.
+    pub fn collect(&'static self, space: &GcSpace) {
+        self.threads.register_current_thread();
+        self.spawn_markers();
+        let mut state = self.state.lock();
+        let CollectorState { segments, stack } = &mut *state;
+        // Libraries are not loaded or unloaded while threads are stopped, as that takes a loader lock.
+        let n = roots::data_segments(segments);
+        let segments = &segments[..n];
+        let mut heap = space.lock();
+        let threads = self.threads.lock();
+        roots::with_registers_on_stack(|sp| {
+            roots::stop_the_world(&threads);
+            for thread in threads.iter() {
+                if let Some(range) = thread.range(sp) {
+                    self.marking.push(range);
+                }
+            }
+            for segment in segments {
+                self.marking.push(segment.clone());
+            }
+            space.for_each_uncollectable(|object, bytes| {
+                if space.mark(object) {
+                    self.marking.push(object..object + bytes);
+                }
+            });
+            self.marking.start();
+            self.markers.notify_all();
+            self.marking.drain(space, stack);
+            space.sweep(&mut heap);
+            roots::resume_the_world();
+        });
+        self.collections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn fork_prepare(&self, space: &GcSpace) {
+        std::mem::forget(self.state.lock());
+        space.fork_prepare();
+        self.threads.fork_prepare();
+    }
+
+    pub fn fork_parent(&self, space: &GcSpace) {
+        self.threads.fork_release();
+        space.fork_release();
+        unsafe { self.state.force_unlock() };
+    }
+
+    pub fn fork_child(&self, space: &GcSpace) {
+        self.fork_parent(space);
+        self.threads.retain_current_after_fork();
+        // The marker threads do not exist in the child.
+        self.markers_spawned.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,729 @@
+use mallockit::{
+    space::{
+        meta::{Meta, Vec},
+        side_metadata::{SideMetadata, SideMetadataSpec},
+        SpaceId,
+    },
+    util::{
+        constants::LOG_MIN_ALIGNMENT,
+        mem::{
+            freelist::{
+                tree_freelist::{FitPolicy, TreeFreeList},
+                FreeListEngine,
+            },
+            heap::HEAP,
+        },
+        sys::RawMemory,
+        *,
+    },
+};
+use spin::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+const LOG_BLOCK_BYTES: usize = 15;
+/// Small objects are carved out of blocks of one size class. Larger ones take a run of whole blocks.
+pub const BLOCK_BYTES: usize = 1 << LOG_BLOCK_BYTES;
+const MAX_CELL_BYTES: usize = BLOCK_BYTES / 4;
+const SIZE_CLASSES: usize = SizeClass::<4>::from_bytes(MAX_CELL_BYTES).as_usize() + 1;
+/// Words of alloc or mark bits covering a block, one bit per granule.
+const BITMAP_WORDS: usize = (BLOCK_BYTES >> LOG_MIN_ALIGNMENT) / usize::BITS as usize;
+/// Virtual memory of the space.
+const ARENA_BYTES: usize = 64 << 30;
+const BLOCKS: usize = ARENA_BYTES / BLOCK_BYTES;
+
+/// A bit per granule, set at the start of each allocated object.
+const ALLOC_BITS: SideMetadataSpec = SideMetadataSpec::new("alloc", LOG_MIN_ALIGNMENT, 0);
+/// A bit per granule, set at the start of each object found reachable by the current collection.
+const MARK_BITS: SideMetadataSpec = SideMetadataSpec::new("mark", LOG_MIN_ALIGNMENT, 0);
+
+/// Block kinds.
+const FREE: u8 = 0;
+/// Cells of one size class.
+const SMALL: u8 = 1;
+/// The first block of a large object.
+const LARGE: u8 = 2;
+/// The other blocks of a large object.
+const LARGE_TAIL: u8 = 3;
+
+/// States of small blocks.
+const ATTACHED: u8 = 0;
+/// Detached, with free cells, and in the partial list of its kind and size class.
+const LISTED: u8 = 1;
+/// Detached and full. Listed again by the first free or collection that frees a cell.
+const UNLISTED: u8 = 2;
+
+/// What the collector does with an object.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    /// Scanned for pointers, and freed once unreachable. Zeroed on allocation.
+    Normal = 0,
+    /// Freed once unreachable, but never scanned: it holds no pointers.
+    Atomic = 1,
+    /// Scanned, but only ever freed explicitly. A root for the collector.
+    Uncollectable = 2,
+}
+
+const KINDS: usize = 3;
+
+impl ObjectKind {
+    pub const fn is_collectable(self) -> bool {
+        !matches!(self, Self::Uncollectable)
+    }
+
+    pub const fn is_scanned(self) -> bool {
+        !matches!(self, Self::Atomic)
+    }
+
+    const fn from_u8(kind: u8) -> Self {
+        match kind {
+            0 => Self::Normal,
+            1 => Self::Atomic,
+            _ => Self::Uncollectable,
+        }
+    }
+}
+
+#[repr(C)]
+struct BlockMeta {
+    kind: AtomicU8,
+    object_kind: AtomicU8,
+    size_class: AtomicU8,
+    state: AtomicU8,
+    /// Blocks of the object for `LARGE`. Blocks back to the first block of the object for `LARGE_TAIL`.
+    blocks: AtomicU32,
+}
+
+impl BlockMeta {
+    fn size_class(&self) -> SizeClass {
+        SizeClass(self.size_class.load(Ordering::Relaxed))
+    }
+
+    fn object_kind(&self) -> ObjectKind {
+        ObjectKind::from_u8(self.object_kind.load(Ordering::Relaxed))
+    }
+
+    fn cells(&self) -> usize {
+        BLOCK_BYTES >> self.size_class().log_bytes()
+    }
+}
+
+/// The parts of a `GcSpace` changed under its lock.
+pub struct GcState {
+    /// Free runs of blocks.
+    runs: TreeFreeList,
+    /// Detached small blocks with free cells, per object kind and size class.
+    partial: [[Vec<u32>; SIZE_CLASSES]; KINDS],
+}
+
+/// A non-moving heap for a conservative mark-sweep collector.
+///
+/// Every object starts at a granule whose alloc bit is set, so that any word that points into an
+/// allocated object can be traced back to it. Small objects live in blocks of one size class and object
+/// kind, and are allocated by their owner thread from a free list threaded through the cells whose alloc
+/// bit is clear. Large objects take a run of blocks of their own.
+///
+/// Sweeping clears the alloc bit of every unmarked collectable object. Sweeps run with all mutators
+/// stopped, so cells allocated from thread-local free lists in the meantime are never swept.
+pub struct GcSpace {
+    start: Address,
+    metas: Address,
+    alloc_bits: SideMetadata,
+    mark_bits: SideMetadata,
+    state: Mutex<GcState>,
+    /// Blocks below this have been used at some point.
+    limit: AtomicUsize,
+    heap_blocks: AtomicUsize,
+    /// Bytes of collectable memory handed out since the last sweep.
+    allocated_since_sweep: AtomicUsize,
+}
+
+impl GcSpace {
+    pub fn new(id: SpaceId) -> Self {
+        let start = HEAP.get_space_range(id).start;
+        let meta_bytes =
+            (BLOCKS * std::mem::size_of::<BlockMeta>()).next_multiple_of(Size4K::BYTES);
+        let mut runs = TreeFreeList::new(FitPolicy::BestFit);
+        runs.add_memory(start..start + ARENA_BYTES);
+        Self {
+            start,
+            metas: RawMemory::map_anonymous(meta_bytes).unwrap(),
+            alloc_bits: SideMetadata::new(id, ALLOC_BITS),
+            mark_bits: SideMetadata::new(id, MARK_BITS),
+            state: Mutex::new(GcState {
+                runs,
+                partial: std::array::from_fn(|_| std::array::from_fn(|_| Vec::new_in(Meta))),
+            }),
+            limit: AtomicUsize::new(0),
+            heap_blocks: AtomicUsize::new(0),
+            allocated_since_sweep: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_small(layout: Layout) -> bool {
+        SizeClass::<4>::from_layout(layout).bytes() <= MAX_CELL_BYTES
+    }
+
+    /// The usable size of an object allocated with `layout`.
+    pub fn good_size(layout: Layout) -> usize {
+        if Self::is_small(layout) {
+            SizeClass::<4>::from_layout(layout).bytes()
+        } else {
+            Self::large_blocks(layout) << LOG_BLOCK_BYTES
+        }
+    }
+
+    pub fn get_layout(&self, ptr: Address) -> Layout {
+        let meta = self.meta(self.block_of(ptr));
+        match meta.kind.load(Ordering::Relaxed) {
+            SMALL => meta.size_class().layout(),
+            _ => {
+                let bytes = (meta.blocks.load(Ordering::Relaxed) as usize) << LOG_BLOCK_BYTES;
+                unsafe { Layout::from_size_align_unchecked(bytes, BLOCK_BYTES) }
+            }
+        }
+    }
+
+    pub fn object_kind(&self, ptr: Address) -> ObjectKind {
+        self.meta(self.block_of(ptr)).object_kind()
+    }
+
+    /// Memory of the blocks in use.
+    pub fn heap_bytes(&self) -> usize {
+        self.heap_blocks.load(Ordering::Relaxed) << LOG_BLOCK_BYTES
+    }
+
+    pub fn allocated_since_sweep(&self) -> usize {
+        self.allocated_since_sweep.load(Ordering::Relaxed)
+    }
+
+    const fn large_blocks(layout: Layout) -> usize {
+        let blocks = layout.size().div_ceil(BLOCK_BYTES);
+        if blocks == 0 {
+            1
+        } else {
+            blocks
+        }
+    }
+
+    fn block_of(&self, ptr: Address) -> u32 {
+        ((ptr - self.start) >> LOG_BLOCK_BYTES) as u32
+    }
+
+    fn block_start(&self, block: u32) -> Address {
+        self.start + ((block as usize) << LOG_BLOCK_BYTES)
+    }
+
+    fn meta(&self, block: u32) -> &'static BlockMeta {
+        debug_assert!((block as usize) < BLOCKS);
+        unsafe { (self.metas + block as usize * std::mem::size_of::<BlockMeta>()).as_ref() }
+    }
+
+    /// The alloc or mark bits of a block, as words.
+    fn bitmap(&self, bits: &SideMetadata, block: u32) -> &'static [AtomicUsize; BITMAP_WORDS] {
+        // Blocks are aligned, so their bits start at a word boundary of the table.
+        let (table, _) = bits.metadata_address(self.block_start(block));
+        unsafe { table.as_ref() }
+    }
+
+    /// Allocated objects in a small block.
+    fn live_cells(&self, block: u32) -> usize {
+        self.bitmap(&self.alloc_bits, block)
+            .iter()
+            .map(|w| w.load(Ordering::SeqCst).count_ones() as usize)
+            .sum()
+    }
+
+    /// The start, size and kind of the allocated object that `value` points into, if any.
+    ///
+    /// Any word can be passed, including ones that are not pointers at all.
+    pub fn find_object(&self, value: usize) -> Option<(Address, usize, ObjectKind)> {
+        let offset = value.wrapping_sub(usize::from(self.start));
+        if offset >= self.limit.load(Ordering::Relaxed) << LOG_BLOCK_BYTES {
+            return None;
+        }
+        let mut block = (offset >> LOG_BLOCK_BYTES) as u32;
+        let mut meta = self.meta(block);
+        match meta.kind.load(Ordering::Relaxed) {
+            SMALL => {
+                let log_bytes = meta.size_class().log_bytes();
+                let cell = self.start + ((offset >> log_bytes) << log_bytes);
+                self.is_allocated(cell)
+                    .then(|| (cell, 1 << log_bytes, meta.object_kind()))
+            }
+            LARGE | LARGE_TAIL => {
+                if meta.kind.load(Ordering::Relaxed) == LARGE_TAIL {
+                    block -= meta.blocks.load(Ordering::Relaxed);
+                    meta = self.meta(block);
+                }
+                let start = self.block_start(block);
+                let bytes = (meta.blocks.load(Ordering::Relaxed) as usize) << LOG_BLOCK_BYTES;
+                self.is_allocated(start)
+                    .then(|| (start, bytes, meta.object_kind()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_allocated(&self, ptr: Address) -> bool {
+        self.alloc_bits.load(ptr, Ordering::Relaxed) != 0
+    }
+
+    /// Mark an object. Returns false if it was marked already.
+    pub fn mark(&self, object: Address) -> bool {
+        self.mark_bits.fetch_or(object, 1, Ordering::Relaxed) == 0
+    }
+
+    pub fn is_marked(&self, object: Address) -> bool {
+        self.mark_bits.load(object, Ordering::Relaxed) != 0
+    }
+
+    /// Visit every allocated uncollectable object, with its size.
+    pub fn for_each_uncollectable(&self, mut f: impl FnMut(Address, usize)) {
+        let limit = self.limit.load(Ordering::Acquire) as u32;
+        let mut block = 0;
+        while block < limit {
+            let meta = self.meta(block);
+            let kind = meta.kind.load(Ordering::Relaxed);
+            if kind == LARGE {
+                let start = self.block_start(block);
+                let blocks = meta.blocks.load(Ordering::Relaxed);
+                if meta.object_kind() == ObjectKind::Uncollectable && self.is_allocated(start) {
+                    f(start, (blocks as usize) << LOG_BLOCK_BYTES);
+                }
+                block += blocks;
+                continue;
+            }
+            if kind == SMALL && meta.object_kind() == ObjectKind::Uncollectable {
+                let bytes = meta.size_class().bytes();
+                let start = self.block_start(block);
+                for (w, word) in self.bitmap(&self.alloc_bits, block).iter().enumerate() {
+                    let mut bits = word.load(Ordering::Relaxed);
+                    while bits != 0 {
+                        let granule = w * usize::BITS as usize + bits.trailing_zeros() as usize;
+                        bits &= bits - 1;
+                        f(start + (granule << LOG_MIN_ALIGNMENT), bytes);
+                    }
+                }
+            }
+            block += 1;
+        }
+    }
+
+    fn note_blocks_used(&self, end: u32) {
+        self.limit.fetch_max(end as usize, Ordering::AcqRel);
+    }
+
+    /// Take a detached block with free cells, or a new one.
+    fn acquire_block(&self, kind: ObjectKind, size_class: SizeClass) -> Option<u32> {
+        let mut state = self.state.lock();
+        let block = match state.partial[kind as usize][size_class.as_usize()].pop() {
+            Some(block) => block,
+            None => {
+                let run = state.runs.allocate_cell(BLOCK_BYTES)?;
+                let block = self.block_of(run.start);
+                let meta = self.meta(block);
+                meta.object_kind.store(kind as u8, Ordering::Relaxed);
+                meta.size_class.store(size_class.0, Ordering::Relaxed);
+                meta.blocks.store(1, Ordering::Relaxed);
+                meta.kind.store(SMALL, Ordering::Release);
+                self.note_blocks_used(block + 1);
+                self.heap_blocks.fetch_add(1, Ordering::Relaxed);
+                block
+            }
+        };
+        let meta = self.meta(block);
+        meta.state.store(ATTACHED, Ordering::SeqCst);
+        if kind.is_collectable() {
+            let free_cells = meta.cells() - self.live_cells(block);
+            self.allocated_since_sweep
+                .fetch_add(free_cells << size_class.log_bytes(), Ordering::Relaxed);
+        }
+        Some(block)
+    }
+
+    /// Thread the free cells of an attached block into a list. Only called by its owner.
+    fn free_cells(&self, block: u32) -> Address {
+        let meta = self.meta(block);
+        let log_bytes = meta.size_class().log_bytes();
+        let start = self.block_start(block);
+        let mut head = Address::ZERO;
+        for cell in (0..meta.cells()).rev() {
+            let cell = start + (cell << log_bytes);
+            if !self.is_allocated(cell) {
+                unsafe { cell.store(head) };
+                head = cell;
+            }
+        }
+        head
+    }
+
+    /// Zero a cell taken from a free list if it may be scanned, then mark it allocated.
+    fn finish_cell(&self, cell: Address, size_class: SizeClass, kind: ObjectKind) -> Address {
+        if kind == ObjectKind::Normal {
+            unsafe { std::ptr::write_bytes(cell.as_mut_ptr::<u8>(), 0, size_class.bytes()) };
+        }
+        let was_allocated = self.alloc_bits.fetch_or(cell, 1, Ordering::SeqCst) != 0;
+        debug_assert!(!was_allocated);
+        cell
+    }
+
+    /// Give up an attached block. Full blocks are only listed again once a cell is freed.
+    fn detach_block(&self, block: u32) {
+        let meta = self.meta(block);
+        let mut current = ATTACHED;
+        if self.live_cells(block) == meta.cells() {
+            meta.state.store(UNLISTED, Ordering::SeqCst);
+            current = UNLISTED;
+            // A free that saw the block attached did not list it.
+            if self.live_cells(block) == meta.cells() {
+                return;
+            }
+        }
+        let mut state = self.state.lock();
+        // A free may have listed the block since, and another thread attached it.
+        if meta
+            .state
+            .compare_exchange(current, LISTED, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+        {
+            state.partial[meta.object_kind() as usize][meta.size_class().as_usize()].push(block);
+        }
+    }
+
+    fn relist(&self, block: u32) {
+        let meta = self.meta(block);
+        let mut state = self.state.lock();
+        if meta
+            .state
+            .compare_exchange(UNLISTED, LISTED, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+        {
+            state.partial[meta.object_kind() as usize][meta.size_class().as_usize()].push(block);
+        }
+    }
+
+    /// Allocate a large object in a run of blocks of its own. Its memory is zeroed.
+    pub fn alloc_large(&self, layout: Layout, kind: ObjectKind) -> Option<Address> {
+        let blocks = Self::large_blocks(layout);
+        let bytes = blocks << LOG_BLOCK_BYTES;
+        let mut state = self.state.lock();
+        let start = if layout.align() <= BLOCK_BYTES {
+            state.runs.allocate_cell(bytes)?.start
+        } else {
+            // Over-allocate, and give back the blocks around the aligned run.
+            let run = state
+                .runs
+                .allocate_cell(bytes + layout.align() - BLOCK_BYTES)?;
+            let start = run.start.align_up(layout.align());
+            if start > run.start {
+                state.runs.release_cell(run.start, start - run.start);
+            }
+            if start + bytes < run.end {
+                state
+                    .runs
+                    .release_cell(start + bytes, run.end - (start + bytes));
+            }
+            start
+        };
+        let block = self.block_of(start);
+        for i in 1..blocks as u32 {
+            let tail = self.meta(block + i);
+            tail.blocks.store(i, Ordering::Relaxed);
+            tail.kind.store(LARGE_TAIL, Ordering::Relaxed);
+        }
+        let meta = self.meta(block);
+        meta.object_kind.store(kind as u8, Ordering::Relaxed);
+        meta.blocks.store(blocks as u32, Ordering::Relaxed);
+        meta.kind.store(LARGE, Ordering::Release);
+        self.note_blocks_used(block + blocks as u32);
+        self.heap_blocks.fetch_add(blocks, Ordering::Relaxed);
+        if kind.is_collectable() {
+            self.allocated_since_sweep
+                .fetch_add(bytes, Ordering::Relaxed);
+        }
+        self.alloc_bits.fetch_or(start, 1, Ordering::SeqCst);
+        Some(start)
+    }
+
+    /// Free an object explicitly, from any thread.
+    pub fn free(&self, ptr: Address) {
+        let block = self.block_of(ptr);
+        let meta = self.meta(block);
+        let was_allocated = self.alloc_bits.fetch_and(ptr, 0, Ordering::SeqCst) != 0;
+        debug_assert!(was_allocated, "double free");
+        if meta.kind.load(Ordering::Relaxed) == LARGE {
+            let blocks = meta.blocks.load(Ordering::Relaxed);
+            self.release_run(&mut self.state.lock(), block, blocks);
+        } else if meta.state.load(Ordering::SeqCst) == UNLISTED {
+            self.relist(block);
+        }
+    }
+
+    /// Return a run of blocks to the free runs. Their memory is released, and reads as zero when reused.
+    fn release_run(&self, state: &mut GcState, block: u32, blocks: u32) {
+        let start = self.block_start(block);
+        let bytes = (blocks as usize) << LOG_BLOCK_BYTES;
+        self.alloc_bits.bzero(start..start + bytes);
+        for i in 0..blocks {
+            self.meta(block + i).kind.store(FREE, Ordering::Relaxed);
+        }
+        RawMemory::madv_dontneed(start, bytes);
+        state.runs.release_cell(start, bytes);
+        self.heap_blocks
+            .fetch_sub(blocks as usize, Ordering::Relaxed);
+    }
+
+    /// Hold the lock of the space, e.g. across a collection. Mutators wait for it to take new blocks.
+    pub fn lock(&self) -> MutexGuard<'_, GcState> {
+        self.state.lock()
+    }
+
+    /// Free every unmarked collectable object, then clear all marks. Returns the bytes of the blocks
+    /// still in use.
+    ///
+    /// All mutators must be stopped, and `state` must be the guard of this space's lock.
+    pub fn sweep(&self, state: &mut GcState) -> usize {
+        for kind in [ObjectKind::Normal, ObjectKind::Atomic] {
+            for blocks in &mut state.partial[kind as usize] {
+                blocks.clear();
+            }
+        }
+        let limit = self.limit.load(Ordering::Acquire) as u32;
+        let mut block = 0;
+        while block < limit {
+            let meta = self.meta(block);
+            match meta.kind.load(Ordering::Relaxed) {
+                SMALL if meta.object_kind().is_collectable() => self.sweep_block(state, block),
+                LARGE => {
+                    let blocks = meta.blocks.load(Ordering::Relaxed);
+                    let start = self.block_start(block);
+                    if meta.object_kind().is_collectable() && !self.is_marked(start) {
+                        self.release_run(state, block, blocks);
+                    }
+                    block += blocks;
+                    continue;
+                }
+                _ => {}
+            }
+            block += 1;
+        }
+        self.mark_bits.bzero(self.start..self.block_start(limit));
+        self.allocated_since_sweep.store(0, Ordering::Relaxed);
+        self.heap_bytes()
+    }
+
+    fn sweep_block(&self, state: &mut GcState, block: u32) {
+        let alloc = self.bitmap(&self.alloc_bits, block);
+        let marks = self.bitmap(&self.mark_bits, block);
+        for (a, m) in alloc.iter().zip(marks) {
+            a.store(
+                a.load(Ordering::Relaxed) & m.load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+        }
+        let meta = self.meta(block);
+        if meta.state.load(Ordering::Relaxed) == ATTACHED {
+            // The owner finds the freed cells once it detaches the block and takes it again.
+            return;
+        }
+        let live = self.live_cells(block);
+        if live == 0 {
+            self.release_run(state, block, 1);
+        } else if live < meta.cells() {
+            meta.state.store(LISTED, Ordering::Relaxed);
+            state.partial[meta.object_kind() as usize][meta.size_class().as_usize()].push(block);
+        } else {
+            meta.state.store(UNLISTED, Ordering::Relaxed);
+        }
+    }
+
+    /// Detach the blocks attached to threads that do not exist in a forked child. `current` is the allocator of
+    /// the forking thread, if it has one.
+    ///
+    /// They are left unlisted. The next free or collection that frees one of their cells lists them again.
+    pub fn abandon_blocks_after_fork(&self, current: Option<&GcAllocator>) {
+        let limit = self.limit.load(Ordering::Acquire) as u32;
+        let owned = |block: u32| {
+            current.is_some_and(|c| c.blocks.iter().flatten().any(|b| *b == Some(block)))
+        };
+        for block in 0..limit {
+            let meta = self.meta(block);
+            if meta.kind.load(Ordering::Relaxed) == SMALL
+                && meta.state.load(Ordering::Relaxed) == ATTACHED
+                && !owned(block)
+            {
+                meta.state.store(UNLISTED, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn fork_prepare(&self) {
+        std::mem::forget(self.state.lock());
+    }
+
+    pub fn fork_release(&self) {
+        unsafe { self.state.force_unlock() }
+    }
+}
+
+/// Thread-local allocation from a `GcSpace`: an attached block and its free cells per kind and size class.
+pub struct GcAllocator {
+    space: &'static GcSpace,
+    blocks: [[Option<u32>; SIZE_CLASSES]; KINDS],
+    free: [[Address; SIZE_CLASSES]; KINDS],
+}
+
+impl GcAllocator {
+    pub const fn new(space: &'static GcSpace) -> Self {
+        Self {
+            space,
+            blocks: [[None; SIZE_CLASSES]; KINDS],
+            free: [[Address::ZERO; SIZE_CLASSES]; KINDS],
+        }
+    }
+
+    /// Allocate a small object from the free cells at hand, if there are any.
+    #[inline(always)]
+    pub fn alloc_fast(&mut self, layout: Layout, kind: ObjectKind) -> Option<Address> {
+        if !GcSpace::is_small(layout) {
+            return None;
+        }
+        let size_class = SizeClass::<4>::from_layout(layout);
+        let head = &mut self.free[kind as usize][size_class.as_usize()];
+        if head.is_zero() {
+            return None;
+        }
+        let cell = *head;
+        *head = unsafe { cell.load::<Address>() };
+        Some(self.space.finish_cell(cell, size_class, kind))
+    }
+
+    /// Allocate an object after `alloc_fast` failed: from a new block, or as a large object.
+    pub fn alloc_slow(&mut self, layout: Layout, kind: ObjectKind) -> Option<Address> {
+        if !GcSpace::is_small(layout) {
+            return self.space.alloc_large(layout, kind);
+        }
+        let size_class = SizeClass::<4>::from_layout(layout);
+        let (k, sc) = (kind as usize, size_class.as_usize());
+        loop {
+            if let Some(block) = self.blocks[k][sc].take() {
+                self.space.detach_block(block);
+            }
+            let block = self.space.acquire_block(kind, size_class)?;
+            self.blocks[k][sc] = Some(block);
+            self.free[k][sc] = self.space.free_cells(block);
+            if let Some(ptr) = self.alloc_fast(layout, kind) {
+                return Some(ptr);
+            }
+        }
+    }
+
+    pub fn dealloc(&mut self, ptr: Address) {
+        self.space.free(ptr)
+    }
+
+    /// Detach all blocks. Their free cells are found again by the next threads to take them.
+    pub fn flush(&mut self) {
+        for (blocks, free) in self.blocks.iter_mut().zip(&mut self.free) {
+            for (block, head) in blocks.iter_mut().zip(free) {
+                *head = Address::ZERO;
+                if let Some(block) = block.take() {
+                    self.space.detach_block(block);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn space(id: SpaceId) -> &'static GcSpace {
+        Box::leak(Box::new(GcSpace::new(id)))
+    }
+
+    fn alloc(allocator: &mut GcAllocator, bytes: usize, kind: ObjectKind) -> Address {
+        let layout = Layout::from_size_align(bytes, 16).unwrap();
+        allocator
+            .alloc_fast(layout, kind)
+            .or_else(|| allocator.alloc_slow(layout, kind))
+            .unwrap()
+    }
+
+    #[test]
+    fn find_objects_from_interior_pointers() {
+        let space = space(SpaceId::LARGE_OBJECT_SPACE.next());
+        let mut allocator = GcAllocator::new(space);
+        let small = alloc(&mut allocator, 48, ObjectKind::Normal);
+        let large = alloc(&mut allocator, 3 * BLOCK_BYTES - 8, ObjectKind::Atomic);
+        let find = |value: Address| space.find_object(usize::from(value));
+        assert_eq!(find(small), Some((small, 64, ObjectKind::Normal)));
+        assert_eq!(find(small + 63usize), Some((small, 64, ObjectKind::Normal)));
+        assert_eq!(find(small + 64usize), None);
+        let large_object = Some((large, 3 * BLOCK_BYTES, ObjectKind::Atomic));
+        assert_eq!(find(large + 2 * BLOCK_BYTES + 100), large_object);
+        assert_eq!(find(large + 3 * BLOCK_BYTES), None);
+        assert_eq!(space.find_object(0), None);
+        assert_eq!(space.find_object(usize::MAX), None);
+        allocator.dealloc(small);
+        allocator.dealloc(large);
+        assert_eq!(find(small), None);
+        assert_eq!(find(large + BLOCK_BYTES), None);
+    }
+
+    #[test]
+    fn sweep_frees_unmarked_objects() {
+        let space = space(SpaceId::LARGE_OBJECT_SPACE.next().next());
+        let mut allocator = GcAllocator::new(space);
+        let objects = (0..1000)
+            .map(|_| alloc(&mut allocator, 128, ObjectKind::Normal))
+            .collect::<std::vec::Vec<_>>();
+        let uncollectable = alloc(&mut allocator, 128, ObjectKind::Uncollectable);
+        let large = alloc(&mut allocator, BLOCK_BYTES, ObjectKind::Normal);
+        let mut roots = 0;
+        space.for_each_uncollectable(|object, bytes| {
+            assert_eq!((object, bytes), (uncollectable, 128));
+            roots += 1;
+        });
+        assert_eq!(roots, 1);
+        for object in objects.iter().step_by(2) {
+            assert!(space.mark(*object));
+            assert!(!space.mark(*object));
+        }
+        allocator.flush();
+        let heap_bytes = space.heap_bytes();
+        space.sweep(&mut space.lock());
+        for (i, object) in objects.iter().enumerate() {
+            assert_eq!(space.is_allocated(*object), i % 2 == 0);
+            assert!(!space.is_marked(*object));
+        }
+        assert!(space.is_allocated(uncollectable));
+        assert!(!space.is_allocated(large));
+        assert_eq!(space.heap_bytes(), heap_bytes - BLOCK_BYTES);
+        // Freed cells are reused, and zeroed.
+        unsafe { objects[1].store(usize::MAX) };
+        let reused = (0..500)
+            .map(|_| alloc(&mut allocator, 128, ObjectKind::Normal))
+            .collect::<std::vec::Vec<_>>();
+        assert!(reused.contains(&objects[1]));
+        assert_eq!(unsafe { objects[1].load::<usize>() }, 0);
+    }
+
+    #[test]
+    fn over_aligned_large_objects() {
+        let space = space(SpaceId::LARGE_OBJECT_SPACE.next().next().next());
+        let mut allocator = GcAllocator::new(space);
+        for align in [BLOCK_BYTES * 2, 1 << 20, 2 << 20] {
+            let layout = Layout::from_size_align(4096, align).unwrap();
+            assert!(!GcSpace::is_small(layout));
+            let ptr = allocator
+                .alloc_slow(layout, ObjectKind::Uncollectable)
+                .unwrap();
+            assert!(ptr.is_aligned_to(align));
+            assert_eq!(space.get_layout(ptr).size(), BLOCK_BYTES);
+            allocator.dealloc(ptr);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+#![feature(thread_local)]
+#![feature(allocator_api)]
+#![feature(naked_functions)]
+
+extern crate mallockit;
+
+mod api;
+mod collector;
+mod gc_space;
+mod mark;
+mod roots;
+
+use collector::{Collector, GcConfig};
+use gc_space::*;
+use mallockit::{space::*, util::*, Mutator, Plan};
+
+const GC_SPACE: SpaceId = SpaceId::DEFAULT;
+
+/// A plan with a conservative, non-moving mark-sweep garbage collector, and the API of the Boehm collector.
+///
+/// Objects from `GC_malloc` and `GC_malloc_atomic` are freed once they are unreachable. Objects from
+/// `malloc` are uncollectable: they are roots, and are only freed by `free`. See `Collector`.
+#[mallockit::plan]
+struct Gc {
+    space: GcSpace,
+    collector: Collector,
+}
+
+impl Gc {
+    /// Collect now, from the current thread.
+    pub fn collect(&'static self) {
+        self.collector.collect(&self.space);
+    }
+}
+
+impl Plan for Gc {
+    type Mutator = GcMutator;
+
+    fn new() -> Self {
+        Self {
+            space: GcSpace::new(GC_SPACE),
+            collector: Collector::new(GcConfig::from_env()),
+        }
+    }
+
+    fn get_layout(ptr: Address) -> Layout {
+        debug_assert!(GC_SPACE.contains(ptr));
+        Self::get().space.get_layout(ptr)
+    }
+
+    fn good_size(layout: Layout) -> usize {
+        GcSpace::good_size(layout)
+    }
+
+    fn fork_prepare(&'static self) {
+        self.collector.fork_prepare(&self.space);
+    }
+
+    fn fork_parent(&'static self) {
+        self.collector.fork_parent(&self.space);
+    }
+
+    fn fork_child(&'static self) {
+        self.collector.fork_child(&self.space);
+        self.space
+            .abandon_blocks_after_fork(GcMutator::try_current().map(|m| &m.gc));
+    }
+}
+
+#[mallockit::mutator]
+struct GcMutator {
+    gc: GcAllocator,
+}
+
+impl GcMutator {
+    /// Allocate an object of `kind`, collecting first if enough collectable memory was allocated since
+    /// the last collection.
+    pub fn alloc_object(&mut self, layout: Layout, kind: ObjectKind) -> Option<Address> {
+        if let Some(ptr) = self.gc.alloc_fast(layout, kind) {
+            return Some(ptr);
+        }
+        let plan = Self::plan();
+        if kind.is_collectable() && plan.collector.should_collect(&plan.space) {
+            plan.collect();
+        }
+        self.gc.alloc_slow(layout, kind)
+    }
+}
+
+impl Mutator for GcMutator {
+    type Plan = Gc;
+
+    fn new() -> Self {
+        Self::plan().collector.threads.register_current_thread();
+        Self {
+            gc: GcAllocator::new(&Self::plan().space),
+        }
+    }
+
+    #[inline(always)]
+    fn alloc(&mut self, layout: Layout) -> Option<Address> {
+        mallockit::stat::track_allocation(layout, !GcSpace::is_small(layout));
+        self.alloc_object(layout, ObjectKind::Uncollectable)
+    }
+
+    #[inline(always)]
+    fn dealloc(&mut self, ptr: Address) {
+        debug_assert!(GC_SPACE.contains(ptr));
+        mallockit::stat::track_deallocation(!GcSpace::is_small(Gc::get_layout(ptr)));
+        self.gc.dealloc(ptr)
+    }
+
+    /// Reallocate as an object of the same kind.
+    fn realloc(&mut self, ptr: Address, new_layout: Layout) -> Option<Address> {
+        let layout = Gc::get_layout(ptr);
+        if layout.size() >= new_layout.size() && layout.align() >= new_layout.align() {
+            return Some(ptr);
+        }
+        let kind = Self::plan().space.object_kind(ptr);
+        let new_ptr = self.alloc_object(new_layout, kind)?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                ptr.as_ptr::<u8>(),
+                new_ptr.as_mut_ptr::<u8>(),
+                usize::min(layout.size(), new_layout.size()),
+            );
+        }
+        self.dealloc(ptr);
+        Some(new_ptr)
+    }
+
+    fn on_thread_exit(&mut self) {
+        self.gc.flush();
+        Self::plan().collector.threads.unregister_current_thread();
+    }
+}
+
+#[cfg(test)]
+mod thread_exit_tests {
+    mallockit::rust_allocator_tests!(crate::Global, short_lived_threads);
+}
+
+#[cfg(test)]
+mod cross_thread_tests {
+    mallockit::rust_allocator_tests!(crate::Global, producer_consumer);
+}
+
+#[cfg(test)]
+mod gc_tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Each test allocates its own size class, so that concurrent tests do not reuse its freed cells.
+    fn gc_malloc(bytes: usize, kind: ObjectKind) -> Address {
+        let layout = Layout::from_size_align(bytes, 16).unwrap();
+        GcMutator::current().alloc_object(layout, kind).unwrap()
+    }
+
+    /// Addresses hidden from the collector.
+    fn hide(ptr: Address) -> usize {
+        !usize::from(ptr)
+    }
+
+    fn unhide(hidden: usize) -> Address {
+        Address::from(!hidden)
+    }
+
+    fn is_live(hidden: usize) -> bool {
+        Gc::get().space.is_allocated(unhide(hidden))
+    }
+
+    /// Allocate objects that are unreachable once this returns.
+    #[inline(never)]
+    fn garbage(n: usize, bytes: usize) -> std::vec::Vec<usize> {
+        (0..n)
+            .map(|_| hide(gc_malloc(bytes, ObjectKind::Normal)))
+            .collect()
+    }
+
+    /// Build a list of `n` objects on the heap, and return its head.
+    #[inline(never)]
+    fn list(n: usize, bytes: usize) -> Address {
+        let mut head = Address::ZERO;
+        for i in 0..n {
+            let node = gc_malloc(bytes, ObjectKind::Normal);
+            unsafe {
+                node.store(head);
+                (node + 8usize).store(i);
+            }
+            head = node;
+        }
+        head
+    }
+
+    fn check_list(mut node: Address, n: usize) {
+        for i in (0..n).rev() {
+            assert_eq!(unsafe { (node + 8usize).load::<usize>() }, i);
+            node = unsafe { node.load::<Address>() };
+        }
+        assert!(node.is_zero());
+    }
+
+    #[test]
+    fn unreachable_objects_are_collected() {
+        let garbage = garbage(1000, 48);
+        let head = std::hint::black_box(list(1000, 48));
+        Gc::get().collect();
+        check_list(head, 1000);
+        // Stale copies on the stack may keep a few alive.
+        let live = garbage.iter().filter(|g| is_live(**g)).count();
+        assert!(live < 100, "{} unreachable objects survived", live);
+    }
+
+    #[test]
+    fn atomic_objects_are_not_scanned() {
+        let atomic = gc_malloc(96, ObjectKind::Atomic);
+        let hidden = hide(gc_malloc(96, ObjectKind::Normal));
+        unsafe { atomic.store(unhide(hidden)) };
+        let atomic = std::hint::black_box(atomic);
+        Gc::get().collect();
+        assert!(Gc::get().space.is_allocated(atomic));
+        assert!(!is_live(hidden));
+    }
+
+    #[test]
+    fn uncollectable_and_static_roots() {
+        static ROOT: AtomicUsize = AtomicUsize::new(0);
+        let large = list(100, 200 << 10);
+        let holder = gc_malloc(160, ObjectKind::Uncollectable);
+        unsafe { holder.store(large) };
+        ROOT.store(usize::from(list(100, 160)), Ordering::SeqCst);
+        let holder = hide(holder);
+        Gc::get().collect();
+        check_list(unsafe { unhide(holder).load::<Address>() }, 100);
+        check_list(Address::from(ROOT.load(Ordering::SeqCst)), 100);
+        GcMutator::current().dealloc(unhide(holder));
+    }
+
+    /// Objects only reachable from the stack of a thread are kept while another thread collects.
+    #[test]
+    fn other_threads_are_stopped_and_scanned() {
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let head = std::hint::black_box(list(1000, 224));
+            ready_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+            check_list(std::hint::black_box(head), 1000);
+        });
+        ready_rx.recv().unwrap();
+        for _ in 0..3 {
+            Gc::get().collect();
+        }
+        done_tx.send(()).unwrap();
+        thread.join().unwrap();
+    }
+
+    /// Allocation triggers collections, which keep the heap bounded.
+    #[test]
+    fn allocation_collects() {
+        let collections = Gc::get().collector.collections();
+        let head = std::hint::black_box(list(100, 1000));
+        for _ in 0..64 {
+            garbage(1000, 1000);
+        }
+        assert!(Gc::get().collector.collections() > collections);
+        check_list(head, 100);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::gc_space::GcSpace;
+use mallockit::{
+    util::{sys::RawMemory, *},
+    worker::{Worker, WorkerId},
+    Plan,
+};
+use spin::Mutex;
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Ranges waiting in the shared queue, at most. Reserved up front, and committed as they are used.
+const QUEUE_CAPACITY: usize = 1 << 24;
+/// Ranges in a local mark stack, at most. Half of them go to the shared queue when it fills up.
+const LOCAL_CAPACITY: usize = 1 << 12;
+/// Local ranges above which some are shared whenever the queue runs dry.
+const SHARE_THRESHOLD: usize = 16;
+/// Ranges moved from the shared queue to a local stack at a time.
+const TAKE_BATCH: usize = 64;
+/// Bytes scanned before the rest of a range is pushed back, so that large objects and roots are split
+/// between markers.
+const SCAN_CHUNK: usize = 64 << 10;
+/// How long an idle marker waits for a collection before checking again.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A fixed-capacity stack of address ranges to scan, in memory of its own.
+pub struct MarkStack {
+    base: Address,
+    capacity: usize,
+    len: usize,
+}
+
+impl MarkStack {
+    pub fn new(capacity: usize) -> Self {
+        let bytes =
+            (capacity * std::mem::size_of::<Range<Address>>()).next_multiple_of(Size4K::BYTES);
+        Self {
+            base: RawMemory::map_anonymous(bytes).unwrap(),
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub fn local() -> Self {
+        Self::new(LOCAL_CAPACITY)
+    }
+
+    const fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == self.capacity
+    }
+
+    fn slot(&self, i: usize) -> Address {
+        self.base + i * std::mem::size_of::<Range<Address>>()
+    }
+
+    fn push(&mut self, range: Range<Address>) {
+        if self.is_full() {
+            mallockit::eprintln!("[gc] mark stack overflow");
+            std::process::abort();
+        }
+        unsafe { self.slot(self.len).store((range.start, range.end)) };
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Range<Address>> {
+        if self.is_empty() {
+            return None;
+        }
+        self.len -= 1;
+        let (start, end) = unsafe { self.slot(self.len).load::<(Address, Address)>() };
+        Some(start..end)
+    }
+}
+
+/// Marking shared between the collecting thread and the marker threads.
+///
+/// Each participant scans ranges from its local stack, pushing the objects it marks, and shares ranges
+/// through a queue. A participant counts as active from taking ranges from the queue until its local
+/// stack is empty again, so marking is over once the queue is empty and no participant is active.
+pub struct Marking {
+    queue: Mutex<MarkStack>,
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    /// Bumped when a collection starts marking.
+    epoch: AtomicUsize,
+}
+
+impl Marking {
+    pub fn new() -> Self {
+        Self {
+            queue: Mutex::new(MarkStack::new(QUEUE_CAPACITY)),
+            queued: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            epoch: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    /// Queue a root range to scan. Only called before `start`.
+    pub fn push(&self, range: Range<Address>) {
+        let mut queue = self.queue.lock();
+        queue.push(range);
+        self.queued.store(queue.len(), Ordering::Relaxed);
+    }
+
+    /// Let the marker threads join in.
+    pub fn start(&self) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Mark everything reachable from the queued ranges, together with the other participants.
+    /// Returns once marking is over.
+    pub fn drain(&self, space: &GcSpace, local: &mut MarkStack) {
+        loop {
+            if self.take(local) {
+                while let Some(range) = local.pop() {
+                    self.scan(space, local, range);
+                    if local.len() > SHARE_THRESHOLD && self.queued.load(Ordering::Relaxed) == 0 {
+                        self.share(local);
+                    }
+                }
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            let queue = self.queue.lock();
+            if queue.is_empty() && self.active.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            drop(queue);
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Move some queued ranges to `local`, and become active. Returns false if the queue is empty.
+    fn take(&self, local: &mut MarkStack) -> bool {
+        let mut queue = self.queue.lock();
+        if queue.is_empty() {
+            return false;
+        }
+        self.active.fetch_add(1, Ordering::SeqCst);
+        for _ in 0..TAKE_BATCH {
+            match queue.pop() {
+                Some(range) => local.push(range),
+                None => break,
+            }
+        }
+        self.queued.store(queue.len(), Ordering::Relaxed);
+        true
+    }
+
+    /// Move half of `local` to the queue.
+    fn share(&self, local: &mut MarkStack) {
+        let mut queue = self.queue.lock();
+        for _ in 0..local.len() / 2 {
+            queue.push(local.pop().unwrap());
+        }
+        self.queued.store(queue.len(), Ordering::Relaxed);
+    }
+
+    /// Mark the objects that words in `range` point into, and push the ones that may hold pointers.
+    fn scan(&self, space: &GcSpace, local: &mut MarkStack, range: Range<Address>) {
+        let start = range.start.align_up(std::mem::size_of::<usize>());
+        let mut end = range.end;
+        if end > start + SCAN_CHUNK {
+            end = start + SCAN_CHUNK;
+            self.push_local(local, end..range.end);
+        }
+        let mut word = start;
+        while word + std::mem::size_of::<usize>() <= end {
+            // Roots may be written by threads that are not stopped, e.g. in data segments.
+            let value = unsafe { std::ptr::read_volatile(word.as_ptr::<usize>()) };
+            if let Some((object, bytes, kind)) = space.find_object(value) {
+                if space.mark(object) && kind.is_scanned() {
+                    self.push_local(local, object..object + bytes);
+                }
+            }
+            word += std::mem::size_of::<usize>();
+        }
+    }
+
+    fn push_local(&self, local: &mut MarkStack, range: Range<Address>) {
+        if local.is_full() {
+            self.share(local);
+        }
+        local.push(range);
+    }
+}
+
+/// A thread that helps the collecting thread mark.
+pub struct Marker {
+    stack: MarkStack,
+}
+
+impl Worker for Marker {
+    fn new(_id: WorkerId) -> Self {
+        Self {
+            stack: MarkStack::local(),
+        }
+    }
+
+    fn run(&'static mut self) {
+        let gc = crate::Gc::get();
+        let (group, marking) = (&gc.collector.markers, &gc.collector.marking);
+        let mut seen = marking.epoch();
+        loop {
+            let epoch = marking.epoch();
+            if epoch == seen {
+                group.wait_timeout(self, IDLE_TIMEOUT);
+                continue;
+            }
+            seen = epoch;
+            marking.drain(&gc.space, &mut self.stack);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gc_space::{GcAllocator, ObjectKind};
+    use mallockit::space::SpaceId;
+
+    #[test]
+    fn mark_stack() {
+        let mut stack = MarkStack::new(4);
+        for i in 1..=4usize {
+            stack.push(Address::from(i)..Address::from(i * 2));
+        }
+        assert!(stack.is_full());
+        assert_eq!(
+            stack.pop(),
+            Some(Address::from(4usize)..Address::from(8usize))
+        );
+        assert_eq!(stack.len(), 3);
+    }
+
+    /// Mark a list and a large array of pointers from a root range on this thread alone.
+    #[test]
+    fn mark_from_roots() {
+        let space: &'static GcSpace = Box::leak(Box::new(GcSpace::new(
+            SpaceId::LARGE_OBJECT_SPACE.next().next().next().next(),
+        )));
+        let mut allocator = GcAllocator::new(space);
+        let mut alloc = |bytes: usize, kind: ObjectKind| {
+            let layout = Layout::from_size_align(bytes, 16).unwrap();
+            allocator
+                .alloc_fast(layout, kind)
+                .or_else(|| allocator.alloc_slow(layout, kind))
+                .unwrap()
+        };
+        // A list of 10000 nodes, reached through interior pointers.
+        let mut list = Address::ZERO;
+        let mut nodes = std::vec::Vec::new();
+        for _ in 0..10000 {
+            let node = alloc(32, ObjectKind::Normal);
+            unsafe { node.store(list + 8usize) };
+            list = node;
+            nodes.push(node);
+        }
+        // An atomic object, whose contents are not pointers.
+        let atomic = alloc(64, ObjectKind::Atomic);
+        let hidden = alloc(64, ObjectKind::Normal);
+        unsafe { atomic.store(hidden) };
+        // A large array of both.
+        let array = alloc(256 << 10, ObjectKind::Normal);
+        unsafe {
+            array.store(list);
+            (array + (128usize << 10)).store(atomic);
+        }
+        let roots = [usize::from(array) + 100, 42, usize::MAX];
+        let marking = Marking::new();
+        let roots = Address::from(roots.as_ptr());
+        marking.push(roots..roots + 3 * std::mem::size_of::<usize>());
+        marking.start();
+        marking.drain(space, &mut MarkStack::local());
+        assert!(space.is_marked(array));
+        assert!(nodes.iter().all(|node| space.is_marked(*node)));
+        assert!(space.is_marked(atomic));
+        assert!(!space.is_marked(hidden));
+    }
+}
@@ -0,0 +1,344 @@
+use mallockit::{
+    libc,
+    space::meta::{Meta, Vec},
+    util::*,
+};
+use spin::{Mutex, MutexGuard, Once};
+use std::{
+    ops::Range,
+    ptr::addr_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Sent to each registered thread to stop it for a collection.
+#[cfg(target_os = "linux")]
+const SUSPEND_SIGNAL: libc::c_int = libc::SIGPWR;
+#[cfg(not(target_os = "linux"))]
+const SUSPEND_SIGNAL: libc::c_int = libc::SIGXCPU;
+
+/// Odd while the world is stopped.
+static STOP_EPOCH: AtomicUsize = AtomicUsize::new(0);
+/// Threads that saved their stack pointer for the current stop.
+static ACKS: AtomicUsize = AtomicUsize::new(0);
+static HANDLER: Once = Once::new();
+
+/// The stack of a registered thread.
+pub struct ThreadStack {
+    thread: AtomicUsize,
+    /// The highest address of the stack.
+    top: AtomicUsize,
+    /// The lowest address in use while the thread is stopped. Zero if it could not be stopped.
+    sp: AtomicUsize,
+}
+
+impl ThreadStack {
+    const fn new() -> Self {
+        Self {
+            thread: AtomicUsize::new(0),
+            top: AtomicUsize::new(0),
+            sp: AtomicUsize::new(0),
+        }
+    }
+
+    /// The part of the stack in use. Only valid while the world is stopped.
+    pub fn range(&self, current_sp: Address) -> Option<Range<Address>> {
+        let sp = if self.is_current() {
+            usize::from(current_sp)
+        } else {
+            self.sp.load(Ordering::SeqCst)
+        };
+        let top = self.top.load(Ordering::Relaxed);
+        (sp != 0 && sp < top).then(|| Address::from(sp)..Address::from(top))
+    }
+
+    fn is_current(&self) -> bool {
+        std::ptr::eq(self, current())
+    }
+}
+
+#[thread_local]
+static STACK: ThreadStack = ThreadStack::new();
+
+fn current() -> &'static ThreadStack {
+    unsafe { &*addr_of!(STACK) }
+}
+
+/// The threads whose stacks are roots. Threads register on their first allocation.
+///
+/// Threads are stopped with a signal. The handler saves the stack pointer, so that the registers of the
+/// interrupted code, saved on the stack by the kernel, are scanned along with the rest of the stack.
+pub struct ThreadRegistry {
+    threads: Mutex<Vec<&'static ThreadStack>>,
+}
+
+impl ThreadRegistry {
+    pub fn new() -> Self {
+        HANDLER.call_once(install_handler);
+        Self {
+            threads: Mutex::new(Vec::new_in(Meta)),
+        }
+    }
+
+    /// Register the current thread, if it is not registered yet.
+    pub fn register_current_thread(&self) {
+        let stack = current();
+        if stack.top.load(Ordering::Relaxed) != 0 {
+            return;
+        }
+        let marker = 0usize;
+        let sp = std::hint::black_box(addr_of!(marker)) as usize;
+        let top = stack_top(sp).unwrap_or_else(|| {
+            mallockit::eprintln!("[gc] cannot find the stack of a thread, it is not scanned");
+            sp
+        });
+        stack
+            .thread
+            .store(unsafe { libc::pthread_self() } as usize, Ordering::Relaxed);
+        stack.top.store(top, Ordering::Relaxed);
+        self.threads.lock().push(stack);
+    }
+
+    pub fn unregister_current_thread(&self) {
+        let stack = current();
+        if stack.top.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        self.threads.lock().retain(|s| !std::ptr::eq(*s, stack));
+        stack.top.store(0, Ordering::Relaxed);
+    }
+
+    /// Hold the lock of the registry, e.g. across a collection. Threads wait for it to register or exit.
+    pub fn lock(&self) -> MutexGuard<'_, Vec<&'static ThreadStack>> {
+        self.threads.lock()
+    }
+
+    /// Forget the threads that do not exist in a forked child.
+    pub fn retain_current_after_fork(&self) {
+        let stack = current();
+        self.threads.lock().retain(|s| std::ptr::eq(*s, stack));
+    }
+
+    pub fn fork_prepare(&self) {
+        std::mem::forget(self.threads.lock());
+    }
+
+    pub fn fork_release(&self) {
+        unsafe { self.threads.force_unlock() }
+    }
+}
+
+/// Stop every thread in `threads` but the current one, and wait until they saved their stack pointers.
+///
+/// The caller holds the registry lock, so that no thread registers or exits meanwhile.
+pub fn stop_the_world(threads: &[&'static ThreadStack]) {
+    ACKS.store(0, Ordering::SeqCst);
+    let epoch = STOP_EPOCH.fetch_add(1, Ordering::SeqCst);
+    debug_assert_eq!(epoch % 2, 0);
+    let mut stopping = 0;
+    for stack in threads.iter().filter(|s| !s.is_current()) {
+        stack.sp.store(0, Ordering::SeqCst);
+        let thread = stack.thread.load(Ordering::Relaxed) as libc::pthread_t;
+        if unsafe { libc::pthread_kill(thread, SUSPEND_SIGNAL) } == 0 {
+            stopping += 1;
+        }
+    }
+    while ACKS.load(Ordering::SeqCst) < stopping {
+        unsafe { libc::sched_yield() };
+    }
+}
+
+pub fn resume_the_world() {
+    let epoch = STOP_EPOCH.fetch_add(1, Ordering::SeqCst);
+    debug_assert_eq!(epoch % 2, 1);
+}
+
+fn install_handler() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_suspend as usize;
+        // Not on an alternate stack: the saved registers must end up on the stack that is scanned.
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(SUSPEND_SIGNAL, &action, std::ptr::null_mut());
+    }
+}
+
+extern "C" fn handle_suspend(_signal: libc::c_int) {
+    let epoch = STOP_EPOCH.load(Ordering::SeqCst);
+    if epoch % 2 == 0 {
+        return;
+    }
+    let marker = 0usize;
+    let sp = std::hint::black_box(addr_of!(marker)) as usize;
+    current().sp.store(sp, Ordering::SeqCst);
+    ACKS.fetch_add(1, Ordering::SeqCst);
+    while STOP_EPOCH.load(Ordering::SeqCst) == epoch {
+        unsafe { libc::sched_yield() };
+    }
+}
+
+/// Call `f` with the callee-saved registers of its caller stored on the stack, below all live frames of
+/// the caller. `f` gets the lowest address to scan.
+#[inline(never)]
+pub fn with_registers_on_stack<R>(f: impl FnOnce(Address) -> R) -> R {
+    let mut registers = [0usize; 12];
+    let ptr = registers.as_mut_ptr();
+    unsafe {
+        #[cfg(target_arch = "x86_64")]
+        std::arch::asm!(
+            "mov [{0}], rbx",
+            "mov [{0} + 8], rbp",
+            "mov [{0} + 16], r12",
+            "mov [{0} + 24], r13",
+            "mov [{0} + 32], r14",
+            "mov [{0} + 40], r15",
+            in(reg) ptr,
+            options(nostack, preserves_flags),
+        );
+        #[cfg(target_arch = "aarch64")]
+        std::arch::asm!(
+            "stp x19, x20, [{0}]",
+            "stp x21, x22, [{0}, #16]",
+            "stp x23, x24, [{0}, #32]",
+            "stp x25, x26, [{0}, #48]",
+            "stp x27, x28, [{0}, #64]",
+            "str x29, [{0}, #80]",
+            in(reg) ptr,
+            options(nostack, preserves_flags),
+        );
+    }
+    let result = f(Address::from(std::hint::black_box(ptr)));
+    std::hint::black_box(&registers);
+    result
+}
+
+/// The top of the stack that contains `sp`: the end of its mapping in `/proc/self/maps`.
+///
+/// Reads the file with raw system calls, as this runs inside the first allocation of a thread.
+#[cfg(target_os = "linux")]
+fn stack_top(sp: usize) -> Option<usize> {
+    let fd = unsafe {
+        libc::open(
+            c"/proc/self/maps".as_ptr(),
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+    let mut buffer = [0u8; 4096];
+    let mut line = [0u8; 256];
+    let mut len = 0;
+    let mut top = None;
+    'read: loop {
+        let n = unsafe { libc::read(fd, buffer.as_mut_ptr() as _, buffer.len()) };
+        if n <= 0 {
+            break;
+        }
+        for &byte in &buffer[..n as usize] {
+            if byte != b'\n' {
+                if len < line.len() {
+                    line[len] = byte;
+                    len += 1;
+                }
+                continue;
+            }
+            if let Some(range) = parse_mapping(&line[..len]) {
+                if range.contains(&sp) {
+                    top = Some(range.end);
+                    break 'read;
+                }
+            }
+            len = 0;
+        }
+    }
+    unsafe { libc::close(fd) };
+    top
+}
+
+#[cfg(target_vendor = "apple")]
+fn stack_top(_sp: usize) -> Option<usize> {
+    Some(unsafe { libc::pthread_get_stackaddr_np(libc::pthread_self()) } as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+fn stack_top(_sp: usize) -> Option<usize> {
+    None
+}
+
+/// The address range at the start of a `/proc/self/maps` line.
+#[allow(unused)]
+fn parse_mapping(line: &[u8]) -> Option<Range<usize>> {
+    let range = line.split(|b| *b == b' ').next()?;
+    let mut bounds = range.split(|b| *b == b'-');
+    let mut parse = || usize::from_str_radix(std::str::from_utf8(bounds.next()?).ok()?, 16).ok();
+    Some(parse()?..parse()?)
+}
+
+/// The writable segments of the executable and every loaded library, i.e. their data and bss sections.
+/// Returns how many were stored in `segments`.
+#[cfg(target_os = "linux")]
+pub fn data_segments(segments: &mut [Range<Address>]) -> usize {
+    struct Segments<'a> {
+        segments: &'a mut [Range<Address>],
+        len: usize,
+    }
+    extern "C" fn visit(
+        info: *mut libc::dl_phdr_info,
+        _size: libc::size_t,
+        data: *mut libc::c_void,
+    ) -> libc::c_int {
+        let out = unsafe { &mut *(data as *mut Segments) };
+        let info = unsafe { &*info };
+        let headers = unsafe { std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as _) };
+        for header in headers {
+            if header.p_type != libc::PT_LOAD || header.p_flags & libc::PF_W == 0 {
+                continue;
+            }
+            if out.len == out.segments.len() {
+                mallockit::eprintln!("[gc] too many data segments, some are not scanned");
+                return 1;
+            }
+            let start = Address::from((info.dlpi_addr + header.p_vaddr) as usize);
+            out.segments[out.len] = start..start + header.p_memsz as usize;
+            out.len += 1;
+        }
+        0
+    }
+    let mut out = Segments { segments, len: 0 };
+    unsafe { libc::dl_iterate_phdr(Some(visit), &mut out as *mut Segments as _) };
+    out.len
+}
+
+/// Data segments are only found on Linux. Elsewhere, only stacks and uncollectable objects are roots.
+#[cfg(not(target_os = "linux"))]
+pub fn data_segments(_segments: &mut [Range<Address>]) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ROOT: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn parse_maps_line() {
+        let line =
+            b"7ffd4e2a1000-7ffd4e2c2000 rw-p 00000000 00:00 0                          [stack]";
+        assert_eq!(parse_mapping(line), Some(0x7ffd4e2a1000..0x7ffd4e2c2000));
+        assert_eq!(parse_mapping(b"garbage"), None);
+    }
+
+    #[test]
+    fn current_stack_and_data() {
+        let local = 0usize;
+        let local = addr_of!(local) as usize;
+        let top = stack_top(local).unwrap();
+        assert!(local < top);
+        let mut segments = [const { Address::ZERO..Address::ZERO }; 256];
+        let n = data_segments(&mut segments);
+        let root = Address::from(&ROOT);
+        assert!(segments[..n].iter().any(|s| s.contains(&root)));
+    }
+}
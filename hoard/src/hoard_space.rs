@@ -2,17 +2,36 @@ use super::{page_resource::BlockPageResource, Allocator, Space, SpaceId};
 use crate::{pool::Pool, super_block::SuperBlock};
 use mallockit::{
     space::{
-        meta::{Box, Meta},
-        page_resource::MemRegion,
+        meta::{Box, Meta, Vec},
+        page_resource::{MemRegion, PageResource},
+    },
+    util::{
+        mem::alloc::{
+            discrete_tlab::DiscreteTLAB,
+            per_cpu::{self, PerCpuCache},
+            thread_cache::{CACHE_GC, DEFAULT_THREAD_CACHE_BUDGET},
+            transfer_cache::{CellBatch, TransferCache},
+        },
+        *,
     },
-    util::{mem::alloc::discrete_tlab::DiscreteTLAB, *},
 };
+use spin::{rwlock::RwLock, Yield};
 
 /// Global heap
 pub struct HoardSpace {
     id: SpaceId,
     pr: BlockPageResource<SuperBlock>,
     pub(crate) pool: Pool,
+    /// Small cells shared by all threads running on the same CPU. Replaces the TLABs if rseq is available.
+    per_cpu:
+        PerCpuCache<{ HoardAllocator::SMALL_SIZE_CLASSES }, { HoardAllocator::PER_CPU_CAPACITY }>,
+    /// Small cells freed by threads other than the owner of their block.
+    transfer: TransferCache<
+        { HoardAllocator::SMALL_SIZE_CLASSES },
+        { HoardAllocator::TRANSFER_BATCH_SIZE },
+    >,
+    /// Thread-local pools. Only used to lock all pools on `fork`.
+    local_pools: RwLock<Vec<&'static Pool>, Yield>,
 }
 
 impl Space for HoardSpace {
@@ -24,6 +43,9 @@ impl Space for HoardSpace {
             id,
             pr: BlockPageResource::new(id),
             pool: Pool::new(true),
+            per_cpu: PerCpuCache::new(),
+            transfer: TransferCache::new(),
+            local_pools: RwLock::new(Vec::new_in(Meta)),
         }
     }
 
@@ -35,6 +57,34 @@ impl Space for HoardSpace {
         &self.pr
     }
 
+    fn page_resource_mut(&mut self) -> &mut Self::PR {
+        &mut self.pr
+    }
+
+    /// Lock order: the pool registry, then all local pools, then the global pool.
+    fn fork_prepare(&self) {
+        self.transfer.fork_prepare();
+        let local_pools = self.local_pools.write();
+        for pool in local_pools.iter() {
+            pool.lock_all();
+        }
+        std::mem::forget(local_pools);
+        self.pool.lock_all();
+        self.pr.fork_prepare();
+    }
+
+    fn fork_release(&self) {
+        self.pr.fork_release();
+        unsafe {
+            self.pool.force_unlock_all();
+            for pool in (*self.local_pools.as_mut_ptr()).iter().rev() {
+                pool.force_unlock_all();
+            }
+            self.local_pools.force_write_unlock();
+        }
+        self.transfer.fork_release();
+    }
+
     fn get_layout(ptr: Address) -> Layout {
         let block = SuperBlock::containing(ptr);
         block.size_class.layout()
@@ -42,12 +92,23 @@ impl Space for HoardSpace {
 }
 
 impl HoardSpace {
+    /// Cells are aligned to their size class. Objects aligned beyond both their size and a page
+    /// would waste most of their cell, so they go to page-aligned runs elsewhere.
     pub fn can_allocate(layout: Layout) -> bool {
+        if layout.align() > Size4K::BYTES && layout.align() > layout.size() {
+            return false;
+        }
         let layout = unsafe { layout.pad_to_align_unchecked() };
         let size = layout.size().next_power_of_two();
         size <= Self::MAX_ALLOCATION_SIZE
     }
 
+    /// The usable size of an object allocated with `layout`: its size class.
+    pub fn good_size(layout: Layout) -> usize {
+        let size_class: SizeClass = SizeClass::from_layout(layout);
+        size_class.bytes()
+    }
+
     pub fn acquire_block(&self, size_class: SizeClass, local: &Pool) -> Option<SuperBlock> {
         // Try allocate from the global pool
         if let Some((mut block, _guard)) = self.pool.pop_most_empty_block(size_class) {
@@ -74,31 +135,112 @@ impl HoardSpace {
     pub fn release_block(&self, block: SuperBlock) {
         self.pr.release_block(block)
     }
+
+    fn register_pool(&self, pool: &'static Pool) {
+        self.local_pools.write().push(pool);
+        CACHE_GC.register(pool);
+    }
+
+    fn unregister_and_flush_pool(&self, pool: &'static Pool) {
+        CACHE_GC.unregister(pool);
+        let mut local_pools = self.local_pools.write();
+        local_pools.retain(|p| !std::ptr::eq(*p, pool));
+        pool.flush_all();
+    }
+
+    /// Called in a forked child. Hand the blocks of all threads other than the forking one to the global pool.
+    /// `current` is the pool of the forking thread, if it ever allocated.
+    ///
+    /// The abandoned pools are never freed, so they can stay registered with the cache GC.
+    pub fn reclaim_pools_after_fork(&self, current: Option<&'static Pool>) {
+        let is_current = |pool: &&'static Pool| current.is_some_and(|c| std::ptr::eq(*pool, c));
+        let mut local_pools = self.local_pools.write();
+        for pool in local_pools.iter().filter(|p| !is_current(p)) {
+            pool.flush_all();
+        }
+        local_pools.retain(is_current);
+    }
 }
 /// Thread-local heap
 pub struct HoardAllocator {
-    tlab: DiscreteTLAB<{ SizeClass::<4>::from_bytes(Self::LARGEST_SMALL_OBJECT).as_usize() + 1 }>,
+    tlab: DiscreteTLAB<{ HoardAllocator::SMALL_SIZE_CLASSES }>,
+    /// Cache small cells per CPU instead of in the TLAB.
+    per_cpu: bool,
+    /// Freed cells of blocks owned by other threads, handed over through the transfer cache.
+    remote: [CellBatch; HoardAllocator::SMALL_SIZE_CLASSES],
     local: Box<Pool>,
     space: &'static HoardSpace,
+    /// Free bytes allowed in the TLAB and, separately, in the local pool.
+    budget: usize,
+    tlab_misses: usize,
 }
 
 impl HoardAllocator {
-    const LOCAL_HEAP_THRESHOLD: usize = 16 * 1024 * 1024;
     const LARGEST_SMALL_OBJECT: usize = 1024;
+    const SMALL_SIZE_CLASSES: usize =
+        SizeClass::<4>::from_bytes(Self::LARGEST_SMALL_OBJECT).as_usize() + 1;
+    /// Cells per size class in each CPU's cache.
+    const PER_CPU_CAPACITY: usize = 64;
+    const TRANSFER_BATCH_SIZE: usize = 32;
+    /// Half of a TLAB bin goes back to the local pool once it holds more cells than this.
+    const MAX_TLAB_BIN_LEN: usize = 1024;
+    /// Report to the cache GC once every this many TLAB misses.
+    const TLAB_MISSES_PER_TICK: usize = 64;
 
     pub fn new(space: &'static HoardSpace, _space_id: SpaceId) -> Self {
+        let local = Box::new_in(Pool::new(false), Meta);
+        local.activity.touch();
+        space.register_pool(local.static_ref());
         Self {
-            tlab: DiscreteTLAB::new(),
-            local: Box::new_in(Pool::new(false), Meta),
+            tlab: DiscreteTLAB::new().with_max_bin_len(Self::MAX_TLAB_BIN_LEN),
+            per_cpu: per_cpu::ENABLED && space.per_cpu.is_available(),
+            remote: [CellBatch::EMPTY; HoardAllocator::SMALL_SIZE_CLASSES],
+            local,
             space,
+            budget: DEFAULT_THREAD_CACHE_BUDGET / 2,
+            tlab_misses: 0,
         }
     }
+
+    /// Keep at most `bytes` free in the TLAB and the local pool together.
+    pub fn with_cache_budget(mut self, bytes: usize) -> Self {
+        self.budget = bytes / 2;
+        self
+    }
+
+    #[cold]
+    fn trim_local_pool(&self) {
+        self.local.trim_to(self.budget, true);
+    }
+
+    /// Refill the TLAB with a batch of cells freed by other threads.
+    fn refill_tlab(&mut self, size_class: SizeClass) -> Option<Address> {
+        let mut batch = self.space.transfer.remove_batch(size_class.as_usize())?;
+        let cell = batch.pop();
+        batch.for_each(|c| self.tlab.push(size_class, c));
+        cell
+    }
+
+    fn free_remote_cells(&mut self) {
+        for batch in &mut self.remote {
+            batch
+                .take()
+                .for_each(|cell| self.local.free_cell(cell, self.space));
+        }
+    }
+
+    pub fn local_pool(&self) -> &'static Pool {
+        self.local.static_ref()
+    }
 }
 
 impl Drop for HoardAllocator {
     fn drop(&mut self) {
+        self.free_remote_cells();
         self.tlab
             .clear(|cell| self.local.free_cell(cell, self.space));
+        self.space
+            .unregister_and_flush_pool(self.local.static_ref());
     }
 }
 
@@ -106,10 +248,25 @@ impl Allocator for HoardAllocator {
     #[inline(always)]
     fn alloc(&mut self, layout: Layout) -> Option<Address> {
         let size_class = SizeClass::from_layout(layout);
-        if layout.size() <= Self::LARGEST_SMALL_OBJECT {
-            if let Some(cell) = self.tlab.pop(size_class) {
+        if size_class.bytes() <= Self::LARGEST_SMALL_OBJECT {
+            let cell = if self.per_cpu {
+                self.space.per_cpu.pop(size_class.as_usize())
+            } else {
+                self.tlab.pop(size_class)
+            };
+            if let Some(cell) = cell {
                 return Some(cell);
             }
+            if !self.per_cpu {
+                if let Some(cell) = self.refill_tlab(size_class) {
+                    return Some(cell);
+                }
+            }
+        }
+        self.local.activity.touch();
+        self.tlab_misses += 1;
+        if self.tlab_misses % Self::TLAB_MISSES_PER_TICK == 0 {
+            CACHE_GC.tick();
         }
         self.local.alloc_cell(size_class, self.space)
     }
@@ -118,13 +275,38 @@ impl Allocator for HoardAllocator {
     fn dealloc(&mut self, cell: Address) {
         let block = SuperBlock::containing(cell);
         let size = block.size_class.bytes();
-        if size <= Self::LARGEST_SMALL_OBJECT
-            && size + self.tlab.free_bytes() <= Self::LOCAL_HEAP_THRESHOLD
-        // && block.is_owned_by(&self.local)
+        if self.per_cpu {
+            // Falls back to the local pool if the CPU's cache is full.
+            if size <= Self::LARGEST_SMALL_OBJECT
+                && self.space.per_cpu.push(block.size_class.as_usize(), cell)
+            {
+                return;
+            }
+        } else if size <= Self::LARGEST_SMALL_OBJECT && !block.is_owned_by(&self.local) {
+            // Falls back to freeing into the owners' pools if the transfer cache is full.
+            let size_class = block.size_class.as_usize();
+            let batch = &mut self.remote[size_class];
+            if let Err(batch) = self.space.transfer.free_cell(size_class, batch, cell) {
+                batch.for_each(|c| self.local.free_cell(c, self.space));
+            }
+            return;
+        } else if size <= Self::LARGEST_SMALL_OBJECT && size + self.tlab.free_bytes() <= self.budget
         {
-            self.tlab.push(block.size_class, cell);
-        } else {
-            self.local.free_cell(cell, self.space);
+            self.tlab.push_bounded(block.size_class, cell, |c| {
+                self.local.free_cell(c, self.space)
+            });
+            return;
         }
+        self.local.free_cell(cell, self.space);
+        if self.local.free_bytes() > self.budget {
+            self.trim_local_pool();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.free_remote_cells();
+        self.tlab
+            .clear(|cell| self.local.free_cell(cell, self.space));
+        self.local.flush_all();
     }
 }
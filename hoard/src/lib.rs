@@ -1,6 +1,7 @@
 #![feature(thread_local)]
 #![feature(step_trait)]
 #![feature(allocator_api)]
+#![feature(naked_functions)]
 
 extern crate mallockit;
 
@@ -11,12 +12,14 @@ mod super_block;
 use hoard_space::*;
 use mallockit::{
     space::{large_object_space::*, *},
-    util::*,
+    util::{mem::alloc::thread_cache, *},
     Mutator, Plan,
 };
 
 const HOARD_SPACE: SpaceId = SpaceId::DEFAULT;
 const LARGE_OBJECT_SPACE: SpaceId = SpaceId::LARGE_OBJECT_SPACE;
+/// Free memory each thread may keep cached, per allocator.
+const THREAD_CACHE_BUDGET: usize = thread_cache::DEFAULT_THREAD_CACHE_BUDGET;
 
 #[mallockit::plan]
 struct Hoard {
@@ -42,6 +45,30 @@ impl Plan for Hoard {
             Self::get().large_object_space.get_layout::<Size4K>(ptr)
         }
     }
+
+    fn good_size(layout: Layout) -> usize {
+        if HoardSpace::can_allocate(layout) {
+            HoardSpace::good_size(layout)
+        } else {
+            LargeObjectSpace::good_size::<Size4K>(layout)
+        }
+    }
+
+    fn fork_prepare(&'static self) {
+        self.hoard_space.fork_prepare();
+        self.large_object_space.fork_prepare();
+    }
+
+    fn fork_parent(&'static self) {
+        self.large_object_space.fork_release();
+        self.hoard_space.fork_release();
+    }
+
+    fn fork_child(&'static self) {
+        self.fork_parent();
+        let current = HoardMutator::try_current().map(|m| m.hoard.local_pool());
+        self.hoard_space.reclaim_pools_after_fork(current);
+    }
 }
 
 #[mallockit::mutator]
@@ -56,8 +83,10 @@ impl Mutator for HoardMutator {
 
     fn new() -> Self {
         Self {
-            hoard: HoardAllocator::new(&Self::plan().hoard_space, HOARD_SPACE),
-            los: LargeObjectAllocator::new(&Self::plan().large_object_space),
+            hoard: HoardAllocator::new(&Self::plan().hoard_space, HOARD_SPACE)
+                .with_cache_budget(THREAD_CACHE_BUDGET),
+            los: LargeObjectAllocator::new(&Self::plan().large_object_space)
+                .with_cache_budget(THREAD_CACHE_BUDGET),
             _padding: [0; 8],
         }
     }
@@ -84,4 +113,19 @@ impl Mutator for HoardMutator {
             self.los.dealloc(ptr)
         }
     }
+
+    fn on_thread_exit(&mut self) {
+        self.hoard.flush();
+        self.los.flush();
+    }
+}
+
+#[cfg(test)]
+mod thread_exit_tests {
+    mallockit::rust_allocator_tests!(crate::Global, short_lived_threads);
+}
+
+#[cfg(test)]
+mod cross_thread_tests {
+    mallockit::rust_allocator_tests!(crate::Global, producer_consumer);
 }
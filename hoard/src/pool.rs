@@ -1,10 +1,12 @@
 use crate::{hoard_space::HoardSpace, super_block::SuperBlock};
+use mallockit::util::mem::alloc::thread_cache::{CacheActivity, ThreadCache};
 use mallockit::{
     space::page_resource::MemRegion,
     util::{mem::size_class::SizeClass, Address},
     Plan,
 };
 use spin::{relax::Yield, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 type Mutex<T> = spin::mutex::Mutex<T, Yield>;
 
@@ -140,6 +142,10 @@ impl BlockList {
         }
     }
 
+    const fn free_bytes(&self) -> usize {
+        self.total_bytes - self.used_bytes
+    }
+
     const fn should_flush(&self, log_obj_size: usize) -> bool {
         let u = self.used_bytes;
         let a = self.total_bytes;
@@ -240,10 +246,45 @@ pub struct Pool {
     pub global: bool,
     // This is a major difference to the original hoard: we lock bins instead of the entire local heap.
     blocks: [Mutex<BlockList>; Self::MAX_BINS],
+    /// Free bytes in all bins. Updated whenever a bin changes.
+    free_bytes: AtomicUsize,
+    pub(crate) activity: CacheActivity,
 }
 
 impl Drop for Pool {
     fn drop(&mut self) {
+        self.flush_all();
+    }
+}
+
+impl Pool {
+    const MAX_BINS: usize = 32;
+
+    pub const fn new(global: bool) -> Self {
+        Self {
+            global,
+            blocks: [const { Mutex::new(BlockList::new()) }; 32],
+            free_bytes: AtomicUsize::new(0),
+            activity: CacheActivity::new(),
+        }
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.free_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Apply the change of the free bytes of a bin to the pool total.
+    fn update_free_bytes(&self, before: usize, blocks: &BlockList) {
+        let after = blocks.free_bytes();
+        if after > before {
+            self.free_bytes.fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            self.free_bytes.fetch_sub(before - after, Ordering::Relaxed);
+        }
+    }
+
+    /// Move all blocks to the global pool.
+    pub fn flush_all(&self) {
         let space = &crate::Hoard::get().hoard_space;
         for (i, block) in self.blocks.iter().enumerate() {
             let sz: SizeClass = SizeClass(i as _);
@@ -256,17 +297,65 @@ impl Drop for Pool {
                     space.flush_block(sz, b);
                 }
             }
+            self.free_bytes
+                .fetch_sub(block.free_bytes(), Ordering::Relaxed);
+            block.used_bytes = 0;
+            block.total_bytes = 0;
         }
     }
-}
 
-impl Pool {
-    const MAX_BINS: usize = 32;
+    /// Move the most empty blocks out of the pool until at most `target_bytes` are free.
+    /// Empty blocks go back to the page resource, the others to the global pool.
+    ///
+    /// With `blocking == false`, busy bins are skipped. Returns the number of free bytes moved.
+    pub fn trim_to(&self, target_bytes: usize, blocking: bool) -> usize {
+        debug_assert!(!self.global);
+        let space = &crate::Hoard::get().hoard_space;
+        let mut released = 0;
+        for (i, bin) in self.blocks.iter().enumerate() {
+            if self.free_bytes() <= target_bytes {
+                break;
+            }
+            let blocks = if blocking {
+                Some(bin.lock())
+            } else {
+                bin.try_lock()
+            };
+            let Some(mut blocks) = blocks else {
+                continue;
+            };
+            while self.free_bytes() > target_bytes {
+                let before = blocks.free_bytes();
+                let Some(block) = blocks.pop_most_empty_block() else {
+                    break;
+                };
+                self.update_free_bytes(before, &blocks);
+                released += before - blocks.free_bytes();
+                if block.is_empty() {
+                    space.release_block(block);
+                } else {
+                    space.flush_block(SizeClass(i as _), block);
+                }
+            }
+        }
+        released
+    }
 
-    pub const fn new(global: bool) -> Self {
-        Self {
-            global,
-            blocks: [const { Mutex::new(BlockList::new()) }; 32],
+    /// Lock all bins, in size class order. See `Plan::fork_prepare`.
+    pub fn lock_all(&self) {
+        for bin in &self.blocks {
+            std::mem::forget(bin.lock());
+        }
+    }
+
+    /// Release the locks taken by `lock_all`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must be the thread that called `lock_all`, or its forked child.
+    pub unsafe fn force_unlock_all(&self) {
+        for bin in self.blocks.iter().rev() {
+            bin.force_unlock();
         }
     }
 
@@ -277,8 +366,10 @@ impl Pool {
     pub fn put(&self, size_class: SizeClass, mut block: SuperBlock) {
         // debug_assert!(!block.is_full());
         let mut blocks = self.lock_blocks(size_class);
+        let before = blocks.free_bytes();
         block.owner = self.static_ref();
         blocks.put(block);
+        self.update_free_bytes(before, &blocks);
     }
 
     pub fn pop_most_empty_block(
@@ -287,8 +378,10 @@ impl Pool {
     ) -> Option<(SuperBlock, MutexGuard<BlockList>)> {
         debug_assert!(self.global);
         let mut blocks = self.lock_blocks(size_class);
+        let before = blocks.free_bytes();
         if let Some(block) = blocks.pop_most_empty_block() {
             debug_assert!(block.is_owned_by(self));
+            self.update_free_bytes(before, &blocks);
             return Some((block, blocks));
         }
         None
@@ -305,15 +398,21 @@ impl Pool {
     ) -> Option<Address> {
         debug_assert!(!self.global);
         let mut blocks = self.lock_blocks(size_class);
+        let before = blocks.free_bytes();
         if let Some(a) = blocks.alloc_cell(size_class) {
+            self.update_free_bytes(before, &blocks);
             return Some(a);
         }
         // slow-path
         loop {
             if let Some(a) = blocks.alloc_cell(size_class) {
+                self.update_free_bytes(before, &blocks);
                 return Some(a);
             }
-            let block = space.acquire_block(size_class, self)?;
+            let Some(block) = space.acquire_block(size_class, self) else {
+                self.update_free_bytes(before, &blocks);
+                return None;
+            };
             blocks.put(block);
         }
     }
@@ -338,6 +437,7 @@ impl Pool {
         blocks: &mut BlockList,
         block: SuperBlock,
     ) {
+        let before = blocks.free_bytes();
         blocks.free_cell(cell, block, block.size_class);
         if block.is_empty() {
             blocks.remove(block);
@@ -347,6 +447,7 @@ impl Pool {
         if !self.global && blocks.should_flush(block.size_class.log_bytes()) {
             self.flush_block_slow(block.size_class, space, blocks);
         }
+        self.update_free_bytes(before, blocks);
     }
 
     #[cold]
@@ -366,3 +467,17 @@ impl Pool {
         }
     }
 }
+
+impl ThreadCache for Pool {
+    fn cached_bytes(&self) -> usize {
+        self.free_bytes()
+    }
+
+    fn last_active(&self) -> usize {
+        self.activity.last_active()
+    }
+
+    fn trim(&self) -> usize {
+        self.trim_to(0, false)
+    }
+}
@@ -107,6 +107,9 @@ pub fn mutator(_attr: TokenStream, item: TokenStream) -> TokenStream {
             #[cfg(not(target_os = "macos"))]
             extern "C" fn mallockit_pthread_destructor() {
                 unsafe {
+                    if let Some(mutator) = MUTATOR.get_mut() {
+                        <super::#name as ::mallockit::Mutator>::on_thread_exit(mutator);
+                    }
                     MUTATOR.reset(init);
                 }
             }
@@ -115,7 +118,9 @@ pub fn mutator(_attr: TokenStream, item: TokenStream) -> TokenStream {
             #[cfg(target_os = "macos")]
             extern "C" fn mallockit_pthread_destructor() {
                 use crate::mallockit::mutator::TLS;
-                <super::#name as ::mallockit::mutator::TLS>::current().reset();
+                let mutator = <super::#name as ::mallockit::mutator::TLS>::current();
+                <super::#name as ::mallockit::Mutator>::on_thread_exit(mutator);
+                mutator.reset();
             }
         }
 
@@ -128,6 +133,11 @@ pub fn mutator(_attr: TokenStream, item: TokenStream) -> TokenStream {
             fn current() -> &'static mut Self {
                 unsafe { &mut *__mallockit_mutator::MUTATOR }
             }
+
+            #[cfg(not(target_os = "macos"))]
+            fn try_current() -> Option<&'static mut Self> {
+                unsafe { __mallockit_mutator::MUTATOR.get_mut() }
+            }
         }
 
     };
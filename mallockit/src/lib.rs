@@ -2,11 +2,13 @@
 #![feature(step_trait)]
 #![feature(thread_local)]
 #![feature(allocator_api)]
+#![feature(btreemap_alloc)]
 #![feature(never_type)]
 #![feature(associated_type_defaults)]
 #![feature(alloc_layout_extra)]
 #![feature(adt_const_params)]
 #![feature(generic_const_exprs)]
+#![feature(linkage)]
 
 extern crate mallockit_macros;
 pub extern crate spin;
@@ -1,3 +1,4 @@
+use spin::Once;
 use std::alloc::Layout;
 use std::ptr;
 #[cfg(not(target_os = "macos"))]
@@ -16,6 +17,11 @@ pub trait Mutator: Sized + 'static + TLS {
         <Self as TLS>::current()
     }
 
+    /// The mutator of the current thread, or `None` if the thread has not created it yet.
+    fn try_current() -> Option<&'static mut Self> {
+        <Self as TLS>::try_current()
+    }
+
     fn plan() -> &'static Self::Plan {
         Self::Plan::get()
     }
@@ -33,6 +39,20 @@ pub trait Mutator: Sized + 'static + TLS {
 
     fn dealloc(&mut self, ptr: Address);
 
+    /// Free `ptr`, which was allocated or last reallocated with `layout`.
+    ///
+    /// Plans can override this to take the size from `layout` instead of looking it up. A plan that derives
+    /// a size class from `layout` must also override `realloc`, so that no object shrinks in place out of it.
+    fn dealloc_sized(&mut self, ptr: Address, _layout: Layout) {
+        self.dealloc(ptr)
+    }
+
+    /// Called by the pthread destructor, before the thread-local mutator is dropped.
+    ///
+    /// Mutators must `flush` all their allocators here, so that cached memory
+    /// is not stranded in threads that will never allocate again.
+    fn on_thread_exit(&mut self);
+
     fn realloc(&mut self, ptr: Address, new_layout: Layout) -> Option<Address> {
         let layout = Self::Plan::get_layout(ptr);
         if layout.size() >= new_layout.size() && layout.align() >= new_layout.align() {
@@ -101,6 +121,14 @@ pub trait TLS: Sized {
         unsafe { &mut *macos_tls::get_tls::<Self>() }
     }
 
+    #[cfg(not(target_os = "macos"))]
+    fn try_current() -> Option<&'static mut Self>;
+
+    #[cfg(target_os = "macos")]
+    fn try_current() -> Option<&'static mut Self> {
+        unsafe { macos_tls::try_get_tls::<Self>().as_mut() }
+    }
+
     fn reset(&mut self) {
         *self = Self::new();
     }
@@ -115,6 +143,11 @@ impl TLS for u8 {
     fn current() -> &'static mut Self {
         unreachable!()
     }
+
+    #[cfg(not(target_os = "macos"))]
+    fn try_current() -> Option<&'static mut Self> {
+        unreachable!()
+    }
 }
 
 #[cfg(target_os = "macos")]
@@ -188,9 +221,27 @@ mod macos_tls {
         unsafe { &mut (*tls).1 }
     }
 
-    fn alloc_tls<T>() -> *mut T {
-        static ALLOC_BUFFER: Mutex<AllocationArea, Yield> = Mutex::new(AllocationArea::EMPTY);
+    /// Like `get_tls`, but null if the thread-local storage of the thread was not initialized yet.
+    #[allow(unused)]
+    pub(super) fn try_get_tls<T: TLS>() -> *mut T {
+        let tls = _get_tls::<(InternalTLS, T)>();
+        if tls.is_null() {
+            return std::ptr::null_mut();
+        }
+        unsafe { &mut (*tls).1 }
+    }
+
+    static ALLOC_BUFFER: Mutex<AllocationArea, Yield> = Mutex::new(AllocationArea::EMPTY);
+
+    pub(crate) fn fork_prepare() {
+        std::mem::forget(ALLOC_BUFFER.lock());
+    }
+
+    pub(crate) fn fork_release() {
+        unsafe { ALLOC_BUFFER.force_unlock() }
+    }
 
+    fn alloc_tls<T>() -> *mut T {
         let layout = Layout::new::<T>();
         if layout.size() > Page::<Size4K>::MASK / 2 {
             RawMemory::map_anonymous(layout.size()).unwrap().into()
@@ -248,7 +299,12 @@ mod macos_tls {
     }
 }
 
-static mut TLS_KEY: libc::pthread_key_t = libc::pthread_key_t::MAX;
+#[cfg(target_os = "macos")]
+pub(crate) use macos_tls::{
+    fork_prepare as macos_tls_fork_prepare, fork_release as macos_tls_fork_release,
+};
+
+static TLS_KEY: Once<libc::pthread_key_t> = Once::new();
 
 #[thread_local]
 static X: usize = 0;
@@ -264,13 +320,21 @@ extern "C" fn dtor(_ptr: *mut libc::c_void) {
 }
 
 pub fn init_pthread_specific() {
+    let key = init_pthread_key();
     unsafe {
-        libc::pthread_setspecific(TLS_KEY, &X as *const usize as _);
+        libc::pthread_setspecific(key, &X as *const usize as _);
     }
 }
 
-pub(crate) fn init_pthread_key() {
-    unsafe {
-        libc::pthread_key_create(std::ptr::addr_of_mut!(TLS_KEY), Some(dtor));
-    }
+/// Create the key whose destructor runs `Mutator::on_thread_exit`.
+///
+/// Called eagerly on process start, or lazily by the first mutator when mallockit is not the system malloc.
+pub(crate) fn init_pthread_key() -> libc::pthread_key_t {
+    *TLS_KEY.call_once(|| {
+        let mut key = libc::pthread_key_t::MAX;
+        unsafe {
+            libc::pthread_key_create(&mut key, Some(dtor));
+        }
+        key
+    })
 }
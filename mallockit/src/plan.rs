@@ -9,6 +9,23 @@ pub trait Plan: Singleton + Sized + 'static {
     fn init(&'static self) {}
     fn get_layout(ptr: Address) -> Layout;
 
+    /// The usable size of an object allocated with `layout`, i.e. `get_layout(ptr).size()` of the result.
+    fn good_size(layout: Layout) -> usize {
+        layout.size()
+    }
+
+    /// Called before `fork`. Acquire all global locks of the plan, in a fixed order.
+    fn fork_prepare(&'static self) {}
+
+    /// Called in the parent after `fork`. Release the locks taken by `fork_prepare`.
+    fn fork_parent(&'static self) {}
+
+    /// Called in the child after `fork`. Release the locks taken by `fork_prepare`,
+    /// and reclaim any state owned by threads that do not exist in the child.
+    fn fork_child(&'static self) {
+        self.fork_parent()
+    }
+
     fn get() -> &'static Self {
         <Self as Singleton>::singleton()
     }
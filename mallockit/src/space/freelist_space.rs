@@ -1,14 +1,19 @@
 use super::{
-    page_resource::{BlockPageResource, MemRegion},
+    page_resource::{BlockPageResource, MemRegion, PageResource},
+    side_metadata::{SideMetadata, SideMetadataSpec},
     Allocator, Space, SpaceId,
 };
 use crate::util::bits::{BitField, BitFieldSlot};
+use crate::util::mem::alloc::transfer_cache::{CellBatch, TransferCache};
 use crate::util::mem::freelist::intrusive_freelist::AddressSpaceConfig;
 use crate::util::mem::freelist::intrusive_freelist::IntrusiveFreeList;
 use crate::util::mem::heap::HEAP;
 use crate::util::*;
-use spin::Mutex;
-use std::{ops::Range, sync::atomic::AtomicUsize};
+use spin::{Mutex, Once};
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 // type ActivePageSize = Size4K;
 type ActivePageSize = Size2M;
@@ -29,29 +34,61 @@ impl MemRegion for Chunk {
     }
 }
 
+/// Cells up to this size class are exchanged between threads through the transfer cache.
+const MAX_BATCHED_SIZE_CLASS: usize = Size4K::LOG_BYTES - AddressSpace::LOG_MIN_ALIGNMENT;
+const TRANSFER_BATCH_SIZE: usize = 32;
+
+/// The free list that each chunk was last handed to, recorded at the chunk.
+const CHUNK_OWNERS: SideMetadataSpec =
+    SideMetadataSpec::new("chunk owners", ActivePageSize::LOG_BYTES, 6);
+
 pub struct AddressSpace;
 
+type FreeList = IntrusiveFreeList<AddressSpace>;
+
 impl AddressSpaceConfig for AddressSpace {
     const LOG_MIN_ALIGNMENT: usize = 3;
     const LOG_COVERAGE: usize = SpaceId::LOG_MAX_SPACE_SIZE;
     const LOG_MAX_CELL_SIZE: usize = ActivePageSize::LOG_BYTES;
 }
 
-pub struct FreeListSpace {
+/// Cell metadata of the spaces without cell headers, indexed by space id.
+///
+/// Free cell heads are marked by the free lists, and allocated cell heads hold their size class.
+static CELL_METADATA: [Once<SideMetadata>; 16] = [const { Once::new() }; 16];
+
+/// A buddy allocator over 2 MiB chunks.
+///
+/// By default each object is preceded by a `Cell` word recording its cell. With `SIDE_METADATA`, cells
+/// are handed out whole and their size classes are kept in side metadata instead, so objects have no
+/// header at all and are aligned to their cell size.
+pub struct FreeListSpace<const SIDE_METADATA: bool = false> {
     id: SpaceId,
     pr: BlockPageResource<Chunk>,
     pages: Mutex<Option<Page<ActivePageSize>>>,
+    /// Free cells flushed by exited threads, adopted by allocators that run out of cells.
+    orphans: Mutex<FreeList>,
+    transfer: TransferCache<{ MAX_BATCHED_SIZE_CLASS + 1 }, TRANSFER_BATCH_SIZE>,
+    owners: SideMetadata,
+    /// Cells needed by larger objects are left to the plan's large object space.
+    max_cell_size: usize,
 }
 
-impl Space for FreeListSpace {
-    const MAX_ALLOCATION_SIZE: usize = Size4K::BYTES;
+impl<const SIDE_METADATA: bool> Space for FreeListSpace<SIDE_METADATA> {
+    /// Cells can be as large as a whole chunk.
+    const MAX_ALLOCATION_SIZE: usize = ActivePageSize::BYTES;
     type PR = BlockPageResource<Chunk>;
 
     fn new(id: SpaceId) -> Self {
+        let orphans = Self::new_freelist(true, id);
         Self {
             id,
             pr: BlockPageResource::new(id),
             pages: Mutex::new(None),
+            orphans: Mutex::new(orphans),
+            transfer: TransferCache::new(),
+            owners: SideMetadata::new(id, CHUNK_OWNERS),
+            max_cell_size: Self::MAX_ALLOCATION_SIZE,
         }
     }
 
@@ -63,7 +100,33 @@ impl Space for FreeListSpace {
         &self.pr
     }
 
+    fn page_resource_mut(&mut self) -> &mut Self::PR {
+        &mut self.pr
+    }
+
+    fn fork_prepare(&self) {
+        self.transfer.fork_prepare();
+        std::mem::forget(self.orphans.lock());
+        std::mem::forget(self.pages.lock());
+        self.pr.fork_prepare();
+    }
+
+    fn fork_release(&self) {
+        self.pr.fork_release();
+        unsafe {
+            self.pages.force_unlock();
+            self.orphans.force_unlock();
+        }
+        self.transfer.fork_release();
+    }
+
     fn get_layout(ptr: Address) -> Layout {
+        if SIDE_METADATA {
+            let size_class = Self::cell_metadata(SpaceId::from(ptr)).load(ptr, Ordering::Relaxed);
+            debug_assert!(size_class != 0 && size_class & FreeList::FREE_CELL == 0);
+            let bytes = FreeList::cell_bytes(size_class, false);
+            return unsafe { Layout::from_size_align_unchecked(bytes, bytes) };
+        }
         let cell = Cell::from(ptr);
         let bytes = cell.data_size();
         let align = cell.align();
@@ -73,11 +136,85 @@ impl Space for FreeListSpace {
     }
 }
 
-impl FreeListSpace {
-    pub fn can_allocate(layout: Layout) -> bool {
-        let (extended_layout, _) = unsafe { Layout::new::<Cell>().extend_unchecked(layout) };
-        extended_layout.padded_size() + IntrusiveFreeList::<AddressSpace>::HEADER_SIZE
-            <= FreeListSpace::MAX_ALLOCATION_SIZE
+impl<const SIDE_METADATA: bool> FreeListSpace<SIDE_METADATA> {
+    /// Whether cells carry the free list's header word.
+    const HEADERS: bool = !SIDE_METADATA;
+
+    /// Only allocate objects whose cells fit in `bytes`, a power of two no larger than a chunk.
+    ///
+    /// This is the cutoff between this space and the large object space: buddy cells waste up to half of
+    /// their size, but large objects are rounded up to pages.
+    pub const fn with_max_cell_size(mut self, bytes: usize) -> Self {
+        debug_assert!(bytes.is_power_of_two());
+        debug_assert!(bytes >= 1 << (AddressSpace::LOG_MIN_ALIGNMENT + 2));
+        debug_assert!(bytes <= Self::MAX_ALLOCATION_SIZE);
+        self.max_cell_size = bytes;
+        self
+    }
+
+    pub const fn max_cell_size(&self) -> usize {
+        self.max_cell_size
+    }
+
+    /// Objects aligned to more than a page and to more than their size would mostly fill their cells with
+    /// padding, and are left to the page-granular large object space.
+    pub fn can_allocate(&self, layout: Layout) -> bool {
+        if layout.align() > Size4K::BYTES && layout.align() > layout.size() {
+            return false;
+        }
+        let (bytes, _) = Self::cell_layout(layout);
+        let header = if SIDE_METADATA {
+            0
+        } else {
+            FreeList::HEADER_SIZE
+        };
+        bytes + header <= self.max_cell_size
+    }
+
+    /// The usable size of an object allocated with `layout`: the rest of its cell.
+    pub fn good_size(layout: Layout) -> usize {
+        let (bytes, offset) = Self::cell_layout(layout);
+        let size_class = FreeList::cell_size_class(bytes, Self::HEADERS);
+        FreeList::cell_bytes(size_class, Self::HEADERS) - offset
+    }
+
+    fn cell_metadata(id: SpaceId) -> &'static SideMetadata {
+        debug_assert!(SIDE_METADATA);
+        CELL_METADATA[id.0 as usize].call_once(|| SideMetadata::new(id, FreeList::CELL_METADATA))
+    }
+
+    fn new_freelist(shared: bool, id: SpaceId) -> FreeList {
+        let freelist = IntrusiveFreeList::new(shared, HEAP.get_space_range(id).start);
+        if SIDE_METADATA {
+            freelist.with_side_metadata(Self::cell_metadata(id))
+        } else {
+            freelist
+        }
+    }
+
+    /// The cell bytes to request for `layout`, and the offset of the object in the cell.
+    ///
+    /// Cells start `HEADER_SIZE` bytes into a buddy block aligned to its size, and blocks are never smaller
+    /// than the alignment. So the object goes at the first aligned address that leaves room for its `Cell` word.
+    ///
+    /// Without headers the object takes the whole cell, which is aligned to its size.
+    const fn cell_layout(layout: Layout) -> (usize, usize) {
+        if SIDE_METADATA {
+            let bytes = if layout.size() > layout.align() {
+                layout.size()
+            } else {
+                layout.align()
+            };
+            return (
+                bytes.next_multiple_of(1 << AddressSpace::LOG_MIN_ALIGNMENT),
+                0,
+            );
+        }
+        let header = FreeList::HEADER_SIZE;
+        let offset =
+            (header + std::mem::size_of::<Cell>()).next_multiple_of(layout.align()) - header;
+        let bytes = (offset + layout.size()).next_multiple_of(1 << AddressSpace::LOG_MIN_ALIGNMENT);
+        (bytes, offset)
     }
 
     fn add_coalesced_page(&self, page: Page<ActivePageSize>) {
@@ -106,12 +243,13 @@ struct Cell {
 }
 
 impl Cell {
-    const START_OFFSET: BitField = BitField { bits: 21, shift: 0 };
+    /// Wide enough for cells of `MAX_ALLOCATION_SIZE` bytes, and objects at any offset in them.
+    const START_OFFSET: BitField = BitField { bits: 22, shift: 0 };
     const SIZE: BitField = BitField {
-        bits: 21,
-        shift: 21,
+        bits: 22,
+        shift: 22,
     };
-    const LOG_ALIGN: BitField = BitField { bits: 8, shift: 42 };
+    const LOG_ALIGN: BitField = BitField { bits: 8, shift: 44 };
 
     const fn from(ptr: Address) -> &'static mut Self {
         unsafe { &mut *ptr.as_mut_ptr::<Self>().sub(1) }
@@ -139,38 +277,92 @@ impl Cell {
     }
 }
 
-pub struct FreeListAllocator {
-    space: &'static FreeListSpace,
-    freelist: IntrusiveFreeList<AddressSpace>,
+pub struct FreeListAllocator<const SIDE_METADATA: bool = false> {
+    space: &'static FreeListSpace<SIDE_METADATA>,
+    freelist: FreeList,
+    /// Cells of other threads' chunks freed by this thread, per small size class.
+    /// Moved to the space's transfer cache once full.
+    batches: [CellBatch; MAX_BATCHED_SIZE_CLASS + 1],
 }
 
-impl FreeListAllocator {
-    pub fn new<const SPACE_ID: SpaceId>(space: &'static FreeListSpace) -> Self {
+impl<const SIDE_METADATA: bool> FreeListAllocator<SIDE_METADATA> {
+    const HEADERS: bool = !SIDE_METADATA;
+
+    pub fn new<const SPACE_ID: SpaceId>(space: &'static FreeListSpace<SIDE_METADATA>) -> Self {
         Self {
             space,
-            freelist: IntrusiveFreeList::new(false, HEAP.get_space_range(SPACE_ID).start),
+            freelist: FreeListSpace::<SIDE_METADATA>::new_freelist(false, SPACE_ID),
+            batches: [CellBatch::EMPTY; MAX_BATCHED_SIZE_CLASS + 1],
         }
     }
 
+    /// Take over up to a chunk worth of the free cells left behind by exited threads.
+    fn adopt_orphans(&mut self) -> bool {
+        let mut orphans = self.space.orphans.lock();
+        orphans.transfer_to(&mut self.freelist, ActivePageSize::BYTES) != 0
+    }
+
     #[cold]
     fn alloc_cell_slow(&mut self, bytes: usize) -> Option<Range<Address>> {
+        let size_class = FreeList::cell_size_class(bytes, Self::HEADERS);
+        if size_class <= MAX_BATCHED_SIZE_CLASS {
+            let batch = &mut self.batches[size_class];
+            if let Some(start) = self.space.transfer.alloc_cell(size_class, batch) {
+                return Some(start..start + FreeList::cell_bytes(size_class, Self::HEADERS));
+            }
+        }
+        if self.adopt_orphans() {
+            if let Some(range) = self.freelist.allocate_cell(bytes) {
+                return Some(range);
+            }
+        }
         let range = match self.space.get_coalesced_page() {
             Some(page) => page.range(),
             _ => self.space.pr.acquire_block()?.data(),
         };
+        self.space
+            .owners
+            .store(range.start, self.owner(), Ordering::Relaxed);
         self.freelist.add_units(range.start, ActivePageSize::BYTES);
         self.alloc_cell(bytes)
     }
 
+    /// Allocate from the thread-local cells first. The transfer cache is only locked on a local miss.
     fn alloc_cell(&mut self, bytes: usize) -> Option<Range<Address>> {
+        let size_class = FreeList::cell_size_class(bytes, Self::HEADERS);
+        if size_class <= MAX_BATCHED_SIZE_CLASS {
+            if let Some(start) = self.batches[size_class].pop() {
+                return Some(start..start + FreeList::cell_bytes(size_class, Self::HEADERS));
+            }
+        }
         if let Some(range) = self.freelist.allocate_cell(bytes) {
             return Some(range);
         }
         self.alloc_cell_slow(bytes)
     }
 
+    /// Identifies the chunks handed to this allocator's free list.
+    fn owner(&self) -> usize {
+        &self.freelist as *const FreeList as usize
+    }
+
+    /// Like Hoard's `is_owned_by`: whether the chunk of `cell` was last handed to this allocator.
+    fn owns(&self, cell: Address) -> bool {
+        self.space.owners.load(cell, Ordering::Relaxed) == self.owner()
+    }
+
+    /// Cells of this allocator's own chunks go straight back to its free list, where they coalesce.
+    /// Only small cells of other chunks are batched for the transfer cache.
     fn dealloc_cell(&mut self, ptr: Address, bytes: usize) {
-        self.freelist.release_cell(ptr, bytes);
+        let size_class = FreeList::cell_size_class(bytes, Self::HEADERS);
+        if size_class > MAX_BATCHED_SIZE_CLASS || self.owns(ptr) {
+            self.freelist.release_cell(ptr, bytes);
+            return;
+        }
+        let batch = &mut self.batches[size_class];
+        if let Err(batch) = self.space.transfer.free_cell(size_class, batch, ptr) {
+            batch.for_each(|cell| self.freelist.release_cell(cell, bytes));
+        }
     }
 
     fn get_coalesced_pages(&mut self) -> Option<Page<ActivePageSize>> {
@@ -180,24 +372,203 @@ impl FreeListAllocator {
     }
 }
 
-impl Allocator for FreeListAllocator {
+impl<const SIDE_METADATA: bool> Allocator for FreeListAllocator<SIDE_METADATA> {
     fn alloc(&mut self, layout: Layout) -> Option<Address> {
-        let (extended_layout, offset) = unsafe { Layout::new::<Cell>().extend_unchecked(layout) };
-        let Range { start, end } = self.alloc_cell(extended_layout.padded_size())?;
-        let aligned_start = start.align_up(extended_layout.align());
-        let data_start = aligned_start + offset;
+        let (bytes, offset) = FreeListSpace::<SIDE_METADATA>::cell_layout(layout);
+        let Range { start, end } = self.alloc_cell(bytes)?;
+        let data_start = start + offset;
         debug_assert!(end - data_start >= layout.size());
-        Cell::from(data_start).set(start, end - start, layout.align());
-        debug_assert_eq!(usize::from(data_start) & (layout.align() - 1), 0);
+        debug_assert!(data_start.is_aligned_to(layout.align()));
+        if SIDE_METADATA {
+            let size_class = FreeList::cell_size_class(end - start, false);
+            FreeListSpace::<SIDE_METADATA>::cell_metadata(self.space.id).store(
+                start,
+                size_class,
+                Ordering::Relaxed,
+            );
+        } else {
+            Cell::from(data_start).set(start, end - start, layout.align());
+        }
         Some(data_start)
     }
 
     fn dealloc(&mut self, ptr: Address) {
-        let cell = Cell::from(ptr);
-        let bytes = cell.size();
-        self.dealloc_cell(cell.start(), bytes);
+        if SIDE_METADATA {
+            let cells = FreeListSpace::<SIDE_METADATA>::cell_metadata(self.space.id);
+            let size_class = cells.load(ptr, Ordering::Relaxed);
+            self.dealloc_cell(ptr, FreeList::cell_bytes(size_class, false));
+        } else {
+            let cell = Cell::from(ptr);
+            self.dealloc_cell(cell.start(), cell.size());
+        }
         while let Some(page) = self.get_coalesced_pages() {
             self.space.add_coalesced_page(page)
         }
     }
+
+    /// With side metadata, takes the size class from `layout` instead of loading it.
+    fn dealloc_sized(&mut self, ptr: Address, layout: Layout) {
+        if !SIDE_METADATA {
+            return self.dealloc(ptr);
+        }
+        let (bytes, _) = FreeListSpace::<SIDE_METADATA>::cell_layout(layout);
+        let size_class = FreeList::cell_size_class(bytes, false);
+        debug_assert_eq!(
+            size_class,
+            FreeListSpace::<SIDE_METADATA>::cell_metadata(self.space.id)
+                .load(ptr, Ordering::Relaxed),
+            "size mismatch in sized deallocation of {:?}",
+            ptr
+        );
+        self.dealloc_cell(ptr, FreeList::cell_bytes(size_class, false));
+        while let Some(page) = self.get_coalesced_pages() {
+            self.space.add_coalesced_page(page)
+        }
+    }
+
+    fn flush(&mut self) {
+        for (size_class, batch) in self.batches.iter_mut().enumerate() {
+            let bytes = FreeList::cell_bytes(size_class, Self::HEADERS);
+            batch
+                .take()
+                .for_each(|cell| self.freelist.release_cell(cell, bytes));
+        }
+        let mut orphans = self.space.orphans.lock();
+        self.freelist.drain_into(&mut orphans);
+        while let Some(page) = orphans.pop_raw_cell(ActivePageSize::LOG_BYTES) {
+            self.space.add_coalesced_page(Page::new(page))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin::Lazy;
+
+    static SPACE: Lazy<FreeListSpace<true>> = Lazy::new(|| FreeListSpace::new(SpaceId(4)));
+
+    #[test]
+    fn header_free_cells() {
+        let mut fla = FreeListAllocator::new::<{ SpaceId(4) }>(&SPACE);
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        assert!(SPACE.can_allocate(layout));
+        assert_eq!(FreeListSpace::<true>::good_size(layout), 32);
+        // Same-sized cells are packed back to back.
+        let cells = (0..64)
+            .map(|_| fla.alloc(layout).unwrap())
+            .collect::<std::vec::Vec<_>>();
+        assert!(cells.windows(2).any(|w| w[1] - w[0] == 32));
+        for &cell in &cells {
+            let layout = FreeListSpace::<true>::get_layout(cell);
+            assert_eq!(layout.size(), 32);
+            assert!(cell.is_aligned_to(layout.align()));
+        }
+        let odd = fla
+            .alloc(Layout::from_size_align(200, 64).unwrap())
+            .unwrap();
+        assert!(odd.is_aligned_to(256));
+        assert_eq!(FreeListSpace::<true>::get_layout(odd).size(), 256);
+        let page = fla
+            .alloc(Layout::from_size_align(Size4K::BYTES, 8).unwrap())
+            .unwrap();
+        assert_eq!(
+            FreeListSpace::<true>::get_layout(page).size(),
+            Size4K::BYTES
+        );
+        let chunk = fla
+            .alloc(Layout::from_size_align(Size2M::BYTES, 8).unwrap())
+            .unwrap();
+        assert!(chunk.is_aligned_to(Size2M::BYTES));
+        assert_eq!(
+            FreeListSpace::<true>::get_layout(chunk).size(),
+            Size2M::BYTES
+        );
+        assert!(!SPACE.can_allocate(Layout::from_size_align(Size2M::BYTES + 1, 8).unwrap()));
+        fla.dealloc(chunk);
+        fla.dealloc(page);
+        fla.dealloc_sized(odd, Layout::from_size_align(200, 64).unwrap());
+        for cell in cells {
+            fla.dealloc(cell);
+        }
+        fla.flush();
+    }
+
+    #[test]
+    fn local_and_remote_frees() {
+        let mut fla = FreeListAllocator::new::<{ SpaceId(4) }>(&SPACE);
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let size_class = FreeList::cell_size_class(48, false);
+        // Cells freed by their own thread coalesce back into a whole chunk.
+        let cells = (0..64)
+            .map(|_| fla.alloc(layout).unwrap())
+            .collect::<std::vec::Vec<_>>();
+        for cell in cells {
+            fla.dealloc(cell);
+        }
+        assert!(fla.batches.iter().all(CellBatch::is_empty));
+        assert!(fla.freelist.is_empty());
+        // Cells freed by another thread are batched for the transfer cache.
+        let cell = fla.alloc(layout).unwrap();
+        std::thread::spawn(move || {
+            let mut other = FreeListAllocator::new::<{ SpaceId(4) }>(&SPACE);
+            other.dealloc(cell);
+            assert_eq!(other.batches[size_class].pop(), Some(cell));
+            assert!(other.freelist.is_empty());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn largest_header_cells() {
+        static SPACE: Lazy<FreeListSpace> = Lazy::new(|| FreeListSpace::new(SpaceId(0)));
+        let mut fla = FreeListAllocator::new::<{ SpaceId(0) }>(&SPACE);
+        let layout = Layout::from_size_align(Size2M::BYTES - 64, 8).unwrap();
+        assert!(SPACE.can_allocate(layout));
+        let objects = [(); 3].map(|_| fla.alloc(layout).unwrap());
+        for object in objects {
+            // The whole 2 MiB chunk is the cell, past the free list header.
+            assert_eq!(
+                Cell::from(object).size(),
+                Size2M::BYTES - FreeList::HEADER_SIZE
+            );
+            assert!(FreeListSpace::<false>::get_layout(object).size() >= layout.size());
+            unsafe { object.store(object) };
+        }
+        for object in objects {
+            assert_eq!(unsafe { object.load::<Address>() }, object);
+            fla.dealloc(object);
+        }
+        assert!(fla.freelist.is_empty());
+        assert!(SPACE.get_coalesced_page().is_some());
+    }
+
+    #[test]
+    fn mid_size_cells() {
+        static SPACE: Lazy<FreeListSpace> =
+            Lazy::new(|| FreeListSpace::new(SpaceId(2)).with_max_cell_size(64 << 10));
+        let mut fla = FreeListAllocator::new::<{ SpaceId(2) }>(&SPACE);
+        assert!(SPACE.can_allocate(Layout::from_size_align(32 << 10, 8).unwrap()));
+        assert!(!SPACE.can_allocate(Layout::from_size_align(64 << 10, 8).unwrap()));
+        // Fill a chunk with 32 KiB cells, free every other one, and coalesce the rest back into a whole chunk.
+        let layout = Layout::from_size_align(30 << 10, 8).unwrap();
+        let objects = (0..64)
+            .map(|_| fla.alloc(layout).unwrap())
+            .collect::<std::vec::Vec<_>>();
+        for &object in &objects {
+            let cell = FreeListSpace::<false>::get_layout(object);
+            assert!(cell.size() >= layout.size());
+            unsafe { object.store(object) };
+        }
+        for object in objects.iter().step_by(2) {
+            fla.dealloc(*object);
+        }
+        for object in objects.iter().skip(1).step_by(2) {
+            assert_eq!(unsafe { object.load::<Address>() }, *object);
+            fla.dealloc(*object);
+        }
+        assert!(fla.freelist.is_empty());
+        assert!(SPACE.get_coalesced_page().is_some());
+    }
 }
@@ -24,6 +24,10 @@ impl Space for ImmortalSpace {
         &self.pr
     }
 
+    fn page_resource_mut(&mut self) -> &mut Self::PR {
+        &mut self.pr
+    }
+
     fn get_layout(ptr: Address) -> Layout {
         AllocationArea::load_layout(ptr)
     }
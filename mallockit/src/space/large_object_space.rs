@@ -1,15 +1,43 @@
-use std::{alloc::Layout, marker::PhantomData};
+use std::{
+    alloc::Layout,
+    marker::PhantomData,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use super::{
-    meta::Meta,
+    meta::{Box, Meta, Vec},
     page_resource::{FreelistPageResource, PageResource},
     Allocator, Space, SpaceId,
 };
-use crate::util::{Address, Page, PageSize, Size4K};
+use crate::util::mem::alloc::thread_cache::{
+    CacheActivity, ThreadCache, CACHE_GC, DEFAULT_THREAD_CACHE_BUDGET,
+};
+use crate::util::{sys::raw_memory::RawMemory, Address, Page, PageSize, Size4K};
+use spin::{mutex::Mutex, Yield};
+
+/// Default size above which large objects get dedicated mappings, the upper bound of glibc's `M_MMAP_THRESHOLD`.
+pub const DEFAULT_DIRECT_MAP_THRESHOLD: usize = 32 << 20;
+
+/// Objects above the direct-map threshold of a `LargeObjectSpace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirectMapStats {
+    /// Regions currently mapped.
+    pub mapped_regions: usize,
+    /// Bytes of the regions currently mapped.
+    pub mapped_bytes: usize,
+    /// Regions mapped so far.
+    pub total_mapped_regions: usize,
+    /// Regions unmapped so far.
+    pub total_unmapped_regions: usize,
+}
 
 pub struct LargeObjectSpace {
     id: SpaceId,
     pr: FreelistPageResource,
+    direct_map_threshold: usize,
+    mapped_bytes: AtomicUsize,
+    total_mapped_regions: AtomicUsize,
+    total_unmapped_regions: AtomicUsize,
 }
 
 impl Space for LargeObjectSpace {
@@ -19,6 +47,10 @@ impl Space for LargeObjectSpace {
         Self {
             id,
             pr: FreelistPageResource::new(id),
+            direct_map_threshold: DEFAULT_DIRECT_MAP_THRESHOLD,
+            mapped_bytes: AtomicUsize::new(0),
+            total_mapped_regions: AtomicUsize::new(0),
+            total_unmapped_regions: AtomicUsize::new(0),
         }
     }
 
@@ -30,12 +62,69 @@ impl Space for LargeObjectSpace {
         &self.pr
     }
 
+    fn page_resource_mut(&mut self) -> &mut Self::PR {
+        &mut self.pr
+    }
+
     fn get_layout(_: Address) -> Layout {
         unreachable!()
     }
 }
 
 impl LargeObjectSpace {
+    /// Give objects whose page runs are larger than `bytes` a dedicated mapping, which is unmapped as soon as
+    /// the object is freed instead of being cached or `madv_free`d. Defaults to `DEFAULT_DIRECT_MAP_THRESHOLD`.
+    ///
+    /// The mappings are placed in the space's range and tracked by its page resource, so `get_layout` and
+    /// `SpaceId::from` work as for other objects. An unmapped range is reserved for the space again right away.
+    pub fn with_direct_map_threshold(mut self, bytes: usize) -> Self {
+        self.direct_map_threshold = bytes;
+        self
+    }
+
+    pub fn direct_map_stats(&self) -> DirectMapStats {
+        let total_mapped_regions = self.total_mapped_regions.load(Ordering::Relaxed);
+        let total_unmapped_regions = self.total_unmapped_regions.load(Ordering::Relaxed);
+        DirectMapStats {
+            mapped_regions: total_mapped_regions - total_unmapped_regions,
+            mapped_bytes: self.mapped_bytes.load(Ordering::Relaxed),
+            total_mapped_regions,
+            total_unmapped_regions,
+        }
+    }
+
+    const fn is_direct_mapped(&self, aligned_size: usize) -> bool {
+        aligned_size > self.direct_map_threshold
+    }
+
+    fn map_direct<S: PageSize>(
+        &self,
+        pages: usize,
+        align: usize,
+        aligned_size: usize,
+    ) -> Option<Page<S>> {
+        let start = self.pr.acquire_dedicated_pages::<S>(pages, align)?.start;
+        self.mapped_bytes.fetch_add(aligned_size, Ordering::Relaxed);
+        self.total_mapped_regions.fetch_add(1, Ordering::Relaxed);
+        Some(start)
+    }
+
+    fn unmap_direct<S: PageSize>(&self, start: Page<S>, aligned_size: usize) {
+        self.pr.unmap_dedicated_pages(start);
+        self.mapped_bytes.fetch_sub(aligned_size, Ordering::Relaxed);
+        self.total_unmapped_regions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The usable size of an object allocated with `layout`. Runs are rounded up to a power of two pages.
+    pub const fn good_size<S: PageSize>(layout: Layout) -> usize {
+        let size = layout.size().next_power_of_two();
+        if size < S::BYTES {
+            S::BYTES
+        } else {
+            size
+        }
+    }
+
     pub fn get_layout<S: PageSize>(&self, ptr: Address) -> Layout {
         let pages = self
             .page_resource()
@@ -56,17 +145,94 @@ pub const fn bins<S: PageSize>(max_size: usize) -> usize {
     max_size.next_power_of_two().trailing_zeros() as usize - S::LOG_BYTES + 1
 }
 
+/// Pages cached by a `LargeObjectAllocator`.
+///
+/// Boxed and locked, so that the cache GC can trim the cache of an idle thread.
+struct LargeObjectCache<S: PageSize> {
+    space: &'static LargeObjectSpace,
+    bins: Mutex<Vec<Address>, Yield>,
+    cached_bytes: AtomicUsize,
+    activity: CacheActivity,
+    _p: PhantomData<fn() -> S>,
+}
+
+impl<S: PageSize> LargeObjectCache<S> {
+    const fn bin_bytes(size_class: usize) -> usize {
+        1 << (size_class + S::LOG_BYTES)
+    }
+
+    fn pop(&self, size_class: usize) -> Option<Address> {
+        let mut bins = self.bins.lock();
+        let page = bins[size_class];
+        if page.is_zero() {
+            return None;
+        }
+        bins[size_class] = unsafe { page.load() };
+        self.cached_bytes
+            .fetch_sub(Self::bin_bytes(size_class), Ordering::Relaxed);
+        Some(page)
+    }
+
+    fn push(&self, size_class: usize, page: Address) {
+        let mut bins = self.bins.lock();
+        unsafe { page.store(bins[size_class]) }
+        bins[size_class] = page;
+        self.cached_bytes
+            .fetch_add(Self::bin_bytes(size_class), Ordering::Relaxed);
+    }
+
+    /// Release cached pages, largest first, until at most `target_bytes` remain.
+    fn release_locked(&self, bins: &mut [Address], target_bytes: usize) -> usize {
+        let mut released = 0;
+        for size_class in (0..bins.len()).rev() {
+            while !bins[size_class].is_zero()
+                && self.cached_bytes.load(Ordering::Relaxed) > target_bytes
+            {
+                let page = bins[size_class];
+                bins[size_class] = unsafe { page.load() };
+                self.cached_bytes
+                    .fetch_sub(Self::bin_bytes(size_class), Ordering::Relaxed);
+                self.space.release(Page::<S>::new(page));
+                released += Self::bin_bytes(size_class);
+            }
+        }
+        released
+    }
+
+    fn release_until(&self, target_bytes: usize) -> usize {
+        self.release_locked(&mut self.bins.lock(), target_bytes)
+    }
+}
+
+impl<S: PageSize> ThreadCache for LargeObjectCache<S> {
+    fn cached_bytes(&self) -> usize {
+        self.cached_bytes.load(Ordering::Relaxed)
+    }
+
+    fn last_active(&self) -> usize {
+        self.activity.last_active()
+    }
+
+    fn trim(&self) -> usize {
+        match self.bins.try_lock() {
+            Some(mut bins) => self.release_locked(&mut bins, 0),
+            None => 0,
+        }
+    }
+}
+
 pub struct LargeObjectAllocator<
     S: PageSize = Size4K,
     const MAX_CACHEABLE_SIZE: usize = 0,
     const THRESHOLD_SLOP: usize = 0,
 > {
     space: &'static LargeObjectSpace,
-    bins: Vec<Address, Meta>,
+    cache: Box<LargeObjectCache<S>>,
+    budget: usize,
     max_live: usize,
     live: usize,
     cleared: bool,
-    _p: PhantomData<S>,
+    prefault: bool,
 }
 
 impl<S: PageSize, const MAX_CACHEABLE_SIZE: usize, const THRESHOLD_SLOP: usize>
@@ -77,15 +243,52 @@ impl<S: PageSize, const MAX_CACHEABLE_SIZE: usize, const THRESHOLD_SLOP: usize>
     pub fn new(los: &'static LargeObjectSpace) -> Self {
         let mut bins_vec = Vec::new_in(Meta);
         bins_vec.resize(bins::<S>(MAX_CACHEABLE_SIZE), Address::ZERO);
-
-        Self {
+        let cache = Box::new_in(
+            LargeObjectCache {
+                space: los,
+                bins: Mutex::new(bins_vec),
+                cached_bytes: AtomicUsize::new(0),
+                activity: CacheActivity::new(),
+                _p: PhantomData,
+            },
+            Meta,
+        );
+        let allocator = Self {
             space: los,
-            bins: bins_vec,
+            cache,
+            budget: DEFAULT_THREAD_CACHE_BUDGET,
             max_live: 0,
             live: 0,
             cleared: false,
-            _p: PhantomData,
+            prefault: false,
+        };
+        if Self::CACHE_ENABLED {
+            allocator.cache.activity.touch();
+            CACHE_GC.register(allocator.cache_ref());
         }
+        allocator
+    }
+
+    /// Populate pages freshly acquired from the space before returning them,
+    /// even if the space itself does not prefault. Cached pages are already populated.
+    pub fn with_prefault(mut self) -> Self {
+        self.prefault = true;
+        self
+    }
+
+    /// Keep at most `bytes` of free pages cached. Defaults to `DEFAULT_THREAD_CACHE_BUDGET`.
+    pub fn with_cache_budget(mut self, bytes: usize) -> Self {
+        self.budget = bytes;
+        self
+    }
+
+    /// Bytes of free pages currently cached.
+    pub fn cached_bytes(&self) -> usize {
+        self.cache.cached_bytes()
+    }
+
+    fn cache_ref(&self) -> &'static LargeObjectCache<S> {
+        unsafe { &*(&*self.cache as *const LargeObjectCache<S>) }
     }
 
     fn space(&self) -> &'static LargeObjectSpace {
@@ -93,25 +296,53 @@ impl<S: PageSize, const MAX_CACHEABLE_SIZE: usize, const THRESHOLD_SLOP: usize>
     }
 
     fn alloc_slow(&mut self, layout: Layout) -> Option<Address> {
+        CACHE_GC.tick();
         let size = layout.size();
         let pages = (size + Page::<S>::MASK) >> Page::<S>::LOG_BYTES;
-        let start_page = self.space().acquire::<S>(pages)?.start;
+        let aligned_size = usize::max(size.next_power_of_two(), S::BYTES);
+        // Over-aligned runs are carved directly, instead of acquiring `align` bytes.
+        let start_page = if self.space().is_direct_mapped(aligned_size) {
+            self.space()
+                .map_direct::<S>(pages, layout.align(), aligned_size)?
+        } else if layout.align() > S::BYTES {
+            self.space()
+                .acquire_aligned::<S>(pages, layout.align())?
+                .start
+        } else {
+            self.space().acquire::<S>(pages)?.start
+        };
         debug_assert!(start_page.start().is_aligned_to(layout.align()));
+        if self.prefault {
+            RawMemory::populate(start_page.start(), pages << S::LOG_BYTES);
+        }
         Some(start_page.start())
     }
 
     fn clear_bins(&mut self) {
-        let space = self.space();
-        for i in 0..self.bins.len() {
-            let mut page = self.bins[i];
-            if !page.is_zero() {
-                self.bins[i] = Address::ZERO;
-                while !page.is_zero() {
-                    let next_page = unsafe { page.load() };
-                    space.release(Page::<S>::new(page));
-                    page = next_page;
-                }
+        self.cache.release_until(0);
+    }
+
+    fn dealloc_impl(&mut self, ptr: Address, aligned_size: usize) {
+        if self.space().is_direct_mapped(aligned_size) {
+            self.space().unmap_direct(Page::<S>::new(ptr), aligned_size)
+        } else if Self::CACHE_ENABLED && aligned_size <= MAX_CACHEABLE_SIZE {
+            let sc = size_class::<S>(aligned_size);
+            self.cache.push(sc, ptr);
+            self.live -= usize::min(aligned_size, self.live);
+            let crossed_threshold = self.max_live > self.live + (self.live >> 2);
+            if THRESHOLD_SLOP != 0
+                && self.live > THRESHOLD_SLOP
+                && crossed_threshold
+                && !self.cleared
+            {
+                self.clear_bins();
+                self.cleared = true;
+                self.max_live = self.live;
+            } else if self.cache.cached_bytes() > self.budget {
+                self.cache.release_until(self.budget);
             }
+        } else {
+            self.space().release(Page::<S>::new(ptr))
         }
     }
 }
@@ -121,15 +352,18 @@ impl<S: PageSize, const MAX_CACHEABLE_SIZE: usize, const THRESHOLD_SLOP: usize>
 {
     #[cold]
     fn alloc(&mut self, layout: Layout) -> Option<Address> {
-        let aligned_size = layout.size().next_power_of_two();
-        if Self::CACHE_ENABLED && aligned_size <= MAX_CACHEABLE_SIZE {
+        let aligned_size = usize::max(layout.size().next_power_of_two(), S::BYTES);
+        // Cached runs are only aligned to their size.
+        if Self::CACHE_ENABLED
+            && aligned_size <= MAX_CACHEABLE_SIZE
+            && layout.align() <= aligned_size
+            && !self.space.is_direct_mapped(aligned_size)
+        {
+            self.cache.activity.touch();
             let sc = size_class::<S>(aligned_size);
-            let result = if self.bins[sc].is_zero() {
-                self.alloc_slow(layout)
-            } else {
-                let a = self.bins[sc];
-                self.bins[sc] = unsafe { a.load() };
-                Some(a)
+            let result = match self.cache.pop(sc) {
+                Some(a) => Some(a),
+                None => self.alloc_slow(layout),
             };
             if result.is_some() {
                 self.live += aligned_size;
@@ -146,23 +380,24 @@ impl<S: PageSize, const MAX_CACHEABLE_SIZE: usize, const THRESHOLD_SLOP: usize>
 
     fn dealloc(&mut self, ptr: Address) {
         let aligned_size = self.space.get_layout::<S>(ptr).size().next_power_of_two();
-        if Self::CACHE_ENABLED && aligned_size <= MAX_CACHEABLE_SIZE {
-            let sc = size_class::<S>(aligned_size);
-            unsafe { ptr.store(self.bins[sc]) }
-            self.bins[sc] = ptr;
-            self.live -= usize::min(aligned_size, self.live);
-            let crossed_threshold = self.max_live > self.live + (self.live >> 2);
-            if THRESHOLD_SLOP != 0
-                && self.live > THRESHOLD_SLOP
-                && crossed_threshold
-                && !self.cleared
-            {
-                self.clear_bins();
-                self.cleared = true;
-                self.max_live = self.live;
-            }
-        } else {
-            self.space().release(Page::<S>::new(ptr))
+        self.dealloc_impl(ptr, aligned_size)
+    }
+
+    /// Skips the page resource metadata lookup.
+    fn dealloc_sized(&mut self, ptr: Address, layout: Layout) {
+        let aligned_size = usize::max(layout.size().next_power_of_two(), S::BYTES);
+        debug_assert_eq!(
+            aligned_size,
+            self.space.get_layout::<S>(ptr).size().next_power_of_two(),
+            "size mismatch in sized deallocation of {:?}",
+            ptr
+        );
+        self.dealloc_impl(ptr, aligned_size)
+    }
+
+    fn flush(&mut self) {
+        if Self::CACHE_ENABLED {
+            self.clear_bins();
         }
     }
 }
@@ -172,7 +407,48 @@ impl<S: PageSize, const MAX_CACHEABLE_SIZE: usize, const THRESHOLD_SLOP: usize>
 {
     fn drop(&mut self) {
         if Self::CACHE_ENABLED {
-            self.clear_bins();
+            CACHE_GC.unregister(self.cache_ref());
         }
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Size4K;
+    use spin::Lazy;
+
+    static LOS: Lazy<LargeObjectSpace> =
+        Lazy::new(|| LargeObjectSpace::new(SpaceId(3)).with_direct_map_threshold(1 << 20));
+
+    #[test]
+    fn direct_map() {
+        let mut loa = LargeObjectAllocator::<Size4K, { 16 << 20 }>::new(&LOS);
+        let small = loa
+            .alloc(Layout::from_size_align(64 << 10, 8).unwrap())
+            .unwrap();
+        let large = loa
+            .alloc(Layout::from_size_align(3 << 20, 8).unwrap())
+            .unwrap();
+        assert_eq!(LOS.get_layout::<Size4K>(large).size(), 4 << 20);
+        let stats = LOS.direct_map_stats();
+        assert_eq!(stats.mapped_regions, 1);
+        assert_eq!(stats.mapped_bytes, 4 << 20);
+        unsafe { large.store(42usize) };
+        loa.dealloc(large);
+        loa.dealloc(small);
+        // The small object is cached. The large one is gone, along with its contents.
+        assert_eq!(loa.cached_bytes(), 64 << 10);
+        assert_eq!(unsafe { large.load::<usize>() }, 0);
+        assert_eq!(
+            LOS.direct_map_stats(),
+            DirectMapStats {
+                mapped_regions: 0,
+                mapped_bytes: 0,
+                total_mapped_regions: 1,
+                total_unmapped_regions: 1,
+            }
+        );
     }
 }
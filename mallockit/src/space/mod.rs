@@ -1,4 +1,4 @@
-use self::page_resource::PageResource;
+use self::page_resource::{HugePagePolicy, PageResource, PrefaultPolicy};
 use crate::util::*;
 use std::ops::Range;
 pub mod freelist_space;
@@ -6,7 +6,8 @@ pub mod immortal_space;
 pub mod large_object_space;
 pub mod meta;
 pub mod page_resource;
-pub(crate) mod page_table;
+pub mod page_table;
+pub mod side_metadata;
 use std::marker::ConstParamTy;
 
 #[repr(transparent)]
@@ -48,9 +49,32 @@ pub trait Space: Sized + 'static {
     fn new(id: SpaceId) -> Self;
     fn id(&self) -> SpaceId;
     fn page_resource(&self) -> &Self::PR;
+    fn page_resource_mut(&mut self) -> &mut Self::PR;
 
     fn get_layout(ptr: Address) -> Layout;
 
+    /// Back this space with huge pages. Should be called by the plan before any allocation.
+    fn with_huge_page_policy(mut self, policy: HugePagePolicy) -> Self {
+        self.page_resource_mut().set_huge_page_policy(policy);
+        self
+    }
+
+    /// Prefault the memory of this space. Should be called by the plan before any allocation.
+    fn with_prefault_policy(mut self, policy: PrefaultPolicy) -> Self {
+        self.page_resource_mut().set_prefault_policy(policy);
+        self
+    }
+
+    /// Acquire all locks of this space before `fork`. See `Plan::fork_prepare`.
+    fn fork_prepare(&self) {
+        self.page_resource().fork_prepare()
+    }
+
+    /// Release the locks taken by `fork_prepare`, in both the parent and the child.
+    fn fork_release(&self) {
+        self.page_resource().fork_release()
+    }
+
     fn contains(&self, address: Address) -> bool {
         SpaceId::from(address) == self.id()
     }
@@ -63,6 +87,10 @@ pub trait Space: Sized + 'static {
         self.page_resource().acquire_pages(pages)
     }
 
+    fn acquire_aligned<S: PageSize>(&self, pages: usize, align: usize) -> Option<Range<Page<S>>> {
+        self.page_resource().acquire_aligned_pages(pages, align)
+    }
+
     fn release<S: PageSize>(&self, start: Page<S>) {
         self.page_resource().release_pages(start)
     }
@@ -73,5 +101,13 @@ pub trait Allocator {
 
     fn dealloc(&mut self, ptr: Address);
 
+    /// Free `ptr`, which was allocated or last reallocated with `layout`. See `Mutator::dealloc_sized`.
+    fn dealloc_sized(&mut self, ptr: Address, _layout: Layout) {
+        self.dealloc(ptr)
+    }
+
+    /// Return all thread-local cached memory to the space.
+    fn flush(&mut self) {}
+
     // TODO: realloc
 }
@@ -1,9 +1,15 @@
 use super::super::SpaceId;
-use super::PageResource;
-use crate::util::mem::heap::HEAP;
+use super::huge_pages::HugePages;
+use super::{
+    HeapLimit, HugePagePolicy, HugePageStats, NumaPartitions, PageResource, PrefaultPolicy,
+    HEAP_LIMIT,
+};
+use crate::util::sys::numa::{NumaTopology, MAX_NUMA_NODES};
+use crate::util::sys::raw_memory::RawMemory;
 use crate::util::*;
 use atomic::Atomic;
 use std::iter::Step;
+use std::marker::PhantomData;
 use std::{
     ops::Range,
     sync::atomic::{AtomicUsize, Ordering},
@@ -63,43 +69,243 @@ pub trait MemRegion: 'static + Sized + Clone + Copy {
     }
 }
 
+/// A lock-free stack of free blocks, linked through their first word.
+///
+/// The head packs the block index with a version tag that changes on every push and pop.
+/// A pop that read a stale next pointer, because the block was popped and pushed again in between,
+/// then fails its CAS instead of corrupting the list.
+struct BlockStack<B: MemRegion> {
+    head: AtomicUsize,
+    len: AtomicUsize,
+    phantom: PhantomData<B>,
+}
+
+impl<B: MemRegion> BlockStack<B> {
+    /// Block indices of addresses below 2^48.
+    const INDEX_BITS: usize = 48 - B::LOG_BYTES;
+    const INDEX_MASK: usize = (1 << Self::INDEX_BITS) - 1;
+
+    const fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            phantom: PhantomData,
+        }
+    }
+
+    fn pack(block: Option<B>, tag: usize) -> usize {
+        let index = block.map(|b| usize::from(b.start()) >> B::LOG_BYTES);
+        debug_assert!(index.unwrap_or(0) <= Self::INDEX_MASK);
+        (tag << Self::INDEX_BITS) | index.unwrap_or(0)
+    }
+
+    fn unpack(head: usize) -> (Option<B>, usize) {
+        let index = head & Self::INDEX_MASK;
+        let block = (index != 0).then(|| B::from_address((index << B::LOG_BYTES).into()));
+        (block, head >> Self::INDEX_BITS)
+    }
+
+    fn link(b: B) -> &'static AtomicUsize {
+        unsafe { b.start().as_ref::<AtomicUsize>() }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn push(&self, block: B) {
+        // Counted before the block is published, so that its pop never takes `len` below zero.
+        self.len.fetch_add(1, Ordering::Relaxed);
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let (top, tag) = Self::unpack(head);
+            Self::link(block).store(top.map_or(0, |b| b.start().into()), Ordering::Relaxed);
+            let new = Self::pack(Some(block), tag.wrapping_add(1));
+            match self
+                .head
+                .compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<B> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let (top, _) = Self::unpack(head);
+            // May be stale if another thread took the block meanwhile. Blocks stay mapped, so the read is
+            // harmless, and the tag makes `try_pop` fail.
+            let next = Self::link(top?).load(Ordering::Relaxed);
+            match self.try_pop(head, next) {
+                Ok(block) => return block,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Replace `head` with `next`. Returns the current head if `head` is outdated.
+    fn try_pop(&self, head: usize, next: usize) -> Result<Option<B>, usize> {
+        let (top, tag) = Self::unpack(head);
+        // `next` is garbage if `head` is outdated, so it only becomes a block once the CAS succeeds.
+        let new =
+            (tag.wrapping_add(1) << Self::INDEX_BITS) | ((next >> B::LOG_BYTES) & Self::INDEX_MASK);
+        self.head
+            .compare_exchange_weak(head, new, Ordering::Acquire, Ordering::Acquire)?;
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Ok(top)
+    }
+}
+
+/// What to do with the memory of blocks released to a `BlockPageResource`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecommitPolicy {
+    /// Keep released blocks resident.
+    #[default]
+    None,
+    /// `MADV_FREE`: the kernel reclaims the pages lazily, under memory pressure.
+    Free,
+    /// `MADV_DONTNEED`: the pages are dropped immediately.
+    DontNeed,
+}
+
+/// Free blocks held by a `BlockPageResource`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FreeBlockStats {
+    /// Released blocks that are still resident.
+    pub dirty_blocks: usize,
+    /// Released blocks whose pages were given back to the OS.
+    pub clean_blocks: usize,
+    /// Number of blocks decommitted so far.
+    pub decommits: usize,
+}
+
 pub struct BlockPageResource<B: MemRegion> {
     pub id: SpaceId,
-    cursor: Atomic<Address>,
-    highwater: Address,
-    head: Atomic<Option<B>>,
+    partitions: NumaPartitions,
+    cursors: [Atomic<Address>; MAX_NUMA_NODES],
+    /// Resident free blocks. Reused first.
+    dirty: [BlockStack<B>; MAX_NUMA_NODES],
+    /// Decommitted free blocks.
+    clean: [BlockStack<B>; MAX_NUMA_NODES],
+    decommit: DecommitPolicy,
+    /// Released blocks are decommitted once a node holds this many dirty bytes.
+    max_dirty_bytes: usize,
+    decommits: AtomicUsize,
     reserved_bytes: AtomicUsize,
+    huge_pages: HugePages,
+    prefault: PrefaultPolicy,
+    heap_limit: &'static HeapLimit,
 }
 
 impl<B: MemRegion> BlockPageResource<B> {
     pub fn new(id: SpaceId) -> Self {
+        Self::new_with_partitions(id, NumaPartitions::with_default_topology(id))
+    }
+
+    fn new_with_partitions(id: SpaceId, partitions: NumaPartitions) -> Self {
         debug_assert!(id.0 < 0b0000_1111);
         debug_assert!(B::LOG_BYTES >= Size4K::LOG_BYTES);
-        let range = HEAP.get_space_range(id);
+        debug_assert!(B::LOG_BYTES <= partitions.log_partition_bytes());
+        let cursors = std::array::from_fn(|i| {
+            if i < partitions.nodes() {
+                Atomic::new(partitions.range(i).start)
+            } else {
+                Atomic::new(Address::ZERO)
+            }
+        });
         Self {
             id,
-            cursor: Atomic::new(range.start),
-            highwater: range.end,
-            head: Atomic::new(None),
+            partitions,
+            cursors,
+            dirty: [const { BlockStack::new() }; MAX_NUMA_NODES],
+            clean: [const { BlockStack::new() }; MAX_NUMA_NODES],
+            decommit: DecommitPolicy::None,
+            max_dirty_bytes: usize::MAX,
+            decommits: AtomicUsize::new(0),
             reserved_bytes: AtomicUsize::new(0),
+            huge_pages: HugePages::new(),
+            prefault: PrefaultPolicy::None,
+            heap_limit: &HEAP_LIMIT,
         }
     }
 
+    /// Use a custom NUMA topology instead of the process-wide one.
+    ///
+    /// Must be called before any block is acquired. Blocks already reserved by a prefault policy are
+    /// given back to the OS, and reserved again on the new partitions.
+    pub fn with_numa_topology(mut self, topology: &'static dyn NumaTopology) -> Self {
+        assert_eq!(
+            self.reserved_bytes(),
+            0,
+            "the NUMA topology must be set before any block is acquired"
+        );
+        let nodes = self.partitions.nodes();
+        for stack in self.dirty[..nodes].iter().chain(&self.clean[..nodes]) {
+            while let Some(block) = stack.pop() {
+                RawMemory::madv_dontneed(block.start(), B::BYTES);
+            }
+        }
+        self.partitions = NumaPartitions::new(self.id, topology);
+        for (node, cursor) in self.cursors.iter_mut().enumerate() {
+            *cursor.get_mut() = if node < self.partitions.nodes() {
+                self.partitions.range(node).start
+            } else {
+                Address::ZERO
+            };
+        }
+        if let PrefaultPolicy::Reserve(bytes) = self.prefault {
+            self.reserve(bytes);
+        }
+        self
+    }
+
+    /// Decommit released blocks with `policy` once a NUMA node holds more than `max_dirty_bytes` of free,
+    /// resident blocks. The first page of each block stays resident, as it links the free list.
+    pub fn with_decommit_policy(mut self, policy: DecommitPolicy, max_dirty_bytes: usize) -> Self {
+        self.decommit = policy;
+        self.max_dirty_bytes = max_dirty_bytes;
+        self
+    }
+
+    pub fn free_block_stats(&self) -> FreeBlockStats {
+        let nodes = self.partitions.nodes();
+        FreeBlockStats {
+            dirty_blocks: self.dirty[..nodes].iter().map(|s| s.len()).sum(),
+            clean_blocks: self.clean[..nodes].iter().map(|s| s.len()).sum(),
+            decommits: self.decommits.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Charge acquisitions against `limit` instead of the process-wide `HEAP_LIMIT`.
+    pub fn with_heap_limit(mut self, limit: &'static HeapLimit) -> Self {
+        self.heap_limit = limit;
+        self
+    }
+
+    pub fn numa_partitions(&self) -> &NumaPartitions {
+        &self.partitions
+    }
+
     #[cold]
-    fn acquire_block_slow<S: PageSize>(&self, pages: usize) -> Option<Range<Page<S>>> {
+    fn acquire_block_slow<S: PageSize>(&self, node: usize, pages: usize) -> Option<Range<Page<S>>> {
         debug_assert!(B::LOG_BYTES >= S::LOG_BYTES);
         debug_assert_eq!(pages, 1 << (B::LOG_BYTES - S::LOG_BYTES));
-        let block = self
-            .cursor
-            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |a| {
-                if a >= self.highwater {
-                    None
-                } else {
-                    Some(a + (1usize << B::LOG_BYTES))
-                }
-            });
+        let highwater = self.partitions.range(node).end;
+        let block = self.cursors[node].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |a| {
+            if a >= highwater {
+                None
+            } else {
+                Some(a + (1usize << B::LOG_BYTES))
+            }
+        });
         match block {
             Ok(addr) => {
+                self.prepare_huge_pages(addr);
+                if self.prefault.populate_on_acquire() {
+                    RawMemory::populate(addr, B::BYTES);
+                }
                 let start = Page::<S>::new(addr);
                 let end = Step::forward(start, pages);
                 Some(start..end)
@@ -108,67 +314,94 @@ impl<B: MemRegion> BlockPageResource<B> {
         }
     }
 
-    fn set_next(b: B, next: Option<B>) {
-        let a = b.start();
-        let next = match next {
-            Some(b) => b.start(),
-            None => Address::ZERO,
-        };
-        unsafe { a.store(next) }
+    /// Apply the huge page policy to a fresh block.
+    ///
+    /// Blocks smaller than a huge page share their chunk with the following blocks,
+    /// so the whole chunk is advised when its first block is handed out.
+    fn prepare_huge_pages(&self, block: Address) {
+        let log_chunk_bytes = self.huge_pages.policy().log_chunk_bytes();
+        let bytes = usize::max(B::BYTES, 1 << log_chunk_bytes);
+        if !block.is_aligned_to(bytes) {
+            return;
+        }
+        if self.huge_pages.prepare(block, bytes) {
+            self.partitions.rebind(block, bytes);
+        }
     }
 
-    fn get_next(b: B) -> Option<B> {
-        let a = b.start();
-        let next: Address = unsafe { a.load() };
-        if next.is_zero() {
-            None
-        } else {
-            Some(B::from_address(next))
+    fn acquire_block_from(&self, node: usize) -> Option<B> {
+        if let Some(block) = self.dirty[node].pop() {
+            return Some(block);
         }
+        if let Some(block) = self.clean[node].pop() {
+            return Some(block);
+        }
+        let range = self.acquire_block_slow::<Size4K>(node, B::BYTES >> Size4K::LOG_BYTES)?;
+        Some(B::from_address(range.start.start()))
     }
 
     pub fn acquire_block(&self) -> Option<B> {
-        loop {
-            let head = self.head.load(Ordering::Relaxed);
-            if let Some(block) = head {
-                if self
-                    .head
-                    .compare_exchange(
-                        head,
-                        Self::get_next(block),
-                        Ordering::Relaxed,
-                        Ordering::Relaxed,
-                    )
-                    .is_ok()
-                {
-                    self.reserved_bytes.fetch_add(B::BYTES, Ordering::Relaxed);
-                    return Some(block);
-                }
-            } else {
-                break;
-            }
+        let local = self.partitions.current_node();
+        let nodes = self.partitions.nodes();
+        // Prefer the local node. Fall back to remote nodes only when the local partition is exhausted.
+        if !self.heap_limit.charge(B::BYTES) {
+            return None;
         }
-        let range = self.acquire_block_slow::<Size4K>(B::BYTES >> Size4K::LOG_BYTES)?;
-        let block = B::from_address(range.start.start());
-        Self::set_next(block, None);
+        let Some(block) = (0..nodes).find_map(|i| self.acquire_block_from((local + i) % nodes))
+        else {
+            self.heap_limit.uncharge(B::BYTES);
+            return None;
+        };
         self.reserved_bytes.fetch_add(B::BYTES, Ordering::Relaxed);
         Some(block)
     }
 
-    pub fn release_block(&self, block: B) {
-        loop {
-            let head = self.head.load(Ordering::Relaxed);
-            Self::set_next(block, head);
-            if self
-                .head
-                .compare_exchange(head, Some(block), Ordering::Relaxed, Ordering::Relaxed)
-                .is_ok()
-            {
-                break;
+    /// Pre-touch `bytes` worth of fresh blocks on each node and put them on the free lists.
+    fn reserve(&self, bytes: usize) {
+        let nodes = self.partitions.nodes();
+        let blocks_per_node = (bytes / nodes).div_ceil(B::BYTES);
+        for node in 0..nodes {
+            for _ in 0..blocks_per_node {
+                let Some(range) =
+                    self.acquire_block_slow::<Size4K>(node, B::BYTES >> Size4K::LOG_BYTES)
+                else {
+                    break;
+                };
+                let block = B::from_address(range.start.start());
+                RawMemory::populate(block.start(), B::BYTES);
+                self.dirty[node].push(block);
             }
         }
+    }
+
+    /// Whether released blocks can be decommitted. Hugetlb pages cannot be partially decommitted.
+    fn can_decommit(&self) -> bool {
+        self.decommit != DecommitPolicy::None
+            && B::BYTES > Size4K::BYTES
+            && !self.huge_pages.policy().is_huge_tlb()
+    }
+
+    fn push_free_block(&self, block: B) {
+        let node = self.partitions.node_of(block.start());
+        if !self.can_decommit() || (self.dirty[node].len() + 1) * B::BYTES <= self.max_dirty_bytes {
+            self.dirty[node].push(block);
+            return;
+        }
+        let start = block.start() + Size4K::BYTES;
+        let bytes = B::BYTES - Size4K::BYTES;
+        match self.decommit {
+            DecommitPolicy::Free => RawMemory::madv_free(start, bytes),
+            _ => RawMemory::madv_dontneed(start, bytes),
+        }
+        self.decommits.fetch_add(1, Ordering::Relaxed);
+        self.clean[node].push(block);
+    }
+
+    pub fn release_block(&self, block: B) {
+        self.push_free_block(block);
         self.reserved_bytes
             .fetch_sub(1 << B::LOG_BYTES, Ordering::Relaxed);
+        self.heap_limit.uncharge(B::BYTES);
     }
 }
 
@@ -184,4 +417,202 @@ impl<B: MemRegion> PageResource for BlockPageResource<B> {
     fn release_pages<S: PageSize>(&self, _start: Page<S>) {
         unreachable!("Use `release_block` instead")
     }
+
+    fn set_huge_page_policy(&mut self, policy: HugePagePolicy) {
+        assert!(
+            !policy.is_huge_tlb() || B::LOG_BYTES >= policy.log_chunk_bytes(),
+            "hugetlb backed blocks must be at least as large as a huge page"
+        );
+        self.huge_pages.set_policy(policy);
+    }
+
+    fn huge_page_stats(&self) -> HugePageStats {
+        self.huge_pages.stats()
+    }
+
+    fn set_prefault_policy(&mut self, policy: PrefaultPolicy) {
+        self.prefault = policy;
+        if let PrefaultPolicy::Reserve(bytes) = policy {
+            self.reserve(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::sys::numa::FakeTopology;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct Block(Address);
+
+    impl MemRegion for Block {
+        const LOG_BYTES: usize = Size2M::LOG_BYTES;
+
+        fn start(&self) -> Address {
+            self.0
+        }
+
+        fn from_address(addr: Address) -> Self {
+            Self(addr)
+        }
+    }
+
+    #[test]
+    fn numa_local_blocks() {
+        static TOPOLOGY: FakeTopology = FakeTopology::new(2);
+        let mut pr = BlockPageResource::<Block>::new(SpaceId::DEFAULT);
+        pr.set_prefault_policy(PrefaultPolicy::Reserve(2 * Block::BYTES));
+        let pr = pr.with_numa_topology(&TOPOLOGY);
+        assert_eq!(TOPOLOGY.binds(), 2);
+        let partitions = pr.numa_partitions();
+        // The blocks reserved on the single-node layout are reserved again, one per node.
+        assert_eq!(pr.free_block_stats().dirty_blocks, 2);
+        for node in 0..2 {
+            let cursor = pr.cursors[node].load(Ordering::Relaxed);
+            assert_eq!(cursor, partitions.range(node).start + Block::BYTES);
+        }
+        for node in 0..2 {
+            TOPOLOGY.set_current_node(node);
+            let block = pr.acquire_block().unwrap();
+            assert_eq!(partitions.node_of(block.start()), node);
+            assert!(partitions.range(node).contains(&block.start()));
+            pr.release_block(block);
+            assert_eq!(pr.acquire_block(), Some(block));
+        }
+        assert_eq!(pr.reserved_bytes(), 2 * Block::BYTES);
+    }
+
+    #[test]
+    fn tagged_head_prevents_aba() {
+        let mut memory = std::vec::Vec::<u8>::with_capacity(4 * Block::BYTES);
+        let base = Address::from(memory.as_mut_ptr()).align_up(Block::BYTES);
+        let [a, b, c] = [0, 1, 2].map(|i| Block(base + i * Block::BYTES));
+        let stack = BlockStack::<Block>::new();
+        stack.push(c);
+        stack.push(b);
+        stack.push(a);
+        // A pop reads the head and the next pointer of `a`, and is delayed ...
+        let head = stack.head.load(Ordering::Relaxed);
+        let next = BlockStack::link(a).load(Ordering::Relaxed);
+        // ... while another thread pops `a` and `b`, and pushes `a` back.
+        assert_eq!(stack.pop(), Some(a));
+        assert_eq!(stack.pop(), Some(b));
+        stack.push(a);
+        // The stale pop must not install `b`, which is in use, as the head.
+        assert!(stack.try_pop(head, next).is_err());
+        assert_eq!(stack.pop(), Some(a));
+        assert_eq!(stack.pop(), Some(c));
+        assert_eq!(stack.pop(), None);
+        assert_eq!(stack.len(), 0);
+    }
+
+    #[test]
+    fn concurrent_push_pop_len() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 100000;
+        let mut memory = std::vec::Vec::<u8>::with_capacity((THREADS + 1) * Block::BYTES);
+        let base = Address::from(memory.as_mut_ptr()).align_up(Block::BYTES);
+        let stack = BlockStack::<Block>::new();
+        std::thread::scope(|scope| {
+            for t in 0..THREADS {
+                let stack = &stack;
+                scope.spawn(move || {
+                    let mut block = Some(Block(base + t * Block::BYTES));
+                    for _ in 0..ROUNDS {
+                        if let Some(block) = block.take() {
+                            stack.push(block);
+                        }
+                        // Never more than one block per thread, and never wrapped below zero.
+                        assert!(stack.len() <= THREADS);
+                        block = stack.pop();
+                    }
+                    if let Some(block) = block {
+                        stack.push(block);
+                    }
+                });
+            }
+        });
+        assert_eq!(stack.len(), THREADS);
+        while stack.pop().is_some() {}
+        assert_eq!(stack.len(), 0);
+    }
+
+    #[test]
+    fn concurrent_acquire_release() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 100000;
+        static PR: Lazy<BlockPageResource<Block>> =
+            Lazy::new(|| BlockPageResource::new(SpaceId(12)));
+        let check = |block: Block, stamp: usize| {
+            // No other thread was handed the same block.
+            assert_eq!(unsafe { (block.start() + 8usize).load::<usize>() }, stamp);
+        };
+        let threads = (0..THREADS)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    let stamp = t + 1;
+                    for _ in 0..ROUNDS {
+                        // Pop two blocks and push the first one back: the sequence that makes
+                        // an untagged pop on another thread install a block that is in use.
+                        let a = PR.acquire_block().unwrap();
+                        let b = PR.acquire_block().unwrap();
+                        unsafe { (a.start() + 8usize).store(stamp) };
+                        unsafe { (b.start() + 8usize).store(stamp) };
+                        check(a, stamp);
+                        PR.release_block(a);
+                        std::thread::yield_now();
+                        check(b, stamp);
+                        PR.release_block(b);
+                    }
+                })
+            })
+            .collect::<std::vec::Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(PR.reserved_bytes(), 0);
+        // Every block handed out is back on the free lists exactly once.
+        let nodes = PR.numa_partitions().nodes();
+        let fresh = (0..nodes)
+            .map(|n| {
+                (PR.cursors[n].load(Ordering::Relaxed) - PR.numa_partitions().range(n).start)
+                    >> Block::LOG_BYTES
+            })
+            .sum::<usize>();
+        let mut free = std::collections::HashSet::new();
+        for node in 0..nodes {
+            while let Some(block) = PR.dirty[node].pop() {
+                assert!(free.insert(block.start()));
+            }
+        }
+        assert_eq!(free.len(), fresh);
+    }
+
+    #[test]
+    fn decommit_released_blocks() {
+        let pr = BlockPageResource::<Block>::new(SpaceId(13))
+            .with_decommit_policy(DecommitPolicy::DontNeed, Block::BYTES);
+        let blocks = [(); 3].map(|_| pr.acquire_block().unwrap());
+        for block in blocks {
+            unsafe { std::ptr::write_bytes(block.start().as_mut_ptr::<u8>(), 0xff, Block::BYTES) };
+            pr.release_block(block);
+        }
+        let stats = pr.free_block_stats();
+        assert_eq!(
+            (stats.dirty_blocks, stats.clean_blocks, stats.decommits),
+            (1, 2, 2)
+        );
+        // All but the first page of decommitted blocks read back as zero.
+        for block in &blocks[1..] {
+            let data = block.start() + Size4K::BYTES;
+            assert_eq!(unsafe { data.load::<usize>() }, 0);
+            assert_eq!(unsafe { (block.end() - 8usize).load::<usize>() }, 0);
+        }
+        // Resident blocks are reused first.
+        assert_eq!(pr.acquire_block(), Some(blocks[0]));
+        let b = pr.acquire_block().unwrap();
+        assert!(blocks[1..].contains(&b));
+        assert_eq!(pr.free_block_stats().clean_blocks, 1);
+    }
 }
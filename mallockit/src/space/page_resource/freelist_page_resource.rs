@@ -1,78 +1,398 @@
+use super::super::side_metadata::{SideMetadata, SideMetadataSpec};
 use super::super::SpaceId;
-use super::PageResource;
+use super::huge_pages::HugePages;
+use super::{
+    HeapLimit, HugePagePolicy, HugePageStats, NumaPartitions, PageResource, PrefaultPolicy,
+    HEAP_LIMIT,
+};
 use crate::space::meta::Meta;
+use crate::util::mem::alloc::per_cpu;
 use crate::util::mem::freelist::page_freelist::PageFreeList;
-use crate::util::mem::heap::HEAP;
+use crate::util::sys::numa::NumaTopology;
 use crate::util::sys::raw_memory::RawMemory;
+use crate::util::sys::rseq;
 use crate::util::*;
 use spin::mutex::Mutex;
-use spin::rwlock::RwLock;
 use spin::Yield;
 use std::iter::Step;
-use std::sync::atomic::AtomicU32;
 use std::{
     ops::Range,
     sync::atomic::{AtomicUsize, Ordering},
 };
 
 const NUM_SIZE_CLASS: usize = SpaceId::LOG_MAX_SPACE_SIZE - Page::<Size4K>::LOG_BYTES;
+/// Upper bound of free lists per NUMA partition.
+const MAX_SHARDS: usize = 16;
+/// Runs of up to `1 << MAX_CACHED_CLASS` pages are cached per CPU on release.
+const MAX_CACHED_CLASS: usize = 4;
+/// Cached runs per CPU and size class.
+const CACHE_CAPACITY: usize = 8;
+
+/// The CPU the calling thread is running on.
+///
+/// Only reads the rseq area if per-CPU caches are enabled, so that this never registers rseq by itself.
+fn current_cpu() -> usize {
+    if per_cpu::ENABLED {
+        if let Some(cpu) = rseq::current_cpu() {
+            return cpu;
+        }
+    }
+    #[cfg(target_os = "linux")]
+    {
+        i32::max(unsafe { libc::sched_getcpu() }, 0) as usize
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        0
+    }
+}
+
+/// Recently released page runs of one CPU, handed out again without going through the shared free lists.
+struct PageCache {
+    runs: [[Address; CACHE_CAPACITY]; MAX_CACHED_CLASS + 1],
+    len: [usize; MAX_CACHED_CLASS + 1],
+}
+
+impl PageCache {
+    const fn new() -> Self {
+        Self {
+            runs: [[Address::ZERO; CACHE_CAPACITY]; MAX_CACHED_CLASS + 1],
+            len: [0; MAX_CACHED_CLASS + 1],
+        }
+    }
+
+    fn push(&mut self, class: usize, start: Address) -> bool {
+        let len = self.len[class];
+        if len == CACHE_CAPACITY {
+            return false;
+        }
+        self.runs[class][len] = start;
+        self.len[class] += 1;
+        true
+    }
+
+    fn pop(&mut self, class: usize) -> Option<Address> {
+        if self.len[class] == 0 {
+            return None;
+        }
+        self.len[class] -= 1;
+        Some(self.runs[class][self.len[class]])
+    }
+}
+
+struct Partition {
+    base: Address,
+    /// Each shard manages an equal, power-of-two sized slice of the partition.
+    /// Threads allocate from the shard of their CPU, and pages are released to the shard that contains them.
+    shards: Vec<Mutex<PageFreeList<{ NUM_SIZE_CLASS }>, Yield>, Meta>,
+    log_shard_bytes: usize,
+    /// One page cache per shard, indexed by CPU like the shards.
+    caches: Vec<Mutex<PageCache, Yield>, Meta>,
+    /// Pre-touched pages, carved out in address order.
+    reserve: Mutex<Range<Address>, Yield>,
+}
+
+impl Partition {
+    fn new(range: Range<Address>, shards: usize) -> Self {
+        debug_assert!(shards.is_power_of_two());
+        let base = range.start;
+        let log_shard_bytes =
+            (range.end - range.start).trailing_zeros() as usize - shards.trailing_zeros() as usize;
+        let units = usize::min(
+            1 << (log_shard_bytes - Size4K::LOG_BYTES),
+            1 << (NUM_SIZE_CLASS - 1),
+        );
+        let mut freelists = Vec::with_capacity_in(shards, Meta);
+        let mut caches = Vec::with_capacity_in(shards, Meta);
+        for i in 0..shards {
+            let start = base + (i << log_shard_bytes);
+            let mut freelist = PageFreeList::new(start);
+            freelist.release_cell(start, units);
+            freelists.push(Mutex::new(freelist));
+            caches.push(Mutex::new(PageCache::new()));
+        }
+        Self {
+            base,
+            shards: freelists,
+            log_shard_bytes,
+            caches,
+            reserve: Mutex::new(Address::ZERO..Address::ZERO),
+        }
+    }
+
+    fn shard_of(&self, addr: Address) -> &Mutex<PageFreeList<{ NUM_SIZE_CLASS }>, Yield> {
+        &self.shards[(addr - self.base) >> self.log_shard_bytes]
+    }
+
+    fn release(&self, start: Address, units: usize) {
+        self.shard_of(start).lock().release_cell(start, units);
+    }
+
+    /// Cache a released run on `cpu`. Runs that do not fit in the cache go back to their shard.
+    fn release_cached(&self, cpu: usize, start: Address, units: usize) {
+        let class = units.trailing_zeros() as usize;
+        if units.is_power_of_two()
+            && class <= MAX_CACHED_CLASS
+            && self.caches[cpu % self.caches.len()]
+                .lock()
+                .push(class, start)
+        {
+            return;
+        }
+        self.release(start, units);
+    }
+
+    /// Allocate from the page cache and then the shard of `cpu`, falling back to the other shards.
+    fn allocate(&self, cpu: usize, units: usize, align_units: usize) -> Option<Address> {
+        let shards = self.shards.len();
+        let class = units.trailing_zeros() as usize;
+        // Cached runs are aligned to their size, like buddy cells.
+        if units.is_power_of_two() && class <= MAX_CACHED_CLASS && align_units <= units {
+            if let Some(start) = self.caches[cpu % shards].lock().pop(class) {
+                return Some(start);
+            }
+        }
+        (0..shards).find_map(|i| {
+            let mut freelist = self.shards[(cpu + i) % shards].lock();
+            let range = if align_units > units {
+                freelist.allocate_aligned_cell(units, align_units)
+            } else {
+                freelist.allocate_cell(units)
+            };
+            Some(range?.start)
+        })
+    }
+
+    fn take_reserved(&self, units: usize, align_units: usize) -> Option<Address> {
+        let bytes = units << Size4K::LOG_BYTES;
+        let mut reserve = self.reserve.lock();
+        // Keep the natural alignment of buddy cells. The skipped gap goes back to the free list.
+        let start = reserve
+            .start
+            .align_up(usize::max(units, align_units) << Size4K::LOG_BYTES);
+        if start >= reserve.end || reserve.end - start < bytes {
+            return None;
+        }
+        if start != reserve.start {
+            let gap = (start - reserve.start) >> Size4K::LOG_BYTES;
+            self.release(reserve.start, gap);
+        }
+        reserve.start = start + bytes;
+        Some(start)
+    }
+}
+
+/// Length of each allocated page run in 4K pages, recorded at its first page.
+const PAGE_RUNS: SideMetadataSpec = SideMetadataSpec::new("page runs", Size4K::LOG_BYTES, 5);
 
 pub struct FreelistPageResource {
     pub id: SpaceId,
-    freelist: Mutex<PageFreeList<{ NUM_SIZE_CLASS }>, Yield>,
+    numa: NumaPartitions,
+    partitions: Vec<Partition, Meta>,
+    meta: SideMetadata,
     reserved_bytes: AtomicUsize,
-    meta: RwLock<Vec<AtomicU32, Meta>, Yield>,
-    base: Address,
+    huge_pages: HugePages,
+    prefault: PrefaultPolicy,
+    heap_limit: &'static HeapLimit,
 }
 
 impl FreelistPageResource {
     pub fn new(id: SpaceId) -> Self {
+        let cpus = usize::max(
+            unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) } as usize,
+            1,
+        );
+        let shards = usize::min(cpus.next_power_of_two(), MAX_SHARDS);
+        Self::new_with_partitions(id, NumaPartitions::with_default_topology(id), shards)
+    }
+
+    fn new_with_partitions(id: SpaceId, numa: NumaPartitions, shards: usize) -> Self {
         debug_assert!(id.0 < 0b0000_1111);
-        let range = HEAP.get_space_range(id);
-        let base = range.start;
-        let mut freelist = PageFreeList::new(base);
-        freelist.release_cell(base, 1 << (NUM_SIZE_CLASS - 1));
-        let mut meta = Vec::<u32, Meta>::with_capacity_in(1 << 20, Meta);
-        meta.resize(1 << 20, 0u32);
+        let mut partitions = Vec::with_capacity_in(numa.nodes(), Meta);
+        for node in 0..numa.nodes() {
+            partitions.push(Partition::new(numa.range(node), shards));
+        }
         Self {
             id,
-            freelist: Mutex::new(freelist),
+            meta: SideMetadata::new(id, PAGE_RUNS),
+            numa,
+            partitions,
             reserved_bytes: AtomicUsize::new(0),
-            meta: RwLock::new(unsafe {
-                std::mem::transmute::<Vec<u32, Meta>, Vec<AtomicU32, Meta>>(meta)
-            }),
-            base,
+            huge_pages: HugePages::new(),
+            prefault: PrefaultPolicy::None,
+            heap_limit: &HEAP_LIMIT,
         }
     }
 
-    fn map_pages<S: PageSize>(&self, _start: Page<S>, pages: usize) {
-        self.reserved_bytes
-            .fetch_add(pages << S::LOG_BYTES, Ordering::SeqCst);
+    /// Use a custom NUMA topology instead of the process-wide one.
+    ///
+    /// Must be called before any page is acquired. Pages already reserved by a prefault policy are
+    /// given back to the OS, and reserved again on the new partitions.
+    pub fn with_numa_topology(mut self, topology: &'static dyn NumaTopology) -> Self {
+        assert_eq!(
+            self.reserved_bytes(),
+            0,
+            "the NUMA topology must be set before any page is acquired"
+        );
+        for partition in &self.partitions {
+            let reserve = partition.reserve.lock();
+            if reserve.start < reserve.end {
+                RawMemory::madv_dontneed(reserve.start, reserve.end - reserve.start);
+            }
+        }
+        let shards = self.partitions[0].shards.len();
+        self.numa = NumaPartitions::new(self.id, topology);
+        self.partitions.clear();
+        for node in 0..self.numa.nodes() {
+            self.partitions
+                .push(Partition::new(self.numa.range(node), shards));
+        }
+        if let PrefaultPolicy::Reserve(bytes) = self.prefault {
+            self.reserve(bytes);
+        }
+        self
+    }
+
+    /// Charge acquisitions against `limit` instead of the process-wide `HEAP_LIMIT`.
+    pub fn with_heap_limit(mut self, limit: &'static HeapLimit) -> Self {
+        self.heap_limit = limit;
+        self
+    }
+
+    /// Like `acquire_aligned_pages`, but give the run a fresh mapping of its own instead of reusing
+    /// whatever backed its range before. Release it with `unmap_dedicated_pages`.
+    pub fn acquire_dedicated_pages<S: PageSize>(
+        &self,
+        pages: usize,
+        align: usize,
+    ) -> Option<Range<Page<S>>> {
+        self.acquire_run(pages, align, true)
+    }
+
+    /// Unmap a run acquired with `acquire_dedicated_pages`, dropping its memory right away.
+    ///
+    /// The range is mapped again as fresh heap memory in the same call, so that no other mapping
+    /// can take the hole and the run can be handed out again.
+    pub fn unmap_dedicated_pages<S: PageSize>(&self, start: Page<S>) {
+        let units = self.meta.load(start.start(), Ordering::Relaxed);
+        self.unmap_pages(start, units >> (S::LOG_BYTES - Size4K::LOG_BYTES), true);
+        self.partition_of(start.start())
+            .release_cached(current_cpu(), start.start(), units);
     }
 
-    fn unmap_pages<S: PageSize>(&self, start: Page<S>, pages: usize) {
-        RawMemory::madv_free(start.start(), pages << S::LOG_BYTES);
-        self.reserved_bytes
-            .fetch_sub(pages << S::LOG_BYTES, Ordering::SeqCst);
+    pub fn numa_partitions(&self) -> &NumaPartitions {
+        &self.numa
     }
 
-    fn set_meta<S: PageSize>(&self, start: Page<S>, pages: usize) {
-        debug_assert!(pages <= u32::MAX as usize);
-        let index = (start.start() - self.base) >> Page::<Size4K>::LOG_BYTES;
-        let meta = self.meta.upgradeable_read();
-        if index >= meta.len() {
-            let mut meta = meta.upgrade();
-            let len = usize::max(meta.len(), index).next_power_of_two();
-            meta.resize_with(len << 1, Default::default);
-            meta[index].store(pages as _, Ordering::Relaxed);
+    fn acquire_run<S: PageSize>(
+        &self,
+        pages: usize,
+        align: usize,
+        dedicated: bool,
+    ) -> Option<Range<Page<S>>> {
+        let pages = pages.next_power_of_two(); // FIXME
+        let units = pages << (S::LOG_BYTES - Size4K::LOG_BYTES);
+        let align_units = align >> Size4K::LOG_BYTES;
+        let local = self.numa.current_node();
+        let nodes = self.numa.nodes();
+        if !self.heap_limit.charge(pages << S::LOG_BYTES) {
+            return None;
+        }
+        // Reserved pages are already populated, so a dedicated run never takes them.
+        let reserved = if dedicated {
+            None
         } else {
-            meta[index].store(pages as _, Ordering::Relaxed);
+            self.partitions[local].take_reserved(units, align_units)
+        };
+        if let Some(start) = reserved {
+            let start = Page::<S>::new(start);
+            self.reserved_bytes
+                .fetch_add(pages << S::LOG_BYTES, Ordering::SeqCst);
+            self.meta.store(start.start(), units, Ordering::Relaxed);
+            return Some(start..Step::forward(start, pages));
         }
+        // Prefer the local node. Fall back to remote nodes only when the local partition is exhausted.
+        let cpu = current_cpu();
+        let Some(start) = (0..nodes)
+            .find_map(|i| self.partitions[(local + i) % nodes].allocate(cpu, units, align_units))
+        else {
+            self.heap_limit.uncharge(pages << S::LOG_BYTES);
+            return None;
+        };
+        let start = Page::<S>::new(start);
+        self.map_pages(start, pages, dedicated);
+        let end = Step::forward(start, pages);
+        self.meta.store(start.start(), units, Ordering::Relaxed);
+        Some(start..end)
     }
 
-    fn get_meta<S: PageSize>(&self, start: Page<S>) -> usize {
-        let index = (start.start() - self.base) >> Page::<Size4K>::LOG_BYTES;
-        self.meta.read()[index].load(Ordering::Relaxed) as _
+    fn partition_of(&self, addr: Address) -> &Partition {
+        &self.partitions[self.numa.node_of(addr)]
+    }
+
+    fn map_pages<S: PageSize>(&self, start: Page<S>, pages: usize, dedicated: bool) {
+        let bytes = pages << S::LOG_BYTES;
+        let mut remapped = false;
+        if dedicated {
+            RawMemory::remap_heap(start.start(), bytes).unwrap();
+            remapped = true;
+        }
+        remapped |= self.huge_pages.prepare(start.start(), bytes);
+        if remapped {
+            self.numa.rebind(start.start(), bytes);
+        }
+        if self.prefault.populate_on_acquire() {
+            RawMemory::populate(start.start(), bytes);
+        }
+        self.reserved_bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Pre-touch `bytes` worth of pages on each node, to be handed out before any other pages.
+    fn reserve(&self, bytes: usize) {
+        let units = (bytes / self.numa.nodes()).div_ceil(Size4K::BYTES);
+        for partition in &self.partitions {
+            let Some(start) = partition.allocate(0, units, 0) else {
+                continue;
+            };
+            let range = start..start + (units << Size4K::LOG_BYTES);
+            let bytes = range.end - range.start;
+            if self.huge_pages.prepare(range.start, bytes) {
+                self.numa.rebind(range.start, bytes);
+            }
+            RawMemory::populate(range.start, bytes);
+            let old = std::mem::replace(&mut *partition.reserve.lock(), range);
+            if old.start < old.end {
+                let units = (old.end - old.start) >> Size4K::LOG_BYTES;
+                partition.release(old.start, units);
+            }
+        }
+    }
+
+    /// Give the memory of a released run back to the OS. With `discard`, the pages are `madv_dontneed`ed and
+    /// freed right away. Otherwise they are `madv_free`d and only reclaimed under memory pressure.
+    fn unmap_pages<S: PageSize>(&self, start: Page<S>, pages: usize, dedicated: bool) {
+        let bytes = pages << S::LOG_BYTES;
+        if dedicated {
+            // Replacing the whole run also returns its hugetlb chunks.
+            RawMemory::remap_heap(start.start(), bytes).unwrap();
+            self.numa.rebind(start.start(), bytes);
+        } else {
+            let run = start.start()..start.start() + bytes;
+            let remapped = self.huge_pages.release(run.start, bytes);
+            // Remapped hugetlb chunks hold no memory any more. Only the rest of the run needs advice.
+            let rest = match &remapped {
+                Some(chunks) => [run.start..chunks.start, chunks.end..run.end],
+                None => [run.clone(), run.end..run.end],
+            };
+            for range in rest.into_iter().filter(|r| r.start < r.end) {
+                RawMemory::madv_free(range.start, range.end - range.start);
+            }
+            if let Some(chunks) = remapped {
+                self.numa.rebind(chunks.start, chunks.end - chunks.start);
+            }
+        }
+        self.reserved_bytes.fetch_sub(bytes, Ordering::SeqCst);
+        self.heap_limit.uncharge(bytes);
     }
 }
 
@@ -82,23 +402,156 @@ impl PageResource for FreelistPageResource {
     }
 
     fn acquire_pages<S: PageSize>(&self, pages: usize) -> Option<Range<Page<S>>> {
-        let pages = pages.next_power_of_two(); // FIXME
-        let units = pages << (S::LOG_BYTES - Size4K::LOG_BYTES);
-        let start = self.freelist.lock().allocate_cell(units)?.start;
-        let start = Page::<S>::new(start);
-        self.map_pages(start, pages);
-        let end = Step::forward(start, pages);
-        self.set_meta(start, units);
-        Some(start..end)
+        self.acquire_aligned_pages(pages, 0)
+    }
+
+    /// Charges only `pages.next_power_of_two()` pages, however large the alignment.
+    fn acquire_aligned_pages<S: PageSize>(
+        &self,
+        pages: usize,
+        align: usize,
+    ) -> Option<Range<Page<S>>> {
+        self.acquire_run(pages, align, false)
     }
 
     fn release_pages<S: PageSize>(&self, start: Page<S>) {
-        let pages = self.get_meta(start) >> (S::LOG_BYTES - Size4K::LOG_BYTES);
-        self.unmap_pages(start, pages);
-        self.freelist.lock().release_cell(start.start(), pages);
+        let units = self.meta.load(start.start(), Ordering::Relaxed);
+        self.unmap_pages(start, units >> (S::LOG_BYTES - Size4K::LOG_BYTES), false);
+        self.partition_of(start.start())
+            .release_cached(current_cpu(), start.start(), units);
+    }
+
+    fn set_huge_page_policy(&mut self, policy: HugePagePolicy) {
+        self.huge_pages.set_policy(policy);
+    }
+
+    fn huge_page_stats(&self) -> HugePageStats {
+        self.huge_pages.stats()
+    }
+
+    fn set_prefault_policy(&mut self, policy: PrefaultPolicy) {
+        self.prefault = policy;
+        if let PrefaultPolicy::Reserve(bytes) = policy {
+            self.reserve(bytes);
+        }
+    }
+
+    fn fork_prepare(&self) {
+        for partition in &self.partitions {
+            std::mem::forget(partition.reserve.lock());
+            for shard in &partition.shards {
+                std::mem::forget(shard.lock());
+            }
+            for cache in &partition.caches {
+                std::mem::forget(cache.lock());
+            }
+        }
+    }
+
+    fn fork_release(&self) {
+        for partition in self.partitions.iter().rev() {
+            unsafe {
+                for cache in partition.caches.iter().rev() {
+                    cache.force_unlock();
+                }
+                for shard in partition.shards.iter().rev() {
+                    shard.force_unlock();
+                }
+                partition.reserve.force_unlock();
+            }
+        }
     }
 
     fn get_contiguous_pages<S: PageSize>(&self, start: Page<S>) -> usize {
-        self.get_meta(start) >> (S::LOG_BYTES - Size4K::LOG_BYTES)
+        self.meta.load(start.start(), Ordering::Relaxed) >> (S::LOG_BYTES - Size4K::LOG_BYTES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin::Lazy;
+
+    #[test]
+    fn aligned_pages() {
+        // A single shard, so that `c` comes from the same free list as `b` whichever CPU runs the test.
+        let pr = FreelistPageResource::new_with_partitions(
+            SpaceId(11),
+            NumaPartitions::with_default_topology(SpaceId(11)),
+            1,
+        );
+        let a = pr.acquire_pages::<Size4K>(1).unwrap();
+        let b = pr
+            .acquire_aligned_pages::<Size4K>(1, Size2M::BYTES)
+            .unwrap();
+        assert!(b.start.start().is_aligned_to(Size2M::BYTES));
+        assert_eq!(pr.get_contiguous_pages(b.start), 1);
+        assert_eq!(pr.reserved_bytes(), 2 * Size4K::BYTES);
+        // The rest of the aligned block is free again.
+        let c = pr.acquire_pages::<Size4K>(1).unwrap();
+        assert_eq!(c.start, b.end);
+        let last = c.start;
+        for p in [a, b, c] {
+            pr.release_pages(p.start);
+        }
+        assert_eq!(pr.reserved_bytes(), 0);
+        // Released runs are cached. The most recently released one comes back first.
+        let d = pr.acquire_pages::<Size4K>(1).unwrap();
+        assert_eq!(d.start, last);
+        assert_eq!(pr.get_contiguous_pages(d.start), 1);
+        // Runs too large for the cache go straight back to the free list.
+        let e = pr.acquire_pages::<Size4K>(2 << MAX_CACHED_CLASS).unwrap();
+        pr.release_pages(e.start);
+        assert_eq!(pr.partitions[0].caches[0].lock().len[0], 2);
+        assert!(pr.partitions[0].caches[0].lock().len[1..]
+            .iter()
+            .all(|len| *len == 0));
+        pr.release_pages(d.start);
+        assert_eq!(pr.reserved_bytes(), 0);
+    }
+
+    #[test]
+    fn concurrent_acquire_release() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 20000;
+        static PR: Lazy<FreelistPageResource> =
+            Lazy::new(|| FreelistPageResource::new(SpaceId(14)));
+        let threads = (0..THREADS)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    let mut live = std::vec::Vec::new();
+                    for i in 0..ROUNDS {
+                        let pages = 1 << ((t + i) % 6);
+                        let range = PR.acquire_pages::<Size4K>(pages).unwrap();
+                        assert_eq!(PR.get_contiguous_pages(range.start), pages);
+                        let last = Step::backward(range.end, 1);
+                        unsafe { range.start.start().store(range.start.start()) };
+                        unsafe { last.start().store(range.start.start()) };
+                        live.push(range);
+                        if live.len() > 16 {
+                            let range = live.swap_remove(i % live.len());
+                            let last = Step::backward(range.end, 1);
+                            // No other thread was handed an overlapping run.
+                            assert_eq!(
+                                unsafe { range.start.start().load::<Address>() },
+                                range.start.start()
+                            );
+                            assert_eq!(
+                                unsafe { last.start().load::<Address>() },
+                                range.start.start()
+                            );
+                            PR.release_pages(range.start);
+                        }
+                    }
+                    for range in live {
+                        PR.release_pages(range.start);
+                    }
+                })
+            })
+            .collect::<std::vec::Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(PR.reserved_bytes(), 0);
     }
 }
@@ -0,0 +1,216 @@
+use atomic::Atomic;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Maximum number of times an allocation is retried after the OOM callback freed memory.
+pub const MAX_OOM_RETRIES: usize = 16;
+
+/// The process-wide heap limit, shared by all page resources unless overridden.
+pub static HEAP_LIMIT: HeapLimit = HeapLimit::new();
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomKind {
+    /// Committed memory crossed the soft limit. The allocation succeeded.
+    SoftLimit,
+    /// An allocation failed, either because of the hard limit or because the OS is out of memory.
+    OutOfMemory,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct OomInfo {
+    pub kind: OomKind,
+    /// Size of the allocation request that triggered the callback.
+    pub request_bytes: usize,
+    /// Bytes currently held by page resources.
+    pub committed_bytes: usize,
+}
+
+/// Called on memory pressure. Returns `true` if it released memory and the allocation should be retried.
+///
+/// A C function pointer, so that programs can register one through `mallockit_set_oom_callback`.
+pub type OomCallback = extern "C" fn(&OomInfo) -> bool;
+
+/// Soft/hard limit on the bytes held by page resources.
+///
+/// Page resources charge the limit on `acquire_pages`/`acquire_block` and uncharge it on release.
+/// Acquisitions that would exceed the hard limit fail.
+pub struct HeapLimit {
+    committed: AtomicUsize,
+    soft_limit: AtomicUsize,
+    hard_limit: AtomicUsize,
+    soft_limit_pending: AtomicBool,
+    callback: Atomic<Option<OomCallback>>,
+}
+
+impl HeapLimit {
+    pub const fn new() -> Self {
+        Self {
+            committed: AtomicUsize::new(0),
+            soft_limit: AtomicUsize::new(usize::MAX),
+            hard_limit: AtomicUsize::new(usize::MAX),
+            soft_limit_pending: AtomicBool::new(false),
+            callback: Atomic::new(None),
+        }
+    }
+
+    pub fn committed_bytes(&self) -> usize {
+        self.committed.load(Ordering::Relaxed)
+    }
+
+    pub fn soft_limit(&self) -> Option<usize> {
+        Some(self.soft_limit.load(Ordering::Relaxed)).filter(|v| *v != usize::MAX)
+    }
+
+    pub fn hard_limit(&self) -> Option<usize> {
+        Some(self.hard_limit.load(Ordering::Relaxed)).filter(|v| *v != usize::MAX)
+    }
+
+    pub fn set_soft_limit(&self, bytes: Option<usize>) {
+        self.soft_limit
+            .store(bytes.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub fn set_hard_limit(&self, bytes: Option<usize>) {
+        self.hard_limit
+            .store(bytes.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub fn set_oom_callback(&self, callback: Option<OomCallback>) {
+        self.callback.store(callback, Ordering::SeqCst);
+    }
+
+    /// Charge `bytes` against the limit. Fails without side effects if the hard limit would be exceeded.
+    pub fn charge(&self, bytes: usize) -> bool {
+        let hard_limit = self.hard_limit.load(Ordering::Relaxed);
+        let result = self
+            .committed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+                let new = c.checked_add(bytes)?;
+                (new <= hard_limit).then_some(new)
+            });
+        let Ok(old) = result else {
+            return false;
+        };
+        let soft_limit = self.soft_limit.load(Ordering::Relaxed);
+        if old <= soft_limit && old + bytes > soft_limit {
+            self.soft_limit_pending.store(true, Ordering::Relaxed);
+        }
+        true
+    }
+
+    pub fn uncharge(&self, bytes: usize) {
+        self.committed.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    fn notify(&self, kind: OomKind, request_bytes: usize) -> bool {
+        let Some(callback) = self.callback.load(Ordering::SeqCst) else {
+            return false;
+        };
+        callback(&OomInfo {
+            kind,
+            request_bytes,
+            committed_bytes: self.committed_bytes(),
+        })
+    }
+
+    /// Run `alloc`. On failure, let the OOM callback release memory and retry.
+    /// Also reports crossing the soft limit to the callback.
+    #[inline(always)]
+    pub fn alloc_or_retry<T>(
+        &self,
+        request_bytes: usize,
+        mut alloc: impl FnMut() -> Option<T>,
+    ) -> Option<T> {
+        match alloc() {
+            Some(v) => {
+                if self.soft_limit_pending.load(Ordering::Relaxed) {
+                    self.report_soft_limit(request_bytes);
+                }
+                Some(v)
+            }
+            None => self.retry(request_bytes, alloc),
+        }
+    }
+
+    #[cold]
+    fn report_soft_limit(&self, request_bytes: usize) {
+        if self.soft_limit_pending.swap(false, Ordering::Relaxed) {
+            self.notify(OomKind::SoftLimit, request_bytes);
+        }
+    }
+
+    #[cold]
+    fn retry<T>(&self, request_bytes: usize, mut alloc: impl FnMut() -> Option<T>) -> Option<T> {
+        for _ in 0..MAX_OOM_RETRIES {
+            if !self.notify(OomKind::OutOfMemory, request_bytes) {
+                return None;
+            }
+            if let Some(v) = alloc() {
+                return Some(v);
+            }
+        }
+        None
+    }
+}
+
+impl Default for HeapLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{FreelistPageResource, PageResource};
+    use super::*;
+    use crate::space::SpaceId;
+    use crate::util::*;
+    use spin::{Lazy, Mutex};
+    use std::ops::Range;
+
+    static LIMIT: HeapLimit = HeapLimit::new();
+    static PR: Lazy<FreelistPageResource> =
+        Lazy::new(|| FreelistPageResource::new(SpaceId(9)).with_heap_limit(&LIMIT));
+    static CACHE: Mutex<Option<Range<Page>>> = Mutex::new(None);
+    static EVENTS: Mutex<[usize; 2]> = Mutex::new([0; 2]);
+
+    extern "C" fn release_cache(info: &OomInfo) -> bool {
+        EVENTS.lock()[info.kind as usize] += 1;
+        match CACHE.lock().take() {
+            Some(pages) => {
+                PR.release_pages(pages.start);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn hard_limit_with_oom_callback() {
+        LIMIT.set_soft_limit(Some(8 * Size4K::BYTES));
+        LIMIT.set_hard_limit(Some(16 * Size4K::BYTES));
+        LIMIT.set_oom_callback(Some(release_cache));
+        // Crossing the soft limit is reported once.
+        let a = LIMIT.alloc_or_retry(0, || PR.acquire_pages::<Size4K>(8));
+        let b = LIMIT.alloc_or_retry(0, || PR.acquire_pages::<Size4K>(4));
+        assert!(a.is_some() && b.is_some());
+        assert_eq!(*EVENTS.lock(), [1, 0]);
+        assert_eq!(LIMIT.committed_bytes(), 12 * Size4K::BYTES);
+        // Over the hard limit, the callback releases the cache and the allocation is retried.
+        *CACHE.lock() = a;
+        let c = LIMIT.alloc_or_retry(0, || PR.acquire_pages::<Size4K>(8));
+        assert!(c.is_some());
+        assert_eq!(*EVENTS.lock(), [1, 1]);
+        // Nothing left to release.
+        assert!(LIMIT
+            .alloc_or_retry(0, || PR.acquire_pages::<Size4K>(8))
+            .is_none());
+        assert_eq!(*EVENTS.lock(), [1, 2]);
+        for pages in [b, c] {
+            PR.release_pages(pages.unwrap().start);
+        }
+        assert_eq!(LIMIT.committed_bytes(), 0);
+        assert_eq!(PR.reserved_bytes(), 0);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::stat::{self, Counter, CounterGroup};
+use crate::util::sys::raw_memory::RawMemory;
+use crate::util::*;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static HUGE_PAGE_COUNTERS: CounterGroup = CounterGroup::new("huge-pages");
+static HUGE_CHUNKS: Counter = HUGE_PAGE_COUNTERS.new_counter("huge-chunks");
+static ADVISED_CHUNKS: Counter = HUGE_PAGE_COUNTERS.new_counter("advised-chunks");
+static FALLBACK_CHUNKS: Counter = HUGE_PAGE_COUNTERS.new_counter("fallback-chunks");
+
+/// How a page resource backs its memory with huge pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HugePagePolicy {
+    /// Regular 4K pages. Only the process-wide `transparent_huge_page` feature applies.
+    #[default]
+    None,
+    /// `madvise(MADV_HUGEPAGE)` on every acquired 2M chunk.
+    Transparent,
+    /// Back acquired 2M chunks with `MAP_HUGETLB` pages.
+    /// Falls back to transparent huge pages when the hugetlb pool is exhausted.
+    HugeTlb2M,
+    /// Back acquired 1G chunks with `MAP_HUGETLB` pages.
+    /// Falls back to transparent huge pages when the hugetlb pool is exhausted.
+    HugeTlb1G,
+}
+
+impl HugePagePolicy {
+    pub const fn log_chunk_bytes(&self) -> usize {
+        match self {
+            Self::None | Self::Transparent | Self::HugeTlb2M => Size2M::LOG_BYTES,
+            Self::HugeTlb1G => Size1G::LOG_BYTES,
+        }
+    }
+
+    pub const fn is_huge_tlb(&self) -> bool {
+        matches!(self, Self::HugeTlb2M | Self::HugeTlb1G)
+    }
+}
+
+/// A snapshot of how many chunks got huge pages. Each chunk is counted once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HugePageStats {
+    /// Chunks mapped from the hugetlb pool.
+    pub huge_chunks: usize,
+    /// Chunks advised with `MADV_HUGEPAGE` under `HugePagePolicy::Transparent`. The kernel may still back
+    /// them with regular pages, e.g. if it finds no free huge page on the first touch.
+    pub advised_chunks: usize,
+    /// Chunks that fell back to regular pages, or to THP after a hugetlb failure.
+    pub fallback_chunks: usize,
+}
+
+/// Per-page-resource huge page state.
+pub(crate) struct HugePages {
+    policy: HugePagePolicy,
+    huge_chunks: AtomicUsize,
+    advised_chunks: AtomicUsize,
+    fallback_chunks: AtomicUsize,
+}
+
+impl HugePages {
+    pub const fn new() -> Self {
+        Self {
+            policy: HugePagePolicy::None,
+            huge_chunks: AtomicUsize::new(0),
+            advised_chunks: AtomicUsize::new(0),
+            fallback_chunks: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn policy(&self) -> HugePagePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: HugePagePolicy) {
+        self.policy = policy;
+    }
+
+    pub fn stats(&self) -> HugePageStats {
+        HugePageStats {
+            huge_chunks: self.huge_chunks.load(Ordering::Relaxed),
+            advised_chunks: self.advised_chunks.load(Ordering::Relaxed),
+            fallback_chunks: self.fallback_chunks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The huge page aligned part of `[start, start + bytes)`.
+    fn chunks(&self, start: Address, bytes: usize) -> Option<(Address, usize)> {
+        let chunk_bytes = 1usize << self.policy.log_chunk_bytes();
+        let chunk_start = start.align_up(chunk_bytes);
+        let chunk_end = (start + bytes).align_down(chunk_bytes);
+        if chunk_end <= chunk_start {
+            return None;
+        }
+        Some((chunk_start, chunk_end - chunk_start))
+    }
+
+    fn record(local: &AtomicUsize, global: &'static Counter, chunks: usize) {
+        local.fetch_add(chunks, Ordering::Relaxed);
+        stat::run(|| global.inc(chunks));
+    }
+
+    /// Apply the policy to newly acquired memory that has not been written yet.
+    ///
+    /// Returns `true` if the range was remapped, which drops any NUMA binding.
+    pub fn prepare(&self, start: Address, bytes: usize) -> bool {
+        let Some((start, bytes)) = self.chunks(start, bytes) else {
+            return false;
+        };
+        let chunks = bytes >> self.policy.log_chunk_bytes();
+        match self.policy {
+            HugePagePolicy::None => false,
+            HugePagePolicy::Transparent => {
+                if RawMemory::madv_hugepage(start, bytes) {
+                    Self::record(&self.advised_chunks, &ADVISED_CHUNKS, chunks);
+                } else {
+                    Self::record(&self.fallback_chunks, &FALLBACK_CHUNKS, chunks);
+                }
+                false
+            }
+            HugePagePolicy::HugeTlb2M | HugePagePolicy::HugeTlb1G => {
+                let result = if self.policy == HugePagePolicy::HugeTlb1G {
+                    RawMemory::map_huge_tlb::<Size1G>(start, bytes)
+                } else {
+                    RawMemory::map_huge_tlb::<Size2M>(start, bytes)
+                };
+                if result.is_ok() {
+                    Self::record(&self.huge_chunks, &HUGE_CHUNKS, chunks);
+                } else {
+                    RawMemory::remap_anonymous(start, bytes).unwrap();
+                    RawMemory::madv_hugepage(start, bytes);
+                    Self::record(&self.fallback_chunks, &FALLBACK_CHUNKS, chunks);
+                }
+                true
+            }
+        }
+    }
+
+    /// Return hugetlb chunks of a released range to the regular page pool.
+    ///
+    /// Returns the remapped chunks, which are fresh memory and lost any NUMA binding.
+    pub fn release(&self, start: Address, bytes: usize) -> Option<Range<Address>> {
+        if !self.policy.is_huge_tlb() {
+            return None;
+        }
+        let (start, bytes) = self.chunks(start, bytes)?;
+        RawMemory::remap_anonymous(start, bytes).unwrap();
+        Some(start..start + bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{FreelistPageResource, PageResource};
+    use super::*;
+    use crate::space::SpaceId;
+
+    fn touch(start: Address, bytes: usize) {
+        for offset in (0..bytes).step_by(Size4K::BYTES) {
+            unsafe { (start + offset).store(offset) }
+        }
+        for offset in (0..bytes).step_by(Size4K::BYTES) {
+            assert_eq!(unsafe { (start + offset).load::<usize>() }, offset);
+        }
+    }
+
+    /// `MADV_HUGEPAGE` only fails if the kernel has no transparent huge pages.
+    fn has_transparent_huge_pages() -> bool {
+        std::path::Path::new("/sys/kernel/mm/transparent_hugepage/enabled").exists()
+    }
+
+    /// Whether the hugetlb pool can back `chunks` more 2M chunks.
+    fn free_huge_tlb_chunks(chunks: usize) -> bool {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").unwrap();
+        let field = |name: &str| -> usize {
+            meminfo
+                .lines()
+                .find_map(|l| l.strip_prefix(name))
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(0)
+        };
+        field("HugePages_Free:") - field("HugePages_Rsvd:") >= chunks
+    }
+
+    #[test]
+    fn transparent_huge_pages() {
+        let mut pr = FreelistPageResource::new(SpaceId(5));
+        pr.set_huge_page_policy(HugePagePolicy::Transparent);
+        let pages = pr.acquire_pages::<Size2M>(2).unwrap();
+        touch(pages.start.start(), 2 * Size2M::BYTES);
+        let stats = pr.huge_page_stats();
+        assert_eq!(stats.huge_chunks, 0);
+        if has_transparent_huge_pages() {
+            assert_eq!((stats.advised_chunks, stats.fallback_chunks), (2, 0));
+        } else {
+            assert_eq!((stats.advised_chunks, stats.fallback_chunks), (0, 2));
+        }
+        // Sub-chunk acquisitions are left alone.
+        let small = pr.acquire_pages::<Size4K>(1).unwrap();
+        assert_eq!(pr.huge_page_stats(), stats);
+        pr.release_pages(small.start);
+        pr.release_pages(pages.start);
+    }
+
+    #[test]
+    fn huge_tlb_with_fallback() {
+        let mut pr = FreelistPageResource::new(SpaceId(6));
+        pr.set_huge_page_policy(HugePagePolicy::HugeTlb2M);
+        for _ in 0..2 {
+            let before = pr.huge_page_stats();
+            let huge = free_huge_tlb_chunks(1);
+            let pages = pr.acquire_pages::<Size2M>(1).unwrap();
+            touch(pages.start.start(), Size2M::BYTES);
+            let stats = pr.huge_page_stats();
+            assert_eq!(stats.advised_chunks, 0);
+            if huge {
+                assert_eq!(stats.huge_chunks, before.huge_chunks + 1);
+                assert_eq!(stats.fallback_chunks, before.fallback_chunks);
+            } else {
+                assert_eq!(stats.huge_chunks, before.huge_chunks);
+                assert_eq!(stats.fallback_chunks, before.fallback_chunks + 1);
+            }
+            pr.release_pages(pages.start);
+        }
+        // Released chunks are regular memory again.
+        let pages = pr.acquire_pages::<Size4K>(4).unwrap();
+        touch(pages.start.start(), 4 * Size4K::BYTES);
+        pr.release_pages(pages.start);
+    }
+}
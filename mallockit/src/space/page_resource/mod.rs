@@ -1,8 +1,16 @@
 mod block_page_resource;
 mod freelist_page_resource;
+mod heap_limit;
+mod huge_pages;
+mod numa_partitions;
+mod prefault;
 
 pub use block_page_resource::*;
 pub use freelist_page_resource::*;
+pub use heap_limit::*;
+pub use huge_pages::{HugePagePolicy, HugePageStats};
+pub use numa_partitions::*;
+pub use prefault::*;
 
 use crate::util::*;
 use std::ops::Range;
@@ -12,9 +20,39 @@ pub trait PageResource: Sized {
 
     fn acquire_pages<S: PageSize>(&self, pages: usize) -> Option<Range<Page<S>>>;
 
+    /// Acquire `pages` pages starting at an address aligned to `align` bytes.
+    ///
+    /// The default acquires `align` bytes worth of pages if that is more than `pages`.
+    fn acquire_aligned_pages<S: PageSize>(
+        &self,
+        pages: usize,
+        align: usize,
+    ) -> Option<Range<Page<S>>> {
+        let pages = usize::max(pages, align >> S::LOG_BYTES);
+        let range = self.acquire_pages::<S>(pages)?;
+        debug_assert!(range.start.start().is_aligned_to(align));
+        Some(range)
+    }
+
     fn release_pages<S: PageSize>(&self, start: Page<S>);
 
     fn get_contiguous_pages<S: PageSize>(&self, _start: Page<S>) -> usize {
         unimplemented!()
     }
+
+    /// Back acquired pages as `policy` says. Page resources that cannot use huge pages ignore it.
+    fn set_huge_page_policy(&mut self, _policy: HugePagePolicy) {}
+
+    fn huge_page_stats(&self) -> HugePageStats {
+        HugePageStats::default()
+    }
+
+    /// Prefault acquired pages as `policy` says. Page resources that cannot prefault ignore it.
+    fn set_prefault_policy(&mut self, _policy: PrefaultPolicy) {}
+
+    /// Acquire all locks before `fork`. See `Plan::fork_prepare`.
+    fn fork_prepare(&self) {}
+
+    /// Release the locks taken by `fork_prepare`, in both the parent and the child.
+    fn fork_release(&self) {}
 }
@@ -0,0 +1,89 @@
+use super::super::SpaceId;
+use crate::util::mem::heap::HEAP;
+use crate::util::sys::numa::{self, NumaTopology, MAX_NUMA_NODES};
+use crate::util::*;
+use std::ops::Range;
+
+/// Splits the virtual range of a space into one power-of-two sized partition per NUMA node.
+///
+/// With a single-node topology there is exactly one partition covering the whole space.
+pub struct NumaPartitions {
+    base: Address,
+    log_partition_bytes: usize,
+    nodes: usize,
+    topology: &'static dyn NumaTopology,
+    /// Whether threads are routed to their home node instead of the node they are running on.
+    home_nodes: bool,
+}
+
+impl NumaPartitions {
+    pub fn new(id: SpaceId, topology: &'static dyn NumaTopology) -> Self {
+        let nodes = usize::clamp(topology.num_nodes(), 1, MAX_NUMA_NODES);
+        let log_nodes = nodes.next_power_of_two().trailing_zeros() as usize;
+        let range = HEAP.get_space_range(id);
+        let partitions = Self {
+            base: range.start,
+            log_partition_bytes: SpaceId::LOG_MAX_SPACE_SIZE - log_nodes,
+            nodes,
+            topology,
+            home_nodes: false,
+        };
+        if nodes > 1 {
+            for node in 0..nodes {
+                let range = partitions.range(node);
+                topology.bind(range.start, range.end - range.start, node);
+            }
+        }
+        partitions
+    }
+
+    pub fn with_default_topology(id: SpaceId) -> Self {
+        Self {
+            home_nodes: true,
+            ..Self::new(id, numa::topology())
+        }
+    }
+
+    pub const fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    pub const fn is_numa(&self) -> bool {
+        self.nodes > 1
+    }
+
+    pub const fn log_partition_bytes(&self) -> usize {
+        self.log_partition_bytes
+    }
+
+    pub fn range(&self, node: usize) -> Range<Address> {
+        debug_assert!(node < self.nodes);
+        let start = self.base + (node << self.log_partition_bytes);
+        let end = start + (1usize << self.log_partition_bytes);
+        start..end
+    }
+
+    pub fn node_of(&self, addr: Address) -> usize {
+        (addr - self.base) >> self.log_partition_bytes
+    }
+
+    /// Re-apply the node binding of a range that has been remapped.
+    pub fn rebind(&self, start: Address, size: usize) {
+        if self.is_numa() {
+            self.topology.bind(start, size, self.node_of(start));
+        }
+    }
+
+    /// The partition local to the calling thread.
+    pub fn current_node(&self) -> usize {
+        if !self.is_numa() {
+            return 0;
+        }
+        let node = if self.home_nodes {
+            numa::home_node()
+        } else {
+            self.topology.current_node()
+        };
+        usize::min(node, self.nodes - 1)
+    }
+}
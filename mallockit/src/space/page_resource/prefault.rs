@@ -0,0 +1,66 @@
+/// When a page resource faults in its memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrefaultPolicy {
+    /// Memory is lazily faulted on first touch.
+    #[default]
+    None,
+    /// Populate pages before handing them out, trading acquisition time for no first-touch faults.
+    OnAcquire,
+    /// Pre-touch a reserve of this many bytes at startup. Pages acquired from the reserve
+    /// are already populated. Later acquisitions are lazily faulted.
+    Reserve(usize),
+}
+
+impl PrefaultPolicy {
+    pub const fn populate_on_acquire(&self) -> bool {
+        matches!(self, Self::OnAcquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{FreelistPageResource, PageResource};
+    use super::*;
+    use crate::space::SpaceId;
+    use crate::util::*;
+
+    fn resident_pages(start: Address, bytes: usize) -> usize {
+        let mut vec = [0u8; 1024];
+        let pages = bytes >> Size4K::LOG_BYTES;
+        assert!(pages <= vec.len());
+        unsafe { libc::mincore(start.as_mut_ptr(), bytes, vec.as_mut_ptr() as _) };
+        vec[..pages].iter().filter(|v| **v & 1 != 0).count()
+    }
+
+    #[test]
+    fn populate_on_acquire() {
+        let mut pr = FreelistPageResource::new(SpaceId(7));
+        pr.set_prefault_policy(PrefaultPolicy::OnAcquire);
+        let pages = pr.acquire_pages::<Size4K>(16).unwrap();
+        assert_eq!(resident_pages(pages.start.start(), 16 * Size4K::BYTES), 16);
+        pr.release_pages(pages.start);
+    }
+
+    #[test]
+    fn prefaulted_reserve() {
+        let mut pr = FreelistPageResource::new(SpaceId(8));
+        pr.set_prefault_policy(PrefaultPolicy::Reserve(Size2M::BYTES));
+        assert_eq!(pr.reserved_bytes(), 0);
+        let a = pr.acquire_pages::<Size4K>(1).unwrap();
+        let b = pr.acquire_pages::<Size4K>(16).unwrap();
+        assert!(a.start < b.start);
+        assert!(b.start.start().is_aligned_to(16 * Size4K::BYTES));
+        assert_eq!(resident_pages(a.start.start(), Size4K::BYTES), 1);
+        assert_eq!(resident_pages(b.start.start(), 16 * Size4K::BYTES), 16);
+        assert_eq!(pr.reserved_bytes(), 17 * Size4K::BYTES);
+        // Larger than what is left in the reserve.
+        let c = pr.acquire_pages::<Size4K>(512).unwrap();
+        assert!(c.start.start() >= a.start.start() + Size2M::BYTES);
+        let d = pr.acquire_pages::<Size4K>(8).unwrap();
+        assert_eq!(d.start, b.end);
+        for p in [a, b, c, d] {
+            pr.release_pages(p.start);
+        }
+        assert_eq!(pr.reserved_bytes(), 0);
+    }
+}
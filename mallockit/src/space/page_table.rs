@@ -1,296 +1,546 @@
+//! A four-level radix tree from 4K pages to per-page values, like a hardware page table.
+//!
+//! Reads are wait-free. Updates take no locks: missing tables are installed with a CAS, and tables whose
+//! entries are all gone are unlinked and kept in a pool for reuse. Tables are never unmapped while the
+//! `PageTable` is alive, so a reader racing with reclamation only ever sees a stale table, which it detects
+//! through the table's key.
+
 use crate::space::meta::Meta;
-use crate::util::bits::{BitField, BitFieldSlot};
 use crate::util::*;
-use std::iter::Step;
-use std::{marker::PhantomData, mem};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const LEVELS: usize = 4;
+const LOG_ENTRIES: usize = 9;
+const ENTRIES: usize = 1 << LOG_ENTRIES;
+/// Bits of address covered by the root table.
+const LOG_COVERAGE: usize = Size4K::LOG_BYTES + LEVELS * LOG_ENTRIES;
+/// Set in leaf entries that hold a value.
+const PRESENT: usize = 1 << 63;
+/// Set in `Table::used` once the table is reclaimed.
+const DEAD: usize = 1 << 63;
+
+/// Per-page data of a `PageTable`, packed into the low 63 bits of an entry.
+pub trait PageTableValue: Copy + 'static {
+    fn into_bits(self) -> usize;
+    fn from_bits(bits: usize) -> Self;
+}
 
-#[repr(transparent)]
-struct PageTableEntry<L: PageTableLevel>(usize, PhantomData<L>);
+impl PageTableValue for () {
+    fn into_bits(self) -> usize {
+        0
+    }
 
-enum PageTableEntryData<L: PageTableLevel> {
-    NextLevelPageTable { table: &'static mut PageTable<L> },
-    Page { pointer_meta: Address },
+    fn from_bits(_: usize) -> Self {}
 }
 
-impl<L: PageTableLevel> PageTableEntry<L> {
-    // Global fields
-    const PRESENT: BitField = BitField { bits: 1, shift: 63 };
-    const IS_PAGE_TABLE: BitField = BitField { bits: 1, shift: 62 };
-    // Page table fields
-    const PAGE_TABLE_POINTER_MASK: usize = 0x0000_ffff_ffff_f000; // 1: page table, 0: page
-    const PAGE_TABLE_USED_ENTRIES: BitField = BitField { bits: 10, shift: 0 };
-    // Page fields
-    const PAGE_POINTER_META: BitField = BitField { bits: 45, shift: 0 };
-
-    fn clear(&mut self) {
-        let value = self.0;
-        if value.get(Self::PRESENT) != 0 && value.get(Self::IS_PAGE_TABLE) != 0 {
-            let table: &'static mut PageTable<L::NextLevel> =
-                unsafe { mem::transmute(value & Self::PAGE_TABLE_POINTER_MASK) };
-            let _ = unsafe { Box::from_raw_in(table, Meta) };
-        }
-        self.0 = 0;
+impl PageTableValue for bool {
+    fn into_bits(self) -> usize {
+        self as _
     }
 
-    fn get(&self) -> Option<PageTableEntryData<L::NextLevel>> {
-        let value = self.0;
-        if value.get(Self::PRESENT) == 0 {
-            return None;
-        }
-        if value.get(Self::IS_PAGE_TABLE) != 0 {
-            let table: &'static mut PageTable<L::NextLevel> =
-                unsafe { mem::transmute(value & Self::PAGE_TABLE_POINTER_MASK) };
-            Some(PageTableEntryData::NextLevelPageTable { table })
-        } else {
-            let pointer_meta = Address::from(value.get(Self::PAGE_POINTER_META) << 3);
-            Some(PageTableEntryData::Page { pointer_meta })
-        }
+    fn from_bits(bits: usize) -> Self {
+        bits != 0
     }
+}
 
-    fn set_next_page_table(&mut self, table: &'static mut PageTable<L::NextLevel>) {
-        debug_assert!(self.0 == 0);
-        self.0.set(Self::PRESENT, 1);
-        self.0.set(Self::IS_PAGE_TABLE, 1);
-        self.0.set(Self::PAGE_TABLE_USED_ENTRIES, 0);
-        let word = table as *mut _ as usize;
-        debug_assert!((word & !Self::PAGE_TABLE_POINTER_MASK) == 0);
-        self.0 |= word;
-    }
+macro_rules! impl_page_table_value {
+    ($($t: ty),*) => {
+        $(
+            impl PageTableValue for $t {
+                fn into_bits(self) -> usize {
+                    self as _
+                }
+
+                fn from_bits(bits: usize) -> Self {
+                    bits as _
+                }
+            }
+        )*
+    };
+}
 
-    fn set_next_page(&mut self, _pages: Option<usize>) {
-        debug_assert!(self.0 == 0);
-        self.0.set(Self::PRESENT, 1);
-        self.0.set(Self::IS_PAGE_TABLE, 0);
-    }
+impl_page_table_value!(u8, u16, u32);
 
-    fn delta_entries(&mut self, entries: i32) -> usize {
-        self.0.delta(Self::PAGE_TABLE_USED_ENTRIES, entries as _)
+/// Values must be below `1 << 63`.
+impl PageTableValue for usize {
+    fn into_bits(self) -> usize {
+        debug_assert_eq!(self & PRESENT, 0);
+        self
     }
-}
 
-impl PageTableEntry<L1> {
-    fn set_pointer_meta(&mut self, ptr: Address) {
-        debug_assert!(self.0.get(Self::PRESENT) != 0);
-        debug_assert!(self.0.get(Self::IS_PAGE_TABLE) == 0);
-        self.0.set(Self::PAGE_POINTER_META, usize::from(ptr) >> 3);
+    fn from_bits(bits: usize) -> Self {
+        bits
     }
 }
 
-pub(crate) trait PageTableLevel: 'static {
-    type NextLevel: PageTableLevel;
-    const SHIFT: usize = Self::NextLevel::SHIFT + 9;
-    const MASK: usize = 0b1_1111_1111 << Self::SHIFT;
+impl PageTableValue for Address {
+    fn into_bits(self) -> usize {
+        usize::from(self).into_bits()
+    }
 
-    fn get_index(addr: Address) -> usize {
-        (usize::from(addr) & Self::MASK) >> Self::SHIFT
+    fn from_bits(bits: usize) -> Self {
+        Address::from(bits)
     }
 }
 
-pub(crate) struct L4;
-
-impl PageTableLevel for L4 {
-    type NextLevel = L3;
+#[repr(C)]
+struct Table {
+    /// Child tables, or `PRESENT | value` in leaf tables. The first entry links free tables in the pool.
+    entries: [AtomicUsize; ENTRIES],
+    /// Non-empty entries plus threads in the middle of an update. Set to `DEAD` when the table is reclaimed.
+    used: AtomicUsize,
+    /// The first address covered by the table, or'd with its level. Zero while in the pool.
+    key: AtomicUsize,
 }
 
-pub(crate) struct L3;
+impl Table {
+    const fn new() -> Self {
+        Self {
+            entries: [const { AtomicUsize::new(0) }; ENTRIES],
+            used: AtomicUsize::new(0),
+            key: AtomicUsize::new(0),
+        }
+    }
 
-impl PageTableLevel for L3 {
-    type NextLevel = L2;
-}
+    const fn shift(level: usize) -> usize {
+        Size4K::LOG_BYTES + level * LOG_ENTRIES
+    }
 
-pub(crate) struct L2;
+    fn index(addr: Address, level: usize) -> usize {
+        (usize::from(addr) >> Self::shift(level)) & (ENTRIES - 1)
+    }
 
-impl PageTableLevel for L2 {
-    type NextLevel = L1;
-}
+    fn key_of(addr: Address, level: usize) -> usize {
+        let start = usize::from(addr) & !((1usize << Self::shift(level + 1)) - 1);
+        start | level
+    }
 
-pub(crate) struct L1;
+    /// The child table of `entry`. Tables are only freed when the `PageTable` is dropped.
+    fn child(entry: usize) -> Option<&'static Table> {
+        (entry != 0).then(|| unsafe { &*(entry as *const Table) })
+    }
 
-impl PageTableLevel for L1 {
-    type NextLevel = !;
-    const SHIFT: usize = 12;
+    fn try_hold(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |used| {
+                (used & DEAD == 0).then_some(used + 1)
+            })
+            .is_ok()
+    }
 }
 
-impl PageTableLevel for ! {
-    type NextLevel = !;
-    const SHIFT: usize = 0;
+pub struct PageTable<T: PageTableValue> {
+    root: Table,
+    /// Reclaimed tables, a stack with an ABA tag in the top 16 bits.
+    pool: AtomicUsize,
+    tables: AtomicUsize,
+    _p: std::marker::PhantomData<T>,
 }
 
-struct PageMeta {
-    pub pointer_meta: Address,
-}
+unsafe impl<T: PageTableValue> Send for PageTable<T> {}
+unsafe impl<T: PageTableValue> Sync for PageTable<T> {}
 
-pub(crate) struct PageTable<L: PageTableLevel = L4> {
-    table: [PageTableEntry<L>; 512],
-    phantom: PhantomData<L>,
+impl<T: PageTableValue> Default for PageTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<L: PageTableLevel> PageTable<L> {
-    fn get_entry(&self, address: Address) -> Option<PageTableEntryData<L::NextLevel>> {
-        self.table[L::get_index(address)].get()
+impl<T: PageTableValue> PageTable<T> {
+    const TAG_SHIFT: usize = 48;
+    const POINTER_MASK: usize = (1 << Self::TAG_SHIFT) - 1;
+
+    pub const fn new() -> Self {
+        Self {
+            root: Table::new(),
+            pool: AtomicUsize::new(0),
+            tables: AtomicUsize::new(0),
+            _p: std::marker::PhantomData,
+        }
+    }
+
+    /// Number of tables in use, not counting the root.
+    pub fn tables(&self) -> usize {
+        self.tables.load(Ordering::Relaxed)
+    }
+
+    fn alloc_table(&self, key: usize) -> &'static Table {
+        let mut head = self.pool.load(Ordering::Acquire);
+        let table = loop {
+            let Some(table) = Table::child(head & Self::POINTER_MASK) else {
+                break &*Box::leak(Box::new_in(Table::new(), Meta));
+            };
+            // Stale if another thread took the table meanwhile. The tag makes the CAS fail.
+            let next = table.entries[0].load(Ordering::Relaxed);
+            let tag = (head >> Self::TAG_SHIFT).wrapping_add(1);
+            match self.pool.compare_exchange_weak(
+                head,
+                (tag << Self::TAG_SHIFT) | next,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break table,
+                Err(h) => head = h,
+            }
+        };
+        table.entries[0].store(0, Ordering::Relaxed);
+        // Held by the caller until it is linked.
+        table.used.store(1, Ordering::Relaxed);
+        table.key.store(key, Ordering::Release);
+        self.tables.fetch_add(1, Ordering::Relaxed);
+        table
     }
 
-    fn get_next_page_table(&self, address: Address) -> &'static mut PageTable<L::NextLevel> {
-        match self.table[L::get_index(address)].get() {
-            Some(PageTableEntryData::NextLevelPageTable { table, .. }) => table,
-            _ => unreachable!(),
+    fn free_table(&self, table: &Table) {
+        table.key.store(0, Ordering::Release);
+        let ptr = table as *const Table as usize;
+        let mut head = self.pool.load(Ordering::Relaxed);
+        loop {
+            table.entries[0].store(head & Self::POINTER_MASK, Ordering::Release);
+            let tag = (head >> Self::TAG_SHIFT).wrapping_add(1);
+            match self.pool.compare_exchange_weak(
+                head,
+                (tag << Self::TAG_SHIFT) | ptr,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(h) => head = h,
+            }
         }
+        self.tables.fetch_sub(1, Ordering::Relaxed);
     }
 
-    fn get_or_allocate_next_page_table(
-        &mut self,
-        address: Address,
-        mut on_create: impl FnMut(),
-    ) -> &'static mut PageTable<L::NextLevel> {
-        let index = L::get_index(address);
-        match self.table[index].get() {
-            Some(PageTableEntryData::NextLevelPageTable { table, .. }) => table,
-            Some(_) => unreachable!(),
-            _ => {
-                let table = Box::leak(meta_box!(PageTable::<L::NextLevel> {
-                    table: unsafe {
-                        mem::transmute::<[usize; 512], [PageTableEntry<L::NextLevel>; 512]>(
-                            [0usize; 512],
-                        )
-                    },
-                    phantom: PhantomData,
-                }));
-                self.table[index].set_next_page_table(table);
-                on_create();
-                self.get_next_page_table(address)
+    /// Drop a hold on `table`, the child of `parent` at the entry of `addr`. Reclaims `table` if it is now empty.
+    /// The caller must hold `parent`.
+    ///
+    /// Only the thread that marks the table as dead unlinks it. Others wait for it instead of helping,
+    /// as the table may be reused and linked again at the same entry by the time they get to it.
+    fn release(&self, table: &Table, parent: &Table, parent_level: usize, addr: Address) {
+        if table.used.fetch_sub(1, Ordering::AcqRel) == 1
+            && table
+                .used
+                .compare_exchange(0, DEAD, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            let entry = &parent.entries[Table::index(addr, parent_level)];
+            let old = entry.swap(0, Ordering::AcqRel);
+            debug_assert_eq!(old, table as *const Table as usize);
+            self.free_table(table);
+            parent.used.fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    /// Hold the child of `parent` covering `addr`, installing it first if `create` is set.
+    /// The caller must hold `parent`.
+    fn hold_child(
+        &self,
+        parent: &Table,
+        parent_level: usize,
+        addr: Address,
+        create: bool,
+    ) -> Option<&'static Table> {
+        let entry = &parent.entries[Table::index(addr, parent_level)];
+        loop {
+            let ptr = entry.load(Ordering::Acquire);
+            let Some(child) = Table::child(ptr) else {
+                if !create {
+                    return None;
+                }
+                let table = self.alloc_table(Table::key_of(addr, parent_level - 1));
+                parent.used.fetch_add(1, Ordering::Relaxed);
+                let new = table as *const Table as usize;
+                match entry.compare_exchange(0, new, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => return Some(table),
+                    Err(_) => {
+                        parent.used.fetch_sub(1, Ordering::Relaxed);
+                        table.used.store(DEAD, Ordering::Relaxed);
+                        self.free_table(table);
+                        continue;
+                    }
+                }
+            };
+            if !child.try_hold() {
+                // Dead, about to be unlinked
+                std::hint::spin_loop();
+                continue;
+            }
+            // The table may have been reclaimed and reused before it was held. Then it is not ours to reclaim,
+            // and is left alone even if this was the last hold.
+            if entry.load(Ordering::Acquire) == ptr {
+                return Some(child);
+            }
+            if child.used.fetch_sub(1, Ordering::AcqRel) == 1 {
+                // That was the last hold on a table linked elsewhere. Reclaim it through its own path.
+                let key = child.key.load(Ordering::Acquire);
+                let (start, level) = (
+                    Address::from(key & !Page::<Size4K>::MASK),
+                    key & Page::<Size4K>::MASK,
+                );
+                if level < LEVELS - 1 {
+                    if let Some(path) = self.hold_path(start, level, false) {
+                        self.release_path(&path[..LEVELS - level], start);
+                    }
+                }
             }
         }
     }
-}
 
-impl PageTable<L1> {
-    fn set_pointer_meta(&mut self, address: Address, pointer_meta: Address) {
-        self.table[L1::get_index(address)].set_pointer_meta(pointer_meta);
+    /// Hold the tables on the path to the table of level `depth` covering `addr`, root first.
+    /// The path is `LEVELS - depth` tables long.
+    fn hold_path(&self, addr: Address, depth: usize, create: bool) -> Option<[&Table; LEVELS]> {
+        debug_assert!(usize::from(addr) >> LOG_COVERAGE == 0);
+        let mut path = [&self.root; LEVELS];
+        for level in (depth..LEVELS - 1).rev() {
+            let parent_level = level + 1;
+            match self.hold_child(path[LEVELS - 1 - parent_level], parent_level, addr, create) {
+                Some(table) => path[LEVELS - 1 - level] = table,
+                None => {
+                    self.release_path(&path[..LEVELS - 1 - level], addr);
+                    return None;
+                }
+            }
+        }
+        Some(path)
     }
-}
 
-impl PageTable<L4> {
-    pub(crate) const fn new() -> Self {
-        Self {
-            table: unsafe {
-                mem::transmute::<[usize; 512], [PageTableEntry<L4>; 512]>([0usize; 512])
-            },
-            phantom: PhantomData,
+    /// Drop the holds taken by `hold_path`, leaf first, so that parents outlive the reclamation of children.
+    fn release_path(&self, path: &[&Table], addr: Address) {
+        for i in (1..path.len()).rev() {
+            let level = LEVELS - 1 - i;
+            self.release(path[i], path[i - 1], level + 1, addr);
         }
     }
 
-    fn get(&self, address: Address) -> Option<PageMeta> {
-        let l3 = match self.get_entry(address)? {
-            PageTableEntryData::NextLevelPageTable { table, .. } => table,
-            _ => unreachable!(),
-        };
-        let l2 = match l3.get_entry(address)? {
-            PageTableEntryData::NextLevelPageTable { table, .. } => table,
-            PageTableEntryData::Page { pointer_meta } => return Some(PageMeta { pointer_meta }),
-        };
-        let l1 = match l2.get_entry(address)? {
-            PageTableEntryData::NextLevelPageTable { table, .. } => table,
-            PageTableEntryData::Page { pointer_meta } => return Some(PageMeta { pointer_meta }),
-        };
-        match l1.get_entry(address)? {
-            PageTableEntryData::Page { pointer_meta } => Some(PageMeta { pointer_meta }),
-            _ => unreachable!(),
+    /// The value of the page containing `addr`. Wait-free.
+    pub fn get(&self, addr: Address) -> Option<T> {
+        debug_assert!(usize::from(addr) >> LOG_COVERAGE == 0);
+        let mut table = &self.root;
+        for level in (1..LEVELS).rev() {
+            let entry = table.entries[Table::index(addr, level)].load(Ordering::Acquire);
+            if level != LEVELS - 1
+                && table.key.load(Ordering::Acquire) != Table::key_of(addr, level)
+            {
+                return None;
+            }
+            table = Table::child(entry)?;
         }
+        let entry = table.entries[Table::index(addr, 0)].load(Ordering::Acquire);
+        if entry & PRESENT == 0 || table.key.load(Ordering::Acquire) != Table::key_of(addr, 0) {
+            return None;
+        }
+        Some(T::from_bits(entry & !PRESENT))
     }
 
-    fn insert_one_page<S: PageSize>(&mut self, start_page: Page<S>, num_pages: Option<usize>) {
-        let start = start_page.start();
-        let l4 = self;
-        let l3 = l4.get_or_allocate_next_page_table(start, || {});
-        if S::BYTES == Size1G::BYTES {
-            debug_assert!(l3.get_entry(start).is_none());
-            l3.table[L3::get_index(start)].set_next_page(num_pages);
-            l4.table[L4::get_index(start)].delta_entries(1);
-            return;
+    /// Set the value of the page containing `addr`. Returns the previous value.
+    pub fn insert(&self, addr: Address, value: T) -> Option<T> {
+        let bits = value.into_bits();
+        debug_assert_eq!(bits & PRESENT, 0);
+        let path = self.hold_path(addr, 0, true).unwrap();
+        let leaf = path[LEVELS - 1];
+        let old = leaf.entries[Table::index(addr, 0)].swap(PRESENT | bits, Ordering::AcqRel);
+        if old & PRESENT == 0 {
+            // One more used entry
+            leaf.used.fetch_add(1, Ordering::Relaxed);
         }
-        let l2 = l3.get_or_allocate_next_page_table(start, || {
-            l4.table[L4::get_index(start)].delta_entries(1);
-        });
-        if S::BYTES == Size2M::BYTES {
-            debug_assert!(l2.get_entry(start).is_none());
-            l2.table[L2::get_index(start)].set_next_page(num_pages);
-            l3.table[L3::get_index(start)].delta_entries(1);
-            return;
+        self.release_path(&path, addr);
+        (old & PRESENT != 0).then(|| T::from_bits(old & !PRESENT))
+    }
+
+    /// Set the value of each page in `pages`.
+    pub fn insert_pages<S: PageSize>(&self, pages: Range<Page<S>>, value: T) {
+        for page in pages {
+            let start = page.start();
+            for offset in (0..S::BYTES).step_by(Size4K::BYTES) {
+                self.insert(start + offset, value);
+            }
         }
-        let l1 = l2.get_or_allocate_next_page_table(start, || {
-            l3.table[L3::get_index(start)].delta_entries(1);
-        });
-        debug_assert!(l1.get_entry(start).is_none());
-        l1.table[L1::get_index(start)].set_next_page(num_pages);
-        l2.table[L2::get_index(start)].delta_entries(1);
     }
 
-    pub(crate) fn insert_pages<S: PageSize>(&mut self, start: Page<S>, num_pages: usize) {
-        for i in 0..num_pages {
-            let page = Step::forward(start, i);
-            self.insert_one_page(page, if i == 0 { Some(num_pages) } else { None })
+    /// Remove the value of the page containing `addr`. Returns the removed value.
+    pub fn remove(&self, addr: Address) -> Option<T> {
+        let path = self.hold_path(addr, 0, false)?;
+        let leaf = path[LEVELS - 1];
+        let old = leaf.entries[Table::index(addr, 0)].swap(0, Ordering::AcqRel);
+        if old & PRESENT != 0 {
+            leaf.used.fetch_sub(1, Ordering::Relaxed);
         }
+        self.release_path(&path, addr);
+        (old & PRESENT != 0).then(|| T::from_bits(old & !PRESENT))
     }
 
-    fn decrease_used_entries<S: PageSize, L: PageTableLevel>(
-        parent_table: &mut PageTable<L>,
-        page: Page<S>,
-    ) -> usize {
-        let index = L::get_index(page.start());
-        let entries = parent_table.table[index].delta_entries(-1);
-        if entries == 0 {
-            parent_table.table[index].clear();
+    /// Remove the values of each page in `pages`.
+    pub fn remove_pages<S: PageSize>(&self, pages: Range<Page<S>>) {
+        for page in pages {
+            let start = page.start();
+            for offset in (0..S::BYTES).step_by(Size4K::BYTES) {
+                self.remove(start + offset);
+            }
         }
-        entries
     }
 
-    fn delete_one_page<S: PageSize>(&mut self, start_page: Page<S>) {
-        let start = start_page.start();
-        let l4 = self;
-        let l3 = l4.get_next_page_table(start);
-        if S::BYTES == Size1G::BYTES {
-            debug_assert!(l3.get_entry(start).is_some());
-            l3.table[L3::get_index(start)].clear();
-            Self::decrease_used_entries(l4, start_page);
-            return;
+    fn for_each_in(
+        table: &Table,
+        level: usize,
+        base: usize,
+        range: &Range<usize>,
+        f: &mut impl FnMut(Address, T),
+    ) {
+        let shift = Table::shift(level);
+        for (i, entry) in table.entries.iter().enumerate() {
+            let start = base + (i << shift);
+            let end = start + (1 << shift);
+            if end <= range.start || start >= range.end {
+                continue;
+            }
+            let entry = entry.load(Ordering::Acquire);
+            if level == 0 {
+                if entry & PRESENT != 0 {
+                    f(start.into(), T::from_bits(entry & !PRESENT));
+                }
+            } else if let Some(child) = Table::child(entry) {
+                if child.key.load(Ordering::Acquire) == start | (level - 1) {
+                    Self::for_each_in(child, level - 1, start, range, f);
+                }
+            }
         }
-        let l2 = l3.get_next_page_table(start);
-        if S::BYTES == Size2M::BYTES {
-            debug_assert!(l2.get_entry(start).is_some());
-            l2.table[L2::get_index(start)].clear();
-            let dead = Self::decrease_used_entries(l3, start_page) == 0;
-            if dead {
-                Self::decrease_used_entries(l4, start_page);
+    }
+
+    /// Visit every page with a value in `range`, in address order.
+    ///
+    /// Not a snapshot: pages updated concurrently may or may not be visited.
+    pub fn for_each(&self, range: Range<Address>, mut f: impl FnMut(Address, T)) {
+        let range = usize::from(range.start)..usize::from(range.end);
+        Self::for_each_in(&self.root, LEVELS - 1, 0, &range, &mut f)
+    }
+
+    /// Visit every maximal run of consecutive pages with a value in `range`, in address order.
+    pub fn for_each_present_range(&self, range: Range<Address>, mut f: impl FnMut(Range<Address>)) {
+        let mut current: Option<Range<Address>> = None;
+        self.for_each(range, |page, _| match &mut current {
+            Some(run) if run.end == page => run.end = page + Size4K::BYTES,
+            _ => {
+                if let Some(run) = current.replace(page..page + Size4K::BYTES) {
+                    f(run);
+                }
             }
+        });
+        if let Some(run) = current {
+            f(run);
+        }
+    }
+
+    fn free_children(table: &Table, level: usize) {
+        if level == 0 {
             return;
         }
-        let l1 = l2.get_next_page_table(start);
-        debug_assert!(l1.get_entry(start).is_some());
-        l1.table[L1::get_index(start)].clear();
-        let dead = Self::decrease_used_entries(l2, start_page) == 0;
-        let dead = dead && Self::decrease_used_entries(l3, start_page) == 0;
-        if dead {
-            Self::decrease_used_entries(l4, start_page);
+        for entry in &table.entries {
+            if let Some(child) = Table::child(entry.load(Ordering::Relaxed)) {
+                Self::free_children(child, level - 1);
+                let _ = unsafe { Box::from_raw_in(child as *const Table as *mut Table, Meta) };
+            }
         }
     }
+}
 
-    pub(crate) fn delete_pages<S: PageSize>(&mut self, start: Page<S>, num_pages: usize) {
-        for i in 0..num_pages {
-            let page = Step::forward(start, i);
-            self.delete_one_page(page)
+impl<T: PageTableValue> Drop for PageTable<T> {
+    fn drop(&mut self) {
+        Self::free_children(&self.root, LEVELS - 1);
+        let mut next = self.pool.load(Ordering::Relaxed) & Self::POINTER_MASK;
+        while let Some(table) = Table::child(next) {
+            next = table.entries[0].load(Ordering::Relaxed);
+            let _ = unsafe { Box::from_raw_in(table as *const Table as *mut Table, Meta) };
         }
     }
+}
 
-    pub fn get_pointer_meta(&self, start: Address) -> Address {
-        self.get(start).unwrap().pointer_meta
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_get_remove() {
+        let pt = PageTable::<u32>::new();
+        let a = Address::from(0x7f12_3456_7000usize);
+        assert_eq!(pt.get(a), None);
+        assert_eq!(pt.insert(a, 7), None);
+        assert_eq!(pt.insert(a, 8), Some(7));
+        assert_eq!(pt.get(a + 0xfffusize), Some(8));
+        assert_eq!(pt.get(a + Size4K::BYTES), None);
+        assert_eq!(pt.tables(), 3);
+        assert_eq!(pt.remove(a), Some(8));
+        assert_eq!(pt.get(a), None);
+        assert_eq!(pt.remove(a), None);
+        // Empty tables are reclaimed.
+        assert_eq!(pt.tables(), 0);
     }
 
-    pub fn set_pointer_meta(&mut self, address: Address, pointer_meta: Address) {
-        debug_assert!(usize::from(pointer_meta) & !(((1 << 45) - 1) << 3) == 0);
-        let l4 = self;
-        let l3 = l4.get_next_page_table(address);
-        let l2 = l3.get_next_page_table(address);
-        let l1 = l2.get_next_page_table(address);
-        l1.set_pointer_meta(address, pointer_meta);
+    #[test]
+    fn present_ranges() {
+        let pt = PageTable::<()>::new();
+        let base = Address::from(1usize << 40);
+        let page = |i: usize| Page::<Size4K>::new(base + (i << Size4K::LOG_BYTES));
+        // Across leaf tables
+        pt.insert_pages(page(510)..page(515), ());
+        pt.insert_pages(page(600)..page(601), ());
+        pt.insert_pages(
+            Page::<Size2M>::new(base + (4usize << 30))
+                ..Page::new(base + (4usize << 30) + Size2M::BYTES),
+            (),
+        );
+        let mut ranges = std::vec::Vec::new();
+        pt.for_each_present_range(base..base + (8usize << 30), |r| ranges.push(r));
+        assert_eq!(
+            ranges,
+            [
+                page(510).start()..page(515).start(),
+                page(600).start()..page(601).start(),
+                base + (4usize << 30)..base + (4usize << 30) + Size2M::BYTES,
+            ]
+        );
+        let mut pages = 0;
+        pt.for_each(page(512).start()..page(601).start(), |_, _| pages += 1);
+        assert_eq!(pages, 4);
+        pt.remove_pages(page(510)..page(515));
+        pt.remove_pages(page(600)..page(601));
+        pt.remove_pages(
+            Page::<Size2M>::new(base + (4usize << 30))
+                ..Page::new(base + (4usize << 30) + Size2M::BYTES),
+        );
+        assert_eq!(pt.tables(), 0);
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 2000;
+        static PT: PageTable<usize> = PageTable::new();
+        let base = Address::from(1usize << 42);
+        let threads = (0..THREADS)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    for round in 0..ROUNDS {
+                        // Threads share leaf tables, which are repeatedly emptied and reclaimed.
+                        let pages = (0..8).map(|i| {
+                            base + ((i * THREADS + t) << Size4K::LOG_BYTES) + ((round % 3) << 30)
+                        });
+                        for (i, page) in pages.clone().enumerate() {
+                            assert_eq!(PT.insert(page, t * 100 + i), None);
+                        }
+                        for (i, page) in pages.clone().enumerate() {
+                            assert_eq!(PT.get(page), Some(t * 100 + i));
+                        }
+                        for (i, page) in pages.enumerate() {
+                            assert_eq!(PT.remove(page), Some(t * 100 + i));
+                        }
+                    }
+                })
+            })
+            .collect::<std::vec::Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(PT.tables(), 0);
     }
 }
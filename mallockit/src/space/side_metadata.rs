@@ -0,0 +1,348 @@
+//! Side metadata: per-granule bits, bytes or words kept outside the objects they describe, like MMTk's.
+//!
+//! A space declares a `SideMetadataSpec` for each kind of metadata it needs, e.g. a mark bit per 16 bytes
+//! or a size tag per 4K page, and creates a `SideMetadata` for it. The metadata of an address is found by
+//! arithmetic alone: its offset in the space, divided by the granule size, indexes a table reserved up front
+//! with `MAP_NORESERVE`. Only the parts of the table covering used memory are ever committed.
+
+use super::SpaceId;
+use crate::util::mem::heap::HEAP;
+use crate::util::sys::raw_memory::RawMemory;
+use crate::util::*;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+/// `1 << log_bits` bits of metadata for every `1 << log_granule` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SideMetadataSpec {
+    pub name: &'static str,
+    pub log_granule: usize,
+    /// 0 to 6: bits, bit pairs, nibbles, bytes, up to words.
+    pub log_bits: usize,
+}
+
+impl SideMetadataSpec {
+    pub const fn new(name: &'static str, log_granule: usize, log_bits: usize) -> Self {
+        assert!(log_bits <= 6, "side metadata entries are at most 64 bits");
+        Self {
+            name,
+            log_granule,
+            log_bits,
+        }
+    }
+
+    /// Bytes of side metadata needed to cover `bytes` of memory.
+    pub const fn metadata_bytes(&self, bytes: usize) -> usize {
+        let bits = (bytes >> self.log_granule) << self.log_bits;
+        bits.div_ceil(8)
+    }
+}
+
+/// The side metadata of one space, laid out as specified by a `SideMetadataSpec`.
+///
+/// Values are `usize`s masked to the entry width. Accesses to sub-byte entries are atomic read-modify-writes
+/// of the containing byte, so neighbouring entries can be updated concurrently.
+pub struct SideMetadata {
+    spec: SideMetadataSpec,
+    base: Address,
+    table: Address,
+    table_bytes: usize,
+}
+
+unsafe impl Send for SideMetadata {}
+unsafe impl Sync for SideMetadata {}
+
+impl SideMetadata {
+    /// Reserve the side metadata of space `id`. All entries start as zero.
+    pub fn new(id: SpaceId, spec: SideMetadataSpec) -> Self {
+        let table_bytes = spec
+            .metadata_bytes(1 << SpaceId::LOG_MAX_SPACE_SIZE)
+            .next_multiple_of(Size4K::BYTES);
+        Self {
+            spec,
+            base: HEAP.get_space_range(id).start,
+            table: RawMemory::map_anonymous(table_bytes).unwrap(),
+            table_bytes,
+        }
+    }
+
+    pub const fn spec(&self) -> &SideMetadataSpec {
+        &self.spec
+    }
+
+    const fn entry_mask(&self) -> usize {
+        usize::MAX >> (usize::BITS as usize - (1 << self.spec.log_bits))
+    }
+
+    /// The bit index of the entry of `addr` in the table.
+    fn bit_index(&self, addr: Address) -> usize {
+        debug_assert!(addr >= self.base && addr - self.base < 1 << SpaceId::LOG_MAX_SPACE_SIZE);
+        ((addr - self.base) >> self.spec.log_granule) << self.spec.log_bits
+    }
+
+    /// The address of the metadata byte (or first byte) of `addr`, and the bit offset of its entry in that byte.
+    pub fn metadata_address(&self, addr: Address) -> (Address, usize) {
+        let bit = self.bit_index(addr);
+        (self.table + (bit >> 3), bit & 7)
+    }
+
+    pub fn load(&self, addr: Address, order: Ordering) -> usize {
+        let (meta, shift) = self.metadata_address(addr);
+        unsafe {
+            match self.spec.log_bits {
+                0..=2 => {
+                    ((meta.as_ref::<AtomicU8>().load(order) >> shift) as usize) & self.entry_mask()
+                }
+                3 => meta.as_ref::<AtomicU8>().load(order) as _,
+                4 => meta.as_ref::<AtomicU16>().load(order) as _,
+                5 => meta.as_ref::<AtomicU32>().load(order) as _,
+                _ => meta.as_ref::<AtomicU64>().load(order) as _,
+            }
+        }
+    }
+
+    pub fn store(&self, addr: Address, value: usize, order: Ordering) {
+        debug_assert_eq!(value & !self.entry_mask(), 0);
+        let (meta, shift) = self.metadata_address(addr);
+        unsafe {
+            match self.spec.log_bits {
+                0..=2 => {
+                    let mask = (self.entry_mask() << shift) as u8;
+                    let byte = meta.as_ref::<AtomicU8>();
+                    let _ = byte.fetch_update(order, Ordering::Relaxed, |b| {
+                        Some((b & !mask) | ((value << shift) as u8))
+                    });
+                }
+                3 => meta.as_ref::<AtomicU8>().store(value as _, order),
+                4 => meta.as_ref::<AtomicU16>().store(value as _, order),
+                5 => meta.as_ref::<AtomicU32>().store(value as _, order),
+                _ => meta.as_ref::<AtomicU64>().store(value as _, order),
+            }
+        }
+    }
+
+    /// Set the entry of `addr` to `new` if it is `current`. Returns the previous value.
+    pub fn compare_exchange(
+        &self,
+        addr: Address,
+        current: usize,
+        new: usize,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<usize, usize> {
+        debug_assert_eq!(new & !self.entry_mask(), 0);
+        let (meta, shift) = self.metadata_address(addr);
+        unsafe {
+            match self.spec.log_bits {
+                0..=2 => {
+                    let mask = (self.entry_mask() << shift) as u8;
+                    let byte = meta.as_ref::<AtomicU8>();
+                    byte.fetch_update(success, failure, |b| {
+                        ((b & mask) >> shift == current as u8)
+                            .then_some((b & !mask) | ((new << shift) as u8))
+                    })
+                    .map(|b| ((b & mask) >> shift) as usize)
+                    .map_err(|b| ((b & mask) >> shift) as usize)
+                }
+                3 => meta
+                    .as_ref::<AtomicU8>()
+                    .compare_exchange(current as _, new as _, success, failure)
+                    .map(|v| v as _)
+                    .map_err(|v| v as _),
+                4 => meta
+                    .as_ref::<AtomicU16>()
+                    .compare_exchange(current as _, new as _, success, failure)
+                    .map(|v| v as _)
+                    .map_err(|v| v as _),
+                5 => meta
+                    .as_ref::<AtomicU32>()
+                    .compare_exchange(current as _, new as _, success, failure)
+                    .map(|v| v as _)
+                    .map_err(|v| v as _),
+                _ => meta
+                    .as_ref::<AtomicU64>()
+                    .compare_exchange(current as _, new as _, success, failure)
+                    .map(|v| v as _)
+                    .map_err(|v| v as _),
+            }
+        }
+    }
+
+    /// Bitwise-or `value` into the entry of `addr`. Returns the previous value.
+    pub fn fetch_or(&self, addr: Address, value: usize, order: Ordering) -> usize {
+        debug_assert_eq!(value & !self.entry_mask(), 0);
+        let (meta, shift) = self.metadata_address(addr);
+        unsafe {
+            match self.spec.log_bits {
+                0..=2 => {
+                    let old = meta
+                        .as_ref::<AtomicU8>()
+                        .fetch_or((value << shift) as u8, order);
+                    ((old >> shift) as usize) & self.entry_mask()
+                }
+                3 => meta.as_ref::<AtomicU8>().fetch_or(value as _, order) as _,
+                4 => meta.as_ref::<AtomicU16>().fetch_or(value as _, order) as _,
+                5 => meta.as_ref::<AtomicU32>().fetch_or(value as _, order) as _,
+                _ => meta.as_ref::<AtomicU64>().fetch_or(value as _, order) as _,
+            }
+        }
+    }
+
+    /// Bitwise-and `value` into the entry of `addr`. Returns the previous value.
+    pub fn fetch_and(&self, addr: Address, value: usize, order: Ordering) -> usize {
+        debug_assert_eq!(value & !self.entry_mask(), 0);
+        let (meta, shift) = self.metadata_address(addr);
+        unsafe {
+            match self.spec.log_bits {
+                0..=2 => {
+                    let keep = !((self.entry_mask() << shift) as u8);
+                    let old = meta
+                        .as_ref::<AtomicU8>()
+                        .fetch_and(((value << shift) as u8) | keep, order);
+                    ((old >> shift) as usize) & self.entry_mask()
+                }
+                3 => meta.as_ref::<AtomicU8>().fetch_and(value as _, order) as _,
+                4 => meta.as_ref::<AtomicU16>().fetch_and(value as _, order) as _,
+                5 => meta.as_ref::<AtomicU32>().fetch_and(value as _, order) as _,
+                _ => meta.as_ref::<AtomicU64>().fetch_and(value as _, order) as _,
+            }
+        }
+    }
+
+    /// Zero the entries of every granule in `range`. Not atomic with respect to concurrent updates
+    /// of the same entries, but safe for neighbouring entries outside the range.
+    pub fn bzero(&self, range: Range<Address>) {
+        debug_assert!(range.start.is_aligned_to(1 << self.spec.log_granule));
+        debug_assert!(range.end.is_aligned_to(1 << self.spec.log_granule));
+        if range.start >= range.end {
+            return;
+        }
+        let mut start = self.bit_index(range.start);
+        let end = self.bit_index(range.end);
+        // Partial bytes at either end
+        let clear_bits = |from: usize, to: usize| {
+            let mask = ((1u16 << (to - from)) - 1) as u8;
+            let byte = unsafe { (self.table + (from >> 3)).as_ref::<AtomicU8>() };
+            byte.fetch_and(!(mask << (from & 7)), Ordering::Relaxed);
+        };
+        if start & 7 != 0 {
+            let to = usize::min(end, (start + 8) & !7);
+            clear_bits(start, to);
+            start = to;
+        }
+        let end_byte = end >> 3;
+        let start_byte = start.div_ceil(8);
+        if start_byte < end_byte {
+            unsafe {
+                std::ptr::write_bytes(
+                    (self.table + start_byte).as_mut_ptr::<u8>(),
+                    0,
+                    end_byte - start_byte,
+                )
+            };
+        }
+        if end & 7 != 0 && start < end {
+            clear_bits(usize::max(start, end & !7), end);
+        }
+    }
+}
+
+impl Drop for SideMetadata {
+    fn drop(&mut self) {
+        RawMemory::unmap(self.table, self.table_bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARK_BITS: SideMetadataSpec = SideMetadataSpec::new("mark", 4, 0);
+    const TAGS: SideMetadataSpec = SideMetadataSpec::new("tag", 4, 2);
+    const SIZES: SideMetadataSpec = SideMetadataSpec::new("size", 12, 6);
+
+    fn space_start() -> Address {
+        HEAP.get_space_range(SpaceId::DEFAULT).start
+    }
+
+    #[test]
+    fn sub_byte_entries() {
+        let meta = SideMetadata::new(SpaceId::DEFAULT, TAGS);
+        let a = space_start() + (1usize << 20);
+        meta.store(a, 0xa, Ordering::Relaxed);
+        meta.store(a + 16usize, 0x5, Ordering::Relaxed);
+        assert_eq!(meta.load(a, Ordering::Relaxed), 0xa);
+        assert_eq!(meta.load(a + 16usize, Ordering::Relaxed), 0x5);
+        assert_eq!(
+            meta.compare_exchange(a, 0x5, 0x1, Ordering::Relaxed, Ordering::Relaxed),
+            Err(0xa)
+        );
+        assert_eq!(
+            meta.compare_exchange(a, 0xa, 0x1, Ordering::Relaxed, Ordering::Relaxed),
+            Ok(0xa)
+        );
+        assert_eq!(meta.fetch_or(a, 0x6, Ordering::Relaxed), 0x1);
+        assert_eq!(meta.fetch_and(a, 0x3, Ordering::Relaxed), 0x7);
+        assert_eq!(meta.load(a, Ordering::Relaxed), 0x3);
+        assert_eq!(meta.load(a + 16usize, Ordering::Relaxed), 0x5);
+    }
+
+    #[test]
+    fn word_entries() {
+        let meta = SideMetadata::new(SpaceId::DEFAULT, SIZES);
+        let page = space_start() + (3usize << 30);
+        meta.store(page, usize::MAX, Ordering::Relaxed);
+        assert_eq!(meta.load(page, Ordering::Relaxed), usize::MAX);
+        assert_eq!(meta.load(page + 4095usize, Ordering::Relaxed), usize::MAX);
+        assert_eq!(meta.load(page + 4096usize, Ordering::Relaxed), 0);
+        meta.bzero(page..page + 4096usize);
+        assert_eq!(meta.load(page, Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn bzero_keeps_neighbours() {
+        let meta = SideMetadata::new(SpaceId::DEFAULT, MARK_BITS);
+        let start = space_start();
+        let granules = 256;
+        for i in 0..granules {
+            meta.store(start + (i << 4), 1, Ordering::Relaxed);
+        }
+        meta.bzero(start + (3usize << 4)..start + (200usize << 4));
+        for i in 0..granules {
+            let expected = (!(3..200).contains(&i)) as usize;
+            assert_eq!(
+                meta.load(start + (i << 4), Ordering::Relaxed),
+                expected,
+                "{i}"
+            );
+        }
+        meta.bzero(start + (250usize << 4)..start + (252usize << 4));
+        assert_eq!(meta.load(start + (249usize << 4), Ordering::Relaxed), 1);
+        assert_eq!(meta.load(start + (250usize << 4), Ordering::Relaxed), 0);
+        assert_eq!(meta.load(start + (252usize << 4), Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn concurrent_bit_updates() {
+        const THREADS: usize = 8;
+        static META: spin::Lazy<SideMetadata> =
+            spin::Lazy::new(|| SideMetadata::new(SpaceId::DEFAULT, MARK_BITS));
+        let start = space_start() + (1usize << 30);
+        let threads = (0..THREADS)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    // Every thread owns one bit of each byte.
+                    for i in (t..4096).step_by(THREADS) {
+                        assert_eq!(META.fetch_or(start + (i << 4), 1, Ordering::Relaxed), 0);
+                    }
+                })
+            })
+            .collect::<std::vec::Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        for i in 0..4096usize {
+            assert_eq!(META.load(start + (i << 4), Ordering::Relaxed), 1);
+        }
+    }
+}
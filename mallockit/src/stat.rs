@@ -1,13 +1,14 @@
 use std::{
     alloc::Layout,
+    ptr,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicPtr, Ordering},
         Arc,
     },
 };
 
 use atomic::Atomic;
-use spin::{Mutex, Once};
+use spin::{Mutex, MutexGuard, Once};
 
 use crate::space::meta::{Meta, Vec};
 
@@ -265,3 +266,24 @@ pub(crate) fn report() {
         group.report();
     }
 }
+
+/// The group list locked by `fork_prepare`.
+static FORK_LOCKED_GROUPS: AtomicPtr<Vec<&'static CounterGroup>> = AtomicPtr::new(ptr::null_mut());
+
+pub(crate) fn fork_prepare() {
+    let groups = MutexGuard::leak(ALL_GROUPS.lock());
+    for group in groups.iter() {
+        std::mem::forget(group.counters.lock());
+    }
+    FORK_LOCKED_GROUPS.store(groups, Ordering::SeqCst);
+}
+
+pub(crate) fn fork_release() {
+    let groups = FORK_LOCKED_GROUPS.swap(ptr::null_mut(), Ordering::SeqCst);
+    unsafe {
+        for group in (*groups).iter() {
+            group.counters.force_unlock();
+        }
+        ALL_GROUPS.force_unlock();
+    }
+}
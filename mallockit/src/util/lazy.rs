@@ -51,6 +51,14 @@ impl<T, F: FnOnce() -> T> Lazy<T, Local, F> {
         self.init.set(Some(new));
         self.value = UnsafeCell::new(MaybeUninit::uninit());
     }
+
+    /// The value, if it has been initialized. Never runs the initializer.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if self.state.load(Ordering::SeqCst) != INITIALIZED {
+            return None;
+        }
+        Some(unsafe { &mut *(*self.value.get()).as_mut_ptr() })
+    }
 }
 
 impl<T, TL: ThreadLocality, F: FnOnce() -> T> Lazy<T, TL, F> {
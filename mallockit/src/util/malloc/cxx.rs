@@ -0,0 +1,194 @@
+//! C++ `operator new` and `operator delete`.
+//!
+//! `operator new` calls the installed `std::new_handler` and retries until the allocation succeeds,
+//! or throws `std::bad_alloc` if no handler is installed. Both the handler and the throw unwind with C++
+//! exceptions, which abort once they reach a Rust frame. So the exported functions are naked stubs that jump to
+//! `__mallockit_cxx_new`, an assembly loop with unwind info that only calls Rust to allocate.
+//!
+//! The `nothrow` variants return null instead of throwing. A handler that throws still unwinds to the caller.
+
+mod libstdcxx {
+    extern "C" {
+        /// `std::get_new_handler()`
+        #[linkage = "extern_weak"]
+        pub static _ZSt15get_new_handlerv: *const u8;
+        /// `std::__throw_bad_alloc()`
+        #[linkage = "extern_weak"]
+        pub static _ZSt17__throw_bad_allocv: *const u8;
+    }
+}
+
+/// The installed `std::new_handler`, or null if there is none or libstdc++ is not loaded.
+extern "C" fn new_handler() -> *const u8 {
+    unsafe {
+        if libstdcxx::_ZSt15get_new_handlerv.is_null() {
+            return std::ptr::null();
+        }
+        let get_new_handler: extern "C" fn() -> *const u8 =
+            std::mem::transmute(libstdcxx::_ZSt15get_new_handlerv);
+        get_new_handler()
+    }
+}
+
+/// The function that throws `std::bad_alloc`. Aborts if libstdc++ is not loaded.
+extern "C" fn bad_alloc_thrower() -> *const u8 {
+    unsafe {
+        if libstdcxx::_ZSt17__throw_bad_allocv.is_null() {
+            std::process::abort();
+        }
+        libstdcxx::_ZSt17__throw_bad_allocv
+    }
+}
+
+extern "C" {
+    /// `__mallockit_cxx_new(size, align, nothrow, alloc) -> *mut u8`, where `alloc(size, align)` returns null on failure.
+    ///
+    /// Must be tail-called, so that exceptions unwind straight to the C++ caller.
+    pub fn __mallockit_cxx_new();
+}
+
+std::arch::global_asm!(
+    ".pushsection .text.__mallockit_cxx_new, \"ax\", @progbits",
+    ".globl __mallockit_cxx_new",
+    ".hidden __mallockit_cxx_new",
+    ".type __mallockit_cxx_new, @function",
+    ".p2align 4",
+    "__mallockit_cxx_new:",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "sub rsp, 8",
+    "__mallockit_cxx_new_body:",
+    "mov rbx, rdi",
+    "mov r12, rsi",
+    "mov r13, rdx",
+    "mov r14, rcx",
+    // Retry until the allocation succeeds
+    "2:",
+    "mov rdi, rbx",
+    "mov rsi, r12",
+    "call r14",
+    "test rax, rax",
+    "jnz 3f",
+    "call {new_handler}",
+    "test rax, rax",
+    "jz 4f",
+    "call rax",
+    "jmp 2b",
+    // No handler: return null or throw
+    "4:",
+    "test r13, r13",
+    "jnz 3f",
+    "call {bad_alloc_thrower}",
+    "call rax",
+    "ud2",
+    "3:",
+    "add rsp, 8",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "ret",
+    "__mallockit_cxx_new_end:",
+    ".size __mallockit_cxx_new, . - __mallockit_cxx_new",
+    ".popsection",
+    // Unwind info. `.cfi_*` directives would end up in `.debug_frame` with `panic = "abort"`, which the unwinder
+    // ignores, so the CIE and FDE are written out by hand. Only the body, where all the calls are, is described.
+    ".pushsection .eh_frame, \"a\", @progbits",
+    "5:",
+    ".long 7f - 6f",
+    "6:",
+    ".long 0", // CIE id
+    ".byte 1", // Version
+    ".asciz \"zR\"", // Augmentation
+    ".uleb128 1", // Code alignment
+    ".sleb128 -8", // Data alignment
+    ".uleb128 16", // Return address register: rip
+    ".uleb128 1", // Augmentation data length
+    ".byte 0x1b", // FDE pointer encoding: pcrel sdata4
+    ".byte 0x0c, 7, 8", // DW_CFA_def_cfa: rsp + 8
+    ".byte 0x90, 1", // DW_CFA_offset: rip at cfa - 8
+    ".balign 4",
+    "7:",
+    ".long 9f - 8f",
+    "8:",
+    ".long 8b - 5b", // CIE pointer
+    ".long __mallockit_cxx_new - .",
+    ".long __mallockit_cxx_new_end - __mallockit_cxx_new",
+    ".uleb128 0", // Augmentation data length
+    ".byte 0x04", // DW_CFA_advance_loc4
+    ".long __mallockit_cxx_new_body - __mallockit_cxx_new",
+    ".byte 0x0e, 48", // DW_CFA_def_cfa_offset: 48
+    ".byte 0x83, 2", // DW_CFA_offset: rbx at cfa - 16
+    ".byte 0x8c, 3", // DW_CFA_offset: r12 at cfa - 24
+    ".byte 0x8d, 4", // DW_CFA_offset: r13 at cfa - 32
+    ".byte 0x8e, 5", // DW_CFA_offset: r14 at cfa - 40
+    ".balign 4",
+    "9:",
+    ".popsection",
+    new_handler = sym new_handler,
+    bad_alloc_thrower = sym bad_alloc_thrower,
+);
+
+/// Export all `operator new` and `operator delete` variants. Expanded by `export_malloc_api!`.
+///
+/// Requires `#![feature(naked_functions)]` in the plan crate.
+#[macro_export]
+#[doc(hidden)]
+macro_rules! export_cxx_api {
+    () => {
+        #[cfg(not(test))]
+        unsafe extern "C" fn __cxx_alloc(size: usize, align: usize) -> *mut u8 {
+            match MALLOC_IMPL.alloc(size, align) {
+                Ok(Some(ptr)) => ptr,
+                _ => ::std::ptr::null_mut(),
+            }
+        }
+
+        $crate::export_cxx_api!(@new _Znwm, "mov esi, 16", "xor edx, edx");
+        $crate::export_cxx_api!(@new _Znam, "mov esi, 16", "xor edx, edx");
+        $crate::export_cxx_api!(@new _ZnwmRKSt9nothrow_t, "mov esi, 16", "mov edx, 1");
+        $crate::export_cxx_api!(@new _ZnamRKSt9nothrow_t, "mov esi, 16", "mov edx, 1");
+        $crate::export_cxx_api!(@new _ZnwmSt11align_val_t, "", "xor edx, edx");
+        $crate::export_cxx_api!(@new _ZnamSt11align_val_t, "", "xor edx, edx");
+        $crate::export_cxx_api!(@new _ZnwmSt11align_val_tRKSt9nothrow_t, "", "mov edx, 1");
+        $crate::export_cxx_api!(@new _ZnamSt11align_val_tRKSt9nothrow_t, "", "mov edx, 1");
+
+        $crate::export_cxx_api!(@delete _ZdlPv(ptr) => MALLOC_IMPL.free(ptr));
+        $crate::export_cxx_api!(@delete _ZdaPv(ptr) => MALLOC_IMPL.free(ptr));
+        $crate::export_cxx_api!(@delete _ZdlPvRKSt9nothrow_t(ptr, _nothrow: *const u8) => MALLOC_IMPL.free(ptr));
+        $crate::export_cxx_api!(@delete _ZdaPvRKSt9nothrow_t(ptr, _nothrow: *const u8) => MALLOC_IMPL.free(ptr));
+        $crate::export_cxx_api!(@delete _ZdlPvSt11align_val_t(ptr, _align: usize) => MALLOC_IMPL.free(ptr));
+        $crate::export_cxx_api!(@delete _ZdaPvSt11align_val_t(ptr, _align: usize) => MALLOC_IMPL.free(ptr));
+        $crate::export_cxx_api!(@delete _ZdlPvSt11align_val_tRKSt9nothrow_t(ptr, _align: usize, _nothrow: *const u8) => MALLOC_IMPL.free(ptr));
+        $crate::export_cxx_api!(@delete _ZdaPvSt11align_val_tRKSt9nothrow_t(ptr, _align: usize, _nothrow: *const u8) => MALLOC_IMPL.free(ptr));
+        $crate::export_cxx_api!(@delete _ZdlPvm(ptr, size: usize) => MALLOC_IMPL.free_sized(ptr, size, Malloc::MIN_ALIGNMENT));
+        $crate::export_cxx_api!(@delete _ZdaPvm(ptr, size: usize) => MALLOC_IMPL.free_sized(ptr, size, Malloc::MIN_ALIGNMENT));
+        $crate::export_cxx_api!(@delete _ZdlPvmSt11align_val_t(ptr, size: usize, align: usize) => MALLOC_IMPL.free_sized(ptr, size, align));
+        $crate::export_cxx_api!(@delete _ZdaPvmSt11align_val_t(ptr, size: usize, align: usize) => MALLOC_IMPL.free_sized(ptr, size, align));
+    };
+    (@new $name: ident, $set_align: literal, $set_nothrow: literal) => {
+        #[cfg(not(test))]
+        #[naked]
+        #[allow(non_snake_case)]
+        #[no_mangle]
+        pub unsafe extern "C" fn $name() {
+            ::std::arch::naked_asm!(
+                $set_align,
+                $set_nothrow,
+                "lea rcx, [rip + {alloc}]",
+                "jmp {new}",
+                alloc = sym __cxx_alloc,
+                new = sym $crate::util::malloc::cxx::__mallockit_cxx_new,
+            )
+        }
+    };
+    (@delete $name: ident($ptr: ident $(, $arg: ident: $arg_ty: ty)*) => $free: expr) => {
+        #[$crate::interpose]
+        #[allow(non_snake_case)]
+        pub unsafe extern "C" fn $name($ptr: *mut u8 $(, $arg: $arg_ty)*) {
+            $free
+        }
+    };
+}
@@ -1,3 +1,4 @@
+use crate::space::page_resource::HEAP_LIMIT;
 use crate::util::constants::MIN_ALIGNMENT;
 use crate::util::mem::heap::HEAP;
 use crate::util::Address;
@@ -61,17 +62,29 @@ impl<P: Plan> MallocAPI<P> {
         P::get_layout(ptr).size()
     }
 
+    /// The usable size of `malloc(size)`, as reported by `malloc_usable_size`
+    pub fn malloc_good_size(&self, size: usize) -> usize {
+        let size = std::cmp::max(size, Self::MIN_ALIGNMENT);
+        match Layout::from_size_align(size, Self::MIN_ALIGNMENT) {
+            Ok(layout) => P::good_size(layout),
+            // Too large to ever be allocated
+            Err(_) => size,
+        }
+    }
+
     /// Allocate memory
     ///
+    /// On failure, the OOM callback registered on `HEAP_LIMIT` may release memory before the allocation is retried.
+    ///
     /// # Safety
     ///
     /// The caller must ensure that `size` and `align` are valid
     #[inline(always)]
     pub unsafe fn alloc(&self, mut size: usize, align: usize) -> Result<Option<*mut u8>, i32> {
+        // The size is not rounded up to the alignment. Spaces place over-aligned objects themselves.
         size = std::cmp::max(size, Self::MIN_ALIGNMENT);
-        let size = Self::align_up(size, align);
         let layout = Layout::from_size_align_unchecked(size, align);
-        match self.mutator().alloc(layout) {
+        match HEAP_LIMIT.alloc_or_retry(size, || self.mutator().alloc(layout)) {
             Some(ptr) => Ok(Some(ptr.into())),
             None => Err(libc::ENOMEM),
         }
@@ -110,6 +123,67 @@ impl<P: Plan> MallocAPI<P> {
         self.mutator().dealloc(ptr.into());
     }
 
+    /// Free memory allocated with `size` bytes and `align` alignment, as in C23 `free_sized` and `free_aligned_sized`.
+    ///
+    /// Debug builds check that `size` fits in the usable size of `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `ptr` is a valid heap pointer, allocated or last reallocated with `size` and `align`
+    #[inline(always)]
+    pub unsafe fn free_sized(&self, ptr: *mut u8, size: usize, align: usize) {
+        if ptr.is_null() {
+            return;
+        }
+        #[cfg(target_os = "macos")]
+        if !Self::is_in_mallockit_heap(ptr.into()) {
+            return;
+        }
+        debug_assert!(
+            size <= P::get_layout(ptr.into()).size(),
+            "free_sized: size {} exceeds the usable size of {:?}",
+            size,
+            ptr
+        );
+        let size = std::cmp::max(size, Self::MIN_ALIGNMENT);
+        let align = std::cmp::max(align, Self::MIN_ALIGNMENT);
+        let layout = Layout::from_size_align_unchecked(size, align);
+        self.mutator().dealloc_sized(ptr.into(), layout);
+    }
+
+    /// Allocate zeroed memory for an array, or set errno to ENOMEM if `count * size` overflows
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `count` and `size` are valid
+    #[inline(always)]
+    pub unsafe fn calloc(&self, count: usize, size: usize) -> *mut u8 {
+        let Some(size) = count.checked_mul(size) else {
+            Self::set_error(libc::ENOMEM);
+            return ptr::null_mut();
+        };
+        let ptr = self.alloc_or_enomem(size, Self::MIN_ALIGNMENT);
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, size);
+        }
+        ptr
+    }
+
+    /// Reallocate memory for an array, or set errno to ENOMEM if `count * size` overflows.
+    /// `ptr` is left untouched on failure.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that `ptr` is a valid heap pointer
+    #[inline(always)]
+    pub unsafe fn reallocarray(&self, ptr: *mut u8, count: usize, size: usize) -> *mut u8 {
+        let Some(size) = count.checked_mul(size) else {
+            Self::set_error(libc::ENOMEM);
+            return ptr::null_mut();
+        };
+        self.reallocate_or_enomem(ptr, size, true, false)
+    }
+
     /// Reallocate memory
     ///
     /// # Safety
@@ -156,7 +230,7 @@ impl<P: Plan> MallocAPI<P> {
         }
 
         let layout = Layout::from_size_align_unchecked(new_size, Self::MIN_ALIGNMENT);
-        match self.mutator().realloc(ptr.into(), layout) {
+        match HEAP_LIMIT.alloc_or_retry(new_size, || self.mutator().realloc(ptr.into(), layout)) {
             Some(ptr) => ptr.into(),
             None => {
                 if free_if_fail {
@@ -254,11 +328,10 @@ macro_rules! export_malloc_api {
                 MALLOC_IMPL.malloc_size(ptr.into())
             }
 
-            // #[cfg(target_os = "macos")]
-            // #[$crate::interpose]
-            // pub unsafe fn malloc_good_size(ptr: *mut u8) -> usize {
-            //     MALLOC_IMPL.malloc_size(ptr.into())
-            // }
+            #[$crate::interpose]
+            pub unsafe extern "C" fn malloc_good_size(size: usize) -> usize {
+                MALLOC_IMPL.malloc_good_size(size)
+            }
 
             #[cfg(target_os = "linux")]
             #[$crate::interpose]
@@ -277,12 +350,23 @@ macro_rules! export_malloc_api {
                 MALLOC_IMPL.free(ptr)
             }
 
+            #[$crate::interpose]
+            pub unsafe extern "C" fn free_sized(ptr: *mut u8, size: usize) {
+                MALLOC_IMPL.free_sized(ptr, size, Malloc::MIN_ALIGNMENT)
+            }
+
+            #[$crate::interpose]
+            pub unsafe extern "C" fn free_aligned_sized(
+                ptr: *mut u8,
+                alignment: usize,
+                size: usize,
+            ) {
+                MALLOC_IMPL.free_sized(ptr, size, alignment)
+            }
+
             #[$crate::interpose]
             pub unsafe extern "C" fn calloc(count: usize, size: usize) -> *mut u8 {
-                let size = count * size;
-                let ptr = MALLOC_IMPL.alloc_or_enomem(size, Malloc::MIN_ALIGNMENT);
-                std::ptr::write_bytes(ptr, 0, size);
-                ptr
+                MALLOC_IMPL.calloc(count, size)
             }
 
             #[cfg(any(target_os = "linux", target_os = "macos"))]
@@ -307,6 +391,16 @@ macro_rules! export_malloc_api {
                 )
             }
 
+            #[cfg(target_os = "linux")]
+            #[$crate::interpose]
+            pub unsafe extern "C" fn reallocarray(
+                ptr: *mut u8,
+                count: usize,
+                size: usize,
+            ) -> *mut u8 {
+                MALLOC_IMPL.reallocarray(ptr, count, size)
+            }
+
             #[cfg(target_os = "macos")]
             #[$crate::interpose]
             pub unsafe extern "C" fn reallocf(ptr: *mut u8, size: usize) -> *mut u8 {
@@ -340,6 +434,39 @@ macro_rules! export_malloc_api {
             pub unsafe extern "C" fn _aligned_malloc(size: usize, alignment: usize) -> *mut u8 {
                 MALLOC_IMPL.aligned_alloc(size, alignment, false, true)
             }
+
+            /// Limit the bytes held by the heap. Crossing the limit is reported to the OOM callback.
+            /// Zero removes the limit.
+            #[no_mangle]
+            pub extern "C" fn mallockit_set_soft_heap_limit(bytes: usize) {
+                $crate::space::page_resource::HEAP_LIMIT
+                    .set_soft_limit((bytes != 0).then_some(bytes));
+            }
+
+            /// Limit the bytes held by the heap. Allocations that would exceed it fail, after the OOM callback
+            /// had a chance to release memory. Zero removes the limit.
+            #[no_mangle]
+            pub extern "C" fn mallockit_set_hard_heap_limit(bytes: usize) {
+                $crate::space::page_resource::HEAP_LIMIT
+                    .set_hard_limit((bytes != 0).then_some(bytes));
+            }
+
+            /// Register the callback for the soft limit and for failed allocations, or remove it with null.
+            #[no_mangle]
+            pub extern "C" fn mallockit_set_oom_callback(
+                callback: Option<$crate::space::page_resource::OomCallback>,
+            ) {
+                $crate::space::page_resource::HEAP_LIMIT.set_oom_callback(callback);
+            }
+
+            /// Bytes held by the heap, as counted against the limits.
+            #[no_mangle]
+            pub extern "C" fn mallockit_committed_heap_bytes() -> usize {
+                $crate::space::page_resource::HEAP_LIMIT.committed_bytes()
+            }
+
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            $crate::export_cxx_api!();
         }
     };
 }
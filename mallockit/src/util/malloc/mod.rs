@@ -1,5 +1,8 @@
 #[cfg(target_os = "macos")]
 pub mod macos_malloc_zone;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[macro_use]
+pub mod cxx;
 #[macro_use]
 mod malloc_api;
 #[macro_use]
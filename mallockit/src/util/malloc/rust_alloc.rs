@@ -56,7 +56,8 @@ macro_rules! export_rust_global_alloc_api {
                     ptr: ::std::ptr::NonNull<u8>,
                     layout: ::std::alloc::Layout,
                 ) {
-                    <$plan_ty as $crate::Plan>::Mutator::current().dealloc(ptr.as_ptr().into())
+                    <$plan_ty as $crate::Plan>::Mutator::current()
+                        .dealloc_sized(ptr.as_ptr().into(), Self::__fix_layout(layout))
                 }
 
                 unsafe fn grow(
@@ -152,8 +153,9 @@ macro_rules! export_rust_global_alloc_api {
                         .into()
                 }
 
-                unsafe fn dealloc(&self, ptr: *mut u8, _layout: ::std::alloc::Layout) {
-                    <$plan_ty as $crate::Plan>::Mutator::current().dealloc(ptr.into())
+                unsafe fn dealloc(&self, ptr: *mut u8, layout: ::std::alloc::Layout) {
+                    <$plan_ty as $crate::Plan>::Mutator::current()
+                        .dealloc_sized(ptr.into(), Self::__fix_layout(layout))
                 }
 
                 unsafe fn realloc(
@@ -1,9 +1,27 @@
+use std::ops::Range;
+
 use crate::util::{Address, SizeClass};
 
+/// Occupancy statistics of one TLAB bin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BinStats {
+    /// Cells currently in the bin.
+    pub len: usize,
+    /// The largest `len` seen so far.
+    pub high_water: usize,
+    /// Number of `refill` calls.
+    pub refills: usize,
+    /// Number of times the bin overflowed its length limit and was partially flushed.
+    pub overflows: usize,
+}
+
 pub struct DiscreteTLAB<const MAX_SIZE_CLASS: usize = { Address::LOG_BYTES }> {
     _padding: [usize; 16],
     bins: [Address; MAX_SIZE_CLASS],
     bytes: usize,
+    /// Bins holding more cells than this are flushed by `push_bounded`.
+    max_bin_len: usize,
+    stats: [BinStats; MAX_SIZE_CLASS],
 }
 
 impl<const MAX_SIZE_CLASS: usize> Default for DiscreteTLAB<MAX_SIZE_CLASS> {
@@ -18,17 +36,61 @@ impl<const MAX_SIZE_CLASS: usize> DiscreteTLAB<MAX_SIZE_CLASS> {
             _padding: [0; 16],
             bins: [Address::ZERO; MAX_SIZE_CLASS],
             bytes: 0,
+            max_bin_len: usize::MAX,
+            stats: [BinStats {
+                len: 0,
+                high_water: 0,
+                refills: 0,
+                overflows: 0,
+            }; MAX_SIZE_CLASS],
         }
     }
 
+    /// Limit the number of cells per bin. See `push_bounded`.
+    pub const fn with_max_bin_len(mut self, len: usize) -> Self {
+        self.max_bin_len = len;
+        self
+    }
+
     pub const fn free_bytes(&self) -> usize {
         self.bytes
     }
 
+    pub const fn bin_len(&self, size_class: SizeClass) -> usize {
+        self.stats[size_class.as_usize()].len
+    }
+
+    pub const fn bin_stats(&self, size_class: SizeClass) -> BinStats {
+        self.stats[size_class.as_usize()]
+    }
+
     pub fn push(&mut self, size_class: SizeClass, cell: Address) {
         unsafe { cell.store(self.bins[size_class.as_usize()]) };
         self.bins[size_class.as_usize()] = cell;
         self.bytes += size_class.bytes();
+        let stats = &mut self.stats[size_class.as_usize()];
+        stats.len += 1;
+        stats.high_water = usize::max(stats.high_water, stats.len);
+    }
+
+    /// Push a cell. If the bin then holds more than `max_bin_len` cells, half of them are passed to `flush`.
+    pub fn push_bounded(
+        &mut self,
+        size_class: SizeClass,
+        cell: Address,
+        flush: impl FnMut(Address),
+    ) {
+        self.push(size_class, cell);
+        if self.bin_len(size_class) > self.max_bin_len {
+            self.overflow(size_class, flush);
+        }
+    }
+
+    #[cold]
+    fn overflow(&mut self, size_class: SizeClass, flush: impl FnMut(Address)) {
+        self.stats[size_class.as_usize()].overflows += 1;
+        let count = (self.bin_len(size_class) + 1) / 2;
+        self.flush(size_class, count, flush);
     }
 
     pub fn pop(&mut self, size_class: SizeClass) -> Option<Address> {
@@ -38,11 +100,42 @@ impl<const MAX_SIZE_CLASS: usize> DiscreteTLAB<MAX_SIZE_CLASS> {
         }
         self.bins[size_class.as_usize()] = unsafe { cell.load() };
         self.bytes -= size_class.bytes();
+        self.stats[size_class.as_usize()].len -= 1;
         Some(cell)
     }
 
+    /// Carve `range` into cells of `size_class` and add them all to the bin.
+    /// Cells are popped in address order. Returns the number of cells added.
+    pub fn refill(&mut self, size_class: SizeClass, range: Range<Address>) -> usize {
+        let bytes = size_class.bytes();
+        let count = (range.end - range.start) / bytes;
+        for i in (0..count).rev() {
+            self.push(size_class, range.start + i * bytes);
+        }
+        self.stats[size_class.as_usize()].refills += 1;
+        count
+    }
+
+    /// Pass up to `count` cells of the bin to `f`. Returns the number of cells flushed.
+    pub fn flush(
+        &mut self,
+        size_class: SizeClass,
+        count: usize,
+        mut f: impl FnMut(Address),
+    ) -> usize {
+        let mut flushed = 0;
+        while flushed < count {
+            let Some(cell) = self.pop(size_class) else {
+                break;
+            };
+            f(cell);
+            flushed += 1;
+        }
+        flushed
+    }
+
     pub fn clear(&mut self, mut f: impl FnMut(Address)) {
-        for bin in self.bins.iter_mut() {
+        for (bin, stats) in self.bins.iter_mut().zip(self.stats.iter_mut()) {
             let mut cell = *bin;
             while !cell.is_zero() {
                 let next = unsafe { cell.load() };
@@ -50,7 +143,42 @@ impl<const MAX_SIZE_CLASS: usize> DiscreteTLAB<MAX_SIZE_CLASS> {
                 cell = next;
             }
             *bin = Address::ZERO;
+            stats.len = 0;
         }
         self.bytes = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill_and_flush() {
+        let mut memory = vec![0usize; 64];
+        let start = Address::from(memory.as_mut_ptr());
+        let size_class = SizeClass::<4>::from_bytes(32);
+        let mut tlab = DiscreteTLAB::<4>::new().with_max_bin_len(12);
+        assert_eq!(tlab.refill(size_class, start..start + 64 * 8), 16);
+        assert_eq!(tlab.free_bytes(), 16 * 32);
+        let stats = tlab.bin_stats(size_class);
+        assert_eq!((stats.len, stats.high_water, stats.refills), (16, 16, 1));
+        // Overflowing the bin flushes half of it.
+        let mut flushed = vec![];
+        let cell = tlab.pop(size_class).unwrap();
+        assert_eq!(cell, start);
+        tlab.push_bounded(size_class, cell, |c| flushed.push(c));
+        assert_eq!(flushed.len(), 8);
+        assert_eq!(flushed[0], start);
+        assert_eq!(tlab.bin_len(size_class), 8);
+        assert_eq!(tlab.bin_stats(size_class).overflows, 1);
+        assert_eq!(tlab.flush(size_class, 3, |_| {}), 3);
+        // Remaining cells come out in address order.
+        let mut cells = vec![];
+        tlab.clear(|c| cells.push(c));
+        assert_eq!(cells.len(), 5);
+        assert!(cells.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(tlab.free_bytes(), 0);
+        assert_eq!(tlab.bin_len(size_class), 0);
+    }
+}
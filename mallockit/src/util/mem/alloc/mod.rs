@@ -1,3 +1,6 @@
 pub mod allocation_area;
 pub mod arena;
 pub mod discrete_tlab;
+pub mod per_cpu;
+pub mod thread_cache;
+pub mod transfer_cache;
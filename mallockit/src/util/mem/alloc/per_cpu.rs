@@ -0,0 +1,229 @@
+use crate::util::sys::{rseq, RawMemory};
+use crate::util::Address;
+use std::ptr::NonNull;
+
+/// Whether plans should use per-CPU caches when rseq is available.
+pub const ENABLED: bool = cfg!(feature = "rseq");
+
+/// Bounded per-CPU free lists of cells, one per size class, like tcmalloc's per-CPU mode.
+///
+/// Each CPU owns a slab of `NUM_SIZE_CLASSES` stacks with up to `CAPACITY` cells each.
+/// `push` and `pop` are restartable sequences on the current CPU's slab, so they need no locks or atomics.
+/// The slabs are mapped on first use.
+///
+/// Callers must fall back to per-thread caches when `is_available` returns `false`.
+pub struct PerCpuCache<const NUM_SIZE_CLASSES: usize, const CAPACITY: usize> {
+    slabs: spin::Once<(Address, usize)>,
+}
+
+impl<const NUM_SIZE_CLASSES: usize, const CAPACITY: usize> Default
+    for PerCpuCache<NUM_SIZE_CLASSES, CAPACITY>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const NUM_SIZE_CLASSES: usize, const CAPACITY: usize> PerCpuCache<NUM_SIZE_CLASSES, CAPACITY> {
+    const LENS_BYTES: usize = NUM_SIZE_CLASSES << Address::LOG_BYTES;
+    const SLOTS_BYTES: usize = CAPACITY << Address::LOG_BYTES;
+    /// Bytes per CPU, rounded up to whole pages so that CPUs never share a cache line.
+    const STRIDE: usize = (Self::LENS_BYTES + NUM_SIZE_CLASSES * Self::SLOTS_BYTES + 4095) & !4095;
+
+    pub const fn new() -> Self {
+        Self {
+            slabs: spin::Once::new(),
+        }
+    }
+
+    /// Whether per-CPU caching works on the calling thread.
+    pub fn is_available(&self) -> bool {
+        rseq::current().is_some()
+    }
+
+    /// Number of CPUs with a slab.
+    pub fn num_cpus(&self) -> usize {
+        self.slabs().1
+    }
+
+    #[cold]
+    fn map_slabs() -> (Address, usize) {
+        let cpus = usize::max(
+            unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) } as usize,
+            1,
+        );
+        let slabs = RawMemory::map_anonymous(cpus * Self::STRIDE).unwrap();
+        (slabs, cpus)
+    }
+
+    fn slabs(&self) -> (Address, usize) {
+        *self.slabs.call_once(Self::map_slabs)
+    }
+
+    const fn len_offset(size_class: usize) -> usize {
+        size_class << Address::LOG_BYTES
+    }
+
+    const fn slots_offset(size_class: usize) -> usize {
+        Self::LENS_BYTES + size_class * Self::SLOTS_BYTES
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[inline(always)]
+    fn pop_impl(&self, rseq: NonNull<rseq::Rseq>, size_class: usize) -> Option<Address> {
+        let cell = unsafe {
+            rseq::slab::pop(
+                rseq,
+                self.slabs().0.as_usize(),
+                Self::STRIDE,
+                Self::len_offset(size_class),
+                Self::slots_offset(size_class),
+            )
+        };
+        Some(Address::from(cell)).filter(|c| !c.is_zero())
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[inline(always)]
+    fn push_impl(&self, rseq: NonNull<rseq::Rseq>, size_class: usize, cell: Address) -> bool {
+        unsafe {
+            rseq::slab::push(
+                rseq,
+                self.slabs().0.as_usize(),
+                Self::STRIDE,
+                Self::len_offset(size_class),
+                Self::slots_offset(size_class),
+                CAPACITY,
+                cell.as_usize(),
+            )
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    fn pop_impl(&self, _rseq: NonNull<rseq::Rseq>, _size_class: usize) -> Option<Address> {
+        unreachable!()
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    fn push_impl(&self, _rseq: NonNull<rseq::Rseq>, _size_class: usize, _cell: Address) -> bool {
+        unreachable!()
+    }
+
+    /// Take a cell of `size_class` from the current CPU's cache.
+    #[inline(always)]
+    pub fn pop(&self, size_class: usize) -> Option<Address> {
+        debug_assert!(size_class < NUM_SIZE_CLASSES);
+        let rseq = rseq::current()?;
+        self.pop_impl(rseq, size_class)
+    }
+
+    /// Put a free cell into the current CPU's cache.
+    /// Returns `false` if the cache is full or per-CPU caching is unavailable.
+    #[inline(always)]
+    pub fn push(&self, size_class: usize, cell: Address) -> bool {
+        debug_assert!(size_class < NUM_SIZE_CLASSES);
+        debug_assert!(!cell.is_zero());
+        let Some(rseq) = rseq::current() else {
+            return false;
+        };
+        self.push_impl(rseq, size_class, cell)
+    }
+
+    /// Empty the current CPU's cache of `size_class`.
+    pub fn drain_current_cpu(&self, size_class: usize, mut f: impl FnMut(Address)) {
+        while let Some(cell) = self.pop(size_class) {
+            f(cell)
+        }
+    }
+
+    /// Empty the caches of all CPUs.
+    ///
+    /// # Safety
+    ///
+    /// No other thread may access the cache concurrently, e.g. in a forked child.
+    pub unsafe fn drain_all(&self, mut f: impl FnMut(usize, Address)) {
+        let Some((slabs, cpus)) = self.slabs.get().copied() else {
+            return;
+        };
+        for cpu in 0..cpus {
+            let slab = slabs + cpu * Self::STRIDE;
+            for size_class in 0..NUM_SIZE_CLASSES {
+                let len = slab + Self::len_offset(size_class);
+                let slots = slab + Self::slots_offset(size_class);
+                for i in (0..len.load::<usize>()).rev() {
+                    f(
+                        size_class,
+                        (slots + (i << Address::LOG_BYTES)).load::<Address>(),
+                    );
+                }
+                len.store(0usize);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn push_and_pop() {
+        let cache = PerCpuCache::<4, 8>::new();
+        assert!(cache.is_available());
+        for i in 1..=8 {
+            assert!(cache.push(1, Address::from(i << 4)));
+        }
+        // Full.
+        assert!(!cache.push(1, Address::from(9 << 4)));
+        // Other size classes are independent.
+        assert_eq!(cache.pop(0), None);
+        assert!(cache.push(3, Address::from(0x1000)));
+        // LIFO.
+        for i in (1..=8).rev() {
+            assert_eq!(cache.pop(1), Some(Address::from(i << 4)));
+        }
+        assert_eq!(cache.pop(1), None);
+        let mut drained = vec![];
+        cache.drain_current_cpu(3, |c| drained.push(c));
+        assert_eq!(drained, vec![Address::from(0x1000)]);
+    }
+
+    #[test]
+    fn concurrent_push_and_pop() {
+        const THREADS: usize = 8;
+        const CELLS: usize = 64;
+        static CACHE: PerCpuCache<2, 16> = PerCpuCache::new();
+        let threads = (0..THREADS)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    let mut owned = (0..CELLS)
+                        .map(|i| Address::from((t * CELLS + i + 1) << 4))
+                        .collect::<Vec<_>>();
+                    for _ in 0..1000 {
+                        // Cells may move between threads, but are never lost or duplicated.
+                        while let Some(c) = owned.pop() {
+                            if !CACHE.push(0, c) {
+                                owned.push(c);
+                                break;
+                            }
+                        }
+                        while let Some(c) = CACHE.pop(0) {
+                            owned.push(c);
+                        }
+                    }
+                    owned
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut cells = HashSet::new();
+        for t in threads {
+            for c in t.join().unwrap() {
+                assert!(cells.insert(c));
+            }
+        }
+        unsafe { CACHE.drain_all(|_, c| assert!(cells.insert(c))) };
+        assert_eq!(cells.len(), THREADS * CELLS);
+    }
+}
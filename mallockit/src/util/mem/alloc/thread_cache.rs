@@ -0,0 +1,197 @@
+use crate::space::meta::{Meta, Vec};
+use spin::{rwlock::RwLock, Yield};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Default upper bound on the free memory a single thread keeps cached.
+pub const DEFAULT_THREAD_CACHE_BUDGET: usize = 32 << 20;
+
+/// The process-wide cache GC.
+pub static CACHE_GC: CacheGc = CacheGc::new();
+
+/// A thread-local cache that the cache GC may trim from any thread.
+pub trait ThreadCache: Sync {
+    /// Free bytes currently held by the cache.
+    fn cached_bytes(&self) -> usize;
+
+    /// The GC epoch of the last allocation through this cache.
+    fn last_active(&self) -> usize;
+
+    /// Return cached memory to the global spaces. Runs on a thread other than the owner,
+    /// so implementations must only `try_lock` and skip whatever is busy.
+    ///
+    /// Returns the number of bytes released.
+    fn trim(&self) -> usize;
+}
+
+/// Records the GC epoch a thread cache was last used in.
+pub struct CacheActivity(AtomicUsize);
+
+impl CacheActivity {
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    pub fn touch(&self) {
+        self.0.store(CACHE_GC.epoch(), Ordering::Relaxed);
+    }
+
+    pub fn last_active(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for CacheActivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Incrementally trims the caches of threads that have not allocated recently.
+///
+/// Allocators call `tick` on their slow paths. Every `TICKS_PER_EPOCH` ticks the epoch advances,
+/// and the next `CACHES_PER_STEP` registered caches are visited. Caches that have not been used
+/// for `IDLE_EPOCHS` epochs are trimmed.
+pub struct CacheGc {
+    caches: RwLock<Vec<&'static dyn ThreadCache>, Yield>,
+    ticks: AtomicUsize,
+    epoch: AtomicUsize,
+    cursor: AtomicUsize,
+}
+
+impl CacheGc {
+    pub const TICKS_PER_EPOCH: usize = 256;
+    pub const IDLE_EPOCHS: usize = 4;
+    pub const CACHES_PER_STEP: usize = 8;
+
+    pub const fn new() -> Self {
+        Self {
+            caches: RwLock::new(Vec::new_in(Meta)),
+            ticks: AtomicUsize::new(0),
+            epoch: AtomicUsize::new(Self::IDLE_EPOCHS),
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn epoch(&self) -> usize {
+        self.epoch.load(Ordering::Relaxed)
+    }
+
+    pub fn register(&self, cache: &'static dyn ThreadCache) {
+        self.caches.write().push(cache);
+    }
+
+    /// Must be called before the cache is freed.
+    pub fn unregister(&self, cache: &'static dyn ThreadCache) {
+        self.caches
+            .write()
+            .retain(|c| !std::ptr::addr_eq(*c, cache));
+    }
+
+    fn is_idle(&self, cache: &dyn ThreadCache, epoch: usize) -> bool {
+        epoch.saturating_sub(cache.last_active()) >= Self::IDLE_EPOCHS && cache.cached_bytes() != 0
+    }
+
+    /// Report allocator slow-path activity. Must not be called with any allocator lock held.
+    pub fn tick(&self) {
+        if (self.ticks.fetch_add(1, Ordering::Relaxed) + 1) % Self::TICKS_PER_EPOCH == 0 {
+            self.step();
+        }
+    }
+
+    /// Advance the epoch and visit the next few caches. Returns the number of bytes released.
+    #[cold]
+    pub fn step(&self) -> usize {
+        let epoch = self.epoch.fetch_add(1, Ordering::Relaxed) + 1;
+        let Some(caches) = self.caches.try_read() else {
+            return 0;
+        };
+        if caches.is_empty() {
+            return 0;
+        }
+        let start = self
+            .cursor
+            .fetch_add(Self::CACHES_PER_STEP, Ordering::Relaxed);
+        (start..start + usize::min(Self::CACHES_PER_STEP, caches.len()))
+            .map(|i| caches[i % caches.len()])
+            .filter(|c| self.is_idle(*c, epoch))
+            .map(|c| c.trim())
+            .sum()
+    }
+
+    /// Trim all idle caches at once. Returns the number of bytes released.
+    pub fn trim_idle_caches(&self) -> usize {
+        let epoch = self.epoch();
+        self.caches
+            .read()
+            .iter()
+            .filter(|c| self.is_idle(**c, epoch))
+            .map(|c| c.trim())
+            .sum()
+    }
+
+    /// Total free bytes held by all registered caches.
+    pub fn cached_bytes(&self) -> usize {
+        self.caches.read().iter().map(|c| c.cached_bytes()).sum()
+    }
+
+    /// Lock the registry before `fork`. Trimming takes plan locks while holding the registry,
+    /// so this must happen before the plan is locked.
+    pub(crate) fn fork_prepare(&self) {
+        std::mem::forget(self.caches.write());
+    }
+
+    pub(crate) fn fork_release(&self) {
+        unsafe { self.caches.force_write_unlock() }
+    }
+}
+
+impl Default for CacheGc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::space::large_object_space::{LargeObjectAllocator, LargeObjectSpace};
+    use crate::space::{Allocator, Space, SpaceId};
+    use crate::util::*;
+    use spin::Lazy;
+
+    static LOS: Lazy<LargeObjectSpace> = Lazy::new(|| LargeObjectSpace::new(SpaceId(10)));
+
+    type CachingAllocator = LargeObjectAllocator<Size4K, { 1 << 20 }>;
+
+    fn alloc_and_free(loa: &mut CachingAllocator, objects: usize, bytes: usize) {
+        let layout = Layout::from_size_align(bytes, Size4K::BYTES).unwrap();
+        let objects = (0..objects)
+            .map(|_| loa.alloc(layout).unwrap())
+            .collect::<std::vec::Vec<_>>();
+        for o in objects {
+            loa.dealloc(o);
+        }
+    }
+
+    #[test]
+    fn cache_budget() {
+        let mut loa = CachingAllocator::new(&LOS).with_cache_budget(64 << 10);
+        alloc_and_free(&mut loa, 16, 16 << 10);
+        assert!(loa.cached_bytes() <= 64 << 10);
+    }
+
+    #[test]
+    fn trim_idle_caches() {
+        let mut loa = CachingAllocator::new(&LOS);
+        alloc_and_free(&mut loa, 4, 16 << 10);
+        assert_eq!(loa.cached_bytes(), 4 * (16 << 10));
+        // Recently used caches are left alone.
+        CACHE_GC.trim_idle_caches();
+        assert_eq!(loa.cached_bytes(), 4 * (16 << 10));
+        for _ in 0..CacheGc::IDLE_EPOCHS {
+            CACHE_GC.step();
+        }
+        CACHE_GC.trim_idle_caches();
+        assert_eq!(loa.cached_bytes(), 0);
+    }
+}
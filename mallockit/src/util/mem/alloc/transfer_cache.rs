@@ -0,0 +1,215 @@
+use crate::util::Address;
+use spin::Mutex;
+
+/// A singly linked list of free cells of one size class.
+///
+/// The first word of each cell links to the next cell. When the batch sits in a `TransferCache`,
+/// the second word of its first cell links to the next batch, so cells must be at least two words large.
+pub struct CellBatch {
+    head: Address,
+    len: usize,
+}
+
+impl CellBatch {
+    pub const EMPTY: Self = Self {
+        head: Address::ZERO,
+        len: 0,
+    };
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, cell: Address) {
+        unsafe { cell.store(self.head) };
+        self.head = cell;
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<Address> {
+        if self.head.is_zero() {
+            return None;
+        }
+        let cell = self.head;
+        self.head = unsafe { cell.load() };
+        self.len -= 1;
+        Some(cell)
+    }
+
+    /// Remove all cells from the batch.
+    pub fn take(&mut self) -> Self {
+        std::mem::replace(self, Self::EMPTY)
+    }
+
+    pub fn for_each(mut self, mut f: impl FnMut(Address)) {
+        while let Some(cell) = self.pop() {
+            f(cell)
+        }
+    }
+}
+
+impl Default for CellBatch {
+    fn default() -> Self {
+        Self::EMPTY
+    }
+}
+
+struct Bin {
+    /// The first cell of the first batch.
+    head: Address,
+    batches: usize,
+}
+
+/// Moves free cells between threads in fixed-size batches.
+///
+/// A thread collects freed cells of a size class in a local `CellBatch`. Once it holds `BATCH_SIZE` cells,
+/// the whole batch goes to the central store, from where any thread that runs out of cells of that size class
+/// can take it with a single lock acquisition. The store holds at most `MAX_BATCHES` batches per size class.
+pub struct TransferCache<
+    const NUM_SIZE_CLASSES: usize,
+    const BATCH_SIZE: usize,
+    const MAX_BATCHES: usize = 64,
+> {
+    bins: [Mutex<Bin>; NUM_SIZE_CLASSES],
+}
+
+impl<const NUM_SIZE_CLASSES: usize, const BATCH_SIZE: usize, const MAX_BATCHES: usize> Default
+    for TransferCache<NUM_SIZE_CLASSES, BATCH_SIZE, MAX_BATCHES>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const NUM_SIZE_CLASSES: usize, const BATCH_SIZE: usize, const MAX_BATCHES: usize>
+    TransferCache<NUM_SIZE_CLASSES, BATCH_SIZE, MAX_BATCHES>
+{
+    pub const fn new() -> Self {
+        Self {
+            bins: [const {
+                Mutex::new(Bin {
+                    head: Address::ZERO,
+                    batches: 0,
+                })
+            }; NUM_SIZE_CLASSES],
+        }
+    }
+
+    /// Add a full batch to the central store. Gives the batch back if the store is full.
+    pub fn insert_batch(&self, size_class: usize, batch: CellBatch) -> Result<(), CellBatch> {
+        debug_assert_eq!(batch.len(), BATCH_SIZE);
+        let mut bin = self.bins[size_class].lock();
+        if bin.batches >= MAX_BATCHES {
+            return Err(batch);
+        }
+        unsafe { (batch.head + std::mem::size_of::<usize>()).store(bin.head) };
+        bin.head = batch.head;
+        bin.batches += 1;
+        Ok(())
+    }
+
+    /// Take a full batch from the central store.
+    pub fn remove_batch(&self, size_class: usize) -> Option<CellBatch> {
+        let mut bin = self.bins[size_class].lock();
+        if bin.batches == 0 {
+            return None;
+        }
+        let head = bin.head;
+        bin.head = unsafe { (head + std::mem::size_of::<usize>()).load() };
+        bin.batches -= 1;
+        Some(CellBatch {
+            head,
+            len: BATCH_SIZE,
+        })
+    }
+
+    /// Free a cell into the thread-local `batch`, moving the batch to the central store once it is full.
+    ///
+    /// Returns the full batch if the store has no room for it. The caller must free its cells elsewhere.
+    #[inline(always)]
+    pub fn free_cell(
+        &self,
+        size_class: usize,
+        batch: &mut CellBatch,
+        cell: Address,
+    ) -> Result<(), CellBatch> {
+        batch.push(cell);
+        if batch.len() < BATCH_SIZE {
+            return Ok(());
+        }
+        self.insert_batch(size_class, batch.take())
+    }
+
+    /// Allocate a cell from the thread-local `batch`, refilling it from the central store if it is empty.
+    #[inline(always)]
+    pub fn alloc_cell(&self, size_class: usize, batch: &mut CellBatch) -> Option<Address> {
+        if let Some(cell) = batch.pop() {
+            return Some(cell);
+        }
+        *batch = self.remove_batch(size_class)?;
+        batch.pop()
+    }
+
+    /// Number of batches in the central store.
+    pub fn batches(&self, size_class: usize) -> usize {
+        self.bins[size_class].lock().batches
+    }
+
+    pub fn fork_prepare(&self) {
+        for bin in &self.bins {
+            std::mem::forget(bin.lock());
+        }
+    }
+
+    pub fn fork_release(&self) {
+        for bin in self.bins.iter().rev() {
+            unsafe { bin.force_unlock() }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn cells(n: usize) -> std::vec::Vec<[usize; 2]> {
+        (0..n).map(|_| [0; 2]).collect()
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let cache = TransferCache::<2, 4, 2>::new();
+        let mut memory = cells(12);
+        let cells = memory
+            .iter_mut()
+            .map(|c| Address::from(c.as_mut_ptr()))
+            .collect::<std::vec::Vec<_>>();
+        let mut local = CellBatch::EMPTY;
+        for c in &cells[..8] {
+            assert!(cache.free_cell(1, &mut local, *c).is_ok());
+        }
+        assert!(local.is_empty());
+        assert_eq!(cache.batches(1), 2);
+        assert_eq!(cache.batches(0), 0);
+        // The store is full.
+        for c in &cells[8..11] {
+            assert!(cache.free_cell(1, &mut local, *c).is_ok());
+        }
+        let overflow = cache.free_cell(1, &mut local, cells[11]).unwrap_err();
+        assert_eq!(overflow.len(), 4);
+        // Another thread takes the batches.
+        let mut remote = CellBatch::EMPTY;
+        let mut seen = HashSet::new();
+        while let Some(c) = cache.alloc_cell(1, &mut remote) {
+            assert!(seen.insert(c));
+        }
+        assert_eq!(seen.len(), 8);
+        assert!(seen.iter().all(|c| cells[..8].contains(c)));
+        assert_eq!(cache.batches(1), 0);
+    }
+}
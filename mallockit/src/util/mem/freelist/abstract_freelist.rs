@@ -277,6 +277,7 @@ pub(super) trait InternalAbstractFreeList: Sized {
                 curr_size_class - 1
             };
             let size_class = usize::min(prev_size_class, (*start).trailing_zeros() as usize);
+            let size_class = usize::min(size_class, Self::NON_COALESCEABLE_SIZE_CLASS_THRESHOLD);
             let size = 1usize << size_class;
             let end = Unit(*start + size);
             debug_assert_eq!((*start & (size - 1)), 0);
@@ -1,6 +1,7 @@
-use super::abstract_freelist::*;
+use super::{abstract_freelist::*, FreeListEngine};
+use crate::space::side_metadata::{SideMetadata, SideMetadataSpec};
 use crate::util::*;
-use std::{marker::PhantomData, ops::Range, ptr::NonNull};
+use std::{marker::PhantomData, ops::Range, ptr::NonNull, sync::atomic::Ordering};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -39,6 +40,8 @@ where
     #[allow(unused)]
     shared: bool,
     base: Address,
+    /// Marks the heads of free cells when cells have no header. See `with_side_metadata`.
+    cells: Option<&'static SideMetadata>,
     table: [Option<CellPtr>; Config::NUM_SIZE_CLASS],
     phantom: PhantomData<Config>,
 }
@@ -63,16 +66,25 @@ where
         Config::LOG_MAX_CELL_SIZE - Config::LOG_MIN_ALIGNMENT;
 
     fn is_free(&self, unit: Unit, size_class: usize) -> bool {
+        if let Some(cells) = self.cells {
+            // The cell body is user data unless the side metadata says the cell is free.
+            let entry = cells.load(self.unit_to_value(unit), Ordering::Relaxed);
+            if entry != Self::FREE_CELL | size_class {
+                return false;
+            }
+            let cell = unsafe { self.unit_to_cell(unit).as_ref() };
+            return cell.owner == self as *const _ as _;
+        }
         let cell = unsafe { self.unit_to_cell(unit).as_ref() };
         cell.is_free == (1, size_class as u32) && cell.owner == self as *const _ as _
     }
 
     fn set_as_free(&mut self, unit: Unit, size_class: usize) {
-        unsafe { self.unit_to_cell(unit).as_mut().is_free = (1, size_class as u32) }
+        self.mark(unit, Some(size_class));
     }
 
     fn set_as_used(&mut self, unit: Unit, _size_class: usize) {
-        unsafe { self.unit_to_cell(unit).as_mut().is_free = (0, 0) }
+        self.mark(unit, None);
     }
 
     fn split_cell(&mut self, parent: Unit, parent_size_class: usize) -> (Unit, Unit) {
@@ -90,7 +102,6 @@ where
         let cell = unsafe { cell_ptr.as_mut() };
         cell.prev = None;
         cell.owner = self as *const _ as _;
-        cell.is_free = (1, size_class as _);
         if let Some(mut head) = head {
             unsafe {
                 debug_assert!(head.as_ref().prev.is_none());
@@ -99,6 +110,7 @@ where
         }
         cell.next = head;
         self.table[size_class] = Some(cell_ptr);
+        self.mark(unit, Some(size_class));
         debug_assert!(self.cell_to_unit(cell_ptr) == unit);
     }
 
@@ -119,9 +131,9 @@ where
             }
             self.table[size_class] = next;
             debug_assert!(head.prev.is_none());
-            head.is_free = (0, 0);
             head.owner = 0 as _;
             let unit = self.cell_to_unit(head_ptr);
+            self.mark(unit, None);
             Some(unit)
         }
     }
@@ -145,8 +157,8 @@ where
                 next.as_mut().prev = prev;
             }
         }
-        cell.is_free = (0, 0);
         cell.owner = 0 as _;
+        self.mark(unit, None);
     }
 }
 
@@ -156,16 +168,61 @@ where
 {
     pub const HEADER_SIZE: usize = Cell::HEADER_BYTES;
 
+    /// Side metadata entry of a free cell head: this flag or'd with the size class of the cell.
+    ///
+    /// Entries without the flag are left to the user of the free list, e.g. to record the size class
+    /// of an allocated cell.
+    pub const FREE_CELL: usize = 1 << 7;
+
+    /// Side metadata for `with_side_metadata`: one byte for each smallest cell.
+    pub const CELL_METADATA: SideMetadataSpec = SideMetadataSpec::new(
+        "free cells",
+        Config::LOG_MIN_ALIGNMENT + <Self as InternalAbstractFreeList>::MIN_SIZE_CLASS,
+        3,
+    );
+
     pub const fn new(shared: bool, base: Address) -> Self {
         debug_assert!(std::mem::size_of::<Cell>() == 32);
         Self {
             shared,
             base,
+            cells: None,
             table: [None; Config::NUM_SIZE_CLASS],
             phantom: PhantomData,
         }
     }
 
+    /// Track free cells in `cells`, a `CELL_METADATA` table of the space, instead of a header word.
+    ///
+    /// Allocated cells then have no header: `allocate_cell` returns the whole cell, and
+    /// `cell_size_class`/`cell_bytes` should be called with `headers == false`.
+    /// All free lists exchanging cells must use the same mode.
+    pub const fn with_side_metadata(mut self, cells: &'static SideMetadata) -> Self {
+        self.cells = Some(cells);
+        self
+    }
+
+    const fn header_units(&self) -> usize {
+        if self.cells.is_some() {
+            0
+        } else {
+            Cell::HEADER_UNITS
+        }
+    }
+
+    fn mark(&self, unit: Unit, free_size_class: Option<usize>) {
+        match self.cells {
+            Some(cells) => {
+                let entry = free_size_class.map_or(0, |sc| Self::FREE_CELL | sc);
+                cells.store(self.unit_to_value(unit), entry, Ordering::Relaxed);
+            }
+            None => {
+                let is_free = free_size_class.map_or((0, 0), |sc| (1, sc as u32));
+                unsafe { (*self.unit_to_cell(unit).as_ptr()).is_free = is_free }
+            }
+        }
+    }
+
     fn unit_to_cell(&self, unit: Unit) -> CellPtr {
         let ptr = self.base + (*unit << Config::LOG_MIN_ALIGNMENT);
         unsafe { NonNull::new_unchecked(ptr.as_mut_ptr()) }
@@ -175,6 +232,33 @@ where
         Unit((Address::from(cell.as_ptr()) - self.base) >> Config::LOG_MIN_ALIGNMENT)
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.table.iter().all(|c| c.is_none())
+    }
+
+    /// Move all free cells to `other`, coalescing them with the free cells already there.
+    pub fn drain_into(&mut self, other: &mut Self) {
+        self.transfer_to(other, usize::MAX);
+    }
+
+    /// Move free cells to `other`, largest first, until at least `bytes` have been moved.
+    ///
+    /// Returns the number of bytes moved.
+    pub fn transfer_to(&mut self, other: &mut Self, bytes: usize) -> usize {
+        debug_assert_eq!(self.base, other.base);
+        let mut moved = 0;
+        for size_class in (0..Config::NUM_SIZE_CLASS).rev() {
+            while moved < bytes {
+                let Some(unit) = self.pop(size_class) else {
+                    break;
+                };
+                other.release_aligned_units(unit, size_class);
+                moved += 1 << (size_class + Config::LOG_MIN_ALIGNMENT);
+            }
+        }
+        moved
+    }
+
     pub fn pop_raw_cell(&mut self, log_size: usize) -> Option<Address> {
         let size_class =
             <Self as InternalAbstractFreeList>::size_class(self.process_input_units(1 << log_size));
@@ -199,17 +283,34 @@ where
         units >> Config::LOG_MIN_ALIGNMENT
     }
 
+    /// The size class of the cells returned by `allocate_cell(bytes)`.
+    ///
+    /// `headers` is false for free lists using `with_side_metadata`.
+    pub fn cell_size_class(bytes: usize, headers: bool) -> usize {
+        let header_units = if headers { Cell::HEADER_UNITS } else { 0 };
+        let units = ((bytes >> Config::LOG_MIN_ALIGNMENT) + header_units).next_power_of_two();
+        <Self as InternalAbstractFreeList>::size_class(units)
+    }
+
+    /// The usable bytes of a cell of `size_class`.
+    pub const fn cell_bytes(size_class: usize, headers: bool) -> usize {
+        let header_bytes = if headers { Cell::HEADER_BYTES } else { 0 };
+        (1 << (size_class + Config::LOG_MIN_ALIGNMENT)) - header_bytes
+    }
+
     pub fn allocate_cell(&mut self, units: usize) -> Option<Range<Address>> {
-        let units = (self.process_input_units(units) + Cell::HEADER_UNITS).next_power_of_two();
+        let header_units = self.header_units();
+        let units = (self.process_input_units(units) + header_units).next_power_of_two();
         let Range { start, end } = self.allocate_cell_aligned_size(units)?;
-        let start = self.unit_to_value(start) + Cell::HEADER_BYTES;
+        let start = self.unit_to_value(start) + (header_units << Config::LOG_MIN_ALIGNMENT);
         let end = self.unit_to_value(end);
         Some(start..end)
     }
 
     pub fn release_cell(&mut self, start: Address, units: usize) {
-        let units = (self.process_input_units(units) + Cell::HEADER_UNITS).next_power_of_two();
-        let unit = self.value_to_unit(start - Cell::HEADER_BYTES);
+        let header_units = self.header_units();
+        let units = (self.process_input_units(units) + header_units).next_power_of_two();
+        let unit = self.value_to_unit(start - (header_units << Config::LOG_MIN_ALIGNMENT));
         self.release_cell_aligned_size(unit, units);
     }
 
@@ -220,3 +321,28 @@ where
         self.release_cell_aligned_size(unit, units);
     }
 }
+
+impl<Config: AddressSpaceConfig> FreeListEngine for IntrusiveFreeList<Config>
+where
+    [(); Config::NUM_SIZE_CLASS]: Sized,
+{
+    const MIN_ALIGNMENT: usize =
+        1 << (Config::LOG_MIN_ALIGNMENT + <Self as InternalAbstractFreeList>::MIN_SIZE_CLASS);
+
+    /// The range is split into buddy cells, largest and aligned first.
+    fn add_memory(&mut self, range: Range<Address>) {
+        debug_assert!(range.start.is_aligned_to(Self::MIN_ALIGNMENT));
+        debug_assert!(range.end.is_aligned_to(Self::MIN_ALIGNMENT));
+        let unit = self.value_to_unit(range.start);
+        let units = self.process_input_units(range.end - range.start);
+        self.release_cell_unaligned_size(unit, units);
+    }
+
+    fn allocate_cell(&mut self, bytes: usize) -> Option<Range<Address>> {
+        IntrusiveFreeList::allocate_cell(self, bytes)
+    }
+
+    fn release_cell(&mut self, start: Address, bytes: usize) {
+        IntrusiveFreeList::release_cell(self, start, bytes)
+    }
+}
@@ -1,3 +1,26 @@
+use crate::util::Address;
+use std::ops::Range;
+
 mod abstract_freelist;
 pub mod intrusive_freelist;
 pub mod page_freelist;
+pub mod tlsf_freelist;
+pub mod tree_freelist;
+
+/// A free list handing out cells from the memory given to it with `add_memory`.
+///
+/// Engines differ in their fit policy, bookkeeping and time bounds, but all return cells of at least the
+/// requested bytes, aligned to `MIN_ALIGNMENT`, and take them back with the same requested bytes.
+pub trait FreeListEngine {
+    /// Alignment of the returned cells. Ranges given to `add_memory` must be aligned to it too.
+    const MIN_ALIGNMENT: usize;
+
+    /// Make `range` available for allocation.
+    fn add_memory(&mut self, range: Range<Address>);
+
+    /// Allocate a cell of at least `bytes` bytes. Returns its usable range.
+    fn allocate_cell(&mut self, bytes: usize) -> Option<Range<Address>>;
+
+    /// Free a cell returned by `allocate_cell(bytes)`.
+    fn release_cell(&mut self, start: Address, bytes: usize);
+}
@@ -20,7 +20,8 @@ pub struct PageFreeList<const NUM_SIZE_CLASS: usize> {
     base: Address,
     table: [Option<CellPtr>; NUM_SIZE_CLASS],
     bst: LazyBst,
-    page_table: PageTable,
+    /// The cell of each free unit
+    page_table: PageTable<Address>,
     arena: Arena<Cell>,
 }
 
@@ -130,19 +131,17 @@ impl<const NUM_SIZE_CLASS: usize> PageFreeList<NUM_SIZE_CLASS> {
     }
 
     fn unit_to_cell(&self, unit: Unit) -> CellPtr {
-        let ptr = self.page_table.get_pointer_meta(self.unit_to_address(unit));
+        let ptr = self.page_table.get(self.unit_to_address(unit)).unwrap();
         unsafe { NonNull::new_unchecked(ptr.as_mut_ptr()) }
     }
 
     fn delete_pages(&mut self, unit: Unit) {
-        self.page_table
-            .delete_pages::<Size4K>(Page::new(self.unit_to_address(unit)), 1);
+        self.page_table.remove(self.unit_to_address(unit));
     }
 
     fn insert_pages(&mut self, unit: Unit, pointer_meta: Address) {
-        let addr = self.unit_to_address(unit);
-        self.page_table.insert_pages::<Size4K>(Page::new(addr), 1);
-        self.page_table.set_pointer_meta(addr, pointer_meta);
+        self.page_table
+            .insert(self.unit_to_address(unit), pointer_meta);
     }
 }
 
@@ -164,6 +163,23 @@ impl<const NUM_SIZE_CLASS: usize> PageFreeList<NUM_SIZE_CLASS> {
         Some(start..end)
     }
 
+    /// Allocate `units` units aligned to `align_units` units.
+    /// The rest of the aligned block goes back to the free list.
+    pub fn allocate_aligned_cell(
+        &mut self,
+        units: usize,
+        align_units: usize,
+    ) -> Option<Range<Address>> {
+        debug_assert!(align_units.is_power_of_two());
+        let block_units = usize::max(units, align_units).next_power_of_two();
+        let Range { start, end } = self.allocate_cell(block_units)?;
+        let used_end = start + (units << Size4K::LOG_BYTES);
+        if used_end < end {
+            self.release_cell(used_end, (end - used_end) >> Size4K::LOG_BYTES);
+        }
+        Some(start..used_end)
+    }
+
     pub fn release_cell(&mut self, start: Address, units: usize) {
         let unit = self.address_to_unit(start);
         self.release_cell_unaligned_size(unit, units);
@@ -0,0 +1,440 @@
+use super::FreeListEngine;
+use crate::util::*;
+use std::{ops::Range, ptr::NonNull};
+
+const LOG_ALIGNMENT: usize = 4;
+const ALIGNMENT: usize = 1 << LOG_ALIGNMENT;
+/// Each power-of-two size range is split into `SL_COUNT` second-level lists.
+const LOG_SL_COUNT: usize = 4;
+const SL_COUNT: usize = 1 << LOG_SL_COUNT;
+/// Blocks smaller than `SMALL_BLOCK` share the first first-level list, `ALIGNMENT` bytes apart.
+const FL_SHIFT: usize = LOG_SL_COUNT + LOG_ALIGNMENT;
+const SMALL_BLOCK: usize = 1 << FL_SHIFT;
+const FL_COUNT: usize = usize::BITS as usize - FL_SHIFT + 1;
+
+/// Boundary tag at the start of every block, free or used.
+#[repr(C)]
+struct Block {
+    /// The physically previous block. Only valid while that block is free.
+    prev_phys: Option<BlockPtr>,
+    /// Block size including this header, with `FREE`, `PREV_FREE` and `SLACK` in the low bits.
+    size: usize,
+    /// Free list links, overlapping the cell of used blocks.
+    next_free: Option<BlockPtr>,
+    prev_free: Option<BlockPtr>,
+}
+
+type BlockPtr = NonNull<Block>;
+
+impl Block {
+    const FREE: usize = 0b01;
+    const PREV_FREE: usize = 0b10;
+    /// The last `ALIGNMENT` bytes of a used block were too small to split off, and are not part of its cell.
+    const SLACK: usize = 0b100;
+    const FLAGS: usize = Self::FREE | Self::PREV_FREE | Self::SLACK;
+    const HEADER_BYTES: usize = 2 * std::mem::size_of::<usize>();
+    const MIN_BYTES: usize = std::mem::size_of::<Self>();
+
+    fn size(&self) -> usize {
+        self.size & !Self::FLAGS
+    }
+
+    fn set_size(&mut self, size: usize) {
+        self.size = size | (self.size & Self::FLAGS);
+    }
+
+    fn is_free(&self) -> bool {
+        self.size & Self::FREE != 0
+    }
+
+    fn is_prev_free(&self) -> bool {
+        self.size & Self::PREV_FREE != 0
+    }
+
+    fn set_flag(&mut self, flag: usize, value: bool) {
+        if value {
+            self.size |= flag;
+        } else {
+            self.size &= !flag;
+        }
+    }
+
+    fn from_cell(start: Address) -> BlockPtr {
+        unsafe { NonNull::new_unchecked((start - Self::HEADER_BYTES).as_mut_ptr()) }
+    }
+
+    fn at(addr: Address) -> BlockPtr {
+        unsafe { NonNull::new_unchecked(addr.as_mut_ptr()) }
+    }
+
+    fn cell(&self) -> Range<Address> {
+        let start = Address::from(self as *const Self);
+        let slack = if self.size & Self::SLACK != 0 {
+            ALIGNMENT
+        } else {
+            0
+        };
+        start + Self::HEADER_BYTES..start + self.size() - slack
+    }
+
+    fn next_phys(&self) -> BlockPtr {
+        Self::at(Address::from(self as *const Self) + self.size())
+    }
+}
+
+/// Two-level segregated fit free list, with O(1) allocation and free.
+///
+/// Free blocks are kept in `FL_COUNT` x `SL_COUNT` lists: the first level picks the power of two of the block
+/// size, the second a linear slice of it. Allocation rounds the request up to the next slice, so that the first
+/// block of any non-empty list at or above it fits, and finds that list with two bitmap scans. Freed blocks
+/// are coalesced with their physical neighbours through boundary tags.
+///
+/// Cells are aligned to 16 bytes and preceded by a 16-byte header.
+pub struct TlsfFreeList {
+    fl_bitmap: usize,
+    sl_bitmap: [u32; FL_COUNT],
+    heads: [[Option<BlockPtr>; SL_COUNT]; FL_COUNT],
+    free_bytes: usize,
+}
+
+unsafe impl Send for TlsfFreeList {}
+unsafe impl Sync for TlsfFreeList {}
+
+impl Default for TlsfFreeList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsfFreeList {
+    pub const fn new() -> Self {
+        Self {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            heads: [[None; SL_COUNT]; FL_COUNT],
+            free_bytes: 0,
+        }
+    }
+
+    /// Bytes in free blocks, including their headers.
+    pub const fn free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    /// The log of a size that the largest free block reaches, or `None` without free blocks.
+    pub fn largest_free_log(&self) -> Option<usize> {
+        if self.fl_bitmap == 0 {
+            return None;
+        }
+        match self.fl_bitmap.ilog2() as usize {
+            0 => Some(((self.sl_bitmap[0].ilog2() as usize) << LOG_ALIGNMENT).ilog2() as usize),
+            fl => Some(fl + FL_SHIFT - 1),
+        }
+    }
+
+    /// The log of a free block size that always has room for a cell of `bytes` bytes aligned to `align`.
+    pub const fn fit_log(bytes: usize, align: usize) -> usize {
+        let mut size = Self::block_size(bytes);
+        if align > ALIGNMENT {
+            size += align + Block::MIN_BYTES;
+        }
+        size.next_power_of_two().ilog2() as usize
+    }
+
+    /// The usable bytes of the cell at `start`, as returned by `allocate_cell`.
+    pub fn cell_bytes(start: Address) -> usize {
+        let block = unsafe { Block::from_cell(start).as_ref() };
+        debug_assert!(!block.is_free());
+        let cell = block.cell();
+        cell.end - cell.start
+    }
+
+    /// The usable bytes of the cells allocated for `bytes`, with any alignment.
+    pub const fn good_size(bytes: usize) -> usize {
+        Self::block_size(bytes) - Block::HEADER_BYTES
+    }
+
+    const fn block_size(bytes: usize) -> usize {
+        let size = bytes.next_multiple_of(ALIGNMENT) + Block::HEADER_BYTES;
+        if size < Block::MIN_BYTES {
+            Block::MIN_BYTES
+        } else {
+            size
+        }
+    }
+
+    /// The list holding free blocks of `size` bytes.
+    const fn mapping(size: usize) -> (usize, usize) {
+        if size < SMALL_BLOCK {
+            (0, size >> LOG_ALIGNMENT)
+        } else {
+            let log = size.ilog2() as usize;
+            let sl = (size >> (log - LOG_SL_COUNT)) ^ SL_COUNT;
+            (log - FL_SHIFT + 1, sl)
+        }
+    }
+
+    /// The first list whose blocks are all at least `size` bytes.
+    const fn mapping_search(size: usize) -> Option<(usize, usize)> {
+        if size < SMALL_BLOCK {
+            return Some(Self::mapping(size));
+        }
+        let round = (1 << (size.ilog2() as usize - LOG_SL_COUNT)) - 1;
+        match size.checked_add(round) {
+            Some(size) => Some(Self::mapping(size)),
+            None => None,
+        }
+    }
+
+    fn find_suitable(&self, fl: usize, sl: usize) -> Option<BlockPtr> {
+        let mut fl = fl;
+        let mut sl_map = self.sl_bitmap[fl] & (!0u32 << sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & (!0usize).checked_shl(fl as u32 + 1).unwrap_or(0);
+            if fl_map == 0 {
+                return None;
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+            debug_assert_ne!(sl_map, 0);
+        }
+        let sl = sl_map.trailing_zeros() as usize;
+        self.heads[fl][sl]
+    }
+
+    fn insert(&mut self, mut block_ptr: BlockPtr) {
+        let block = unsafe { block_ptr.as_mut() };
+        debug_assert!(block.is_free());
+        let (fl, sl) = Self::mapping(block.size());
+        let head = self.heads[fl][sl];
+        block.prev_free = None;
+        block.next_free = head;
+        if let Some(mut head) = head {
+            unsafe { head.as_mut().prev_free = Some(block_ptr) };
+        }
+        self.heads[fl][sl] = Some(block_ptr);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+        self.free_bytes += block.size();
+    }
+
+    fn remove(&mut self, mut block_ptr: BlockPtr) {
+        let block = unsafe { block_ptr.as_mut() };
+        debug_assert!(block.is_free());
+        let (fl, sl) = Self::mapping(block.size());
+        let (prev, next) = (block.prev_free, block.next_free);
+        if let Some(mut prev) = prev {
+            unsafe { prev.as_mut().next_free = next };
+        } else {
+            debug_assert_eq!(self.heads[fl][sl], Some(block_ptr));
+            self.heads[fl][sl] = next;
+            if next.is_none() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+        if let Some(mut next) = next {
+            unsafe { next.as_mut().prev_free = prev };
+        }
+        self.free_bytes -= block.size();
+    }
+
+    /// Take the first `size` bytes of a free block that was just removed from its list.
+    fn use_block(&mut self, mut block_ptr: BlockPtr, size: usize) -> Range<Address> {
+        let block = unsafe { block_ptr.as_mut() };
+        let total = block.size();
+        debug_assert!(total >= size);
+        if total - size >= Block::MIN_BYTES {
+            block.set_size(size);
+            let mut rest_ptr = block.next_phys();
+            let rest = unsafe { rest_ptr.as_mut() };
+            rest.size = (total - size) | Block::FREE;
+            unsafe { rest.next_phys().as_mut().prev_phys = Some(rest_ptr) };
+            self.insert(rest_ptr);
+        } else {
+            unsafe { block.next_phys().as_mut().set_flag(Block::PREV_FREE, false) };
+            block.set_flag(Block::SLACK, total != size);
+        }
+        block.set_flag(Block::FREE, false);
+        let cell = block.cell();
+        debug_assert_eq!(
+            cell.end - cell.start,
+            Self::good_size(size - Block::HEADER_BYTES)
+        );
+        cell
+    }
+
+    /// Allocate a cell of at least `bytes` bytes, aligned to `align`.
+    pub fn allocate_aligned_cell(&mut self, bytes: usize, align: usize) -> Option<Range<Address>> {
+        debug_assert!(align.is_power_of_two());
+        if align <= ALIGNMENT {
+            return FreeListEngine::allocate_cell(self, bytes);
+        }
+        let size = Self::block_size(bytes);
+        // Leave room for a free block before the aligned cell.
+        let (fl, sl) = Self::mapping_search(size.checked_add(align + Block::MIN_BYTES)?)?;
+        let mut block_ptr = self.find_suitable(fl, sl)?;
+        self.remove(block_ptr);
+        let block = unsafe { block_ptr.as_mut() };
+        let start = Address::from(block_ptr.as_ptr());
+        let mut cell = (start + Block::HEADER_BYTES).align_up(align);
+        if cell - Block::HEADER_BYTES != start
+            && cell - Block::HEADER_BYTES - start < Block::MIN_BYTES
+        {
+            cell = (start + Block::HEADER_BYTES + Block::MIN_BYTES).align_up(align);
+        }
+        let gap = cell - Block::HEADER_BYTES - start;
+        if gap == 0 {
+            return Some(self.use_block(block_ptr, size));
+        }
+        let total = block.size();
+        block.set_size(gap);
+        let mut aligned_ptr = block.next_phys();
+        let aligned = unsafe { aligned_ptr.as_mut() };
+        aligned.prev_phys = Some(block_ptr);
+        aligned.size = (total - gap) | Block::FREE | Block::PREV_FREE;
+        unsafe { aligned.next_phys().as_mut().prev_phys = Some(aligned_ptr) };
+        self.insert(block_ptr);
+        Some(self.use_block(aligned_ptr, size))
+    }
+}
+
+impl FreeListEngine for TlsfFreeList {
+    const MIN_ALIGNMENT: usize = ALIGNMENT;
+
+    /// The range ends with a used header-only block, so coalescing never runs past it.
+    fn add_memory(&mut self, range: Range<Address>) {
+        debug_assert!(range.start.is_aligned_to(ALIGNMENT));
+        debug_assert!(range.end.is_aligned_to(ALIGNMENT));
+        debug_assert!(range.end - range.start >= Block::MIN_BYTES + Block::HEADER_BYTES);
+        let block_ptr = Block::at(range.start);
+        let sentinel_ptr = Block::at(range.end - Block::HEADER_BYTES);
+        unsafe {
+            (*block_ptr.as_ptr()).size =
+                (range.end - Block::HEADER_BYTES - range.start) | Block::FREE;
+            (*sentinel_ptr.as_ptr()).prev_phys = Some(block_ptr);
+            (*sentinel_ptr.as_ptr()).size = Block::PREV_FREE;
+        }
+        self.insert(block_ptr);
+    }
+
+    fn allocate_cell(&mut self, bytes: usize) -> Option<Range<Address>> {
+        let size = Self::block_size(bytes);
+        let (fl, sl) = Self::mapping_search(size)?;
+        let block = self.find_suitable(fl, sl)?;
+        self.remove(block);
+        Some(self.use_block(block, size))
+    }
+
+    fn release_cell(&mut self, start: Address, _bytes: usize) {
+        let mut block_ptr = Block::from_cell(start);
+        let block = unsafe { block_ptr.as_mut() };
+        debug_assert!(!block.is_free());
+        block.set_flag(Block::SLACK, false);
+        block.set_flag(Block::FREE, true);
+        if block.is_prev_free() {
+            let mut prev_ptr = block.prev_phys.unwrap();
+            self.remove(prev_ptr);
+            let prev = unsafe { prev_ptr.as_mut() };
+            prev.set_size(prev.size() + block.size());
+            block_ptr = prev_ptr;
+        }
+        let block = unsafe { block_ptr.as_mut() };
+        let next_ptr = block.next_phys();
+        if unsafe { next_ptr.as_ref() }.is_free() {
+            self.remove(next_ptr);
+            block.set_size(block.size() + unsafe { next_ptr.as_ref() }.size());
+        }
+        let next = unsafe { block.next_phys().as_mut() };
+        next.prev_phys = Some(block_ptr);
+        next.set_flag(Block::PREV_FREE, true);
+        self.insert(block_ptr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::sys::raw_memory::RawMemory;
+
+    const BYTES: usize = 4 << 20;
+
+    #[test]
+    fn mapping() {
+        assert_eq!(TlsfFreeList::mapping(32), (0, 2));
+        assert_eq!(TlsfFreeList::mapping(SMALL_BLOCK), (1, 0));
+        assert_eq!(
+            TlsfFreeList::mapping(SMALL_BLOCK * 2 - 1),
+            (1, SL_COUNT - 1)
+        );
+        // Every block in the list found for a size is large enough.
+        for size in (Block::MIN_BYTES..(1 << 20)).step_by(ALIGNMENT) {
+            let (fl, sl) = TlsfFreeList::mapping_search(size).unwrap();
+            let (block_fl, block_sl) = TlsfFreeList::mapping(size);
+            assert!((fl, sl) >= (block_fl, block_sl));
+            let smallest = if fl == 0 {
+                sl << LOG_ALIGNMENT
+            } else {
+                (SL_COUNT + sl) << (fl + FL_SHIFT - 1 - LOG_SL_COUNT)
+            };
+            assert!(smallest >= size, "{} {} {}", size, fl, sl);
+        }
+    }
+
+    #[test]
+    fn fit_log() {
+        let memory = RawMemory::map_anonymous(BYTES).unwrap();
+        for (bytes, align) in [(1, 8), (100, 16), (5000, 4096), (100 << 10, 64 << 10)] {
+            // A single free block of exactly the fit size, starting just past an alignment boundary.
+            let log = TlsfFreeList::fit_log(bytes, align);
+            let start = memory + ALIGNMENT;
+            let mut tlsf = TlsfFreeList::new();
+            assert_eq!(tlsf.largest_free_log(), None);
+            tlsf.add_memory(start..start + (1 << log) + Block::HEADER_BYTES);
+            assert_eq!(tlsf.largest_free_log(), Some(log));
+            assert!(tlsf.allocate_aligned_cell(bytes, align).is_some());
+        }
+    }
+
+    #[test]
+    fn allocate_and_coalesce() {
+        let memory = RawMemory::map_anonymous(BYTES).unwrap();
+        let mut tlsf = TlsfFreeList::new();
+        tlsf.add_memory(memory..memory + BYTES);
+        let initial = tlsf.free_bytes();
+        let mut cells = std::vec::Vec::new();
+        for i in 0..1000 {
+            let bytes = 1 + (i * 37) % 5000;
+            let cell = tlsf.allocate_cell(bytes).unwrap();
+            assert!(cell.end - cell.start >= bytes);
+            assert!(cell.start.is_aligned_to(ALIGNMENT));
+            assert_eq!(TlsfFreeList::cell_bytes(cell.start), cell.end - cell.start);
+            assert_eq!(cell.end - cell.start, TlsfFreeList::good_size(bytes));
+            unsafe { cell.start.store(i) };
+            cells.push((cell.start, bytes, i));
+        }
+        let aligned = tlsf.allocate_aligned_cell(100, 4096).unwrap();
+        assert!(aligned.start.is_aligned_to(4096));
+        assert_eq!(aligned.end - aligned.start, TlsfFreeList::good_size(100));
+        for (j, &(start, bytes, i)) in cells.iter().enumerate() {
+            if j % 2 == 0 {
+                assert_eq!(unsafe { start.load::<usize>() }, i);
+                tlsf.release_cell(start, bytes);
+            }
+        }
+        tlsf.release_cell(aligned.start, 100);
+        for (j, &(start, bytes, i)) in cells.iter().enumerate() {
+            if j % 2 == 1 {
+                assert_eq!(unsafe { start.load::<usize>() }, i);
+                tlsf.release_cell(start, bytes);
+            }
+        }
+        // Everything coalesced back into a single block.
+        assert_eq!(tlsf.free_bytes(), initial);
+        assert_eq!(tlsf.fl_bitmap.count_ones(), 1);
+        assert!(tlsf.allocate_cell(initial / 2).is_some());
+        RawMemory::unmap(memory, BYTES);
+    }
+}
@@ -0,0 +1,187 @@
+use super::FreeListEngine;
+use crate::space::meta::{BTreeMap, BTreeSet, Meta};
+use crate::util::*;
+use std::ops::Range;
+
+const LOG_ALIGNMENT: usize = 4;
+const ALIGNMENT: usize = 1 << LOG_ALIGNMENT;
+
+/// How a `TreeFreeList` picks the free range to allocate from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// The smallest fitting range, lowest address first. O(log n).
+    BestFit,
+    /// The lowest fitting range. O(n) in the number of free ranges below it.
+    FirstFit,
+}
+
+/// Address-ordered free list keeping its free ranges in side trees.
+///
+/// Cells have no header: the bookkeeping lives in metadata memory, and cells are freed with their requested
+/// size. Freed ranges are coalesced with their free neighbours.
+pub struct TreeFreeList {
+    policy: FitPolicy,
+    /// Free ranges, by start address.
+    by_address: BTreeMap<Address, usize>,
+    /// Free ranges, by size and then start address.
+    by_size: BTreeSet<(usize, Address)>,
+    free_bytes: usize,
+}
+
+impl TreeFreeList {
+    pub fn new(policy: FitPolicy) -> Self {
+        Self {
+            policy,
+            by_address: BTreeMap::new_in(Meta),
+            by_size: BTreeSet::new_in(Meta),
+            free_bytes: 0,
+        }
+    }
+
+    pub const fn policy(&self) -> FitPolicy {
+        self.policy
+    }
+
+    pub const fn free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    /// Number of disjoint free ranges.
+    pub fn free_ranges(&self) -> usize {
+        self.by_address.len()
+    }
+
+    const fn cell_size(bytes: usize) -> usize {
+        if bytes == 0 {
+            ALIGNMENT
+        } else {
+            bytes.next_multiple_of(ALIGNMENT)
+        }
+    }
+
+    fn insert(&mut self, start: Address, size: usize) {
+        self.by_address.insert(start, size);
+        self.by_size.insert((size, start));
+        self.free_bytes += size;
+    }
+
+    fn remove(&mut self, start: Address, size: usize) {
+        self.by_address.remove(&start);
+        self.by_size.remove(&(size, start));
+        self.free_bytes -= size;
+    }
+
+    fn find(&self, size: usize) -> Option<(Address, usize)> {
+        match self.policy {
+            FitPolicy::BestFit => self
+                .by_size
+                .range((size, Address::ZERO)..)
+                .next()
+                .map(|&(size, start)| (start, size)),
+            FitPolicy::FirstFit => self
+                .by_address
+                .iter()
+                .find(|(_, &free)| free >= size)
+                .map(|(&start, &size)| (start, size)),
+        }
+    }
+}
+
+impl FreeListEngine for TreeFreeList {
+    const MIN_ALIGNMENT: usize = ALIGNMENT;
+
+    fn add_memory(&mut self, range: Range<Address>) {
+        debug_assert!(range.start.is_aligned_to(ALIGNMENT));
+        debug_assert!(range.end.is_aligned_to(ALIGNMENT));
+        self.release_cell(range.start, range.end - range.start);
+    }
+
+    fn allocate_cell(&mut self, bytes: usize) -> Option<Range<Address>> {
+        let size = Self::cell_size(bytes);
+        let (start, free) = self.find(size)?;
+        self.remove(start, free);
+        if free > size {
+            self.insert(start + size, free - size);
+        }
+        Some(start..start + size)
+    }
+
+    fn release_cell(&mut self, start: Address, bytes: usize) {
+        let mut start = start;
+        let mut size = Self::cell_size(bytes);
+        debug_assert!(!self.by_address.contains_key(&start));
+        if let Some((&prev, &prev_size)) = self.by_address.range(..start).next_back() {
+            debug_assert!(prev + prev_size <= start);
+            if prev + prev_size == start {
+                self.remove(prev, prev_size);
+                start = prev;
+                size += prev_size;
+            }
+        }
+        if let Some(&next_size) = self.by_address.get(&(start + size)) {
+            self.remove(start + size, next_size);
+            size += next_size;
+        }
+        self.insert(start, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::sys::raw_memory::RawMemory;
+
+    const BYTES: usize = 1 << 20;
+
+    fn fragment(policy: FitPolicy) -> (TreeFreeList, Address, std::vec::Vec<Address>) {
+        let memory = RawMemory::map_anonymous(BYTES).unwrap();
+        let mut freelist = TreeFreeList::new(policy);
+        freelist.add_memory(memory..memory + BYTES);
+        // Free ranges of 256, 64 and 128 bytes, separated by used cells.
+        let cells = [256, 16, 64, 16, 128, 16]
+            .iter()
+            .map(|&bytes| freelist.allocate_cell(bytes).unwrap().start)
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(cells[0], memory);
+        for i in [0, 2, 4] {
+            freelist.release_cell(cells[i], [256, 64, 128][i / 2]);
+        }
+        assert_eq!(freelist.free_ranges(), 4);
+        (freelist, memory, cells)
+    }
+
+    #[test]
+    fn best_fit() {
+        let (mut freelist, memory, cells) = fragment(FitPolicy::BestFit);
+        assert_eq!(freelist.allocate_cell(60).unwrap().start, cells[2]);
+        assert_eq!(freelist.allocate_cell(100).unwrap().start, cells[4]);
+        // The 16 bytes left over from the 128 bytes range fit exactly.
+        assert_eq!(freelist.allocate_cell(1).unwrap().start, cells[4] + 112);
+        assert_eq!(freelist.allocate_cell(200).unwrap().start, cells[0]);
+        RawMemory::unmap(memory, BYTES);
+    }
+
+    #[test]
+    fn first_fit() {
+        let (mut freelist, memory, cells) = fragment(FitPolicy::FirstFit);
+        assert_eq!(freelist.allocate_cell(60).unwrap().start, cells[0]);
+        assert_eq!(freelist.allocate_cell(128).unwrap().start, cells[0] + 64);
+        assert_eq!(freelist.allocate_cell(100).unwrap().start, cells[4]);
+        RawMemory::unmap(memory, BYTES);
+    }
+
+    #[test]
+    fn coalesce() {
+        let (mut freelist, memory, cells) = fragment(FitPolicy::BestFit);
+        for i in [1, 5, 3] {
+            freelist.release_cell(cells[i], 16);
+        }
+        assert_eq!(freelist.free_ranges(), 1);
+        assert_eq!(freelist.free_bytes(), BYTES);
+        assert_eq!(
+            freelist.allocate_cell(BYTES).unwrap(),
+            memory..memory + BYTES
+        );
+        RawMemory::unmap(memory, BYTES);
+    }
+}
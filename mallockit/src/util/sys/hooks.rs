@@ -1,5 +1,6 @@
 use std::panic::PanicHookInfo;
 
+use crate::util::mem::alloc::thread_cache::CACHE_GC;
 use crate::Plan;
 
 fn panic_handler(panic_info: &PanicHookInfo<'_>) {
@@ -11,11 +12,16 @@ pub fn set_panic_handler() {
     std::panic::set_hook(unsafe { Box::from_raw(&mut panic_handler) });
 }
 
-pub extern "C" fn process_start(plan: &'static impl Plan) {
+pub extern "C" fn process_start<P: Plan>(plan: &'static P) {
     set_panic_handler();
     crate::mutator::init_pthread_key();
     unsafe {
         libc::atexit(process_exit);
+        libc::pthread_atfork(
+            Some(fork_prepare::<P>),
+            Some(fork_parent::<P>),
+            Some(fork_child::<P>),
+        );
     }
     #[cfg(target_os = "macos")]
     crate::util::malloc::macos_malloc_zone::init();
@@ -25,3 +31,36 @@ pub extern "C" fn process_start(plan: &'static impl Plan) {
 extern "C" fn process_exit() {
     crate::stat::report();
 }
+
+/// Acquire every global allocator lock before `fork`, so that the child never inherits a lock held by another thread.
+///
+/// The thread cache registry is locked first, then the plan locks, then the other mallockit-internal ones.
+/// They are released in reverse order.
+extern "C" fn fork_prepare<P: Plan>() {
+    CACHE_GC.fork_prepare();
+    P::get().fork_prepare();
+    crate::stat::fork_prepare();
+    #[cfg(target_os = "macos")]
+    crate::mutator::macos_tls_fork_prepare();
+}
+
+extern "C" fn fork_parent<P: Plan>() {
+    #[cfg(target_os = "macos")]
+    crate::mutator::macos_tls_fork_release();
+    crate::stat::fork_release();
+    P::get().fork_parent();
+    CACHE_GC.fork_release();
+}
+
+/// Only the forking thread survives in the child. Its thread-local state stays valid,
+/// while the state of all other threads is abandoned.
+///
+/// The thread cache registry is released before the plan's `fork_child`, which may allocate, e.g. when it
+/// respawns a thread. The forking thread may not have a mutator yet, so `fork_child` must not create one.
+extern "C" fn fork_child<P: Plan>() {
+    #[cfg(target_os = "macos")]
+    crate::mutator::macos_tls_fork_release();
+    crate::stat::fork_release();
+    CACHE_GC.fork_release();
+    P::get().fork_child();
+}
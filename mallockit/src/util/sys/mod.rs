@@ -1,6 +1,8 @@
 #[doc(hidden)]
 pub mod hooks;
 pub mod log;
+pub mod numa;
 pub mod raw_memory;
+pub mod rseq;
 
 pub use raw_memory::RawMemory;
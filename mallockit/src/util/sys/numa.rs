@@ -0,0 +1,239 @@
+use crate::util::Address;
+use spin::Once;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of NUMA nodes a page resource can partition its space into.
+pub const MAX_NUMA_NODES: usize = 8;
+
+/// Source of NUMA topology information.
+///
+/// Page resources consult the topology at construction time to split their
+/// virtual address range into per-node partitions, and on each page/block
+/// acquisition to pick the partition local to the calling thread.
+pub trait NumaTopology: Sync + 'static {
+    /// Number of NUMA nodes. Must be stable for the lifetime of the process.
+    fn num_nodes(&self) -> usize;
+
+    /// The node the calling thread is currently running on.
+    fn current_node(&self) -> usize;
+
+    /// Set the memory policy of a (not yet faulted) range to prefer `node`.
+    fn bind(&self, start: Address, size: usize, node: usize);
+}
+
+/// A machine with a single memory node. No binding is performed.
+pub struct UniformTopology;
+
+impl NumaTopology for UniformTopology {
+    fn num_nodes(&self) -> usize {
+        1
+    }
+
+    fn current_node(&self) -> usize {
+        0
+    }
+
+    fn bind(&self, _start: Address, _size: usize, _node: usize) {}
+}
+
+/// The topology reported by the operating system.
+pub struct SystemTopology {
+    nodes: Once<usize>,
+}
+
+impl SystemTopology {
+    pub const fn new() -> Self {
+        Self { nodes: Once::new() }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn detect_nodes() -> usize {
+        // e.g. "0" or "0-1"
+        let mut buf = [0u8; 64];
+        let path = b"/sys/devices/system/node/possible\0";
+        let len = unsafe {
+            let fd = libc::open(path.as_ptr() as _, libc::O_RDONLY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return 1;
+            }
+            let len = libc::read(fd, buf.as_mut_ptr() as _, buf.len());
+            libc::close(fd);
+            len
+        };
+        if len <= 0 {
+            return 1;
+        }
+        let mut max_node = 0usize;
+        let mut value = 0usize;
+        for &c in &buf[..len as usize] {
+            if c.is_ascii_digit() {
+                value = value * 10 + (c - b'0') as usize;
+            } else {
+                max_node = usize::max(max_node, value);
+                value = 0;
+            }
+        }
+        usize::max(max_node, value) + 1
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn detect_nodes() -> usize {
+        1
+    }
+}
+
+impl Default for SystemTopology {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NumaTopology for SystemTopology {
+    fn num_nodes(&self) -> usize {
+        *self.nodes.call_once(Self::detect_nodes)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn current_node(&self) -> usize {
+        if self.num_nodes() == 1 {
+            return 0;
+        }
+        let mut cpu = 0u32;
+        let mut node = 0u32;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_getcpu,
+                &mut cpu as *mut u32,
+                &mut node as *mut u32,
+                0usize,
+            )
+        };
+        if result != 0 {
+            return 0;
+        }
+        node as _
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn current_node(&self) -> usize {
+        0
+    }
+
+    #[cfg(target_os = "linux")]
+    fn bind(&self, start: Address, size: usize, node: usize) {
+        debug_assert!(node < usize::BITS as usize);
+        let mask: libc::c_ulong = 1 << node;
+        unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                start.as_mut_ptr::<u8>(),
+                size,
+                libc::MPOL_PREFERRED,
+                &mask as *const libc::c_ulong,
+                libc::c_ulong::BITS as usize,
+                0usize,
+            );
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn bind(&self, _start: Address, _size: usize, _node: usize) {}
+}
+
+/// A configurable topology for testing NUMA-aware code on single-node machines.
+pub struct FakeTopology {
+    nodes: usize,
+    current: AtomicUsize,
+    binds: AtomicUsize,
+}
+
+impl FakeTopology {
+    pub const fn new(nodes: usize) -> Self {
+        Self {
+            nodes,
+            current: AtomicUsize::new(0),
+            binds: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pretend that all threads are now running on `node`.
+    pub fn set_current_node(&self, node: usize) {
+        debug_assert!(node < self.nodes);
+        self.current.store(node, Ordering::SeqCst);
+    }
+
+    /// Number of `bind` calls received so far.
+    pub fn binds(&self) -> usize {
+        self.binds.load(Ordering::SeqCst)
+    }
+}
+
+impl NumaTopology for FakeTopology {
+    fn num_nodes(&self) -> usize {
+        self.nodes
+    }
+
+    fn current_node(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    fn bind(&self, _start: Address, _size: usize, node: usize) {
+        debug_assert!(node < self.nodes);
+        self.binds.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+static UNIFORM_TOPOLOGY: UniformTopology = UniformTopology;
+#[allow(unused)]
+static SYSTEM_TOPOLOGY: SystemTopology = SystemTopology::new();
+
+static TOPOLOGY: Once<&'static dyn NumaTopology> = Once::new();
+
+fn default_topology() -> &'static dyn NumaTopology {
+    if cfg!(feature = "numa") {
+        &SYSTEM_TOPOLOGY
+    } else {
+        &UNIFORM_TOPOLOGY
+    }
+}
+
+/// Install a custom topology provider.
+///
+/// This must happen before the plan is created. Returns `false` if a topology is already in use.
+pub fn set_topology(topology: &'static dyn NumaTopology) -> bool {
+    let mut installed = false;
+    TOPOLOGY.call_once(|| {
+        installed = true;
+        topology
+    });
+    installed
+}
+
+/// The process-wide topology provider.
+///
+/// Without the `numa` feature this is a single-node topology, unless overridden by `set_topology`.
+pub fn topology() -> &'static dyn NumaTopology {
+    *TOPOLOGY.call_once(default_topology)
+}
+
+/// The node the calling thread is currently running on.
+pub fn current_node() -> usize {
+    topology().current_node()
+}
+
+#[thread_local]
+static HOME_NODE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// The node the calling thread was running on when it first asked, normally on its first page acquisition.
+///
+/// Page resources on the process-wide topology route the acquisitions of a thread to its home node,
+/// without a system call each time.
+pub fn home_node() -> usize {
+    let node = HOME_NODE.load(Ordering::Relaxed);
+    if node != usize::MAX {
+        return node;
+    }
+    let node = current_node();
+    HOME_NODE.store(node, Ordering::Relaxed);
+    node
+}
@@ -1,5 +1,5 @@
-use crate::util::Size4K;
-use crate::util::{Address, Page};
+use crate::util::{Address, Page, PageSize, Size4K};
+use std::{ffi::CStr, os::fd::RawFd};
 
 #[derive(Debug)]
 pub struct MemoryMapError;
@@ -97,4 +97,227 @@ impl RawMemory {
             libc::madvise(start.as_mut_ptr(), size, libc::MADV_FREE);
         }
     }
+
+    /// Drop the backing of `[start, start + size)` immediately. The range reads back as zero.
+    pub fn madv_dontneed(start: Address, size: usize) {
+        debug_assert!(
+            (size & Page::<Size4K>::MASK) == 0,
+            "mmap size is not page aligned"
+        );
+        unsafe {
+            libc::madvise(start.as_mut_ptr(), size, libc::MADV_DONTNEED);
+        }
+    }
+
+    /// Replace `[start, start + size)` with fresh anonymous memory, dropping any previous backing.
+    pub fn remap_anonymous(start: Address, size: usize) -> Result<(), MemoryMapError> {
+        Self::map_fixed(start, size, libc::MAP_NORESERVE)
+    }
+
+    /// Unmap `[start, start + size)` of the heap and map it again right away, like `map_heap` does.
+    ///
+    /// The old mapping and all its pages are gone, but other mappings never get the hole.
+    pub fn remap_heap(start: Address, size: usize) -> Result<(), MemoryMapError> {
+        Self::map_fixed(start, size, libc::MAP_NORESERVE)?;
+        #[cfg(target_os = "linux")]
+        if cfg!(feature = "transparent_huge_page") {
+            unsafe {
+                libc::madvise(start.as_mut_ptr(), size, libc::MADV_HUGEPAGE);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace `[start, start + size)` with pages from the hugetlb pool.
+    ///
+    /// The pages are reserved up front (no `MAP_NORESERVE`), so this fails instead of raising
+    /// `SIGBUS` on first touch if the pool cannot satisfy the request. The caller should remap
+    /// the range with `remap_anonymous` on failure.
+    #[cfg(target_os = "linux")]
+    pub fn map_huge_tlb<S: PageSize>(start: Address, size: usize) -> Result<(), MemoryMapError> {
+        debug_assert!(start.is_aligned_to(S::BYTES));
+        debug_assert!((size & (S::BYTES - 1)) == 0);
+        let log_bytes = S::LOG_BYTES as libc::c_int;
+        Self::map_fixed(
+            start,
+            size,
+            libc::MAP_HUGETLB | (log_bytes << libc::MAP_HUGE_SHIFT),
+        )
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn map_huge_tlb<S: PageSize>(_start: Address, _size: usize) -> Result<(), MemoryMapError> {
+        Err(MemoryMapError)
+    }
+
+    fn map_fixed(start: Address, size: usize, flags: libc::c_int) -> Result<(), MemoryMapError> {
+        debug_assert!(
+            (size & Page::<Size4K>::MASK) == 0,
+            "mmap size is not page aligned"
+        );
+        let ptr = unsafe {
+            libc::mmap(
+                start.as_mut_ptr(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED | flags,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(MemoryMapError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Create an anonymous shared memory file of `size` bytes, for mapping with `map_shared`.
+    ///
+    /// The file is sparse: its pages are only allocated once written through a mapping.
+    #[cfg(target_os = "linux")]
+    pub fn create_shared_file(name: &CStr, size: usize) -> Result<RawFd, MemoryMapError> {
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(MemoryMapError);
+        }
+        if unsafe { libc::ftruncate(fd, size as libc::off_t) } != 0 {
+            unsafe { libc::close(fd) };
+            return Err(MemoryMapError);
+        }
+        Ok(fd)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn create_shared_file(_name: &CStr, _size: usize) -> Result<RawFd, MemoryMapError> {
+        Err(MemoryMapError)
+    }
+
+    /// Replace `[start, start + size)` with a shared mapping of `fd` from `offset`.
+    ///
+    /// Several ranges may map the same offset, and then see the same physical pages.
+    pub fn map_shared(
+        start: Address,
+        size: usize,
+        fd: RawFd,
+        offset: usize,
+    ) -> Result<(), MemoryMapError> {
+        debug_assert!(
+            (size & Page::<Size4K>::MASK) == 0,
+            "mmap size is not page aligned"
+        );
+        let ptr = unsafe {
+            libc::mmap(
+                start.as_mut_ptr(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_FIXED | libc::MAP_NORESERVE,
+                fd,
+                offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(MemoryMapError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Replace `[start, start + size)` with a private, copy-on-write mapping of `fd` from `offset`.
+    ///
+    /// The range reads the pages of the file until written. Writes are never seen by the file.
+    pub fn map_private(
+        start: Address,
+        size: usize,
+        fd: RawFd,
+        offset: usize,
+    ) -> Result<(), MemoryMapError> {
+        debug_assert!(
+            (size & Page::<Size4K>::MASK) == 0,
+            "mmap size is not page aligned"
+        );
+        let ptr = unsafe {
+            libc::mmap(
+                start.as_mut_ptr(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_FIXED | libc::MAP_NORESERVE,
+                fd,
+                offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(MemoryMapError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Free the pages of `fd` in `[offset, offset + size)`. They read back as zero.
+    pub fn punch_hole(fd: RawFd, offset: usize, size: usize) {
+        debug_assert!(
+            (size & Page::<Size4K>::MASK) == 0,
+            "hole size is not page aligned"
+        );
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::fallocate(
+                fd,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                size as libc::off_t,
+            );
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (fd, offset, size);
+    }
+
+    /// Make `[start, start + size)` read-only, or writable again.
+    pub fn protect(start: Address, size: usize, writable: bool) {
+        debug_assert!(
+            (size & Page::<Size4K>::MASK) == 0,
+            "mprotect size is not page aligned"
+        );
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        unsafe {
+            libc::mprotect(start.as_mut_ptr(), size, prot);
+        }
+    }
+
+    /// Ask the kernel to back `[start, start + size)` with transparent huge pages.
+    pub fn madv_hugepage(start: Address, size: usize) -> bool {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::madvise(start.as_mut_ptr(), size, libc::MADV_HUGEPAGE) == 0
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (start, size);
+            false
+        }
+    }
+
+    /// Fault in `[start, start + size)` for writing, so that the first touch does not trap.
+    pub fn populate(start: Address, size: usize) {
+        debug_assert!(
+            (size & Page::<Size4K>::MASK) == 0,
+            "mmap size is not page aligned"
+        );
+        #[cfg(target_os = "linux")]
+        {
+            // Not exposed by libc yet. Available since Linux 5.14.
+            const MADV_POPULATE_WRITE: libc::c_int = 23;
+            if unsafe { libc::madvise(start.as_mut_ptr(), size, MADV_POPULATE_WRITE) } == 0 {
+                return;
+            }
+        }
+        // The range is not handed out yet, so it is safe to write to it.
+        for offset in (0..size).step_by(Size4K::BYTES) {
+            unsafe { std::ptr::write_volatile((start + offset).as_mut_ptr::<u8>(), 0) }
+        }
+    }
 }
@@ -0,0 +1,304 @@
+//! Restartable sequences.
+//!
+//! A thread registers a `struct rseq` area with the kernel. The kernel keeps its `cpu_id` field up to date,
+//! and aborts any critical section described by `rseq_cs` when the thread is preempted, migrated
+//! or signalled. A critical section that commits with a single store is therefore atomic with respect
+//! to all other threads running on the same CPU.
+//!
+//! glibc 2.35+ registers an area for every thread. Otherwise mallockit registers its own.
+
+use std::ptr::NonNull;
+
+/// Signature placed before every abort handler. Must match the one used for registration.
+/// This is the glibc value, so critical sections also work with glibc's area.
+pub const RSEQ_SIG: u32 = 0x53053053;
+
+/// The kernel ABI of the per-thread area.
+#[repr(C, align(32))]
+pub struct Rseq {
+    pub cpu_id_start: u32,
+    pub cpu_id: u32,
+    pub rseq_cs: u64,
+    pub flags: u32,
+    _padding: [u32; 3],
+}
+
+impl Rseq {
+    const fn new() -> Self {
+        Self {
+            cpu_id_start: 0,
+            cpu_id: u32::MAX,
+            rseq_cs: 0,
+            flags: 0,
+            _padding: [0; 3],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Unknown,
+    Registered,
+    Unavailable,
+}
+
+#[thread_local]
+static mut STATE: State = State::Unknown;
+
+#[thread_local]
+static mut AREA: Option<NonNull<Rseq>> = None;
+
+#[thread_local]
+static mut OWN_AREA: Rseq = Rseq::new();
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod glibc {
+    extern "C" {
+        #[linkage = "extern_weak"]
+        static __rseq_offset: *const isize;
+        #[linkage = "extern_weak"]
+        static __rseq_size: *const u32;
+    }
+
+    /// The area glibc registered for the calling thread, if any.
+    pub(super) fn area() -> Option<*mut super::Rseq> {
+        unsafe {
+            if __rseq_size.is_null() || __rseq_offset.is_null() || *__rseq_size == 0 {
+                return None;
+            }
+            let tp: *mut u8;
+            std::arch::asm!("mov {}, fs:0", out(reg) tp, options(nostack, readonly, preserves_flags));
+            Some(tp.offset(*__rseq_offset) as _)
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn register() -> Option<NonNull<Rseq>> {
+    if let Some(area) = glibc::area() {
+        return NonNull::new(area);
+    }
+    let area = std::ptr::addr_of_mut!(OWN_AREA);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_rseq,
+            area,
+            std::mem::size_of::<Rseq>() as u32,
+            0,
+            RSEQ_SIG,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    NonNull::new(area)
+}
+
+/// Critical sections are only implemented for x86_64 Linux.
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn register() -> Option<NonNull<Rseq>> {
+    None
+}
+
+#[cold]
+fn init() -> Option<NonNull<Rseq>> {
+    let area = register();
+    unsafe {
+        AREA = area;
+        STATE = if area.is_some() {
+            State::Registered
+        } else {
+            State::Unavailable
+        };
+    }
+    area
+}
+
+/// The area of the calling thread. Registers one on first use.
+///
+/// Returns `None` if rseq is not supported, so that callers can fall back to per-thread caches.
+#[inline(always)]
+pub fn current() -> Option<NonNull<Rseq>> {
+    unsafe {
+        match STATE {
+            State::Registered => AREA,
+            State::Unavailable => None,
+            State::Unknown => init(),
+        }
+    }
+}
+
+/// The CPU the calling thread is running on, as last reported by the kernel.
+pub fn current_cpu() -> Option<usize> {
+    let area = current()?;
+    let cpu = unsafe { std::ptr::read_volatile(&area.as_ref().cpu_id) };
+    Some(cpu as usize)
+}
+
+/// Per-CPU slab operations. A slab is a `[usize]` length array, one per size class,
+/// followed by the slots of each size class. Slabs of consecutive CPUs are `stride` bytes apart.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub(crate) mod slab {
+    use super::Rseq;
+    use std::arch::asm;
+    use std::ptr::NonNull;
+
+    /// Pop a value from the current CPU's slab. `len` and `slots` are byte offsets into the slab
+    /// of the length and of the first slot of the size class. Returns zero if the size class is empty.
+    #[inline(always)]
+    pub unsafe fn pop(
+        rseq: NonNull<Rseq>,
+        slabs: usize,
+        stride: usize,
+        len: usize,
+        slots: usize,
+    ) -> usize {
+        let value: usize;
+        asm!(
+            "2:",
+            "lea {tmp}, [rip + 3f]",
+            "mov qword ptr [{rseq} + 8], {tmp}",
+            "4:",
+            "mov {slab:e}, dword ptr [{rseq} + 4]",
+            "imul {slab}, {stride}",
+            "add {slab}, {slabs}",
+            "mov {n}, qword ptr [{slab} + {len}]",
+            "test {n}, {n}",
+            "jz 6f",
+            "dec {n}",
+            "lea {tmp}, [{slab} + {slots}]",
+            "mov {value}, qword ptr [{tmp} + {n} * 8]",
+            // Commit
+            "mov qword ptr [{slab} + {len}], {n}",
+            "5:",
+            "jmp 7f",
+            ".pushsection __rseq_cs, \"aw\"",
+            ".balign 32",
+            "3:",
+            ".long 0, 0",
+            ".quad 4b, 5b - 4b, 8f",
+            ".popsection",
+            ".pushsection __rseq_failure, \"ax\"",
+            ".byte 0x0f, 0xb9, 0x3d",
+            ".long 0x53053053",
+            "8:",
+            "jmp 2b",
+            ".popsection",
+            "6:",
+            "xor {value:e}, {value:e}",
+            "7:",
+            rseq = in(reg) rseq.as_ptr(),
+            slabs = in(reg) slabs,
+            stride = in(reg) stride,
+            len = in(reg) len,
+            slots = in(reg) slots,
+            slab = out(reg) _,
+            n = out(reg) _,
+            tmp = out(reg) _,
+            value = out(reg) value,
+            options(nostack),
+        );
+        value
+    }
+
+    /// Push `value` to the current CPU's slab. Returns `false` if the size class already holds `capacity` values.
+    #[inline(always)]
+    pub unsafe fn push(
+        rseq: NonNull<Rseq>,
+        slabs: usize,
+        stride: usize,
+        len: usize,
+        slots: usize,
+        capacity: usize,
+        value: usize,
+    ) -> bool {
+        let pushed: usize;
+        asm!(
+            "2:",
+            "lea {tmp}, [rip + 3f]",
+            "mov qword ptr [{rseq} + 8], {tmp}",
+            "4:",
+            "mov {slab:e}, dword ptr [{rseq} + 4]",
+            "imul {slab}, {stride}",
+            "add {slab}, {slabs}",
+            "mov {n}, qword ptr [{slab} + {len}]",
+            "cmp {n}, {capacity}",
+            "jae 6f",
+            "lea {tmp}, [{slab} + {slots}]",
+            "mov qword ptr [{tmp} + {n} * 8], {value}",
+            "inc {n}",
+            // Commit
+            "mov qword ptr [{slab} + {len}], {n}",
+            "5:",
+            "mov {pushed:e}, 1",
+            "jmp 7f",
+            ".pushsection __rseq_cs, \"aw\"",
+            ".balign 32",
+            "3:",
+            ".long 0, 0",
+            ".quad 4b, 5b - 4b, 8f",
+            ".popsection",
+            ".pushsection __rseq_failure, \"ax\"",
+            ".byte 0x0f, 0xb9, 0x3d",
+            ".long 0x53053053",
+            "8:",
+            "jmp 2b",
+            ".popsection",
+            "6:",
+            "xor {pushed:e}, {pushed:e}",
+            "7:",
+            rseq = in(reg) rseq.as_ptr(),
+            slabs = in(reg) slabs,
+            stride = in(reg) stride,
+            len = in(reg) len,
+            slots = in(reg) slots,
+            capacity = in(reg) capacity,
+            value = in(reg) value,
+            slab = out(reg) _,
+            n = out(reg) _,
+            tmp = out(reg) _,
+            pushed = out(reg) pushed,
+            options(nostack),
+        );
+        pushed != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{mem::alloc::per_cpu::PerCpuCache, Address};
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn registration() {
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) } as usize;
+        if current().is_none() {
+            assert_eq!(current_cpu(), None);
+            assert_per_thread_fallback();
+            return;
+        }
+        assert!(current_cpu().unwrap() < cpus);
+        assert!(PerCpuCache::<1, 1>::new().is_available());
+        // Registering again on another thread also works.
+        let cpu = std::thread::spawn(current_cpu).join().unwrap();
+        assert!(cpu.unwrap() < cpus);
+        // So does a thread that finds rseq unavailable.
+        std::thread::spawn(|| {
+            unsafe { STATE = State::Unavailable };
+            assert_eq!(current_cpu(), None);
+            assert_per_thread_fallback();
+        })
+        .join()
+        .unwrap();
+    }
+
+    /// Without rseq, the per-CPU cache refuses every cell, so that callers use their per-thread caches.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn assert_per_thread_fallback() {
+        let cache = PerCpuCache::<1, 1>::new();
+        assert!(!cache.is_available());
+        assert!(!cache.push(0, Address::from(16usize)));
+        assert_eq!(cache.pop(0), None);
+    }
+}
@@ -1,4 +1,5 @@
-use std::{alloc::Allocator, collections::LinkedList};
+use crate::space::page_resource::HEAP_LIMIT;
+use std::{alloc::Allocator, collections::LinkedList, thread};
 
 pub fn simple_boxed(alloc: impl Allocator) {
     let mut v = Box::new_in(42, alloc);
@@ -24,6 +25,82 @@ pub fn simple_linked_list(alloc: impl Allocator) {
     assert_eq!(list.pop_front(), None);
 }
 
+/// Run thousands of short-lived threads, each leaving one object behind.
+/// Memory held by the page resources must not grow with the number of exited threads.
+pub fn short_lived_threads(alloc: impl Allocator + Sync) {
+    const THREADS: usize = 4096;
+    const WARMUP_THREADS: usize = 256;
+    const CONCURRENT_THREADS: usize = 8;
+    const MAX_GROWTH: usize = 64 << 20;
+    const SIZES: [usize; 6] = [16, 128, 1024, 8 << 10, 64 << 10, 1 << 20];
+    let alloc = &alloc;
+    let mut survivors = Vec::new();
+    let mut baseline = 0;
+    for i in (0..THREADS).step_by(CONCURRENT_THREADS) {
+        if i == WARMUP_THREADS {
+            baseline = HEAP_LIMIT.committed_bytes();
+        }
+        survivors = thread::scope(|s| {
+            let handles = (0..CONCURRENT_THREADS)
+                .map(|_| {
+                    s.spawn(move || {
+                        let objects = SIZES.map(|size| Vec::<u8, _>::with_capacity_in(size, alloc));
+                        let survivor = Box::new_in(i, alloc);
+                        drop(objects);
+                        survivor
+                    })
+                })
+                .collect::<Vec<_>>();
+            // Joining waits for the thread-exit hooks to finish.
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+    }
+    drop(survivors);
+    let growth = HEAP_LIMIT.committed_bytes().saturating_sub(baseline);
+    assert!(growth < MAX_GROWTH, "grew by {} bytes", growth);
+}
+
+/// One thread allocates small objects, another frees them.
+/// Freed cells must flow back to the producer instead of piling up in the consumer.
+pub fn producer_consumer(alloc: impl Allocator + Sync) {
+    const ROUNDS: usize = 2048;
+    const WARMUP_ROUNDS: usize = 64;
+    const OBJECTS: usize = 256;
+    const MAX_GROWTH: usize = 64 << 20;
+    const SIZES: [usize; 4] = [16, 64, 256, 1024];
+    let alloc = &alloc;
+    let (sender, receiver) = std::sync::mpsc::sync_channel(4);
+    let mut baseline = 0;
+    thread::scope(|s| {
+        s.spawn(move || {
+            for round in 0..ROUNDS {
+                let objects = (0..OBJECTS)
+                    .map(|i| {
+                        let mut v =
+                            Vec::<usize, _>::with_capacity_in(SIZES[i % SIZES.len()] / 8, alloc);
+                        v.push(round ^ i);
+                        v
+                    })
+                    .collect::<Vec<_>>();
+                sender.send(objects).unwrap();
+            }
+        });
+        for round in 0..ROUNDS {
+            if round == WARMUP_ROUNDS {
+                baseline = HEAP_LIMIT.committed_bytes();
+            }
+            for (i, v) in receiver.recv().unwrap().into_iter().enumerate() {
+                assert_eq!(v[0], round ^ i);
+            }
+        }
+    });
+    let growth = HEAP_LIMIT.committed_bytes().saturating_sub(baseline);
+    assert!(growth < MAX_GROWTH, "grew by {} bytes", growth);
+}
+
 #[macro_export]
 #[doc(hidden)]
 macro_rules! rust_allocator_tests {
@@ -1,4 +1,7 @@
-use std::sync::{Barrier, Condvar, Mutex};
+use std::{
+    sync::{Barrier, Condvar, Mutex},
+    time::Duration,
+};
 
 use crate::space::meta::Meta;
 
@@ -36,6 +39,17 @@ impl<W: Worker> WorkerGroup<W> {
         let _guard = self.monitor.0.wait(should_wake_up).unwrap();
     }
 
+    /// Wait for a notification, or until `timeout` passes. Returns false on timeout.
+    pub fn wait_timeout(&self, _: &mut W, timeout: Duration) -> bool {
+        let should_wake_up = self.monitor.1.lock().unwrap();
+        let (_guard, result) = self
+            .monitor
+            .0
+            .wait_timeout(should_wake_up, timeout)
+            .unwrap();
+        !result.timed_out()
+    }
+
     fn spawn_one(&'static self, ctx: &'static W) {
         let ctx = ctx as *const W as *mut W;
         unsafe {
@@ -0,0 +1,51 @@
+#include <assert.h>
+#include <malloc.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define OBJECTS 64
+
+static const size_t sizes[] = {1, 16, 100, 4096, 5000};
+
+int main()
+{
+    for (size_t align = 16; align <= (2 << 20); align <<= 1)
+    {
+        for (size_t i = 0; i < sizeof(sizes) / sizeof(sizes[0]); i++)
+        {
+            size_t size = sizes[i];
+            void *objects[OBJECTS];
+            for (int j = 0; j < OBJECTS; j++)
+            {
+                void *p = NULL;
+                switch (j % 3)
+                {
+                case 0:
+                    assert(posix_memalign(&p, align, size) == 0);
+                    break;
+                case 1:
+                    p = memalign(align, size);
+                    break;
+                default:
+                    p = aligned_alloc(align, (size + align - 1) & ~(align - 1));
+                    break;
+                }
+                assert(p != NULL);
+                assert(((uintptr_t)p & (align - 1)) == 0);
+                size_t usable = malloc_usable_size(p);
+                assert(usable >= size);
+                // Small objects with large alignments must not take up a whole alignment unit.
+                if (j % 3 != 2 && align >= (64 << 10) && size <= 4096)
+                    assert(usable < align);
+                memset(p, 0xab, size);
+                objects[j] = p;
+            }
+            for (int j = 0; j < OBJECTS; j++)
+                free(objects[j]);
+        }
+    }
+    printf("OK\n");
+    return 0;
+}
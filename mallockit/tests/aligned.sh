@@ -0,0 +1,5 @@
+set -ex
+cd $(dirname $0)
+rm -f ./_aligned
+gcc ./aligned.c -O2 -o ./_aligned
+./_aligned
@@ -0,0 +1,5 @@
+set -ex
+cd $(dirname $0)
+rm -f ./_cxx
+g++ ./test.cpp -std=c++17 -O2 -o ./_cxx -ldl
+./_cxx
@@ -0,0 +1,173 @@
+#include <pthread.h>
+#include <stdatomic.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#define THREADS 4
+#define FORKS 100
+#define SLOTS 256
+// Bounded, so that plans that never free memory do not run out of it.
+#define MAX_ITERATIONS 100000
+
+static atomic_int stop;
+
+static size_t next_size(unsigned *seed)
+{
+    unsigned r = rand_r(seed);
+    // Mostly small objects, with the occasional large one.
+    return (r % 64 == 0) ? (r % (4 << 20)) + 1 : (r % 2048) + 1;
+}
+
+static void churn(unsigned seed, int iterations)
+{
+    void *slots[SLOTS] = {0};
+    for (int i = 0; i < iterations; i++)
+    {
+        if (atomic_load(&stop))
+            break;
+        int j = rand_r(&seed) % SLOTS;
+        free(slots[j]);
+        size_t size = next_size(&seed);
+        slots[j] = malloc(size);
+        memset(slots[j], 0xab, size < 64 ? size : 64);
+    }
+    for (int j = 0; j < SLOTS; j++)
+        free(slots[j]);
+}
+
+static void *mutator(void *arg)
+{
+    churn((unsigned)(size_t)arg, MAX_ITERATIONS);
+    return NULL;
+}
+
+static void *child_mutator(void *arg)
+{
+    churn((unsigned)(size_t)arg, 1000);
+    return NULL;
+}
+
+static int wait_for(pid_t pid)
+{
+    int status;
+    waitpid(pid, &status, 0);
+    return WIFEXITED(status) && WEXITSTATUS(status) == 0;
+}
+
+// Fork from a thread that never allocated, so that it has no mutator when the child handlers run.
+static void *fork_from_new_thread(void *arg)
+{
+    pid_t pid = fork();
+    if (pid == 0)
+    {
+        alarm(10);
+        churn((unsigned)(size_t)arg, 1000);
+        _exit(0);
+    }
+    return (void *)(size_t)(pid > 0 && wait_for(pid));
+}
+
+// Use a heap-backed FILE in children, and check that their writes to the heap stay out of the parent's.
+static int fork_with_file(void)
+{
+    char path[] = "/tmp/mallockit-fork-XXXXXX";
+    int fd = mkstemp(path);
+    if (fd < 0)
+        return 0;
+    close(fd);
+    FILE *file = fopen(path, "a");
+    int *shared = malloc(64);
+    *shared = 1;
+    for (int i = 0; i < 4; i++)
+    {
+        fflush(file);
+        pid_t pid = fork();
+        if (pid == 0)
+        {
+            alarm(10);
+            int ok = *shared == 1;
+            *shared = 2;
+            fprintf(file, "child %d\n", i);
+            // Outlive a background pass of the allocator, if it has one.
+            usleep(200000);
+            churn(i, 1000);
+            fprintf(file, "child %d\n", i);
+            fclose(file);
+            _exit(ok ? 0 : 1);
+        }
+        if (pid < 0 || !wait_for(pid) || *shared != 1)
+        {
+            fprintf(stderr, "child %d with an open file failed\n", i);
+            return 0;
+        }
+        fprintf(file, "parent %d\n", i);
+    }
+    fclose(file);
+    free(shared);
+    file = fopen(path, "r");
+    char line[32];
+    int lines = 0;
+    while (fgets(line, sizeof(line), file))
+        lines++;
+    fclose(file);
+    unlink(path);
+    return lines == 12;
+}
+
+int main()
+{
+    pthread_t threads[THREADS];
+    for (size_t i = 0; i < THREADS; i++)
+        pthread_create(&threads[i], NULL, mutator, (void *)(i + 1));
+    for (size_t i = 0; i < 8; i++)
+    {
+        pthread_t t;
+        void *ok;
+        pthread_create(&t, NULL, fork_from_new_thread, (void *)i);
+        pthread_join(t, &ok);
+        if (!ok)
+        {
+            fprintf(stderr, "child of a new thread failed\n");
+            return 1;
+        }
+    }
+    if (!fork_with_file())
+    {
+        fprintf(stderr, "fork with an open file failed\n");
+        return 1;
+    }
+    for (int i = 0; i < FORKS; i++)
+    {
+        pid_t pid = fork();
+        if (pid < 0)
+        {
+            perror("fork");
+            return 1;
+        }
+        if (pid == 0)
+        {
+            // A deadlocked child is killed by the alarm.
+            alarm(10);
+            churn(i, 1000);
+            pthread_t t;
+            pthread_create(&t, NULL, child_mutator, (void *)(size_t)i);
+            pthread_join(t, NULL);
+            _exit(0);
+        }
+        int status;
+        waitpid(pid, &status, 0);
+        if (!WIFEXITED(status) || WEXITSTATUS(status) != 0)
+        {
+            fprintf(stderr, "child %d failed with status %d\n", i, status);
+            return 1;
+        }
+    }
+    atomic_store(&stop, 1);
+    for (int i = 0; i < THREADS; i++)
+        pthread_join(threads[i], NULL);
+    printf("%d forks ok\n", FORKS);
+    return 0;
+}
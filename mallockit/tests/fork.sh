@@ -0,0 +1,5 @@
+set -ex
+cd $(dirname $0)
+rm -f ./_fork
+gcc ./fork.c -O2 -pthread -o ./_fork
+./_fork
@@ -0,0 +1,46 @@
+#define _GNU_SOURCE
+#include <assert.h>
+#include <errno.h>
+#include <malloc.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+// Not in glibc. Weak, so that the test links and picks it up from the preloaded allocator.
+size_t malloc_good_size(size_t size) __attribute__((weak));
+
+int main()
+{
+    assert(malloc_good_size != NULL);
+    for (size_t size = 0; size <= (4 << 20); size = size < 64 ? size + 1 : size + size / 7)
+    {
+        void *p = malloc(size);
+        assert(p != NULL);
+        size_t good_size = malloc_good_size(size);
+        assert(good_size >= size);
+        if (malloc_usable_size(p) != good_size)
+        {
+            printf("size %zu: usable size %zu, good size %zu\n", size, malloc_usable_size(p), good_size);
+            return 1;
+        }
+        // The whole usable size can be written.
+        memset(p, 0xab, good_size);
+        free(p);
+    }
+    assert(malloc_good_size(SIZE_MAX) == SIZE_MAX);
+    // Overflowing element counts
+    errno = 0;
+    assert(calloc(SIZE_MAX / 2, 3) == NULL && errno == ENOMEM);
+    char *p = malloc(16);
+    memset(p, 1, 16);
+    errno = 0;
+    assert(reallocarray(p, SIZE_MAX / 2, 3) == NULL && errno == ENOMEM);
+    assert(p[15] == 1);
+    p = reallocarray(p, 100, 40);
+    assert(p != NULL && p[15] == 1);
+    assert(malloc_usable_size(p) >= 4000);
+    free(p);
+    printf("OK\n");
+    return 0;
+}
@@ -0,0 +1,5 @@
+set -ex
+cd $(dirname $0)
+rm -f ./_good_size
+gcc ./good_size.c -O2 -o ./_good_size
+./_good_size
@@ -0,0 +1,108 @@
+#include <assert.h>
+#include <errno.h>
+#include <stdbool.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+enum oom_kind
+{
+    SOFT_LIMIT,
+    OUT_OF_MEMORY,
+};
+
+struct oom_info
+{
+    enum oom_kind kind;
+    size_t request_bytes;
+    size_t committed_bytes;
+};
+
+// Not in glibc. Weak, so that the test links and picks them up from the preloaded allocator.
+void mallockit_set_soft_heap_limit(size_t bytes) __attribute__((weak));
+void mallockit_set_hard_heap_limit(size_t bytes) __attribute__((weak));
+void mallockit_set_oom_callback(bool (*callback)(const struct oom_info *)) __attribute__((weak));
+size_t mallockit_committed_heap_bytes(void) __attribute__((weak));
+
+#define MB (1 << 20)
+
+// Written by the callback from inside `malloc`, which the compiler assumes does not touch globals.
+static void *volatile cache;
+static volatile int events[2];
+
+static bool release_cache(const struct oom_info *info)
+{
+    events[info->kind]++;
+    if (cache == NULL)
+        return false;
+    free(cache);
+    cache = NULL;
+    return true;
+}
+
+// Whether the preloaded allocator is the plan `name`.
+static bool is_plan(const char *name)
+{
+    const char *preload = getenv("LD_PRELOAD");
+    char lib[64];
+    snprintf(lib, sizeof(lib), "/lib%s.", name);
+    return preload != NULL && strstr(preload, lib) != NULL;
+}
+
+int main()
+{
+    assert(mallockit_set_soft_heap_limit != NULL && mallockit_set_hard_heap_limit != NULL);
+    assert(mallockit_set_oom_callback != NULL && mallockit_committed_heap_bytes != NULL);
+    size_t committed = mallockit_committed_heap_bytes();
+    void *p = malloc(40 * MB);
+    assert(p != NULL);
+    size_t used = mallockit_committed_heap_bytes();
+    free(p);
+    if (used == committed)
+    {
+        // Only gc does not count its memory against the limits. Every other plan must enforce them.
+        assert(is_plan("gc"));
+        printf("SKIP (gc does not charge the heap limits)\n");
+        return 0;
+    }
+    // Freed memory is given back, so that the callback can make room. Except by bump, which never frees.
+    bool releases = mallockit_committed_heap_bytes() < used;
+    assert(releases == !is_plan("bump"));
+    committed = mallockit_committed_heap_bytes();
+    mallockit_set_oom_callback(release_cache);
+    mallockit_set_soft_heap_limit(committed + 32 * MB);
+    mallockit_set_hard_heap_limit(committed + 64 * MB);
+    // Crossing the soft limit is reported once.
+    cache = malloc(40 * MB);
+    assert(cache != NULL);
+    memset(cache, 1, 40 * MB);
+    assert(events[SOFT_LIMIT] == 1 && events[OUT_OF_MEMORY] == 0);
+    p = NULL;
+    if (releases)
+    {
+        // Over the hard limit, the callback releases the cache and the allocation is retried.
+        p = malloc(40 * MB);
+        assert(p != NULL && cache == NULL);
+        assert(events[OUT_OF_MEMORY] == 1);
+    }
+    // Nothing left to release. The callback is still asked, and the allocation fails.
+    int reported = events[OUT_OF_MEMORY];
+    errno = 0;
+    assert(malloc(40 * MB) == NULL && errno == ENOMEM);
+    assert(events[OUT_OF_MEMORY] > reported);
+    assert(cache == NULL);
+    // Without a callback, an allocation over the hard limit fails right away.
+    mallockit_set_oom_callback(NULL);
+    reported = events[OUT_OF_MEMORY];
+    errno = 0;
+    assert(malloc(80 * MB) == NULL && errno == ENOMEM);
+    assert(events[OUT_OF_MEMORY] == reported);
+    free(p);
+    mallockit_set_hard_heap_limit(0);
+    mallockit_set_soft_heap_limit(0);
+    p = malloc(100 * MB);
+    assert(p != NULL);
+    free(p);
+    printf("OK\n");
+    return 0;
+}
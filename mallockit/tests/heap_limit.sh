@@ -0,0 +1,5 @@
+set -ex
+cd $(dirname $0)
+rm -f ./_heap_limit
+gcc ./heap_limit.c -O2 -o ./_heap_limit
+./_heap_limit
@@ -0,0 +1,52 @@
+#include <assert.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+// C23. Weak, so that the test links against a libc without them and picks them up from the preloaded allocator.
+void free_sized(void *ptr, size_t size) __attribute__((weak));
+void free_aligned_sized(void *ptr, size_t alignment, size_t size) __attribute__((weak));
+
+#define OBJECTS 64
+
+static const size_t sizes[] = {0, 1, 16, 100, 1000, 4096, 5000, 70000, 1 << 20, 3 << 20};
+
+int main()
+{
+    assert(free_sized != NULL && free_aligned_sized != NULL);
+    for (int round = 0; round < 4; round++)
+    {
+        for (size_t i = 0; i < sizeof(sizes) / sizeof(sizes[0]); i++)
+        {
+            size_t size = sizes[i];
+            void *objects[OBJECTS];
+            for (int j = 0; j < OBJECTS; j++)
+            {
+                objects[j] = malloc(size);
+                assert(objects[j] != NULL || size == 0);
+                memset(objects[j], 0xab, size);
+            }
+            for (int j = 0; j < OBJECTS; j++)
+                free_sized(objects[j], size);
+            // Shrinking keeps the new size valid for sized deallocation.
+            void *p = malloc(size);
+            p = realloc(p, size / 3 + 1);
+            free_sized(p, size / 3 + 1);
+            for (size_t align = 16; align <= (1 << 20); align <<= 2)
+            {
+                size_t aligned_size = (size + align - 1) & ~(align - 1);
+                p = aligned_alloc(align, aligned_size);
+                assert(p != NULL || aligned_size == 0);
+                assert(((uintptr_t)p & (align - 1)) == 0);
+                free_aligned_sized(p, align, aligned_size);
+                assert(posix_memalign(&p, align, size) == 0);
+                free_aligned_sized(p, align, size);
+            }
+        }
+    }
+    free_sized(NULL, 16);
+    free_aligned_sized(NULL, 64, 16);
+    printf("OK\n");
+    return 0;
+}
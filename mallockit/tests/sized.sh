@@ -0,0 +1,5 @@
+set -ex
+cd $(dirname $0)
+rm -f ./_sized
+gcc ./sized.c -O2 -o ./_sized
+./_sized
@@ -1,5 +1,10 @@
 #include <array>
+#include <cassert>
+#include <cstdint>
+#include <cstring>
+#include <dlfcn.h>
 #include <iostream>
+#include <new>
 
 using namespace std;
 
@@ -17,8 +22,117 @@ constexpr Matrix<M, P> operator*(Matrix<M, N> A, Matrix<N, P> B)
     return C;
 }
 
+// All replaceable operator new and delete variants
+static const char *operators[] = {
+    "_Znwm",
+    "_Znam",
+    "_ZnwmRKSt9nothrow_t",
+    "_ZnamRKSt9nothrow_t",
+    "_ZnwmSt11align_val_t",
+    "_ZnamSt11align_val_t",
+    "_ZnwmSt11align_val_tRKSt9nothrow_t",
+    "_ZnamSt11align_val_tRKSt9nothrow_t",
+    "_ZdlPv",
+    "_ZdaPv",
+    "_ZdlPvRKSt9nothrow_t",
+    "_ZdaPvRKSt9nothrow_t",
+    "_ZdlPvSt11align_val_t",
+    "_ZdaPvSt11align_val_t",
+    "_ZdlPvSt11align_val_tRKSt9nothrow_t",
+    "_ZdaPvSt11align_val_tRKSt9nothrow_t",
+    "_ZdlPvm",
+    "_ZdaPvm",
+    "_ZdlPvmSt11align_val_t",
+    "_ZdaPvmSt11align_val_t",
+};
+
+static const char *object_of(void *symbol)
+{
+    Dl_info info;
+    assert(symbol != nullptr && dladdr(symbol, &info) != 0);
+    return info.dli_fname;
+}
+
+// Only meaningful under LD_PRELOAD: every operator must come from the same library as malloc, not libstdc++.
+static void check_symbols()
+{
+    if (getenv("LD_PRELOAD") == nullptr)
+        return;
+    const char *malloc_object = object_of(dlsym(RTLD_DEFAULT, "malloc"));
+    assert(strcmp(malloc_object, object_of(dlsym(RTLD_DEFAULT, "printf"))) != 0);
+    for (auto name : operators)
+    {
+        const char *object = object_of(dlsym(RTLD_DEFAULT, name));
+        if (strcmp(object, malloc_object) != 0)
+        {
+            cerr << name << " resolves to " << object << ", not " << malloc_object << "\n";
+            abort();
+        }
+    }
+}
+
+static int handler_calls = 0;
+
+static void retry_handler()
+{
+    if (++handler_calls % 3 == 0)
+        set_new_handler(nullptr);
+}
+
+static void check_new_handler()
+{
+    const size_t too_large = size_t(1) << 62;
+    set_new_handler(retry_handler);
+    try
+    {
+        void *volatile p = ::operator new(too_large);
+        abort();
+    }
+    catch (const bad_alloc &)
+    {
+    }
+    assert(handler_calls == 3);
+    set_new_handler(retry_handler);
+    try
+    {
+        void *volatile p = ::operator new(too_large, align_val_t(64));
+        abort();
+    }
+    catch (const bad_alloc &)
+    {
+    }
+    assert(handler_calls == 6);
+    assert(::operator new(too_large, nothrow) == nullptr);
+    assert(::operator new[](too_large, align_val_t(64), nothrow) == nullptr);
+}
+
+static void check_new_delete()
+{
+    for (size_t size = 1; size <= (1 << 20); size *= 3)
+    {
+        auto p = new char[size];
+        memset(p, 1, size);
+        delete[] p;
+        void *q = ::operator new(size);
+        ::operator delete(q, size);
+        for (size_t align = 16; align <= 8192; align *= 4)
+        {
+            void *r = ::operator new[](size, align_val_t(align));
+            assert((uintptr_t(r) & (align - 1)) == 0);
+            ::operator delete[](r, size, align_val_t(align));
+            r = ::operator new(size, align_val_t(align), nothrow);
+            assert(r != nullptr && (uintptr_t(r) & (align - 1)) == 0);
+            ::operator delete(r, align_val_t(align), nothrow);
+        }
+    }
+}
+
 int main()
 {
+    check_symbols();
+    check_new_handler();
+    check_new_delete();
+
     constexpr Matrix<2, 3> A = {{
         {1, 2, 3},
         {4, 5, 6},
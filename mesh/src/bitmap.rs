@@ -0,0 +1,158 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bits per word.
+const BITS: usize = usize::BITS as usize;
+
+/// Live cells of a block, one bit per cell.
+///
+/// Only the owner of a block sets bits. Any thread may clear them, without a lock.
+#[repr(C)]
+pub struct OccupancyBitmap<const WORDS: usize>([AtomicUsize; WORDS]);
+
+impl<const WORDS: usize> OccupancyBitmap<WORDS> {
+    /// Bits of word `w` that stand for one of the first `cells` cells.
+    const fn valid_bits(w: usize, cells: usize) -> usize {
+        if cells >= (w + 1) * BITS {
+            !0
+        } else if cells <= w * BITS {
+            0
+        } else {
+            (1 << (cells - w * BITS)) - 1
+        }
+    }
+
+    const fn words(cells: usize) -> usize {
+        cells.div_ceil(BITS)
+    }
+
+    pub fn clear_all(&self) {
+        for word in &self.0 {
+            word.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Set a clear bit among the first `cells`. The search starts from a word and bit picked by `hint`.
+    /// Only called by the owner.
+    pub fn set_any(&self, cells: usize, hint: usize) -> Option<usize> {
+        let words = Self::words(cells);
+        let first_bit = (hint / words) % BITS;
+        for i in 0..words {
+            let w = (hint + i) % words;
+            let free = !self.0[w].load(Ordering::Relaxed) & Self::valid_bits(w, cells);
+            if free != 0 {
+                let bit = (free.rotate_right(first_bit as u32).trailing_zeros() as usize
+                    + first_bit)
+                    % BITS;
+                self.0[w].fetch_or(1 << bit, Ordering::Relaxed);
+                return Some(w * BITS + bit);
+            }
+        }
+        None
+    }
+
+    /// Clear bit `i`. Returns false if it was clear already.
+    pub fn clear(&self, i: usize) -> bool {
+        let mask = 1 << (i % BITS);
+        self.0[i / BITS].fetch_and(!mask, Ordering::SeqCst) & mask != 0
+    }
+
+    pub fn is_set(&self, i: usize) -> bool {
+        self.0[i / BITS].load(Ordering::Relaxed) & (1 << (i % BITS)) != 0
+    }
+
+    pub fn count(&self) -> usize {
+        self.0
+            .iter()
+            .map(|w| w.load(Ordering::Relaxed).count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|w| w.load(Ordering::Relaxed) == 0)
+    }
+
+    /// Whether all of the first `cells` bits are set.
+    pub fn is_full(&self, cells: usize) -> bool {
+        (0..Self::words(cells)).all(|w| {
+            let valid = Self::valid_bits(w, cells);
+            self.0[w].load(Ordering::SeqCst) & valid == valid
+        })
+    }
+
+    /// Whether no cell is live in both bitmaps.
+    pub fn is_disjoint(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .all(|(a, b)| a.load(Ordering::Relaxed) & b.load(Ordering::Relaxed) == 0)
+    }
+
+    /// Indices of the set bits.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(w, word)| {
+            let mut bits = word.load(Ordering::Relaxed);
+            std::iter::from_fn(move || {
+                if bits == 0 {
+                    return None;
+                }
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                Some(w * BITS + bit)
+            })
+        })
+    }
+
+    /// Move all set bits into `other`, leaving this bitmap empty.
+    pub fn move_into(&self, other: &Self) {
+        for (a, b) in self.0.iter().zip(&other.0) {
+            b.fetch_or(a.swap(0, Ordering::AcqRel), Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitmap() -> OccupancyBitmap<2> {
+        OccupancyBitmap([AtomicUsize::new(0), AtomicUsize::new(0)])
+    }
+
+    #[test]
+    fn set_and_clear() {
+        let bitmap = bitmap();
+        for i in 0..70 {
+            assert_eq!(bitmap.set_any(70, 0), Some(i));
+        }
+        assert!(bitmap.is_full(70));
+        assert_eq!(bitmap.set_any(70, 1), None);
+        assert!(bitmap.clear(65));
+        assert!(!bitmap.clear(65));
+        assert_eq!(bitmap.set_any(70, 1), Some(65));
+        // Searches start from the hinted bit.
+        bitmap.clear(3);
+        bitmap.clear(9);
+        assert_eq!(bitmap.set_any(70, 2 * 5), Some(9));
+        assert_eq!(bitmap.set_any(70, 2 * 5), Some(3));
+        assert_eq!(bitmap.count(), 70);
+    }
+
+    #[test]
+    fn disjoint_and_move() {
+        let (a, b) = (bitmap(), bitmap());
+        for _ in 0..128 {
+            a.set_any(128, 0);
+            b.set_any(128, 0);
+        }
+        for i in 0..128 {
+            assert!(if i % 2 == 0 { a.clear(i) } else { b.clear(i) });
+        }
+        assert!(a.is_disjoint(&b));
+        b.move_into(&a);
+        assert!(b.is_empty());
+        assert!(a.is_full(128));
+        assert_eq!(a.iter().count(), 128);
+        a.clear(3);
+        assert!(!a.iter().any(|i| i == 3));
+    }
+}
@@ -0,0 +1,225 @@
+#![feature(thread_local)]
+#![feature(allocator_api)]
+#![feature(naked_functions)]
+
+extern crate mallockit;
+
+mod bitmap;
+mod mesh_space;
+mod write_barrier;
+
+use mallockit::{
+    space::{large_object_space::*, *},
+    util::*,
+    worker::{Worker, WorkerGroup, WorkerId},
+    Mutator, Plan,
+};
+use mesh_space::*;
+use std::time::Duration;
+
+const MESH_SPACE: SpaceId = SpaceId::DEFAULT;
+const LARGE_OBJECT_SPACE: SpaceId = SpaceId::LARGE_OBJECT_SPACE;
+/// How often the background thread meshes blocks.
+const MESH_PERIOD: Duration = Duration::from_millis(100);
+
+/// A plan that reduces fragmentation without moving objects, by meshing sparse blocks of small objects.
+///
+/// A background thread periodically meshes the blocks that threads have detached. See `MeshSpace`.
+///
+/// Writes to a block while it is meshed wait on a `SIGSEGV` handler, which has two limitations:
+/// - A system call that writes to the block meanwhile, e.g. `read` into a heap buffer, fails with `EFAULT`.
+/// - A `SIGSEGV` handler installed by the program replaces the plan's, and gets those faults instead.
+#[mallockit::plan]
+struct Mesh {
+    mesh_space: MeshSpace,
+    large_object_space: LargeObjectSpace,
+    mesher: WorkerGroup<Mesher>,
+}
+
+impl Plan for Mesh {
+    type Mutator = MeshMutator;
+
+    fn new() -> Self {
+        Self {
+            mesh_space: MeshSpace::new(MESH_SPACE),
+            large_object_space: LargeObjectSpace::new(LARGE_OBJECT_SPACE),
+            mesher: WorkerGroup::new(1),
+        }
+    }
+
+    fn init(&'static self) {
+        self.mesher.spawn();
+    }
+
+    fn get_layout(ptr: Address) -> Layout {
+        debug_assert!(MESH_SPACE.contains(ptr) || LARGE_OBJECT_SPACE.contains(ptr));
+        if MESH_SPACE.contains(ptr) {
+            Self::get().mesh_space.get_layout(ptr)
+        } else {
+            Self::get().large_object_space.get_layout::<Size4K>(ptr)
+        }
+    }
+
+    fn good_size(layout: Layout) -> usize {
+        if MeshSpace::can_allocate(layout) {
+            MeshSpace::good_size(layout)
+        } else {
+            LargeObjectSpace::good_size::<Size4K>(layout)
+        }
+    }
+
+    fn fork_prepare(&'static self) {
+        self.mesh_space.fork_prepare();
+        self.large_object_space.fork_prepare();
+    }
+
+    fn fork_parent(&'static self) {
+        self.large_object_space.fork_release();
+        self.mesh_space.fork_parent();
+    }
+
+    fn fork_child(&'static self) {
+        self.large_object_space.fork_release();
+        self.mesh_space.fork_child();
+        self.mesh_space
+            .reclaim_blocks_after_fork(MeshMutator::try_current().map(|m| &m.mesh));
+        // The mesher thread does not exist in the child.
+        self.mesher.spawn();
+    }
+}
+
+/// Physical memory backing the small objects, for applications to check.
+#[no_mangle]
+pub extern "C" fn mesh_physical_bytes() -> usize {
+    Mesh::get().mesh_space.physical_bytes()
+}
+
+/// Number of blocks meshed onto others so far.
+#[no_mangle]
+pub extern "C" fn mesh_meshed_blocks() -> usize {
+    Mesh::get().mesh_space.meshes()
+}
+
+/// Meshes the blocks of the plan every `MESH_PERIOD`, or when notified.
+struct Mesher;
+
+impl Worker for Mesher {
+    fn new(_id: WorkerId) -> Self {
+        Self
+    }
+
+    fn run(&'static mut self) {
+        let group = &Mesh::get().mesher;
+        loop {
+            group.wait_timeout(self, MESH_PERIOD);
+            Mesh::get().mesh_space.mesh_all();
+        }
+    }
+}
+
+#[mallockit::mutator]
+struct MeshMutator {
+    mesh: MeshAllocator,
+    los: LargeObjectAllocator<Size4K>,
+}
+
+impl Mutator for MeshMutator {
+    type Plan = Mesh;
+
+    fn new() -> Self {
+        Self {
+            mesh: MeshAllocator::new(&Self::plan().mesh_space),
+            los: LargeObjectAllocator::new(&Self::plan().large_object_space),
+        }
+    }
+
+    #[inline(always)]
+    fn alloc(&mut self, layout: Layout) -> Option<Address> {
+        if MeshSpace::can_allocate(layout) {
+            mallockit::stat::track_allocation(layout, false);
+            self.mesh.alloc(layout)
+        } else {
+            mallockit::stat::track_allocation(layout, true);
+            self.los.alloc(layout)
+        }
+    }
+
+    #[inline(always)]
+    fn dealloc(&mut self, ptr: Address) {
+        debug_assert!(MESH_SPACE.contains(ptr) || LARGE_OBJECT_SPACE.contains(ptr));
+        if MESH_SPACE.contains(ptr) {
+            mallockit::stat::track_deallocation(false);
+            self.mesh.dealloc(ptr)
+        } else {
+            mallockit::stat::track_deallocation(true);
+            self.los.dealloc(ptr)
+        }
+    }
+
+    fn on_thread_exit(&mut self) {
+        self.mesh.flush();
+        self.los.flush();
+    }
+}
+
+#[cfg(test)]
+mod thread_exit_tests {
+    mallockit::rust_allocator_tests!(crate::Global, short_lived_threads);
+}
+
+#[cfg(test)]
+mod cross_thread_tests {
+    mallockit::rust_allocator_tests!(crate::Global, producer_consumer);
+}
+
+#[cfg(test)]
+mod mesh_tests {
+    use super::*;
+    use std::alloc::GlobalAlloc;
+
+    /// Fragment the heap from a thread that then exits, and mesh it while another thread writes to the objects.
+    #[test]
+    fn mesh_fragmented_heap() {
+        const OBJECTS: usize = 1 << 14;
+        const ROUNDS: usize = 1000;
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let survivors = std::thread::spawn(move || {
+            let objects = (0..OBJECTS)
+                .map(|_| Address::from(unsafe { Global.alloc(layout) }))
+                .collect::<std::vec::Vec<_>>();
+            // Keep every eighth object.
+            let mut survivors = std::vec::Vec::new();
+            for (i, object) in objects.into_iter().enumerate() {
+                if i % 8 == 0 {
+                    unsafe { object.store(0usize) };
+                    survivors.push(object);
+                } else {
+                    unsafe { Global.dealloc(object.as_mut_ptr(), layout) };
+                }
+            }
+            survivors
+        })
+        .join()
+        .unwrap();
+        let writer = {
+            let survivors = survivors.clone();
+            std::thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    for &object in &survivors {
+                        assert_eq!(unsafe { object.load::<usize>() }, round);
+                        unsafe { object.store(round + 1) };
+                    }
+                }
+            })
+        };
+        let space = &Mesh::get().mesh_space;
+        let meshes = space.meshes();
+        space.mesh_all();
+        assert!(space.meshes() > meshes);
+        writer.join().unwrap();
+        for &object in &survivors {
+            assert_eq!(unsafe { object.load::<usize>() }, ROUNDS);
+            unsafe { Global.dealloc(object.as_mut_ptr(), layout) };
+        }
+    }
+}
@@ -0,0 +1,804 @@
+use crate::{bitmap::OccupancyBitmap, write_barrier::WriteBarrier};
+use mallockit::{
+    libc,
+    space::{
+        meta::{Meta, Vec},
+        SpaceId,
+    },
+    util::{constants::LOG_MIN_ALIGNMENT, mem::heap::HEAP, sys::RawMemory, *},
+};
+use spin::Mutex;
+use std::{
+    os::fd::RawFd,
+    sync::atomic::{AtomicI32, AtomicU32, AtomicU8, AtomicUsize, Ordering},
+};
+
+const LOG_BLOCK_BYTES: usize = 14;
+/// Blocks hold cells of one size class, and are the unit of meshing.
+pub const BLOCK_BYTES: usize = 1 << LOG_BLOCK_BYTES;
+const MAX_CELL_BYTES: usize = BLOCK_BYTES / 4;
+pub const SIZE_CLASSES: usize = SizeClass::<4>::from_bytes(MAX_CELL_BYTES).as_usize() + 1;
+const MAX_CELLS: usize = BLOCK_BYTES >> LOG_MIN_ALIGNMENT;
+/// Virtual memory of the space, and size of its memory file.
+const ARENA_BYTES: usize = 32 << 30;
+const BLOCKS: usize = ARENA_BYTES / BLOCK_BYTES;
+const NONE: u32 = u32::MAX;
+/// `MeshSpace::deferred_frees` while the space is shared.
+const SHARED: usize = 1;
+
+/// Only blocks at most this full are meshed.
+const MAX_MESH_OCCUPANCY: f64 = 0.5;
+/// Blocks after each candidate that are checked for a disjoint bitmap.
+const MESH_PROBES: usize = 64;
+
+/// Block states.
+const FREE: u8 = 0;
+/// Allocated from by a thread.
+const ATTACHED: u8 = 1;
+/// Detached, with free cells, and in the partial list of its size class.
+const LISTED: u8 = 2;
+/// Detached and full. Listed again by the first free.
+const UNLISTED: u8 = 3;
+/// Meshed onto another block, which backs this one and owns its cells.
+const ALIAS: u8 = 4;
+
+#[repr(C)]
+struct BlockMeta {
+    live: OccupancyBitmap<{ MAX_CELLS / usize::BITS as usize }>,
+    /// The block whose physical memory backs this one. Itself unless meshed.
+    primary: AtomicU32,
+    /// The next block meshed onto the same primary.
+    next_alias: AtomicU32,
+    size_class: AtomicU8,
+    state: AtomicU8,
+}
+
+struct MeshState {
+    /// Detached blocks with free cells, per size class.
+    partial: [Vec<u32>; SIZE_CLASSES],
+    /// Unused virtual blocks below the cursor. Their memory is released.
+    free_blocks: Vec<u32>,
+}
+
+/// Small objects in blocks of a memory file, so that blocks can be meshed without moving objects.
+///
+/// Two blocks of a size class whose live cells do not overlap are meshed by copying the cells of one into
+/// the other, then mapping both virtual blocks onto the same file pages and releasing the others.
+/// Cells are freed by clearing their bit in the occupancy bitmap of the primary block, from any thread.
+///
+/// Without `memfd_create` the space is anonymous memory, and blocks are never meshed.
+///
+/// `fork` makes the space a copy-on-write view of the memory file in both processes, so that neither writes
+/// to the file the other one maps. Each process copies the space to a file of its own before it next meshes.
+pub struct MeshSpace {
+    start: Address,
+    fd: AtomicI32,
+    /// `SHARED`, or the cells freed through meshed blocks since the space became private after `fork`,
+    /// linked through their first word.
+    deferred_frees: AtomicUsize,
+    metas: Address,
+    barrier: WriteBarrier,
+    state: Mutex<MeshState>,
+    /// Blocks are carved out of the arena up to here. Only changed with `state` locked.
+    cursor: AtomicU32,
+    physical_blocks: AtomicUsize,
+    meshes: AtomicUsize,
+    /// Odd while the cells of a block move to another.
+    mesh_epoch: AtomicUsize,
+}
+
+impl MeshSpace {
+    pub fn new(id: SpaceId) -> Self {
+        let start = HEAP.get_space_range(id).start;
+        let fd = RawMemory::create_shared_file(c"mesh", ARENA_BYTES)
+            .ok()
+            .filter(|fd| RawMemory::map_shared(start, ARENA_BYTES, *fd, 0).is_ok())
+            .unwrap_or(-1);
+        let meta_bytes =
+            (BLOCKS * std::mem::size_of::<BlockMeta>()).next_multiple_of(Size4K::BYTES);
+        Self {
+            start,
+            fd: AtomicI32::new(fd),
+            deferred_frees: AtomicUsize::new(SHARED),
+            metas: RawMemory::map_anonymous(meta_bytes).unwrap(),
+            barrier: WriteBarrier::new(start..start + ARENA_BYTES),
+            state: Mutex::new(MeshState {
+                partial: std::array::from_fn(|_| Vec::new_in(Meta)),
+                free_blocks: Vec::new_in(Meta),
+            }),
+            cursor: AtomicU32::new(0),
+            physical_blocks: AtomicUsize::new(0),
+            meshes: AtomicUsize::new(0),
+            mesh_epoch: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn can_allocate(layout: Layout) -> bool {
+        SizeClass::<4>::from_layout(layout).bytes() <= MAX_CELL_BYTES
+    }
+
+    /// The usable size of an object allocated with `layout`: its size class.
+    pub fn good_size(layout: Layout) -> usize {
+        SizeClass::<4>::from_layout(layout).bytes()
+    }
+
+    pub fn get_layout(&self, ptr: Address) -> Layout {
+        self.meta(self.block_of(ptr)).size_class().layout()
+    }
+
+    /// Physical memory backing the blocks in use.
+    pub fn physical_bytes(&self) -> usize {
+        self.physical_blocks.load(Ordering::Relaxed) * BLOCK_BYTES
+    }
+
+    /// Blocks meshed onto others so far.
+    pub fn meshes(&self) -> usize {
+        self.meshes.load(Ordering::Relaxed)
+    }
+
+    fn fd(&self) -> RawFd {
+        self.fd.load(Ordering::Relaxed)
+    }
+
+    fn block_of(&self, ptr: Address) -> u32 {
+        ((ptr - self.start) >> LOG_BLOCK_BYTES) as u32
+    }
+
+    fn block_start(&self, block: u32) -> Address {
+        self.start + ((block as usize) << LOG_BLOCK_BYTES)
+    }
+
+    fn meta(&self, block: u32) -> &'static BlockMeta {
+        debug_assert!((block as usize) < BLOCKS);
+        unsafe { (self.metas + block as usize * std::mem::size_of::<BlockMeta>()).as_ref() }
+    }
+
+    /// The blocks backed by `primary`, starting with itself.
+    fn aliases(&self, primary: u32) -> impl Iterator<Item = u32> + '_ {
+        std::iter::successors(Some(primary), |b| {
+            Some(self.meta(*b).next_alias.load(Ordering::Relaxed)).filter(|b| *b != NONE)
+        })
+    }
+
+    /// Allocate a cell of an attached block. Only called by its owner.
+    pub fn alloc_cell(&self, block: u32, hint: usize) -> Option<Address> {
+        let meta = self.meta(block);
+        let log_bytes = meta.size_class().log_bytes();
+        let cell = meta.live.set_any(BLOCK_BYTES >> log_bytes, hint)?;
+        Some(self.block_start(block) + (cell << log_bytes))
+    }
+
+    /// Free a cell from any thread.
+    pub fn free_cell(&self, ptr: Address) {
+        let block = self.block_of(ptr);
+        if self.defer_free(block, ptr) {
+            return;
+        }
+        let offset = ptr - self.block_start(block);
+        loop {
+            let epoch = self.mesh_epoch.load(Ordering::SeqCst);
+            let primary = self.meta(block).primary.load(Ordering::Acquire);
+            let meta = self.meta(primary);
+            let cell = offset >> meta.size_class().log_bytes();
+            if meta.live.clear(cell) {
+                if meta.state.load(Ordering::SeqCst) == UNLISTED {
+                    self.relist(primary);
+                }
+                return;
+            }
+            // The cell may be moving to the new primary of a block being meshed.
+            if epoch % 2 == 0 && self.mesh_epoch.load(Ordering::SeqCst) == epoch {
+                debug_assert!(false, "double free");
+                return;
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// While the space is private, cells freed through a meshed block are only freed once it is shared again.
+    /// Until then, their memory is not reused, so that it is only ever written through that block.
+    fn defer_free(&self, block: u32, ptr: Address) -> bool {
+        if self.meta(block).state.load(Ordering::Relaxed) != ALIAS {
+            return false;
+        }
+        let mut head = self.deferred_frees.load(Ordering::Relaxed);
+        loop {
+            if head == SHARED {
+                return false;
+            }
+            unsafe { ptr.store(head) };
+            match self.deferred_frees.compare_exchange_weak(
+                head,
+                usize::from(ptr),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    fn is_private(&self) -> bool {
+        self.deferred_frees.load(Ordering::Relaxed) != SHARED
+    }
+
+    fn relist(&self, block: u32) {
+        let meta = self.meta(block);
+        let mut state = self.state.lock();
+        if meta
+            .state
+            .compare_exchange(UNLISTED, LISTED, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+        {
+            state.partial[meta.size_class().as_usize()].push(block);
+        }
+    }
+
+    /// Take a detached block with free cells, or a new one.
+    pub fn acquire_block(&self, size_class: SizeClass) -> Option<u32> {
+        let mut state = self.state.lock();
+        while let Some(block) = state.partial[size_class.as_usize()].pop() {
+            let meta = self.meta(block);
+            debug_assert_eq!(meta.state.load(Ordering::Relaxed), LISTED);
+            let cells = BLOCK_BYTES >> size_class.log_bytes();
+            if meta.live.is_empty() {
+                self.release_block(&mut state, block);
+                continue;
+            }
+            // Meshing may have filled the block up. It is listed again by the next free.
+            if meta.live.is_full(cells) {
+                meta.state.store(UNLISTED, Ordering::SeqCst);
+                if meta.live.is_full(cells) {
+                    continue;
+                }
+            }
+            meta.state.store(ATTACHED, Ordering::SeqCst);
+            return Some(block);
+        }
+        let block = match state.free_blocks.pop() {
+            Some(block) => block,
+            None => {
+                let block = self.cursor.load(Ordering::Relaxed);
+                if block as usize >= BLOCKS {
+                    return None;
+                }
+                self.cursor.store(block + 1, Ordering::Relaxed);
+                block
+            }
+        };
+        let meta = self.meta(block);
+        meta.live.clear_all();
+        meta.primary.store(block, Ordering::Relaxed);
+        meta.next_alias.store(NONE, Ordering::Relaxed);
+        meta.size_class.store(size_class.0, Ordering::Relaxed);
+        meta.state.store(ATTACHED, Ordering::SeqCst);
+        self.physical_blocks.fetch_add(1, Ordering::Relaxed);
+        Some(block)
+    }
+
+    /// Give up an attached block. Full blocks are only listed again once a cell is freed.
+    pub fn detach_block(&self, block: u32) {
+        let meta = self.meta(block);
+        let cells = BLOCK_BYTES >> meta.size_class().log_bytes();
+        let mut current = ATTACHED;
+        if meta.live.is_full(cells) {
+            meta.state.store(UNLISTED, Ordering::SeqCst);
+            current = UNLISTED;
+            // A free that saw the block attached did not list it.
+            if meta.live.is_full(cells) {
+                return;
+            }
+        }
+        let mut state = self.state.lock();
+        // A free may have listed the block since, and another thread attached it.
+        if meta
+            .state
+            .compare_exchange(current, LISTED, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+        {
+            state.partial[meta.size_class().as_usize()].push(block);
+        }
+    }
+
+    /// Release the memory of an empty block, and of the blocks meshed onto it.
+    fn release_block(&self, state: &mut MeshState, block: u32) {
+        let fd = self.fd();
+        let mut next = block;
+        while next != NONE {
+            let alias = next;
+            next = self.meta(alias).next_alias.load(Ordering::Relaxed);
+            let start = self.block_start(alias);
+            if fd < 0 || (alias == block && self.is_private()) {
+                // Another process may still read the file pages.
+                RawMemory::madv_dontneed(start, BLOCK_BYTES);
+            } else if alias == block {
+                RawMemory::punch_hole(fd, Self::offset(block), BLOCK_BYTES);
+            } else if self.is_private() {
+                RawMemory::map_private(start, BLOCK_BYTES, fd, Self::offset(alias)).unwrap();
+            } else {
+                // Back it with its own, already released, file pages again.
+                RawMemory::map_shared(start, BLOCK_BYTES, fd, Self::offset(alias)).unwrap();
+            }
+            let meta = self.meta(alias);
+            meta.primary.store(alias, Ordering::Relaxed);
+            meta.next_alias.store(NONE, Ordering::Relaxed);
+            meta.state.store(FREE, Ordering::Relaxed);
+            state.free_blocks.push(alias);
+        }
+        self.physical_blocks.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    const fn offset(block: u32) -> usize {
+        (block as usize) << LOG_BLOCK_BYTES
+    }
+
+    /// Release empty detached blocks, and mesh detached blocks with disjoint live cells.
+    /// Returns the number of blocks meshed onto others.
+    pub fn mesh_all(&self) -> usize {
+        let mut state = self.state.lock();
+        let shared = !self.is_private() || self.unshare(&mut state);
+        let mut meshed = 0;
+        for size_class in 0..SIZE_CLASSES {
+            let mut blocks = std::mem::replace(&mut state.partial[size_class], Vec::new_in(Meta));
+            blocks.retain(|&block| {
+                let empty = self.meta(block).live.is_empty();
+                if empty {
+                    self.release_block(&mut state, block);
+                }
+                !empty
+            });
+            if self.fd() >= 0 && shared {
+                meshed += self.mesh_size_class(&mut blocks, SizeClass(size_class as u8));
+            }
+            state.partial[size_class] = blocks;
+        }
+        meshed
+    }
+
+    /// Mesh pairs of sparse `blocks`, and remove the blocks meshed away from the list.
+    fn mesh_size_class(&self, blocks: &mut Vec<u32>, size_class: SizeClass) -> usize {
+        let max_live =
+            ((BLOCK_BYTES >> size_class.log_bytes()) as f64 * MAX_MESH_OCCUPANCY) as usize;
+        let mut candidates = Vec::new_in(Meta);
+        candidates.extend(
+            blocks
+                .iter()
+                .filter(|b| self.meta(**b).live.count() <= max_live)
+                .map(|b| Some(*b)),
+        );
+        let mut meshed = 0;
+        for i in 0..candidates.len() {
+            let Some(a) = candidates[i] else { continue };
+            let end = candidates.len().min(i + 1 + MESH_PROBES);
+            for j in i + 1..end {
+                let Some(b) = candidates[j] else { continue };
+                let (live_a, live_b) = (&self.meta(a).live, &self.meta(b).live);
+                if !live_a.is_disjoint(live_b) {
+                    continue;
+                }
+                // Copy the fewer cells.
+                let (dst, src) = if live_a.count() >= live_b.count() {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                self.mesh(dst, src, size_class);
+                blocks.retain(|b| *b != src);
+                candidates[i] = None;
+                candidates[j] = None;
+                meshed += 1;
+                break;
+            }
+        }
+        self.meshes.fetch_add(meshed, Ordering::Relaxed);
+        meshed
+    }
+
+    /// Move the live cells of `src` into `dst`, and back all blocks of `src` with the memory of `dst`.
+    ///
+    /// Both blocks are detached, so no cell is allocated meanwhile. Cells may be freed, and the live
+    /// cells of `src` may be written. Writes wait behind the barrier until the new mapping is in place.
+    fn mesh(&self, dst: u32, src: u32, size_class: SizeClass) {
+        let fd = self.fd();
+        let (dst_meta, src_meta) = (self.meta(dst), self.meta(src));
+        self.barrier.close();
+        for block in self.aliases(src) {
+            RawMemory::protect(self.block_start(block), BLOCK_BYTES, false);
+        }
+        let bytes = size_class.bytes();
+        for cell in src_meta.live.iter() {
+            let offset = cell * bytes;
+            debug_assert!(!dst_meta.live.is_set(cell));
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    (self.block_start(src) + offset).as_ptr::<u8>(),
+                    (self.block_start(dst) + offset).as_mut_ptr::<u8>(),
+                    bytes,
+                );
+            }
+        }
+        self.mesh_epoch.fetch_add(1, Ordering::SeqCst);
+        for block in self.aliases(src) {
+            RawMemory::map_shared(self.block_start(block), BLOCK_BYTES, fd, Self::offset(dst))
+                .unwrap();
+            self.meta(block).primary.store(dst, Ordering::Release);
+            self.meta(block).state.store(ALIAS, Ordering::Relaxed);
+        }
+        let last = self.aliases(src).last().unwrap();
+        self.meta(last).next_alias.store(
+            dst_meta.next_alias.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        dst_meta.next_alias.store(src, Ordering::Relaxed);
+        self.barrier.open();
+        // Frees that miss the cells of `src` retry on `dst` until they are moved.
+        src_meta.live.move_into(&dst_meta.live);
+        self.mesh_epoch.fetch_add(1, Ordering::SeqCst);
+        RawMemory::punch_hole(fd, Self::offset(src), BLOCK_BYTES);
+        self.physical_blocks.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Map the arena from `fd`: meshed blocks at the offset of their primary, and all other blocks at their own.
+    ///
+    /// Each page is replaced once, by a mapping of the same contents, so that concurrent reads are unaffected.
+    fn map_arena(&self, fd: RawFd, shared: bool) {
+        let map = |start: Address, bytes: usize, offset: usize| {
+            if shared {
+                RawMemory::map_shared(start, bytes, fd, offset).unwrap();
+            } else {
+                RawMemory::map_private(start, bytes, fd, offset).unwrap();
+            }
+        };
+        let mut run = 0;
+        for block in 0..self.cursor.load(Ordering::Relaxed) {
+            let meta = self.meta(block);
+            if meta.state.load(Ordering::Relaxed) != ALIAS {
+                continue;
+            }
+            if run < block {
+                let bytes = Self::offset(block) - Self::offset(run);
+                map(self.block_start(run), bytes, Self::offset(run));
+            }
+            let primary = meta.primary.load(Ordering::Relaxed);
+            map(self.block_start(block), BLOCK_BYTES, Self::offset(primary));
+            run = block + 1;
+        }
+        map(
+            self.block_start(run),
+            ARENA_BYTES - Self::offset(run),
+            Self::offset(run),
+        );
+    }
+
+    /// Copy the space to a memory file of its own, map it shared again, and free the cells whose frees were
+    /// deferred meanwhile. Writes wait behind the barrier during the copy.
+    ///
+    /// Returns `false` if no file could be created, and the space stays private.
+    fn unshare(&self, state: &mut MeshState) -> bool {
+        let Ok(new_fd) = RawMemory::create_shared_file(c"mesh", ARENA_BYTES) else {
+            return false;
+        };
+        let fd = self.fd();
+        self.barrier.close();
+        RawMemory::protect(self.start, self.used_bytes(), false);
+        let mut cells = [0u8; BLOCK_BYTES];
+        let mut original = [0u8; BLOCK_BYTES];
+        for block in 0..self.cursor.load(Ordering::Relaxed) {
+            let meta = self.meta(block);
+            let block_state = meta.state.load(Ordering::Relaxed);
+            if block_state == FREE || block_state == ALIAS {
+                continue;
+            }
+            let start = self.block_start(block);
+            unsafe {
+                std::ptr::copy_nonoverlapping(start.as_ptr(), cells.as_mut_ptr(), BLOCK_BYTES)
+            };
+            if meta.next_alias.load(Ordering::Relaxed) != NONE {
+                // Nothing wrote to the file since `fork`. A cell is only written through the block its
+                // object was allocated in, so whatever differs from the file in a meshed block is the latest.
+                let read = unsafe {
+                    libc::pread(
+                        fd,
+                        original.as_mut_ptr() as _,
+                        BLOCK_BYTES,
+                        Self::offset(block) as _,
+                    )
+                };
+                assert_eq!(read, BLOCK_BYTES as isize);
+                for alias in self.aliases(block).skip(1) {
+                    let view = unsafe {
+                        std::slice::from_raw_parts(
+                            self.block_start(alias).as_ptr::<u8>(),
+                            BLOCK_BYTES,
+                        )
+                    };
+                    for i in 0..BLOCK_BYTES {
+                        if view[i] != original[i] {
+                            cells[i] = view[i];
+                        }
+                    }
+                }
+            }
+            let written = unsafe {
+                libc::pwrite(
+                    new_fd,
+                    cells.as_ptr() as _,
+                    BLOCK_BYTES,
+                    Self::offset(block) as _,
+                )
+            };
+            assert_eq!(written, BLOCK_BYTES as isize);
+        }
+        self.map_arena(new_fd, true);
+        self.fd.store(new_fd, Ordering::Relaxed);
+        unsafe { libc::close(fd) };
+        let mut next = Address::from(self.deferred_frees.swap(SHARED, Ordering::Acquire));
+        self.barrier.open();
+        while !next.is_zero() {
+            let cell = next;
+            next = unsafe { cell.load() };
+            self.free_deferred(state, cell);
+        }
+        true
+    }
+
+    /// Free a cell whose free was deferred, with the space locked.
+    fn free_deferred(&self, state: &mut MeshState, ptr: Address) {
+        let block = self.block_of(ptr);
+        let primary = self.meta(block).primary.load(Ordering::Relaxed);
+        let meta = self.meta(primary);
+        let cell = (ptr - self.block_start(block)) >> meta.size_class().log_bytes();
+        if meta.live.clear(cell)
+            && meta
+                .state
+                .compare_exchange(UNLISTED, LISTED, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        {
+            state.partial[meta.size_class().as_usize()].push(primary);
+        }
+    }
+
+    /// Lock the space, and make it a private view of the memory file, so that the child and this process
+    /// stop sharing their writes. Nothing is copied, and writes carry on meanwhile.
+    pub fn fork_prepare(&self) {
+        std::mem::forget(self.state.lock());
+        if self.fd() >= 0
+            && self
+                .deferred_frees
+                .compare_exchange(SHARED, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.map_arena(self.fd(), false);
+        }
+    }
+
+    pub fn fork_parent(&self) {
+        unsafe { self.state.force_unlock() }
+    }
+
+    pub fn fork_child(&self) {
+        unsafe { self.state.force_unlock() }
+    }
+
+    /// Called in a forked child. Detach the blocks of all threads other than the forking one, whose
+    /// allocator is `current` if it ever allocated.
+    pub fn reclaim_blocks_after_fork(&self, current: Option<&MeshAllocator>) {
+        for block in 0..self.cursor.load(Ordering::Relaxed) {
+            if self.meta(block).state.load(Ordering::Relaxed) == ATTACHED
+                && !current.is_some_and(|c| c.blocks.contains(&Some(block)))
+            {
+                self.detach_block(block);
+            }
+        }
+    }
+
+    fn used_bytes(&self) -> usize {
+        Self::offset(self.cursor.load(Ordering::Relaxed))
+    }
+}
+
+impl BlockMeta {
+    fn size_class(&self) -> SizeClass {
+        SizeClass(self.size_class.load(Ordering::Relaxed))
+    }
+}
+
+/// Thread-local blocks, one per size class.
+pub struct MeshAllocator {
+    space: &'static MeshSpace,
+    blocks: [Option<u32>; SIZE_CLASSES],
+    /// Spreads allocations over the words of a bitmap, so that blocks are more likely to mesh.
+    seed: usize,
+}
+
+impl MeshAllocator {
+    pub fn new(space: &'static MeshSpace) -> Self {
+        Self {
+            space,
+            blocks: [None; SIZE_CLASSES],
+            seed: space as *const MeshSpace as usize ^ (&space as *const _ as usize),
+        }
+    }
+
+    fn next_hint(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> Option<Address> {
+        let size_class = SizeClass::<4>::from_layout(layout);
+        let hint = self.next_hint();
+        if let Some(block) = self.blocks[size_class.as_usize()] {
+            if let Some(cell) = self.space.alloc_cell(block, hint) {
+                return Some(cell);
+            }
+        }
+        self.alloc_slow(size_class, hint)
+    }
+
+    #[cold]
+    fn alloc_slow(&mut self, size_class: SizeClass, hint: usize) -> Option<Address> {
+        if let Some(block) = self.blocks[size_class.as_usize()].take() {
+            self.space.detach_block(block);
+        }
+        let block = self.space.acquire_block(size_class)?;
+        self.blocks[size_class.as_usize()] = Some(block);
+        self.space.alloc_cell(block, hint)
+    }
+
+    pub fn dealloc(&mut self, ptr: Address) {
+        self.space.free_cell(ptr)
+    }
+
+    pub fn flush(&mut self) {
+        for block in self.blocks.iter_mut().filter_map(Option::take) {
+            self.space.detach_block(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(space: &'static MeshSpace, size_class: SizeClass) -> (u32, std::vec::Vec<Address>) {
+        let block = space.acquire_block(size_class).unwrap();
+        let cells = (0..BLOCK_BYTES >> size_class.log_bytes())
+            .map(|_| space.alloc_cell(block, 0).unwrap())
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(space.alloc_cell(block, 0), None);
+        (block, cells)
+    }
+
+    #[test]
+    fn mesh_disjoint_blocks() {
+        let space = Box::leak(Box::new(MeshSpace::new(SpaceId::LARGE_OBJECT_SPACE.next())));
+        let size_class = SizeClass::<4>::from_bytes(64);
+        let (a, cells_a) = fill(space, size_class);
+        let (b, cells_b) = fill(space, size_class);
+        assert_eq!(space.physical_bytes(), 2 * BLOCK_BYTES);
+        // Keep the even cells of one block and the odd cells of the other, and tag them.
+        let mut live = std::vec::Vec::new();
+        for (i, (x, y)) in cells_a.iter().zip(&cells_b).enumerate() {
+            let (keep, free) = if i % 2 == 0 { (*x, *y) } else { (*y, *x) };
+            space.free_cell(free);
+            unsafe { keep.store(usize::from(keep)) };
+            live.push(keep);
+        }
+        space.detach_block(a);
+        space.detach_block(b);
+        assert_eq!(space.mesh_all(), 1);
+        assert_eq!(space.physical_bytes(), BLOCK_BYTES);
+        // Objects keep their addresses and contents, and both blocks now share their memory.
+        for &cell in &live {
+            assert_eq!(unsafe { cell.load::<usize>() }, usize::from(cell));
+        }
+        unsafe { cells_a[1].store(42usize) };
+        assert_eq!(unsafe { cells_b[1].load::<usize>() }, 42);
+        // Cells are freed through either block, and the memory goes once they are all free.
+        for &cell in &live {
+            space.free_cell(cell);
+        }
+        assert_eq!(space.mesh_all(), 0);
+        assert_eq!(space.physical_bytes(), 0);
+        let (c, _) = fill(space, size_class);
+        assert!(c == a || c == b);
+        assert_eq!(unsafe { space.block_start(a).load::<usize>() }, 0);
+    }
+
+    #[test]
+    fn private_after_fork() {
+        let space = Box::leak(Box::new(MeshSpace::new(
+            SpaceId::LARGE_OBJECT_SPACE.next().next().next().next(),
+        )));
+        let size_class = SizeClass::<4>::from_bytes(64);
+        let (a, cells_a) = fill(space, size_class);
+        let (b, cells_b) = fill(space, size_class);
+        let mut live = std::vec::Vec::new();
+        for (i, (x, y)) in cells_a.iter().zip(&cells_b).enumerate() {
+            let (keep, free) = if i % 2 == 0 { (*x, *y) } else { (*y, *x) };
+            space.free_cell(free);
+            live.push(keep);
+        }
+        space.detach_block(a);
+        space.detach_block(b);
+        assert_eq!(space.mesh_all(), 1);
+        // As if forking. Both blocks now have their own copy-on-write view of the same file pages.
+        space.fork_prepare();
+        space.fork_parent();
+        assert!(space.is_private());
+        for &cell in &live {
+            unsafe { cell.store(usize::from(cell)) };
+        }
+        let (primary, alias) = if space.meta(a).state.load(Ordering::Relaxed) == ALIAS {
+            (b, a)
+        } else {
+            (a, b)
+        };
+        // Frees through the alias are deferred, so that nothing else writes to the cell meanwhile.
+        let freed = *live.iter().find(|c| space.block_of(**c) == alias).unwrap();
+        let index = (freed - space.block_start(alias)) >> size_class.log_bytes();
+        space.free_cell(freed);
+        assert!(space.meta(primary).live.is_set(index));
+        // The next pass copies the space to a file of its own, with the writes through both blocks.
+        assert_eq!(space.mesh_all(), 0);
+        assert!(!space.is_private());
+        assert!(!space.meta(primary).live.is_set(index));
+        for &cell in live.iter().filter(|c| **c != freed) {
+            assert_eq!(unsafe { cell.load::<usize>() }, usize::from(cell));
+        }
+        unsafe { cells_a[1].store(42usize) };
+        assert_eq!(unsafe { cells_b[1].load::<usize>() }, 42);
+    }
+
+    #[test]
+    fn meshing_fills_a_block() {
+        let space = Box::leak(Box::new(MeshSpace::new(
+            SpaceId::LARGE_OBJECT_SPACE.next().next().next(),
+        )));
+        let size_class = SizeClass::<4>::from_bytes(2048);
+        let (a, cells_a) = fill(space, size_class);
+        let (b, cells_b) = fill(space, size_class);
+        for (i, (x, y)) in cells_a.iter().zip(&cells_b).enumerate() {
+            space.free_cell(if i % 2 == 0 { *y } else { *x });
+        }
+        space.detach_block(a);
+        space.detach_block(b);
+        assert_eq!(space.mesh_all(), 1);
+        // The meshed block is full, so threads get a new one.
+        let c = space.acquire_block(size_class).unwrap();
+        assert!(c != a && c != b);
+        assert!(space.state.lock().partial[size_class.as_usize()].is_empty());
+        space.free_cell(cells_a[0]);
+        assert_eq!(space.state.lock().partial[size_class.as_usize()].len(), 1);
+    }
+
+    #[test]
+    fn blocks_sharing_cells_do_not_mesh() {
+        let space = Box::leak(Box::new(MeshSpace::new(
+            SpaceId::LARGE_OBJECT_SPACE.next().next(),
+        )));
+        let size_class = SizeClass::<4>::from_bytes(1024);
+        let (a, cells_a) = fill(space, size_class);
+        let (b, cells_b) = fill(space, size_class);
+        // Both keep their first cell.
+        for cell in cells_a[1..].iter().chain(&cells_b[1..]) {
+            space.free_cell(*cell);
+        }
+        space.detach_block(a);
+        space.detach_block(b);
+        assert_eq!(space.mesh_all(), 0);
+        assert_eq!(space.physical_bytes(), 2 * BLOCK_BYTES);
+        assert_eq!(space.state.lock().partial[size_class.as_usize()].len(), 2);
+        // Full blocks are listed again by their first free.
+        let larger = SizeClass::<4>::from_bytes(2048);
+        let (c, cells_c) = fill(space, larger);
+        space.detach_block(c);
+        assert!(space.state.lock().partial[larger.as_usize()].is_empty());
+        space.free_cell(cells_c[0]);
+        assert_eq!(space.state.lock().partial[larger.as_usize()].len(), 1);
+    }
+}
@@ -0,0 +1,159 @@
+use mallockit::{libc, util::*};
+use spin::Once;
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+const MAX_REGIONS: usize = 16;
+
+struct Region {
+    start: AtomicUsize,
+    end: AtomicUsize,
+    closed: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED: Region = Region {
+    start: AtomicUsize::new(0),
+    end: AtomicUsize::new(0),
+    closed: AtomicBool::new(false),
+};
+
+static REGIONS: [Region; MAX_REGIONS] = [UNUSED; MAX_REGIONS];
+static NEXT_REGION: AtomicUsize = AtomicUsize::new(0);
+static PREVIOUS_HANDLER: Once<libc::sigaction> = Once::new();
+
+/// The address that faulted the last time this thread found its region open.
+#[thread_local]
+static mut RETRIED: usize = 0;
+
+/// Makes threads that write to read-only pages of a region wait until the region is open again.
+///
+/// Pages are made read-only while their contents move, with `RawMemory::protect` or by a shared mapping that
+/// is replaced afterwards. A write to them raises `SIGSEGV`. The handler waits for the region to open, and
+/// returns to retry the write on the new mapping. Faults anywhere else go to the previous handler.
+///
+/// Only writes by the program itself fault. A system call that writes to a read-only page, e.g. a `read`
+/// into a buffer, fails with `EFAULT` instead of waiting. And a `SIGSEGV` handler installed after the
+/// barrier replaces it, so that writes during a move reach that handler.
+///
+/// Regions are only closed with their space locked, and `fork` locks the space first. So no process
+/// starts with a closed region.
+pub struct WriteBarrier {
+    region: &'static Region,
+}
+
+impl WriteBarrier {
+    pub fn new(range: Range<Address>) -> Self {
+        let index = NEXT_REGION.fetch_add(1, Ordering::Relaxed);
+        assert!(index < MAX_REGIONS, "too many write barriers");
+        let region = &REGIONS[index];
+        region
+            .start
+            .store(usize::from(range.start), Ordering::Relaxed);
+        region.end.store(usize::from(range.end), Ordering::Release);
+        PREVIOUS_HANDLER.call_once(install_handler);
+        Self { region }
+    }
+
+    /// Start blocking writes. Call before making pages read-only.
+    pub fn close(&self) {
+        self.region.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Let blocked writes retry. Call once the pages are writable again.
+    pub fn open(&self) {
+        self.region.closed.store(false, Ordering::SeqCst);
+    }
+}
+
+fn install_handler() -> libc::sigaction {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_segv as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGSEGV, &action, &mut previous);
+        previous
+    }
+}
+
+extern "C" fn handle_segv(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    let region = REGIONS[..NEXT_REGION.load(Ordering::Acquire).min(MAX_REGIONS)]
+        .iter()
+        .find(|r| r.start.load(Ordering::Relaxed) <= addr && addr < r.end.load(Ordering::Relaxed));
+    if let Some(region) = region {
+        if region.closed.load(Ordering::SeqCst) {
+            while region.closed.load(Ordering::SeqCst) {
+                unsafe { libc::sched_yield() };
+            }
+            unsafe { RETRIED = 0 };
+            return;
+        }
+        // The region may have opened just before the handler ran. Retry once.
+        if unsafe { RETRIED } != addr {
+            unsafe { RETRIED = addr };
+            return;
+        }
+    }
+    forward(signal, info, context);
+}
+
+fn forward(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let previous = PREVIOUS_HANDLER.get().unwrap();
+    unsafe {
+        if previous.sa_sigaction == libc::SIG_DFL || previous.sa_sigaction == libc::SIG_IGN {
+            // Returning raises the fault again, now with the default action.
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = libc::SIG_DFL;
+            libc::sigaction(libc::SIGSEGV, &action, std::ptr::null_mut());
+        } else if previous.sa_flags & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                std::mem::transmute(previous.sa_sigaction);
+            handler(signal, info, context);
+        } else {
+            let handler: extern "C" fn(libc::c_int) = std::mem::transmute(previous.sa_sigaction);
+            handler(signal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mallockit::util::sys::RawMemory;
+    use std::time::Duration;
+
+    #[test]
+    fn writes_wait_and_system_calls_fail() {
+        let start = RawMemory::map_anonymous(Size4K::BYTES).unwrap();
+        let barrier = WriteBarrier::new(start..start + Size4K::BYTES);
+        barrier.close();
+        RawMemory::protect(start, Size4K::BYTES, false);
+        let addr = usize::from(start);
+        let writer = std::thread::spawn(move || unsafe { Address::from(addr).store(42usize) });
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        // The kernel does not raise `SIGSEGV` for the write of a system call.
+        let zero = unsafe { libc::open(c"/dev/zero".as_ptr(), libc::O_RDONLY) };
+        assert!(zero >= 0);
+        let read = unsafe { libc::read(zero, start.as_mut_ptr(), 8) };
+        assert_eq!(read, -1);
+        assert_eq!(
+            std::io::Error::last_os_error().raw_os_error(),
+            Some(libc::EFAULT)
+        );
+        unsafe { libc::close(zero) };
+        RawMemory::protect(start, Size4K::BYTES, true);
+        barrier.open();
+        writer.join().unwrap();
+        assert_eq!(unsafe { start.load::<usize>() }, 42);
+    }
+}
@@ -0,0 +1,199 @@
+use mallockit::util::{
+    mem::freelist::{tlsf_freelist::TlsfFreeList, FreeListEngine},
+    *,
+};
+use std::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A thread's TLSF heap, carved out of the pool.
+///
+/// Arenas are aligned to their size, so a cell finds its arena from its address. Only the owning thread
+/// allocates from and frees into the TLSF lists. Other threads push the cells they free onto
+/// `remote_frees`, which the owner takes over a few cells at a time. Cells taken but not freed yet stay
+/// with the arena, for its next owner if it is given up.
+#[repr(C)]
+pub struct Arena {
+    tlsf: UnsafeCell<TlsfFreeList>,
+    /// Cells freed by other threads, linked through their first word.
+    remote_frees: AtomicUsize,
+    /// Cells taken from `remote_frees` but not freed yet.
+    taken_frees: UnsafeCell<Address>,
+    /// The owning mutator, or zero while the arena is in the pool.
+    owner: AtomicUsize,
+    /// The next arena in the pool's free list.
+    pub(crate) next: AtomicUsize,
+}
+
+unsafe impl Sync for Arena {}
+
+impl Arena {
+    pub const LOG_BYTES: usize = 22;
+    pub const BYTES: usize = 1 << Self::LOG_BYTES;
+    /// Larger objects would quickly fragment arenas, and go to the large object space.
+    pub const MAX_CELL_BYTES: usize = Self::BYTES / 4;
+    const HEADER_BYTES: usize =
+        std::mem::size_of::<Self>().next_multiple_of(TlsfFreeList::MIN_ALIGNMENT);
+
+    pub const fn can_allocate(layout: Layout) -> bool {
+        layout.size() <= Self::MAX_CELL_BYTES && layout.align() <= Self::MAX_CELL_BYTES
+    }
+
+    pub const fn good_size(layout: Layout) -> usize {
+        TlsfFreeList::good_size(layout.size())
+    }
+
+    /// The log of a free block size that always has room for `layout`.
+    pub const fn fit_log(layout: Layout) -> usize {
+        TlsfFreeList::fit_log(layout.size(), layout.align())
+    }
+
+    pub fn get_layout(ptr: Address) -> Layout {
+        let bytes = TlsfFreeList::cell_bytes(ptr);
+        unsafe { Layout::from_size_align_unchecked(bytes, TlsfFreeList::MIN_ALIGNMENT) }
+    }
+
+    pub fn of(ptr: Address) -> &'static Self {
+        let start = Address::from(usize::from(ptr) & !(Self::BYTES - 1));
+        unsafe { start.as_ref() }
+    }
+
+    /// Set up an arena over the populated memory at `start`, owned by `owner`.
+    ///
+    /// # Safety
+    ///
+    /// `start` must be aligned to `BYTES`, and the arena memory must not be in use.
+    pub unsafe fn init(start: Address, owner: usize) -> &'static Self {
+        debug_assert!(start.is_aligned_to(Self::BYTES));
+        let arena = start.as_mut_ptr::<Self>();
+        arena.write(Self {
+            tlsf: UnsafeCell::new(TlsfFreeList::new()),
+            remote_frees: AtomicUsize::new(0),
+            taken_frees: UnsafeCell::new(Address::ZERO),
+            owner: AtomicUsize::new(owner),
+            next: AtomicUsize::new(0),
+        });
+        let arena = &*arena;
+        arena
+            .tlsf()
+            .add_memory(start + Self::HEADER_BYTES..start + Self::BYTES);
+        arena
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn tlsf(&self) -> &mut TlsfFreeList {
+        unsafe { &mut *self.tlsf.get() }
+    }
+
+    pub fn start(&self) -> Address {
+        Address::from(self as *const Self)
+    }
+
+    /// The log of a size that the largest free block reaches. Only called by the owner, or by the pool.
+    pub fn largest_free_log(&self) -> Option<usize> {
+        self.tlsf().largest_free_log()
+    }
+
+    pub fn owner(&self) -> usize {
+        self.owner.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_owner(&self, owner: usize) {
+        self.owner.store(owner, Ordering::Relaxed);
+    }
+
+    /// Allocate a cell. Only called by the owner, or by the pool while the arena has no owner.
+    pub fn alloc(&self, layout: Layout) -> Option<Address> {
+        let cell = if layout.align() <= TlsfFreeList::MIN_ALIGNMENT {
+            self.tlsf().allocate_cell(layout.size())?
+        } else {
+            self.tlsf()
+                .allocate_aligned_cell(layout.size(), layout.align())?
+        };
+        Some(cell.start)
+    }
+
+    /// Free a cell of this arena. Only called by the owner, or by the pool while the arena has no owner.
+    pub fn release(&self, ptr: Address) {
+        debug_assert!(std::ptr::eq(Self::of(ptr), self));
+        self.tlsf().release_cell(ptr, 0);
+    }
+
+    /// Free a cell of this arena from any thread. Lock-free.
+    pub fn push_remote(&self, ptr: Address) {
+        let mut head = self.remote_frees.load(Ordering::Relaxed);
+        loop {
+            unsafe { ptr.store(head) };
+            match self.remote_frees.compare_exchange_weak(
+                head,
+                usize::from(ptr),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Free up to `limit` of the cells freed by other threads, so that no call takes unbounded time.
+    /// Only called by the owner, or by the pool while the arena has no owner.
+    pub fn release_remote(&self, limit: usize) {
+        let cells = unsafe { &mut *self.taken_frees.get() };
+        if cells.is_zero() {
+            *cells = Address::from(self.remote_frees.swap(0, Ordering::Acquire));
+        }
+        for _ in 0..limit {
+            if cells.is_zero() {
+                return;
+            }
+            let cell = *cells;
+            *cells = unsafe { cell.load::<Address>() };
+            self.release(cell);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mallockit::util::sys::RawMemory;
+
+    fn taken_frees(arena: &Arena) -> usize {
+        let mut cells = unsafe { *arena.taken_frees.get() };
+        let mut count = 0;
+        while !cells.is_zero() {
+            cells = unsafe { cells.load() };
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn bounded_remote_frees() {
+        let memory = RawMemory::map_anonymous(2 * Arena::BYTES).unwrap();
+        let arena = unsafe { Arena::init(memory.align_up(Arena::BYTES), 1) };
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let cells = (0..1000)
+            .map(|_| arena.alloc(layout).unwrap())
+            .collect::<std::vec::Vec<_>>();
+        for &cell in &cells {
+            arena.push_remote(cell);
+        }
+        // Each call frees at most `limit` cells, and leaves the rest for the next one.
+        arena.release_remote(4);
+        assert_eq!(taken_frees(arena), 996);
+        // Cells freed meanwhile are only taken once the earlier ones are freed.
+        let cell = arena.alloc(layout).unwrap();
+        arena.push_remote(cell);
+        arena.release_remote(1000);
+        assert_eq!(taken_frees(arena), 0);
+        assert_eq!(
+            arena.remote_frees.load(Ordering::Relaxed),
+            usize::from(cell)
+        );
+        arena.release_remote(1);
+        assert_eq!(arena.remote_frees.load(Ordering::Relaxed), 0);
+    }
+}
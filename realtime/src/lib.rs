@@ -0,0 +1,256 @@
+#![feature(thread_local)]
+#![feature(allocator_api)]
+#![feature(naked_functions)]
+
+extern crate mallockit;
+
+mod arena;
+mod pool;
+
+use arena::Arena;
+use mallockit::{
+    space::{large_object_space::*, page_resource::PrefaultPolicy, *},
+    util::*,
+    Mutator, Plan,
+};
+use pool::Pool;
+pub use pool::{KernelEntryPolicy, PoolConfig};
+
+const POOL: SpaceId = SpaceId::DEFAULT;
+const LARGE_OBJECT_SPACE: SpaceId = SpaceId::LARGE_OBJECT_SPACE;
+/// Cells freed by other threads that are taken back on each allocation.
+const REMOTE_FREES_PER_ALLOC: usize = 4;
+/// Cells freed by other threads that are taken back before an arena is given up, or by the pool before
+/// handing an arena over.
+const REMOTE_FREES_PER_REFILL: usize = 256;
+
+/// A plan for real-time threads: allocation and free take bounded time, and make no syscalls as long as
+/// the pool populated at startup lasts.
+///
+/// Each thread allocates from its own arena with a TLSF free list. Frees from other threads are pushed
+/// onto the arena lock-free, and taken back by the owner a few at a time. Leaving the pool, for a new arena
+/// or a large object, is a kernel entry handled by `KernelEntryPolicy`.
+#[mallockit::plan]
+struct Realtime {
+    pool: Pool,
+    large_object_space: LargeObjectSpace,
+}
+
+impl Plan for Realtime {
+    type Mutator = RealtimeMutator;
+
+    fn new() -> Self {
+        Self {
+            pool: Pool::new(POOL, PoolConfig::from_env()),
+            large_object_space: LargeObjectSpace::new(LARGE_OBJECT_SPACE)
+                .with_prefault_policy(PrefaultPolicy::OnAcquire),
+        }
+    }
+
+    fn get_layout(ptr: Address) -> Layout {
+        debug_assert!(POOL.contains(ptr) || LARGE_OBJECT_SPACE.contains(ptr));
+        if POOL.contains(ptr) {
+            Arena::get_layout(ptr)
+        } else {
+            Self::get().large_object_space.get_layout::<Size4K>(ptr)
+        }
+    }
+
+    fn good_size(layout: Layout) -> usize {
+        if Arena::can_allocate(layout) {
+            Arena::good_size(layout)
+        } else {
+            LargeObjectSpace::good_size::<Size4K>(layout)
+        }
+    }
+
+    fn fork_prepare(&'static self) {
+        self.pool.fork_prepare();
+        self.large_object_space.fork_prepare();
+    }
+
+    fn fork_parent(&'static self) {
+        self.large_object_space.fork_release();
+        self.pool.fork_release();
+    }
+
+    fn fork_child(&'static self) {
+        self.fork_parent();
+        let current = RealtimeMutator::try_current().map(|m| m.id());
+        self.pool.abandon_arenas_after_fork(current);
+    }
+}
+
+/// Number of allocations that left the pool populated at startup, for applications to check.
+#[no_mangle]
+pub extern "C" fn realtime_kernel_entries() -> usize {
+    Realtime::get().pool.kernel_entries()
+}
+
+#[mallockit::mutator]
+struct RealtimeMutator {
+    arena: Option<&'static Arena>,
+    /// Times this thread needed a new arena.
+    arena_refills: usize,
+    los: LargeObjectAllocator<Size4K>,
+}
+
+impl RealtimeMutator {
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Give up the arena. Cells freed into it by other threads are taken back by its next owner.
+    fn release_arena(&mut self) {
+        if let Some(arena) = self.arena.take() {
+            Self::plan().pool.release(arena);
+        }
+    }
+
+    #[cold]
+    fn alloc_slow(&mut self, layout: Layout) -> Option<Address> {
+        if let Some(arena) = self.arena {
+            arena.release_remote(REMOTE_FREES_PER_REFILL);
+            if let Some(ptr) = arena.alloc(layout) {
+                return Some(ptr);
+            }
+            self.release_arena();
+        }
+        self.arena_refills += 1;
+        let (arena, ptr) = Self::plan().pool.acquire(self.id(), layout)?;
+        self.arena = Some(arena);
+        Some(ptr)
+    }
+
+    #[cold]
+    fn alloc_large(&mut self, layout: Layout) -> Option<Address> {
+        Self::plan().pool.enter_kernel("large object");
+        mallockit::stat::track_allocation(layout, true);
+        self.los.alloc(layout)
+    }
+}
+
+impl Mutator for RealtimeMutator {
+    type Plan = Realtime;
+
+    fn new() -> Self {
+        Self {
+            arena: None,
+            arena_refills: 0,
+            los: LargeObjectAllocator::new(&Self::plan().large_object_space),
+        }
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Option<Address> {
+        if !Arena::can_allocate(layout) {
+            return self.alloc_large(layout);
+        }
+        mallockit::stat::track_allocation(layout, false);
+        if let Some(arena) = self.arena {
+            arena.release_remote(REMOTE_FREES_PER_ALLOC);
+            if let Some(ptr) = arena.alloc(layout) {
+                return Some(ptr);
+            }
+        }
+        self.alloc_slow(layout)
+    }
+
+    fn dealloc(&mut self, ptr: Address) {
+        debug_assert!(POOL.contains(ptr) || LARGE_OBJECT_SPACE.contains(ptr));
+        if LARGE_OBJECT_SPACE.contains(ptr) {
+            mallockit::stat::track_deallocation(true);
+            return self.los.dealloc(ptr);
+        }
+        mallockit::stat::track_deallocation(false);
+        let arena = Arena::of(ptr);
+        match self.arena {
+            Some(current) if std::ptr::eq(current, arena) => arena.release(ptr),
+            _ => arena.push_remote(ptr),
+        }
+    }
+
+    fn on_thread_exit(&mut self) {
+        self.release_arena();
+        self.los.flush();
+    }
+}
+
+#[cfg(test)]
+mod thread_exit_tests {
+    mallockit::rust_allocator_tests!(crate::Global, short_lived_threads);
+}
+
+#[cfg(test)]
+mod cross_thread_tests {
+    mallockit::rust_allocator_tests!(crate::Global, producer_consumer);
+}
+
+#[cfg(test)]
+mod latency_tests {
+    use super::*;
+    use std::alloc::GlobalAlloc;
+    use std::time::Instant;
+
+    const OPERATIONS: usize = 200_000;
+    const LIVE_OBJECTS: usize = 1024;
+
+    /// Log2 nanosecond buckets.
+    struct Histogram([usize; 32]);
+
+    impl Histogram {
+        fn record(&mut self, nanos: u128) {
+            let bucket = (u128::BITS - nanos.leading_zeros()) as usize;
+            self.0[bucket.min(31)] += 1;
+        }
+
+        /// Upper bound, in nanoseconds, of the bucket holding the `p`th percentile.
+        fn percentile(&self, p: f64) -> u128 {
+            let total = self.0.iter().sum::<usize>();
+            let mut seen = 0;
+            for (bucket, count) in self.0.iter().enumerate() {
+                seen += count;
+                if seen as f64 >= total as f64 * p / 100.0 {
+                    return 1 << bucket;
+                }
+            }
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn latency_histogram() {
+        let mut objects = std::vec![(Address::ZERO, Layout::new::<u8>()); LIVE_OBJECTS];
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let mut next_layout = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let size = usize::max((seed as usize % 8192) >> (seed >> 60), 1);
+            Layout::from_size_align(size, 8).unwrap()
+        };
+        // Warm up, so that the thread owns an arena large enough for the live set.
+        for object in &mut objects {
+            let layout = next_layout();
+            *object = (Address::from(unsafe { Global.alloc(layout) }), layout);
+        }
+        let refills = RealtimeMutator::current().arena_refills;
+        let mut histogram = Histogram([0; 32]);
+        for i in 0..OPERATIONS {
+            let (ptr, layout) = objects[i % LIVE_OBJECTS];
+            let new_layout = next_layout();
+            let t = Instant::now();
+            unsafe { Global.dealloc(ptr.as_mut_ptr(), layout) };
+            let new_ptr = unsafe { Global.alloc(new_layout) };
+            histogram.record(t.elapsed().as_nanos());
+            assert!(!new_ptr.is_null());
+            objects[i % LIVE_OBJECTS] = (Address::from(new_ptr), new_layout);
+        }
+        for (ptr, layout) in objects {
+            unsafe { Global.dealloc(ptr.as_mut_ptr(), layout) };
+        }
+        assert_eq!(histogram.0.iter().sum::<usize>(), OPERATIONS);
+        // The live set never outgrows the arena, so no operation left it.
+        assert_eq!(RealtimeMutator::current().arena_refills, refills);
+        assert!(histogram.percentile(99.0) <= 64 << 10);
+    }
+}
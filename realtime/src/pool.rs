@@ -0,0 +1,312 @@
+use crate::{arena::Arena, REMOTE_FREES_PER_REFILL};
+use mallockit::{
+    libc,
+    space::SpaceId,
+    util::{mem::heap::HEAP, sys::RawMemory, *},
+};
+use spin::Mutex;
+use std::{
+    ffi::CStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// What to do when an allocation has to leave the pre-faulted pool and may enter the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelEntryPolicy {
+    /// Count it and carry on.
+    Allow,
+    /// Count it, and print a warning the first time.
+    Report,
+    /// Print the reason and abort.
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    /// Bytes of arenas reserved and populated at startup.
+    pub bytes: usize,
+    pub kernel_entry: KernelEntryPolicy,
+}
+
+impl PoolConfig {
+    pub const DEFAULT_BYTES: usize = 32 << 20;
+
+    /// Read `REALTIME_POOL_SIZE` (bytes, with an optional `K`, `M` or `G` suffix) and
+    /// `REALTIME_KERNEL_ENTRY` (`allow`, `report` or `abort`).
+    ///
+    /// Uses `getenv` directly, as this runs inside the first `malloc`.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(bytes) = getenv(c"REALTIME_POOL_SIZE").and_then(parse_bytes) {
+            config.bytes = bytes;
+        }
+        match getenv(c"REALTIME_KERNEL_ENTRY") {
+            Some(b"report") => config.kernel_entry = KernelEntryPolicy::Report,
+            Some(b"abort") => config.kernel_entry = KernelEntryPolicy::Abort,
+            _ => {}
+        }
+        config
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            bytes: Self::DEFAULT_BYTES,
+            kernel_entry: KernelEntryPolicy::Allow,
+        }
+    }
+}
+
+fn getenv(name: &CStr) -> Option<&'static [u8]> {
+    let value = unsafe { libc::getenv(name.as_ptr()) };
+    if value.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(value) }.to_bytes())
+}
+
+fn parse_bytes(value: &[u8]) -> Option<usize> {
+    let (digits, shift) = match value.last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 10),
+        b'm' | b'M' => (&value[..value.len() - 1], 20),
+        b'g' | b'G' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    let n = std::str::from_utf8(digits).ok()?.parse::<usize>().ok()?;
+    n.checked_mul(1 << shift)
+}
+
+/// The owner of arenas left behind by threads that do not exist after `fork`.
+const ABANDONED: usize = usize::MAX;
+
+/// Free arenas are binned by `Arena::largest_free_log`.
+const BINS: usize = Arena::LOG_BYTES;
+
+struct PoolState {
+    /// Arenas without an owner, linked through `Arena::next`, by the log of their largest free block.
+    free: [Address; BINS],
+    /// The non-empty bins of `free`.
+    bins: u32,
+    /// Arenas are carved out of the space up to here.
+    cursor: Address,
+}
+
+impl PoolState {
+    fn push(&mut self, arena: &'static Arena) {
+        let bin = usize::min(arena.largest_free_log().unwrap_or(0), BINS - 1);
+        arena
+            .next
+            .store(usize::from(self.free[bin]), Ordering::Relaxed);
+        self.free[bin] = arena.start();
+        self.bins |= 1 << bin;
+    }
+
+    fn pop(&mut self, bin: usize) -> &'static Arena {
+        let arena = unsafe { self.free[bin].as_ref::<Arena>() };
+        self.free[bin] = Address::from(arena.next.load(Ordering::Relaxed));
+        if self.free[bin].is_zero() {
+            self.bins &= !(1 << bin);
+        }
+        arena
+    }
+
+    /// The smallest bin from `fit_log` up. Failing that, the largest bin below it, as cells freed into
+    /// its arenas since they were binned may have made room.
+    fn find(&self, fit_log: usize) -> Option<usize> {
+        let below = 1u32
+            .checked_shl(fit_log as u32)
+            .unwrap_or(0)
+            .wrapping_sub(1);
+        let fits = self.bins & !below;
+        if fits != 0 {
+            return Some(fits.trailing_zeros() as usize);
+        }
+        (self.bins != 0).then(|| self.bins.ilog2() as usize)
+    }
+}
+
+/// Arenas for all threads, carved out of a region populated at startup.
+///
+/// Threads only come here when they start allocating, and when their arena runs out of memory.
+/// Once the populated region is used up, further arenas and all large objects are kernel entries.
+pub struct Pool {
+    start: Address,
+    reserved_end: Address,
+    state: Mutex<PoolState>,
+    kernel_entry: KernelEntryPolicy,
+    kernel_entries: AtomicUsize,
+}
+
+impl Pool {
+    pub fn new(id: SpaceId, config: PoolConfig) -> Self {
+        let start = HEAP.get_space_range(id).start;
+        let bytes = config.bytes.next_multiple_of(Arena::BYTES);
+        RawMemory::populate(start, bytes);
+        Self {
+            start,
+            reserved_end: start + bytes,
+            state: Mutex::new(PoolState {
+                free: [Address::ZERO; BINS],
+                bins: 0,
+                cursor: start,
+            }),
+            kernel_entry: config.kernel_entry,
+            kernel_entries: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocations that left the populated pool so far.
+    pub fn kernel_entries(&self) -> usize {
+        self.kernel_entries.load(Ordering::Relaxed)
+    }
+
+    #[cold]
+    pub fn enter_kernel(&self, reason: &str) {
+        let entries = self.kernel_entries.fetch_add(1, Ordering::Relaxed);
+        match self.kernel_entry {
+            KernelEntryPolicy::Allow => {}
+            KernelEntryPolicy::Report => {
+                if entries == 0 {
+                    mallockit::eprintln!("[realtime] kernel entry: {}", reason);
+                }
+            }
+            KernelEntryPolicy::Abort => {
+                mallockit::eprintln!("[realtime] kernel entry: {}", reason);
+                std::process::abort();
+            }
+        }
+    }
+
+    /// Find an arena with room for `layout`, hand it to `owner`, and allocate from it.
+    ///
+    /// Arenas given up by other threads are tried first, after taking back up to `REMOTE_FREES_PER_REFILL`
+    /// of the cells freed into them since, outside the pool lock. Only one of them is tried, from the bin
+    /// of the smallest arenas that are known to have room.
+    pub fn acquire(&self, owner: usize, layout: Layout) -> Option<(&'static Arena, Address)> {
+        let arena = {
+            let mut state = self.state.lock();
+            let bin = state.find(Arena::fit_log(layout));
+            bin.map(|bin| state.pop(bin))
+        };
+        if let Some(arena) = arena {
+            arena.release_remote(REMOTE_FREES_PER_REFILL);
+            if let Some(ptr) = arena.alloc(layout) {
+                arena.set_owner(owner);
+                return Some((arena, ptr));
+            }
+            self.state.lock().push(arena);
+        }
+        let mut state = self.state.lock();
+        let start = state.cursor;
+        if start + Arena::BYTES > self.reserved_end {
+            self.enter_kernel("the pool is exhausted");
+            RawMemory::populate(start, Arena::BYTES);
+        }
+        state.cursor = start + Arena::BYTES;
+        let arena = unsafe { Arena::init(start, owner) };
+        let ptr = arena.alloc(layout)?;
+        Some((arena, ptr))
+    }
+
+    /// Give up an arena. Its free memory and live cells stay in it for the next owner.
+    pub fn release(&self, arena: &'static Arena) {
+        arena.set_owner(0);
+        self.state.lock().push(arena);
+    }
+
+    /// Abandon the arenas of threads that do not exist in a forked child.
+    ///
+    /// Their owners may have been halfway through updating the TLSF lists, so the arenas are never reused.
+    /// Cells the child frees into them stay on their remote lists. `current` owns the arena of the forking
+    /// thread, if it has one.
+    pub fn abandon_arenas_after_fork(&self, current: Option<usize>) {
+        let cursor = self.state.lock().cursor;
+        let mut start = self.start;
+        while start < cursor {
+            let arena = unsafe { start.as_ref::<Arena>() };
+            if arena.owner() != 0 && Some(arena.owner()) != current {
+                arena.set_owner(ABANDONED);
+            }
+            start += Arena::BYTES;
+        }
+    }
+
+    pub fn fork_prepare(&self) {
+        std::mem::forget(self.state.lock());
+    }
+
+    pub fn fork_release(&self) {
+        unsafe { self.state.force_unlock() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pool_size() {
+        assert_eq!(parse_bytes(b"4096"), Some(4096));
+        assert_eq!(parse_bytes(b"16M"), Some(16 << 20));
+        assert_eq!(parse_bytes(b"1g"), Some(1 << 30));
+        assert_eq!(parse_bytes(b"M"), None);
+        assert_eq!(parse_bytes(b"lots"), None);
+    }
+
+    #[test]
+    fn exhausted_pool() {
+        let pool = Pool::new(
+            SpaceId::LARGE_OBJECT_SPACE.next(),
+            PoolConfig {
+                bytes: Arena::BYTES,
+                kernel_entry: KernelEntryPolicy::Report,
+            },
+        );
+        let layout = Layout::from_size_align(Arena::MAX_CELL_BYTES, 8).unwrap();
+        let (a, x) = pool.acquire(1, layout).unwrap();
+        assert_eq!(pool.kernel_entries(), 0);
+        // Another owner can only get a second arena from outside the populated pool.
+        let (b, y) = pool.acquire(2, layout).unwrap();
+        assert_eq!(pool.kernel_entries(), 1);
+        assert_ne!(a.start(), b.start());
+        b.release(y);
+        pool.release(b);
+        // Arenas given up are reused, along with the cells freed into them from other threads.
+        a.push_remote(x);
+        pool.release(a);
+        let (c, _) = pool
+            .acquire(3, Layout::from_size_align(Arena::BYTES / 2, 8).unwrap())
+            .unwrap();
+        assert_eq!(c.start(), a.start());
+        assert_eq!(c.owner(), 3);
+        assert_eq!(pool.kernel_entries(), 1);
+    }
+
+    #[test]
+    fn binned_arenas() {
+        let pool = Pool::new(
+            SpaceId::LARGE_OBJECT_SPACE.next().next(),
+            PoolConfig {
+                bytes: 2 * Arena::BYTES,
+                kernel_entry: KernelEntryPolicy::Report,
+            },
+        );
+        let large = Layout::from_size_align(Arena::MAX_CELL_BYTES, 8).unwrap();
+        let (a, _) = pool.acquire(1, large).unwrap();
+        let (b, _) = pool.acquire(2, large).unwrap();
+        for _ in 0..2 {
+            b.alloc(large).unwrap();
+        }
+        pool.release(a);
+        pool.release(b);
+        // `b` was given up last, but only `a` has room for another large cell.
+        let (c, _) = pool.acquire(3, large).unwrap();
+        assert_eq!(c.start(), a.start());
+        // Small cells come from the fullest arena that has room.
+        let small = Layout::from_size_align(64, 8).unwrap();
+        let (d, _) = pool.acquire(4, small).unwrap();
+        assert_eq!(d.start(), b.start());
+        assert_eq!(pool.kernel_entries(), 0);
+    }
+}
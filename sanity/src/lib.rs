@@ -1,5 +1,6 @@
 #![feature(thread_local)]
 #![feature(allocator_api)]
+#![feature(naked_functions)]
 
 extern crate mallockit;
 
@@ -29,6 +30,18 @@ impl Plan for Sanity {
         debug_assert!(LARGE_OBJECT_SPACE.contains(ptr));
         Self::get().large_object_space.get_layout::<Size4K>(ptr)
     }
+
+    fn good_size(layout: Layout) -> usize {
+        LargeObjectSpace::good_size::<Size4K>(layout)
+    }
+
+    fn fork_prepare(&'static self) {
+        self.large_object_space.fork_prepare();
+    }
+
+    fn fork_parent(&'static self) {
+        self.large_object_space.fork_release();
+    }
 }
 
 #[mallockit::mutator]
@@ -53,4 +66,13 @@ impl Mutator for SanityMutator {
         debug_assert!(LARGE_OBJECT_SPACE.contains(ptr));
         self.los.dealloc(ptr)
     }
+
+    fn on_thread_exit(&mut self) {
+        self.los.flush();
+    }
+}
+
+#[cfg(test)]
+mod thread_exit_tests {
+    mallockit::rust_allocator_tests!(crate::Global, short_lived_threads);
 }